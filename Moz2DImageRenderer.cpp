@@ -338,7 +338,7 @@ struct Reader {
   layers::BlobFont ReadBlobFont() { return Read<layers::BlobFont>(); }
 };
 
-static bool Moz2DRenderCallback(const Range<const uint8_t> aBlob,
+static bool Moz2DRenderCallback(const Range<const WrBlobItem> aItems,
                                 gfx::SurfaceFormat aFormat,
                                 const mozilla::wr::DeviceIntRect* aVisibleRect,
                                 const mozilla::wr::LayoutIntRect* aRenderRect,
@@ -379,16 +379,6 @@ static bool Moz2DRenderCallback(const Range<const uint8_t> aBlob,
     return false;
   }
 
-  // We try hard to not have empty blobs but we can end up with
-  // them because of CompositorHitTestInfo and merging.
-  size_t footerSize = sizeof(size_t);
-  MOZ_RELEASE_ASSERT(aBlob.length() >= footerSize);
-  size_t indexOffset = ConvertFromBytes<size_t>(aBlob.end().get() - footerSize);
-
-  MOZ_RELEASE_ASSERT(indexOffset <= aBlob.length() - footerSize);
-  Reader reader(aBlob.begin().get() + indexOffset,
-                aBlob.length() - footerSize - indexOffset);
-
   auto bounds = gfx::IntRect(origin, size);
 
   if (aDirtyRect) {
@@ -401,38 +391,36 @@ static bool Moz2DRenderCallback(const Range<const uint8_t> aBlob,
   }
 
   bool ret = true;
-  size_t offset = 0;
   auto absBounds = IntRectAbsolute::FromRect(bounds);
-  while (reader.pos < reader.len) {
-    size_t end = reader.ReadSize();
-    size_t extra_end = reader.ReadSize();
-    MOZ_RELEASE_ASSERT(extra_end >= end);
-    MOZ_RELEASE_ASSERT(extra_end < aBlob.length());
-
-    auto combinedBounds = absBounds.Intersect(reader.ReadBounds());
+  // The items all intersect the tile, but not necessarily the dirty rect.
+  for (const WrBlobItem& item : aItems) {
+    auto combinedBounds = absBounds.Intersect(IntRectAbsolute(
+        item.bounds.min.x, item.bounds.min.y, item.bounds.max.x,
+        item.bounds.max.y));
     if (combinedBounds.IsEmpty()) {
-      offset = extra_end;
       continue;
     }
 
     layers::WebRenderTranslator translator(dt);
-    Reader fontReader(aBlob.begin().get() + end, extra_end - end);
-    size_t count = fontReader.ReadSize();
-    for (size_t i = 0; i < count; i++) {
-      layers::BlobFont blobFont = fontReader.ReadBlobFont();
-      RefPtr<ScaledFont> scaledFont =
-          GetScaledFont(&translator, blobFont.mFontInstanceKey);
-      translator.AddScaledFont(blobFont.mScaledFontPtr, scaledFont);
+    // Items that aren't recorded have no extra data at all.
+    if (item.extra_data.len > 0) {
+      Reader fontReader(item.extra_data.buffer, item.extra_data.len);
+      size_t count = fontReader.ReadSize();
+      for (size_t i = 0; i < count; i++) {
+        layers::BlobFont blobFont = fontReader.ReadBlobFont();
+        RefPtr<ScaledFont> scaledFont =
+            GetScaledFont(&translator, blobFont.mFontInstanceKey);
+        translator.AddScaledFont(blobFont.mScaledFontPtr, scaledFont);
+      }
     }
 
-    Range<const uint8_t> blob(aBlob.begin() + offset, aBlob.begin() + end);
-    ret =
-        translator.TranslateRecording((char*)blob.begin().get(), blob.length());
+    Range<const uint8_t> commands = ByteSliceToRange(item.commands);
+    ret = translator.TranslateRecording((char*)commands.begin().get(),
+                                        commands.length());
     if (!ret) {
       gfxCriticalNote << "Replay failure: " << translator.GetError();
       MOZ_RELEASE_ASSERT(false);
     }
-    offset = extra_end;
   }
 
   if (StaticPrefs::gfx_webrender_debug_blob_paint_flashing()) {
@@ -463,8 +451,8 @@ static bool Moz2DRenderCallback(const Range<const uint8_t> aBlob,
 
 extern "C" {
 
-bool wr_moz2d_render_cb(const mozilla::wr::ByteSlice blob,
-                        mozilla::wr::ImageFormat aFormat,
+bool wr_moz2d_render_cb(const mozilla::wr::WrBlobItem* aItems,
+                        size_t aItemCount, mozilla::wr::ImageFormat aFormat,
                         const mozilla::wr::LayoutIntRect* aRenderRect,
                         const mozilla::wr::DeviceIntRect* aVisibleRect,
                         const uint16_t aTileSize,
@@ -472,7 +460,7 @@ bool wr_moz2d_render_cb(const mozilla::wr::ByteSlice blob,
                         const mozilla::wr::LayoutIntRect* aDirtyRect,
                         mozilla::wr::MutByteSlice output) {
   return mozilla::wr::Moz2DRenderCallback(
-      mozilla::wr::ByteSliceToRange(blob),
+      mozilla::Range<const mozilla::wr::WrBlobItem>(aItems, aItemCount),
       mozilla::wr::ImageFormatToSurfaceFormat(aFormat), aVisibleRect,
       aRenderRect, aTileSize, aTileOffset, aDirtyRect,
       mozilla::wr::MutByteSliceToRange(output));
@@ -1274,7 +1274,8 @@ void RenderThread::InitDeviceTask() {
   nsAutoCString err;
   CreateSingletonGL(err);
   if (gfx::gfxVars::UseWebRenderProgramBinaryDisk()) {
-    mProgramCache = MakeUnique<WebRenderProgramCache>(ThreadPool().Raw());
+    mProgramCache =
+        MakeUnique<WebRenderProgramCache>(ThreadPool().Raw(), mSingletonGL);
   }
   // Query the shared GL context to force the
   // lazy initialization to happen now.
@@ -1577,14 +1578,34 @@ MaybeWebRenderGlyphRasterThread::~MaybeWebRenderGlyphRasterThread() {
   }
 }
 
-WebRenderProgramCache::WebRenderProgramCache(wr::WrThreadPool* aThreadPool) {
+WebRenderProgramCache::WebRenderProgramCache(wr::WrThreadPool* aThreadPool,
+                                             gl::GLContext* aGL) {
   MOZ_ASSERT(aThreadPool);
 
   nsAutoString path;
   if (gfx::gfxVars::UseWebRenderProgramBinaryDisk()) {
     path.Append(gfx::gfxVars::ProfDirectory());
   }
-  mProgramCache = wr_program_cache_new(&path, aThreadPool);
+  // The GL strings are used to partition the disk cache by device and driver.
+  // Software WebRender has no GLContext, so fingerprint a temporary SWGL
+  // context instead to keep its binaries apart from the hardware ones.
+  void* swglContext = nullptr;
+  if (gfx::gfxVars::UseSoftwareWebRender()) {
+    swglContext = wr_swgl_create_context();
+    wr_swgl_make_current(swglContext);
+    aGL = nullptr;
+  } else if (aGL && !aGL->MakeCurrent()) {
+    aGL = nullptr;
+  }
+  // The packed archive replaces the per-digest files whenever the disk cache
+  // is enabled; existing caches are migrated on first load.
+  mProgramCache = wr_program_cache_new(
+      &path, aThreadPool,
+      /* aUseArchive */ gfx::gfxVars::UseWebRenderProgramBinaryDisk(),
+      swglContext, aGL);
+  if (swglContext) {
+    wr_swgl_destroy_context(swglContext);
+  }
   if (gfx::gfxVars::UseWebRenderProgramBinaryDisk()) {
     wr_try_load_startup_shaders_from_disk(mProgramCache);
   }
@@ -89,7 +89,7 @@ class MaybeWebRenderGlyphRasterThread {
 
 class WebRenderProgramCache final {
  public:
-  explicit WebRenderProgramCache(wr::WrThreadPool* aThreadPool);
+  WebRenderProgramCache(wr::WrThreadPool* aThreadPool, gl::GLContext* aGL);
 
   ~WebRenderProgramCache();
 
@@ -0,0 +1,15 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Fuzzes blob image validation and merging. See `fuzz_merge_blob_images` for the
+//! layout of the input.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use webrender_bindings::moz2d_renderer::fuzz_merge_blob_images;
+
+fuzz_target!(|data: &[u8]| {
+    fuzz_merge_blob_images(data);
+});
@@ -0,0 +1,933 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A safe Rust API for embedding WebRender through this crate.
+//!
+//! The `wr_*` entry points in `bindings` are shims over this API for Gecko's C++
+//! side, which keeps both in sync. A window is created with a `WindowBuilder`, which
+//! yields a `Window` owning the `Renderer` and the `Document` to send transactions to.
+//! Display lists are built with a `DisplayListBuilder`. A `HeadlessWindow` renders into
+//! memory with SWGL, without a native GL context or compositor.
+
+use std::error;
+use std::fmt;
+use std::os::raw::c_void;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use gleam::gl;
+use rayon::ThreadPool;
+use webrender::glyph_rasterizer::GlyphRasterThread;
+use webrender::render_api::{RenderApi, Transaction};
+use webrender::sw_compositor::SwCompositor;
+use webrender::{
+    api::units::*, api::*, create_webrender_instance, AsyncPropertySampler, ChunkPool, CompositorConfig, ProgramCache,
+    RenderBackendHooks, RenderResults, Renderer, RendererError, SceneBuilderHooks, ShaderPrecacheFlags, SharedShaders,
+    TextureCacheConfig, UploadMethod, WebRenderOptions, ONE_TIME_USAGE_HINT,
+};
+
+use bindings::{env_var_to_bool, next_namespace_id, DocumentHandle, WrState};
+use memory_compositor::{MemoryCompositor, MemoryCompositorOutput};
+use moz2d_renderer::{BlobRecorder, BlobReplayFallback, BlobTileCache, Moz2dBlobImageHandler};
+
+pub use bindings::{make_transaction, WrWindowId};
+
+/// How long a `HeadlessWindow` waits for a frame to be built.
+const HEADLESS_FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The ways in which creating a window or rendering a frame can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The renderer couldn't be created.
+    CreateRenderer(RendererError),
+    /// A frame couldn't be rendered.
+    Render(Vec<RendererError>),
+    /// No frame was built to render, because no transaction requested one or it took
+    /// too long.
+    NoFrame,
+    /// A headless window was given an empty size.
+    EmptySize(DeviceIntSize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::CreateRenderer(ref err) => write!(f, "failed to create a renderer: {:?}", err),
+            Error::Render(ref errs) => write!(f, "failed to render: {:?}", errs),
+            Error::NoFrame => write!(f, "no frame was built"),
+            Error::EmptySize(size) => write!(f, "headless windows can't be empty, got {:?}", size),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// The options of a window that have a sensible default.
+#[derive(Clone, Debug)]
+pub struct WindowOptions {
+    /// Whether this is the main window, rather than a popup, which gets smaller caches.
+    pub is_main_window: bool,
+    /// Whether transactions may be marked as low priority.
+    pub support_low_priority_transactions: bool,
+    /// Whether texture swizzling may be used to upload BGRA images.
+    pub allow_texture_swizzling: bool,
+    /// Whether caches may be cleared with scissored clears rather than quads.
+    pub allow_scissored_cache_clears: bool,
+    /// Whether the origin of the surface rendered to is its top left corner.
+    pub surface_origin_is_top_left: bool,
+    /// Whether to insert GPU markers for debugging tools.
+    pub enable_gpu_markers: bool,
+    /// Whether to panic when a GL error is found.
+    pub panic_on_gl_error: bool,
+    /// The size of picture cache tiles, if not the default one.
+    pub picture_tile_size: Option<DeviceIntSize>,
+    /// Whether to fail rather than use a software GL rasterizer provided by the system.
+    pub reject_software_rasterizer: bool,
+    /// Whether to trade quality for speed while pinch zooming.
+    pub low_quality_pinch_zoom: bool,
+    /// The maximum size of shared texture cache surfaces.
+    pub max_shared_surface_size: i32,
+    /// Whether to use subpixel antialiasing for text.
+    pub enable_subpixel_aa: bool,
+    /// A directory to load shaders and other resources from instead of the built-in ones.
+    pub resource_override_path: Option<PathBuf>,
+    /// Whether to use the optimized versions of the shaders.
+    pub use_optimized_shaders: bool,
+    /// What to produce for the tiles of blobs that fail to replay.
+    pub blob_replay_fallback: BlobReplayFallback,
+    /// The size of the cache of rasterized blob tiles, or `None` to not cache them.
+    pub blob_tile_cache_size: Option<usize>,
+    /// A directory to record the blob images of the window to, for offline replay.
+    pub blob_recording_dir: Option<PathBuf>,
+}
+
+impl Default for WindowOptions {
+    fn default() -> Self {
+        WindowOptions {
+            is_main_window: true,
+            support_low_priority_transactions: false,
+            allow_texture_swizzling: true,
+            allow_scissored_cache_clears: true,
+            surface_origin_is_top_left: false,
+            enable_gpu_markers: false,
+            panic_on_gl_error: false,
+            picture_tile_size: None,
+            reject_software_rasterizer: false,
+            low_quality_pinch_zoom: false,
+            max_shared_surface_size: 2048,
+            enable_subpixel_aa: false,
+            resource_override_path: None,
+            use_optimized_shaders: true,
+            blob_replay_fallback: BlobReplayFallback::Error,
+            blob_tile_cache_size: None,
+            blob_recording_dir: None,
+        }
+    }
+}
+
+/// A document of a window, to send transactions to.
+pub struct Document {
+    handle: DocumentHandle,
+}
+
+impl Document {
+    /// Returns the id of the document.
+    pub fn id(&self) -> DocumentId {
+        self.handle.document_id()
+    }
+
+    /// Returns the API used to talk to WebRender's threads, to allocate resource keys
+    /// and such.
+    pub fn api(&self) -> &RenderApi {
+        self.handle.api()
+    }
+
+    /// Sends a transaction to the document, unless it is empty.
+    pub fn send_transaction(&mut self, transaction: Transaction) {
+        self.handle.send_transaction(transaction);
+    }
+
+    /// Returns the items at the given point, front to back.
+    pub fn hit_test(&mut self, point: WorldPoint) -> HitTestResult {
+        self.handle.hit_test(point)
+    }
+
+    /// Creates another handle to the same document, with its own resource namespace,
+    /// for use on another thread.
+    pub fn create_similar(&mut self) -> Document {
+        Document {
+            handle: self.handle.create_similar(),
+        }
+    }
+
+    /// Sets the debug flags of the renderer.
+    pub fn set_debug_flags(&mut self, flags: DebugFlags) {
+        self.handle.set_debug_flags(flags);
+    }
+
+    /// Asks WebRender to free as much memory as it can.
+    pub fn notify_memory_pressure(&mut self) {
+        self.handle.notify_memory_pressure();
+    }
+
+    /// Waits for the transactions sent so far to be built into scenes.
+    pub fn flush_scene_builder(&mut self) {
+        self.handle.flush_scene_builder();
+    }
+
+    /// Deletes the document, with all its pipelines.
+    pub fn delete(mut self) {
+        self.handle.delete_document();
+    }
+
+    /// Returns the handle the FFI works with.
+    pub(crate) fn handle_mut(&mut self) -> &mut DocumentHandle {
+        &mut self.handle
+    }
+}
+
+/// Builds the display list of a pipeline.
+///
+/// Items are pushed in the root spatial node and clip chain of the pipeline.
+pub struct DisplayListBuilder {
+    state: WrState,
+}
+
+impl DisplayListBuilder {
+    /// Starts building a display list for the given pipeline.
+    pub fn new(pipeline_id: PipelineId) -> Self {
+        let mut state = WrState::new(pipeline_id);
+        state.begin();
+        DisplayListBuilder { state }
+    }
+
+    /// Returns the pipeline the display list is built for.
+    pub fn pipeline_id(&self) -> PipelineId {
+        self.state.pipeline_id()
+    }
+
+    fn item_properties(&self, clip_rect: LayoutRect) -> CommonItemProperties {
+        CommonItemProperties::new(clip_rect, SpaceAndClipInfo::root_scroll(self.pipeline_id()))
+    }
+
+    /// Pushes a rectangle of the given color, clipped to `clip_rect`.
+    pub fn push_rect(&mut self, rect: LayoutRect, clip_rect: LayoutRect, color: ColorF) {
+        let properties = self.item_properties(clip_rect);
+        self.state.push_rect(&properties, rect, color);
+    }
+
+    /// Pushes a rectangle which clears what is below it to transparent, clipped to
+    /// `clip_rect`.
+    pub fn push_clear_rect(&mut self, rect: LayoutRect, clip_rect: LayoutRect) {
+        let properties = self.item_properties(clip_rect);
+        self.state.push_clear_rect(&properties, rect);
+    }
+
+    /// Pushes an image, stretched to `rect` and clipped to `clip_rect`.
+    pub fn push_image(
+        &mut self,
+        rect: LayoutRect,
+        clip_rect: LayoutRect,
+        image_rendering: ImageRendering,
+        alpha_type: AlphaType,
+        key: ImageKey,
+        color: ColorF,
+    ) {
+        let properties = self.item_properties(clip_rect);
+        self.state
+            .push_image(&properties, rect, image_rendering, alpha_type, key, color);
+    }
+
+    /// Finishes the display list, to set it on a transaction with
+    /// `Transaction::set_display_list`.
+    pub fn build(mut self) -> (PipelineId, BuiltDisplayList) {
+        let display_list = self.state.end();
+        (self.state.pipeline_id(), display_list)
+    }
+}
+
+/// A window created by a `WindowBuilder`.
+///
+/// Dropping the window shuts WebRender's threads down and deinitializes the renderer,
+/// which needs the GL context of the window to be current.
+pub struct Window {
+    // Both are only None once taken by `into_raw_parts`.
+    document: Option<Document>,
+    renderer: Option<Renderer>,
+    max_texture_size: i32,
+}
+
+impl Window {
+    /// Returns the document of the window.
+    pub fn document(&mut self) -> &mut Document {
+        self.document.as_mut().unwrap()
+    }
+
+    /// Returns the renderer of the window.
+    pub fn renderer(&mut self) -> &mut Renderer {
+        self.renderer.as_mut().unwrap()
+    }
+
+    /// Returns the maximum size of the textures of the GL context.
+    pub fn max_texture_size(&self) -> i32 {
+        self.max_texture_size
+    }
+
+    /// Takes the document handle and renderer out of the window, for the FFI, which
+    /// shuts them down on its own.
+    pub(crate) fn into_raw_parts(mut self) -> (DocumentHandle, Renderer) {
+        let document = self.document.take().unwrap();
+        let renderer = self.renderer.take().unwrap();
+        (document.handle, renderer)
+    }
+}
+
+impl Drop for Window {
+    fn drop(&mut self) {
+        if let Some(mut document) = self.document.take() {
+            document.handle.shut_down();
+        }
+        if let Some(renderer) = self.renderer.take() {
+            renderer.deinit();
+        }
+    }
+}
+
+/// Creates a window, with its renderer and document.
+///
+/// The GL context must be current on the calling thread, which becomes the render
+/// thread of the window.
+pub struct WindowBuilder {
+    window_id: WrWindowId,
+    size: DeviceIntSize,
+    gl: Rc<dyn gl::Gl>,
+    swgl: Option<swgl::Context>,
+    notifier: Box<dyn RenderNotifier>,
+    workers: Arc<ThreadPool>,
+    workers_low_priority: Option<Arc<ThreadPool>>,
+    document_id: u32,
+    options: WindowOptions,
+    upload_method: UploadMethod,
+    compositor_config: CompositorConfig,
+    program_cache: Option<Rc<ProgramCache>>,
+    shaders: Option<SharedShaders>,
+    chunk_pool: Option<Arc<ChunkPool>>,
+    glyph_raster_thread: Option<GlyphRasterThread>,
+    size_of_ops: Option<(VoidPtrToSizeFn, VoidPtrToSizeFn)>,
+    crash_annotator: Option<Box<dyn CrashAnnotator>>,
+    scene_builder_hooks: Option<Box<dyn SceneBuilderHooks + Send>>,
+    render_backend_hooks: Option<Box<dyn RenderBackendHooks + Send>>,
+    sampler: Option<Box<dyn AsyncPropertySampler + Send>>,
+}
+
+impl WindowBuilder {
+    /// Starts building a window which renders with the given GL context.
+    pub fn new(
+        window_id: WrWindowId,
+        size: DeviceIntSize,
+        gl: Rc<dyn gl::Gl>,
+        notifier: Box<dyn RenderNotifier>,
+        workers: Arc<ThreadPool>,
+    ) -> Self {
+        WindowBuilder {
+            window_id,
+            size,
+            gl,
+            swgl: None,
+            notifier,
+            workers,
+            workers_low_priority: None,
+            document_id: 0,
+            options: WindowOptions::default(),
+            upload_method: UploadMethod::PixelBuffer(ONE_TIME_USAGE_HINT),
+            compositor_config: CompositorConfig::default(),
+            program_cache: None,
+            shaders: None,
+            chunk_pool: None,
+            glyph_raster_thread: None,
+            size_of_ops: None,
+            crash_annotator: None,
+            scene_builder_hooks: None,
+            render_backend_hooks: None,
+            sampler: None,
+        }
+    }
+
+    /// Starts building a window which renders with the given software GL context.
+    pub fn new_software(
+        window_id: WrWindowId,
+        size: DeviceIntSize,
+        context: swgl::Context,
+        notifier: Box<dyn RenderNotifier>,
+        workers: Arc<ThreadPool>,
+    ) -> Self {
+        let mut builder = Self::new(window_id, size, Rc::new(context), notifier, workers);
+        builder.swgl = Some(context);
+        builder
+    }
+
+    /// Sets the options of the window.
+    pub fn options(mut self, options: WindowOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets the thread pool for low priority work, such as low priority blob
+    /// rasterization. The main pool is used by default.
+    pub fn low_priority_workers(mut self, workers: Arc<ThreadPool>) -> Self {
+        self.workers_low_priority = Some(workers);
+        self
+    }
+
+    /// Sets the id of the document of the window.
+    pub fn document_id(mut self, document_id: u32) -> Self {
+        self.document_id = document_id;
+        self
+    }
+
+    /// Sets how textures are uploaded.
+    pub fn upload_method(mut self, upload_method: UploadMethod) -> Self {
+        self.upload_method = upload_method;
+        self
+    }
+
+    /// Sets how frames are composited.
+    pub fn compositor_config(mut self, compositor_config: CompositorConfig) -> Self {
+        self.compositor_config = compositor_config;
+        self
+    }
+
+    /// Sets the cache of compiled shader programs.
+    pub fn program_cache(mut self, program_cache: Rc<ProgramCache>) -> Self {
+        self.program_cache = Some(program_cache);
+        self
+    }
+
+    /// Sets shaders that were already compiled, to share them with other windows.
+    pub fn shaders(mut self, shaders: SharedShaders) -> Self {
+        self.shaders = Some(shaders);
+        self
+    }
+
+    /// Sets the pool of memory chunks used to build frames, to share it with other
+    /// windows.
+    pub fn chunk_pool(mut self, chunk_pool: Arc<ChunkPool>) -> Self {
+        self.chunk_pool = Some(chunk_pool);
+        self
+    }
+
+    /// Sets a thread dedicated to rasterizing glyphs.
+    pub fn glyph_raster_thread(mut self, glyph_raster_thread: GlyphRasterThread) -> Self {
+        self.glyph_raster_thread = Some(glyph_raster_thread);
+        self
+    }
+
+    /// Sets the functions that measure heap allocations, for memory reports.
+    pub fn size_of_ops(mut self, size_of_op: VoidPtrToSizeFn, enclosing_size_of_op: VoidPtrToSizeFn) -> Self {
+        self.size_of_ops = Some((size_of_op, enclosing_size_of_op));
+        self
+    }
+
+    /// Sets what annotates crash reports.
+    pub fn crash_annotator(mut self, crash_annotator: Box<dyn CrashAnnotator>) -> Self {
+        self.crash_annotator = Some(crash_annotator);
+        self
+    }
+
+    /// Sets the hooks called by the scene builder thread.
+    pub fn scene_builder_hooks(mut self, hooks: Box<dyn SceneBuilderHooks + Send>) -> Self {
+        self.scene_builder_hooks = Some(hooks);
+        self
+    }
+
+    /// Sets the hooks called by the render backend thread.
+    pub fn render_backend_hooks(mut self, hooks: Box<dyn RenderBackendHooks + Send>) -> Self {
+        self.render_backend_hooks = Some(hooks);
+        self
+    }
+
+    /// Sets what samples animated properties before each frame is built.
+    pub fn sampler(mut self, sampler: Box<dyn AsyncPropertySampler + Send>) -> Self {
+        self.sampler = Some(sampler);
+        self
+    }
+
+    /// Creates the window.
+    pub fn build(self) -> Result<Window, Error> {
+        let software = self.swgl.is_some();
+        if let Some(ctx) = self.swgl {
+            ctx.make_current();
+        }
+
+        let version = self.gl.get_string(gl::VERSION);
+
+        info!("WebRender - OpenGL version new {}", version);
+
+        let workers_low_priority = self.workers_low_priority.unwrap_or_else(|| Arc::clone(&self.workers));
+
+        let precache_flags = if env_var_to_bool("MOZ_WR_PRECACHE_SHADERS") {
+            ShaderPrecacheFlags::FULL_COMPILE
+        } else {
+            ShaderPrecacheFlags::empty()
+        };
+
+        let color = if cfg!(target_os = "android") {
+            // The color is for avoiding black flash before receiving display list.
+            ColorF::new(1.0, 1.0, 1.0, 1.0)
+        } else {
+            ColorF::new(0.0, 0.0, 0.0, 0.0)
+        };
+
+        let options = self.options;
+        let texture_cache_config = if options.is_main_window {
+            TextureCacheConfig::DEFAULT
+        } else {
+            TextureCacheConfig {
+                color8_linear_texture_size: 512,
+                color8_nearest_texture_size: 512,
+                color8_glyph_texture_size: 512,
+                alpha8_texture_size: 512,
+                alpha8_glyph_texture_size: 512,
+                alpha16_texture_size: 512,
+            }
+        };
+
+        let (blob_image_handler, blob_tile_cache) =
+            create_blob_image_handler(&options, Arc::clone(&self.workers), workers_low_priority);
+        let blob_image_handler_timings = blob_image_handler.raster_timings();
+        let failed_blob_fonts = blob_image_handler.failed_fonts();
+
+        let opts = WebRenderOptions {
+            enable_aa: true,
+            enable_subpixel_aa: options.enable_subpixel_aa,
+            support_low_priority_transactions: options.support_low_priority_transactions,
+            allow_texture_swizzling: options.allow_texture_swizzling,
+            blob_image_handler: Some(Box::new(blob_image_handler)),
+            crash_annotator: self.crash_annotator,
+            workers: Some(self.workers),
+            chunk_pool: self.chunk_pool,
+            dedicated_glyph_raster_thread: self.glyph_raster_thread,
+            size_of_op: self.size_of_ops.map(|ops| ops.0),
+            enclosing_size_of_op: self.size_of_ops.map(|ops| ops.1),
+            cached_programs: self.program_cache,
+            resource_override_path: options.resource_override_path,
+            use_optimized_shaders: options.use_optimized_shaders,
+            renderer_id: Some(self.window_id.0),
+            upload_method: self.upload_method,
+            scene_builder_hooks: self.scene_builder_hooks,
+            render_backend_hooks: self.render_backend_hooks,
+            sampler: self.sampler,
+            max_internal_texture_size: Some(8192), // We want to tile if larger than this
+            clear_color: color,
+            precache_flags,
+            namespace_alloc_by_client: true,
+            // Font namespace must be allocated by the client
+            shared_font_namespace: Some(next_namespace_id()),
+            // SWGL doesn't support the GL_ALWAYS depth comparison function used by
+            // `clear_caches_with_quads`, but scissored clears work well.
+            clear_caches_with_quads: !software && !options.allow_scissored_cache_clears,
+            // SWGL supports KHR_blend_equation_advanced safely, but we haven't yet
+            // tested other HW platforms determine if it is safe to allow them.
+            allow_advanced_blend_equation: software,
+            surface_origin_is_top_left: options.surface_origin_is_top_left,
+            compositor_config: self.compositor_config,
+            enable_gpu_markers: options.enable_gpu_markers,
+            panic_on_gl_error: options.panic_on_gl_error,
+            picture_tile_size: options.picture_tile_size,
+            texture_cache_config,
+            reject_software_rasterizer: options.reject_software_rasterizer,
+            low_quality_pinch_zoom: options.low_quality_pinch_zoom,
+            max_shared_surface_size: options.max_shared_surface_size,
+            ..Default::default()
+        };
+
+        let (renderer, sender) = create_webrender_instance(self.gl, self.notifier, opts, self.shaders.as_ref())
+            .map_err(Error::CreateRenderer)?;
+
+        let max_texture_size = renderer.get_max_texture_size();
+        let mut document = DocumentHandle::new(
+            sender.create_api_by_client(next_namespace_id()),
+            None,
+            self.size,
+            self.document_id,
+        );
+        document.set_blob_tile_cache(blob_tile_cache);
+        document.set_blob_raster_timings(Some(blob_image_handler_timings));
+        document.set_failed_blob_fonts(Some(failed_blob_fonts));
+
+        Ok(Window {
+            document: Some(Document { handle: document }),
+            renderer: Some(renderer),
+            max_texture_size,
+        })
+    }
+}
+
+/// Creates the handler of the blob images of a window, configured by its options.
+fn create_blob_image_handler(
+    options: &WindowOptions,
+    workers: Arc<ThreadPool>,
+    workers_low_priority: Arc<ThreadPool>,
+) -> (Moz2dBlobImageHandler, Option<Arc<BlobTileCache>>) {
+    let mut blob_image_handler = Moz2dBlobImageHandler::new(workers, workers_low_priority);
+    blob_image_handler.set_replay_fallback(options.blob_replay_fallback);
+    let blob_tile_cache = options
+        .blob_tile_cache_size
+        .map(|size| Arc::new(BlobTileCache::new(size)));
+    blob_image_handler.set_tile_cache(blob_tile_cache.clone());
+    if let Some(ref dir) = options.blob_recording_dir {
+        match BlobRecorder::new(dir.clone()) {
+            Ok(recorder) => blob_image_handler.set_recorder(Some(Arc::new(recorder))),
+            Err(err) => warn!("Failed to start recording blob images: {:?}", err),
+        }
+    }
+
+    (blob_image_handler, blob_tile_cache)
+}
+
+/// Renders a frame of the given size.
+///
+/// `buffer_age` is the number of frames since the buffer rendered to was last
+/// presented, or 0 if unknown.
+pub fn render(renderer: &mut Renderer, size: DeviceIntSize, buffer_age: usize) -> Result<RenderResults, Error> {
+    renderer.render(size, buffer_age).map_err(Error::Render)
+}
+
+/// Reads back the last frame rendered, of the given size, like `wr_renderer_readback`.
+///
+/// `surface_origin_is_top_left` must match the option the window was created with,
+/// as the rows of other surfaces are read from the bottom.
+pub fn read_back(renderer: &mut Renderer, size: DeviceIntSize, surface_origin_is_top_left: bool) -> RgbaFrame {
+    let pixels = renderer.read_pixels_rgba8(FramebufferIntSize::new(size.width, size.height).into());
+    if surface_origin_is_top_left {
+        return RgbaFrame { size, pixels };
+    }
+    let row_size = size.width as usize * 4;
+    let pixels = pixels.chunks(row_size).rev().flatten().cloned().collect();
+    RgbaFrame { size, pixels }
+}
+
+/// Tells a `HeadlessWindow` when frames are built.
+struct HeadlessNotifier {
+    frame_ready: mpsc::Sender<()>,
+}
+
+impl RenderNotifier for HeadlessNotifier {
+    fn clone(&self) -> Box<dyn RenderNotifier> {
+        Box::new(HeadlessNotifier {
+            frame_ready: self.frame_ready.clone(),
+        })
+    }
+
+    fn wake_up(&self, _composite_needed: bool) {}
+
+    fn new_frame_ready(&self, _: DocumentId, _scrolled: bool, _composite_needed: bool, _publish_id: FramePublishId) {
+        // The window may already be gone.
+        let _ = self.frame_ready.send(());
+    }
+
+    fn external_event(&self, _event: ExternalEvent) {}
+}
+
+/// A frame read back from a `HeadlessWindow`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RgbaFrame {
+    /// The size of the frame, in pixels.
+    pub size: DeviceIntSize,
+    /// The pixels, row by row from the top, as RGBA8 with premultiplied alpha.
+    pub pixels: Vec<u8>,
+}
+
+impl RgbaFrame {
+    /// Converts BGRA8 pixels, as SWGL stores them, to a frame.
+    pub(crate) fn from_bgra(size: DeviceIntSize, bgra: &[u32]) -> RgbaFrame {
+        let mut pixels = Vec::with_capacity(bgra.len() * 4);
+        for pixel in bgra {
+            let [b, g, r, a] = pixel.to_le_bytes();
+            pixels.extend_from_slice(&[r, g, b, a]);
+        }
+        RgbaFrame { size, pixels }
+    }
+}
+
+/// A window that renders with SWGL into memory, without a native GL context or
+/// compositor.
+///
+/// Frames are requested by sending transactions that generate one to its document, and
+/// read back with `render`. The thread that creates the window becomes its render
+/// thread.
+///
+/// Frames are either drawn into a single framebuffer, or, with
+/// `with_native_compositor`, rendered as native surfaces of a `MemoryCompositor`
+/// that composites them.
+pub struct HeadlessWindow {
+    // Only None while dropping, as it must be dropped before the context is destroyed.
+    window: Option<Window>,
+    context: swgl::Context,
+    // The storage of the default framebuffer of the context, which frames are read back
+    // from with `read_back`.
+    _framebuffer: Vec<u32>,
+    // Where frames are read from when they are composited by a MemoryCompositor.
+    compositor_output: Option<MemoryCompositorOutput>,
+    size: DeviceIntSize,
+    frame_ready: mpsc::Receiver<()>,
+}
+
+impl HeadlessWindow {
+    /// Creates a window of the given size, which must not be empty.
+    ///
+    /// Returns `Error::EmptySize` if it is.
+    pub fn new(
+        window_id: WrWindowId,
+        size: DeviceIntSize,
+        workers: Arc<ThreadPool>,
+        options: WindowOptions,
+    ) -> Result<HeadlessWindow, Error> {
+        Self::create(window_id, size, workers, options, false)
+    }
+
+    /// Creates a window of the given size, which must not be empty, whose frames are
+    /// composited by a `MemoryCompositor`.
+    pub fn with_native_compositor(
+        window_id: WrWindowId,
+        size: DeviceIntSize,
+        workers: Arc<ThreadPool>,
+        options: WindowOptions,
+    ) -> Result<HeadlessWindow, Error> {
+        Self::create(window_id, size, workers, options, true)
+    }
+
+    fn create(
+        window_id: WrWindowId,
+        size: DeviceIntSize,
+        workers: Arc<ThreadPool>,
+        options: WindowOptions,
+        use_native_compositor: bool,
+    ) -> Result<HeadlessWindow, Error> {
+        if size.is_empty() {
+            return Err(Error::EmptySize(size));
+        }
+
+        let context = swgl::Context::create();
+        context.make_current();
+        let mut framebuffer = vec![0; size.area() as usize];
+        context.init_default_framebuffer(
+            0,
+            0,
+            size.width,
+            size.height,
+            size.width * 4,
+            framebuffer.as_mut_ptr() as *mut c_void,
+        );
+
+        let (sender, frame_ready) = mpsc::channel();
+        let notifier = Box::new(HeadlessNotifier { frame_ready: sender });
+        // The framebuffer is stored from the top row, as it would be by a compositor.
+        let options = WindowOptions {
+            surface_origin_is_top_left: true,
+            ..options
+        };
+        let mut builder = WindowBuilder::new_software(window_id, size, context, notifier, workers).options(options);
+        let mut compositor_output = None;
+        if use_native_compositor {
+            let compositor = MemoryCompositor::new(context, size);
+            compositor_output = Some(compositor.output());
+            builder = builder.compositor_config(CompositorConfig::Native {
+                compositor: Box::new(SwCompositor::new(context, Box::new(compositor), true)),
+            });
+        }
+        let window = match builder.build() {
+            Ok(window) => window,
+            Err(err) => {
+                context.destroy();
+                return Err(err);
+            },
+        };
+
+        Ok(HeadlessWindow {
+            window: Some(window),
+            context,
+            _framebuffer: framebuffer,
+            compositor_output,
+            size,
+            frame_ready,
+        })
+    }
+
+    /// Returns the document of the window.
+    pub fn document(&mut self) -> &mut Document {
+        self.window.as_mut().unwrap().document()
+    }
+
+    /// Returns the renderer of the window.
+    pub fn renderer(&mut self) -> &mut Renderer {
+        self.window.as_mut().unwrap().renderer()
+    }
+
+    /// Waits for the frame requested by the last transaction to be built, renders it
+    /// and reads it back.
+    pub fn render(&mut self) -> Result<RgbaFrame, Error> {
+        self.frame_ready
+            .recv_timeout(HEADLESS_FRAME_TIMEOUT)
+            .map_err(|_| Error::NoFrame)?;
+        // Frames built in the meantime are rendered at once.
+        while self.frame_ready.try_recv().is_ok() {}
+
+        self.context.make_current();
+        let size = self.size;
+        let renderer = self.renderer();
+        renderer.update();
+        render(renderer, size, 0)?;
+
+        if let Some(ref output) = self.compositor_output {
+            return Ok(output.read());
+        }
+        Ok(read_back(self.renderer(), size, true))
+    }
+}
+
+impl Drop for HeadlessWindow {
+    fn drop(&mut self) {
+        self.context.make_current();
+        self.window.take();
+        self.context.destroy();
+    }
+}
+
+/// Returns a pool of a single thread for the workers of test windows.
+#[cfg(test)]
+pub(crate) fn test_workers() -> Arc<ThreadPool> {
+    Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap())
+}
+
+/// Creates a headless window with the default options for tests.
+#[cfg(test)]
+pub(crate) fn headless_window(size: DeviceIntSize) -> HeadlessWindow {
+    HeadlessWindow::new(WrWindowId(1), size, test_workers(), WindowOptions::default()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Renders an opaque red rectangle in the top left quarter of a 16x8 window, which
+    // would end up at the bottom if rows were read in the wrong order.
+    fn render_red_rect(window: &mut HeadlessWindow) -> RgbaFrame {
+        let pipeline_id = PipelineId(0, 1);
+        let mut builder = DisplayListBuilder::new(pipeline_id);
+        let rect = LayoutRect::from_size(LayoutSize::new(8.0, 4.0));
+        builder.push_rect(rect, rect, ColorF::new(1.0, 0.0, 0.0, 1.0));
+
+        let mut txn = make_transaction(false);
+        txn.set_root_pipeline(pipeline_id);
+        txn.set_display_list(Epoch(0), builder.build());
+        txn.generate_frame(0, RenderReasons::TESTING);
+        window.document().send_transaction(txn);
+
+        let frame = window.render().unwrap();
+        assert_eq!(frame.size, DeviceIntSize::new(16, 8));
+        assert_eq!(frame.pixels.len(), 16 * 8 * 4);
+        let pixel = |x: usize, y: usize| &frame.pixels[(y * 16 + x) * 4..][..4];
+        assert_eq!(pixel(0, 0), &[255, 0, 0, 255]);
+        assert_eq!(pixel(7, 3), &[255, 0, 0, 255]);
+        assert_eq!(pixel(0, 4), &[0, 0, 0, 0]);
+        assert_eq!(pixel(7, 7), &[0, 0, 0, 0]);
+        assert_eq!(pixel(12, 2), &[0, 0, 0, 0]);
+        frame
+    }
+
+    #[test]
+    fn headless_windows_render_display_lists() {
+        let mut window = headless_window(DeviceIntSize::new(16, 8));
+        render_red_rect(&mut window);
+    }
+
+    #[test]
+    fn read_back_flips_surfaces_with_a_bottom_left_origin() {
+        let size = DeviceIntSize::new(16, 8);
+        let mut window = headless_window(size);
+        let frame = render_red_rect(&mut window);
+        assert_eq!(read_back(window.renderer(), size, true), frame);
+
+        // Reading the top left surface as if it were bottom left turns it upside down.
+        let flipped = read_back(window.renderer(), size, false);
+        let row_size = 16 * 4;
+        for (row, flipped_row) in frame.pixels.chunks(row_size).zip(flipped.pixels.chunks(row_size).rev()) {
+            assert_eq!(row, flipped_row);
+        }
+        assert_ne!(flipped, frame);
+    }
+
+    #[test]
+    fn headless_windows_cant_be_empty() {
+        let size = DeviceIntSize::new(16, 0);
+        match HeadlessWindow::new(WrWindowId(1), size, test_workers(), WindowOptions::default()) {
+            Err(Error::EmptySize(empty)) => assert_eq!(empty, size),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("created an empty window"),
+        }
+    }
+
+    #[test]
+    fn memory_compositor_matches_the_framebuffer() {
+        let size = DeviceIntSize::new(16, 8);
+        let expected = render_red_rect(&mut headless_window(size));
+
+        let mut window =
+            HeadlessWindow::with_native_compositor(WrWindowId(2), size, test_workers(), WindowOptions::default())
+                .unwrap();
+        assert_eq!(render_red_rect(&mut window), expected);
+    }
+
+    #[test]
+    fn clear_rects_clear_the_items_below() {
+        let size = DeviceIntSize::new(16, 8);
+        let mut window = headless_window(size);
+
+        let pipeline_id = PipelineId(0, 1);
+        let mut builder = DisplayListBuilder::new(pipeline_id);
+        assert_eq!(builder.pipeline_id(), pipeline_id);
+        let rect = LayoutRect::from_size(LayoutSize::new(16.0, 8.0));
+        builder.push_rect(rect, rect, ColorF::new(0.0, 0.0, 1.0, 1.0));
+        let hole = LayoutRect::new(LayoutPoint::new(4.0, 0.0), LayoutPoint::new(8.0, 8.0));
+        builder.push_clear_rect(hole, hole);
+
+        // Documents created with create_similar send to the same document.
+        let mut document = window.document().create_similar();
+        assert_eq!(document.id(), window.document().id());
+        let mut txn = make_transaction(false);
+        txn.set_root_pipeline(pipeline_id);
+        txn.set_display_list(Epoch(0), builder.build());
+        txn.generate_frame(0, RenderReasons::TESTING);
+        document.send_transaction(txn);
+
+        let frame = window.render().unwrap();
+        let pixel = |x: usize, y: usize| &frame.pixels[(y * 16 + x) * 4..][..4];
+        assert_eq!(pixel(0, 0), &[0, 0, 255, 255]);
+        assert_eq!(pixel(5, 3), &[0, 0, 0, 0]);
+        assert_eq!(pixel(12, 7), &[0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn blob_options_configure_the_handler() {
+        let workers = test_workers();
+        let (_, tile_cache) =
+            create_blob_image_handler(&WindowOptions::default(), Arc::clone(&workers), Arc::clone(&workers));
+        assert!(tile_cache.is_none());
+
+        let dir = std::env::temp_dir().join(format!("wr-api-blobs-{}", std::process::id()));
+        let options = WindowOptions {
+            blob_replay_fallback: BlobReplayFallback::Checkerboard,
+            blob_tile_cache_size: Some(1024),
+            blob_recording_dir: Some(dir.clone()),
+            ..WindowOptions::default()
+        };
+        let (_, tile_cache) = create_blob_image_handler(&options, Arc::clone(&workers), workers);
+        assert!(tile_cache.is_some());
+        assert!(dir.is_dir());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Inspects and maintains a WebRender shader disk cache without launching the browser.
+//!
+//! The cache directory is a partition of `<profile>/shader-cache`, ie. one of its
+//! subdirectories named after the device fingerprint.
+
+extern crate webrender_bindings;
+
+use std::env;
+use std::path::Path;
+use std::process;
+
+use webrender_bindings::program_cache::{
+    copy_cache_dir, inspect_cache_dir, prune_cache_dir, CacheEntryInfo, CacheEntryValidity,
+};
+
+const USAGE: &str = "Usage:
+  shader_cache_tool list <cache-dir>            List entries and whether they are valid
+  shader_cache_tool verify <cache-dir>          Exit with an error if any entry is invalid
+  shader_cache_tool prune <cache-dir>           Remove invalid entries
+  shader_cache_tool export <cache-dir> <dest>   Copy valid entries out of a cache
+  shader_cache_tool import <src> <cache-dir>    Copy valid entries into a cache";
+
+fn print_entry(entry: &CacheEntryInfo) {
+    let validity = match entry.validity {
+        CacheEntryValidity::Valid => "valid".to_string(),
+        CacheEntryValidity::Invalid(ref reason) => format!("INVALID ({})", reason),
+    };
+    println!(
+        "{} {:>10} {}{}{}",
+        entry.digest,
+        entry.size,
+        validity,
+        if entry.in_whitelist { " startup" } else { "" },
+        if entry.in_archive { " archived" } else { "" },
+    );
+}
+
+fn run(args: &[String]) -> Result<bool, String> {
+    let arg = |i: usize| args.get(i).map(Path::new).ok_or_else(|| USAGE.to_string());
+    let command = args.get(1).map(String::as_str).unwrap_or("");
+
+    match command {
+        "list" | "verify" => {
+            let entries = inspect_cache_dir(arg(2)?).map_err(|e| e.to_string())?;
+            let invalid = entries.iter().filter(|e| !e.is_valid()).count();
+            for entry in &entries {
+                if command == "list" || !entry.is_valid() {
+                    print_entry(entry);
+                }
+            }
+            println!(
+                "{} entries, {} bytes, {} invalid",
+                entries.len(),
+                entries.iter().map(|e| e.size).sum::<u64>(),
+                invalid
+            );
+            Ok(command == "list" || invalid == 0)
+        },
+        "prune" => {
+            let pruned = prune_cache_dir(arg(2)?).map_err(|e| e.to_string())?;
+            println!("Removed {} invalid entries", pruned);
+            Ok(true)
+        },
+        "export" | "import" => {
+            let copied = copy_cache_dir(arg(2)?, arg(3)?).map_err(|e| e.to_string())?;
+            println!("Copied {} entries", copied);
+            Ok(true)
+        },
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<String>>();
+    match run(&args) {
+        Ok(true) => {},
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        },
+    }
+}
@@ -11,7 +11,7 @@ use std::cell::RefCell;
 #[cfg(not(any(target_os = "macos", target_os = "ios")))]
 use std::ffi::OsString;
 use std::ffi::{CStr, CString};
-use std::io::Cursor;
+use std::io::{self, Cursor};
 use std::marker::PhantomData;
 use std::ops::Range;
 #[cfg(target_os = "android")]
@@ -21,28 +21,38 @@ use std::os::raw::{c_char, c_float, c_void};
 use std::os::unix::ffi::OsStringExt;
 #[cfg(target_os = "windows")]
 use std::os::windows::ffi::OsStringExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use std::{env, mem, ptr, slice};
+use std::{env, fs, mem, ptr, slice};
 use thin_vec::ThinVec;
 use webrender::glyph_rasterizer::GlyphRasterThread;
 
+use api::{self, HeadlessWindow, WindowBuilder, WindowOptions};
 use euclid::SideOffsets2D;
-use moz2d_renderer::Moz2dBlobImageHandler;
-use nsstring::nsAString;
-use program_cache::{remove_disk_cache, WrProgramCache};
+use host::{host, is_in_compositor_thread, is_in_main_thread, is_in_render_thread, is_off_render_thread, ArenaThread};
+use image_encoding::{EncodeOptions, EncodeStatus, EncodeTask, RawFrame};
+use moz2d_renderer::{
+    set_blob_fallback_font, BlobFontError, BlobRasterTiming, BlobRasterTimings, BlobTileCache, FailedBlobFonts,
+};
+#[cfg(feature = "gecko")]
+use moz2d_renderer::inspect_blob_json;
+#[cfg(feature = "gecko")]
+use nsstring::{nsACString, nsAString};
+#[cfg(feature = "gecko")]
+use program_cache::{get_cache_path_from_prof_path, remove_disk_cache, WrDeviceFingerprint};
+use program_cache::{WrProgramCache, WrProgramCacheDiskUsage, WrProgramCacheStats};
+use serde::{Deserialize, Serialize};
 use tracy_rs::register_thread_with_profiler;
 use webrender::sw_compositor::SwCompositor;
 use webrender::{
-    api::units::*, api::*, create_webrender_instance, render_api::*, set_profiler_hooks, AsyncPropertySampler,
-    AsyncScreenshotHandle, Compositor, CompositorCapabilities, CompositorConfig, CompositorSurfaceTransform, Device,
-    MappableCompositor, MappedTileInfo, NativeSurfaceId, NativeSurfaceInfo, NativeTileId, PartialPresentCompositor,
-    PipelineInfo, ProfilerHooks, RecordedFrameHandle, Renderer, RendererStats, SWGLCompositeSurfaceInfo,
-    SceneBuilderHooks, ShaderPrecacheFlags, Shaders, SharedShaders, TextureCacheConfig, UploadMethod, WebRenderOptions,
-    WindowVisibility, RenderBackendHooks, ONE_TIME_USAGE_HINT,
+    api::units::*, api::*, render_api::*, set_profiler_hooks, AsyncPropertySampler, AsyncScreenshotHandle, Compositor,
+    CompositorCapabilities, CompositorConfig, CompositorSurfaceTransform, Device, MappableCompositor, MappedTileInfo,
+    NativeSurfaceId, NativeSurfaceInfo, NativeTileId, PartialPresentCompositor, PipelineInfo, ProfilerHooks,
+    RecordedFrameHandle, RenderBackendHooks, Renderer, RendererStats, SWGLCompositeSurfaceInfo, SceneBuilderHooks,
+    ShaderPrecacheFlags, Shaders, SharedShaders, UploadMethod, WebRenderOptions, WindowVisibility, ONE_TIME_USAGE_HINT,
 };
 use wr_malloc_size_of::MallocSizeOfOps;
 
@@ -57,7 +67,7 @@ static NEXT_NAMESPACE_ID: AtomicUsize = AtomicUsize::new(1);
 /// Special value handled in this wrapper layer to signify a redundant clip chain.
 pub const ROOT_CLIP_CHAIN: u64 = !0;
 
-fn next_namespace_id() -> IdNamespace {
+pub(crate) fn next_namespace_id() -> IdNamespace {
     IdNamespace(NEXT_NAMESPACE_ID.fetch_add(1, Ordering::Relaxed) as u32)
 }
 
@@ -177,6 +187,14 @@ pub struct DocumentHandle {
     // containing enum.
     hit_tester_request: Option<HitTesterRequest>,
     hit_tester: Option<Arc<dyn ApiHitTester>>,
+    // The cache of rasterized blob tiles of the window, whose memory is reported
+    // with this handle's. Clones of the handle don't report it again.
+    blob_tile_cache: Option<Arc<BlobTileCache>>,
+    // The timings of the blob tiles of the window, see wr_take_blob_raster_timings.
+    blob_raster_timings: Option<Arc<BlobRasterTimings>>,
+    // The fonts of the window's blobs that failed to register, see
+    // wr_api_get_failed_blob_fonts.
+    failed_blob_fonts: Option<Arc<FailedBlobFonts>>,
 }
 
 impl DocumentHandle {
@@ -200,6 +218,9 @@ impl DocumentHandle {
             document_id: doc,
             hit_tester_request,
             hit_tester,
+            blob_tile_cache: None,
+            blob_raster_timings: None,
+            failed_blob_fonts: None,
         }
     }
 
@@ -210,6 +231,81 @@ impl DocumentHandle {
         self.hit_tester = Some(self.hit_tester_request.take().unwrap().resolve());
         self.hit_tester.as_ref().unwrap()
     }
+
+    pub(crate) fn set_blob_tile_cache(&mut self, blob_tile_cache: Option<Arc<BlobTileCache>>) {
+        self.blob_tile_cache = blob_tile_cache;
+    }
+
+    pub(crate) fn set_blob_raster_timings(&mut self, blob_raster_timings: Option<Arc<BlobRasterTimings>>) {
+        self.blob_raster_timings = blob_raster_timings;
+    }
+
+    pub(crate) fn set_failed_blob_fonts(&mut self, failed_blob_fonts: Option<Arc<FailedBlobFonts>>) {
+        self.failed_blob_fonts = failed_blob_fonts;
+    }
+
+    /// Returns the API used to talk to WebRender's threads.
+    pub fn api(&self) -> &RenderApi {
+        &self.api
+    }
+
+    /// Returns the id of the document.
+    pub fn document_id(&self) -> DocumentId {
+        self.document_id
+    }
+
+    /// Creates another handle to the same document, with its own resource namespace,
+    /// for use on another thread.
+    pub fn create_similar(&mut self) -> DocumentHandle {
+        let hit_tester = self.ensure_hit_tester().clone();
+        DocumentHandle {
+            api: self.api.create_sender().create_api_by_client(next_namespace_id()),
+            document_id: self.document_id,
+            hit_tester: Some(hit_tester),
+            hit_tester_request: None,
+            blob_tile_cache: None,
+            blob_raster_timings: self.blob_raster_timings.clone(),
+            failed_blob_fonts: self.failed_blob_fonts.clone(),
+        }
+    }
+
+    /// Sends a transaction to the document, unless it is empty.
+    pub fn send_transaction(&mut self, transaction: Transaction) {
+        if transaction.is_empty() {
+            return;
+        }
+        self.api.send_transaction(self.document_id, transaction);
+    }
+
+    /// Returns the items at the given point, front to back.
+    pub fn hit_test(&mut self, point: WorldPoint) -> HitTestResult {
+        self.ensure_hit_tester().hit_test(point)
+    }
+
+    /// Sets the debug flags of the renderer.
+    pub fn set_debug_flags(&mut self, flags: DebugFlags) {
+        self.api.set_debug_flags(flags);
+    }
+
+    /// Asks WebRender to free as much memory as it can.
+    pub fn notify_memory_pressure(&mut self) {
+        self.api.notify_memory_pressure();
+    }
+
+    /// Waits for the transactions sent so far to be built into scenes.
+    pub fn flush_scene_builder(&mut self) {
+        self.api.flush_scene_builder();
+    }
+
+    /// Deletes the document, with all its pipelines.
+    pub fn delete_document(&mut self) {
+        self.api.delete_document(self.document_id);
+    }
+
+    /// Shuts down WebRender's threads, and waits for them to finish.
+    pub fn shut_down(&mut self) {
+        self.api.shut_down(true);
+    }
 }
 
 #[repr(C)]
@@ -387,36 +483,27 @@ impl<'a> From<&'a WrImageDescriptor> for ImageDescriptor {
 
 #[repr(u32)]
 #[allow(dead_code)]
-enum WrExternalImageType {
+pub enum WrExternalImageType {
     RawData,
     NativeTexture,
     Invalid,
 }
 
 #[repr(C)]
-struct WrExternalImage {
-    image_type: WrExternalImageType,
+pub struct WrExternalImage {
+    pub image_type: WrExternalImageType,
 
     // external texture handle
-    handle: u32,
+    pub handle: u32,
     // external texture coordinate
-    u0: f32,
-    v0: f32,
-    u1: f32,
-    v1: f32,
+    pub u0: f32,
+    pub v0: f32,
+    pub u1: f32,
+    pub v1: f32,
 
     // external image buffer
-    buff: *const u8,
-    size: usize,
-}
-
-extern "C" {
-    fn wr_renderer_lock_external_image(
-        renderer: *mut c_void,
-        external_image_id: ExternalImageId,
-        channel_index: u8,
-    ) -> WrExternalImage;
-    fn wr_renderer_unlock_external_image(renderer: *mut c_void, external_image_id: ExternalImageId, channel_index: u8);
+    pub buff: *const u8,
+    pub size: usize,
 }
 
 #[repr(C)]
@@ -427,7 +514,7 @@ pub struct WrExternalImageHandler {
 
 impl ExternalImageHandler for WrExternalImageHandler {
     fn lock(&mut self, id: ExternalImageId, channel_index: u8) -> ExternalImage {
-        let image = unsafe { wr_renderer_lock_external_image(self.external_image_obj, id, channel_index) };
+        let image = host().lock_external_image(self.external_image_obj, id, channel_index);
         ExternalImage {
             uv: TexelRect::new(image.u0, image.v0, image.u1, image.v1),
             source: match image.image_type {
@@ -441,9 +528,7 @@ impl ExternalImageHandler for WrExternalImageHandler {
     }
 
     fn unlock(&mut self, id: ExternalImageId, channel_index: u8) {
-        unsafe {
-            wr_renderer_unlock_external_image(self.external_image_obj, id, channel_index);
-        }
+        host().unlock_external_image(self.external_image_obj, id, channel_index);
     }
 }
 
@@ -497,7 +582,7 @@ pub type WrColorProperty = WrAnimationPropertyValue<ColorF>;
 /// cbindgen:derive-lte=true
 #[repr(C)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub struct WrWindowId(u64);
+pub struct WrWindowId(pub(crate) u64);
 
 #[repr(C)]
 #[derive(Debug)]
@@ -515,13 +600,8 @@ pub struct WrTransformInfo {
 }
 
 fn get_proc_address(glcontext_ptr: *mut c_void, name: &str) -> *const c_void {
-    extern "C" {
-        fn get_proc_address_from_glcontext(glcontext_ptr: *mut c_void, procname: *const c_char) -> *const c_void;
-    }
-
     let symbol_name = CString::new(name).unwrap();
-    let symbol = unsafe { get_proc_address_from_glcontext(glcontext_ptr, symbol_name.as_ptr()) };
-    symbol as *const _
+    host().get_proc_address(glcontext_ptr, &symbol_name)
 }
 
 #[repr(C)]
@@ -531,41 +611,12 @@ pub enum TelemetryProbe {
     FrameBuildTime = 2,
 }
 
-extern "C" {
-    fn is_in_compositor_thread() -> bool;
-    fn is_in_render_thread() -> bool;
-    fn is_in_main_thread() -> bool;
-    fn is_glcontext_gles(glcontext_ptr: *mut c_void) -> bool;
-    fn is_glcontext_angle(glcontext_ptr: *mut c_void) -> bool;
-    fn gfx_wr_resource_path_override() -> *const c_char;
-    fn gfx_wr_use_optimized_shaders() -> bool;
-    // TODO: make gfx_critical_error() work.
-    // We still have problem to pass the error message from render/render_backend
-    // thread to main thread now.
-    #[allow(dead_code)]
-    fn gfx_critical_error(msg: *const c_char);
-    fn gfx_critical_note(msg: *const c_char);
-    fn gfx_wr_set_crash_annotation(annotation: CrashAnnotation, value: *const c_char);
-    fn gfx_wr_clear_crash_annotation(annotation: CrashAnnotation);
-}
-
 struct CppNotifier {
     window_id: WrWindowId,
 }
 
 unsafe impl Send for CppNotifier {}
 
-extern "C" {
-    fn wr_notifier_wake_up(window_id: WrWindowId, composite_needed: bool);
-    fn wr_notifier_new_frame_ready(window_id: WrWindowId, composite_needed: bool, publish_id: FramePublishId);
-    fn wr_notifier_external_event(window_id: WrWindowId, raw_event: usize);
-    fn wr_schedule_render(window_id: WrWindowId, reasons: RenderReasons);
-    // NOTE: This moves away from pipeline_info.
-    fn wr_finished_scene_build(window_id: WrWindowId, pipeline_info: &mut WrPipelineInfo);
-
-    fn wr_transaction_notification_notified(handler: usize, when: Checkpoint);
-}
-
 impl RenderNotifier for CppNotifier {
     fn clone(&self) -> Box<dyn RenderNotifier> {
         Box::new(CppNotifier {
@@ -574,21 +625,15 @@ impl RenderNotifier for CppNotifier {
     }
 
     fn wake_up(&self, composite_needed: bool) {
-        unsafe {
-            wr_notifier_wake_up(self.window_id, composite_needed);
-        }
+        host().notifier_wake_up(self.window_id, composite_needed);
     }
 
     fn new_frame_ready(&self, _: DocumentId, _scrolled: bool, composite_needed: bool, publish_id: FramePublishId) {
-        unsafe {
-            wr_notifier_new_frame_ready(self.window_id, composite_needed, publish_id);
-        }
+        host().notifier_new_frame_ready(self.window_id, composite_needed, publish_id);
     }
 
     fn external_event(&self, event: ExternalEvent) {
-        unsafe {
-            wr_notifier_external_event(self.window_id, event.unwrap());
-        }
+        host().notifier_external_event(self.window_id, event.unwrap());
     }
 }
 
@@ -598,15 +643,11 @@ unsafe impl Send for MozCrashAnnotator {}
 
 impl CrashAnnotator for MozCrashAnnotator {
     fn set(&self, annotation: CrashAnnotation, value: &std::ffi::CStr) {
-        unsafe {
-            gfx_wr_set_crash_annotation(annotation, value.as_ptr());
-        }
+        host().set_crash_annotation(annotation, value);
     }
 
     fn clear(&self, annotation: CrashAnnotation) {
-        unsafe {
-            gfx_wr_clear_crash_annotation(annotation);
-        }
+        host().clear_crash_annotation(annotation);
     }
 
     fn box_clone(&self) -> Box<dyn CrashAnnotator> {
@@ -646,22 +687,23 @@ pub extern "C" fn wr_renderer_render(
     out_stats: &mut RendererStats,
     out_dirty_rects: &mut ThinVec<DeviceIntRect>,
 ) -> bool {
-    match renderer.render(DeviceIntSize::new(width, height), buffer_age) {
+    match api::render(renderer, DeviceIntSize::new(width, height), buffer_age) {
         Ok(results) => {
             *out_stats = results.stats;
             out_dirty_rects.extend(results.dirty_rects);
             true
         },
-        Err(errors) => {
+        Err(api::Error::Render(errors)) => {
             for e in errors {
                 warn!(" Failed to render: {:?}", e);
-                let msg = CString::new(format!("wr_renderer_render: {:?}", e)).unwrap();
-                unsafe {
-                    gfx_critical_note(msg.as_ptr());
-                }
+                host().critical_note(&format!("wr_renderer_render: {:?}", e));
             }
             false
         },
+        Err(e) => {
+            host().critical_note(&format!("wr_renderer_render: {}", e));
+            false
+        },
     }
 }
 
@@ -757,6 +799,109 @@ pub extern "C" fn wr_renderer_map_and_recycle_screenshot(
     )
 }
 
+/// Returns an empty frame to map a screenshot or recorded frame into, or None if the
+/// size is empty.
+fn raw_frame(width: i32, height: i32, image_format: ImageFormat) -> Option<RawFrame> {
+    if width <= 0 || height <= 0 {
+        return None;
+    }
+    let stride = width as usize * image_format.bytes_per_pixel() as usize;
+    Some(RawFrame {
+        size: DeviceIntSize::new(width, height),
+        format: image_format,
+        stride,
+        pixels: vec![0; stride * height as usize],
+    })
+}
+
+/// Maps an async screenshot like `wr_renderer_map_and_recycle_screenshot`, and starts
+/// encoding it on the given thread pool. Returns null if the size is empty or the
+/// screenshot couldn't be mapped.
+#[no_mangle]
+pub extern "C" fn wr_renderer_encode_screenshot(
+    renderer: &mut Renderer,
+    handle: AsyncScreenshotHandle,
+    width: i32,
+    height: i32,
+    image_format: ImageFormat,
+    thread_pool: &WrThreadPool,
+    options: &EncodeOptions,
+) -> *mut EncodeTask {
+    let mut frame = match raw_frame(width, height, image_format) {
+        Some(frame) => frame,
+        None => return ptr::null_mut(),
+    };
+    if !renderer.map_and_recycle_screenshot(handle, &mut frame.pixels, frame.stride) {
+        return ptr::null_mut();
+    }
+    Box::into_raw(Box::new(EncodeTask::spawn(&thread_pool.0, frame, *options)))
+}
+
+/// Maps a recorded frame like `wr_renderer_map_recorded_frame`, and starts encoding it
+/// on the given thread pool. Returns null if the size is empty or the frame couldn't be
+/// mapped.
+#[no_mangle]
+pub extern "C" fn wr_renderer_encode_recorded_frame(
+    renderer: &mut Renderer,
+    handle: RecordedFrameHandle,
+    width: i32,
+    height: i32,
+    image_format: ImageFormat,
+    thread_pool: &WrThreadPool,
+    options: &EncodeOptions,
+) -> *mut EncodeTask {
+    let mut frame = match raw_frame(width, height, image_format) {
+        Some(frame) => frame,
+        None => return ptr::null_mut(),
+    };
+    if !renderer.map_recorded_frame(handle, &mut frame.pixels, frame.stride) {
+        return ptr::null_mut();
+    }
+    Box::into_raw(Box::new(EncodeTask::spawn(&thread_pool.0, frame, *options)))
+}
+
+/// Waits for the image encoded by a task, and deletes the task. Returns false if the
+/// image couldn't be encoded. Use `wr_encode_task_poll` to not block.
+#[no_mangle]
+pub unsafe extern "C" fn wr_encode_task_finish(task: *mut EncodeTask, out_data: &mut ThinVec<u8>) -> bool {
+    match Box::from_raw(task).wait() {
+        Ok(data) => {
+            out_data.clear();
+            out_data.extend(data);
+            true
+        },
+        Err(e) => {
+            warn!("wr_encode_task_finish: {}", e);
+            false
+        },
+    }
+}
+
+/// Returns whether a task has finished encoding its image, without waiting for it.
+/// Once it has, the image is moved to `out_data`, and the task must be deleted with
+/// `wr_encode_task_delete`.
+#[no_mangle]
+pub extern "C" fn wr_encode_task_poll(task: &mut EncodeTask, out_data: &mut ThinVec<u8>) -> EncodeStatus {
+    match task.try_take() {
+        None => EncodeStatus::Pending,
+        Some(Ok(data)) => {
+            out_data.clear();
+            out_data.extend(data);
+            EncodeStatus::Finished
+        },
+        Some(Err(e)) => {
+            warn!("wr_encode_task_poll: {}", e);
+            EncodeStatus::Failed
+        },
+    }
+}
+
+/// Deletes a task without waiting for its image.
+#[no_mangle]
+pub unsafe extern "C" fn wr_encode_task_delete(task: *mut EncodeTask) {
+    mem::drop(Box::from_raw(task));
+}
+
 #[no_mangle]
 pub extern "C" fn wr_renderer_release_profiler_structures(renderer: &mut Renderer) {
     renderer.release_profiler_structures();
@@ -882,10 +1027,7 @@ pub unsafe extern "C" fn wr_renderer_flush_pipeline_info(renderer: &mut Renderer
     *out = WrPipelineInfo::new(&info);
 }
 
-extern "C" {
-    pub fn gecko_profiler_thread_is_being_profiled() -> bool;
-}
-
+#[cfg(feature = "gecko")]
 pub fn gecko_profiler_start_marker(name: &str) {
     use gecko_profiler::{gecko_profiler_category, MarkerOptions, MarkerTiming, ProfilerTime, Tracing};
     gecko_profiler::add_marker(
@@ -898,6 +1040,7 @@ pub fn gecko_profiler_start_marker(name: &str) {
         Tracing::from_str("Webrender"),
     );
 }
+#[cfg(feature = "gecko")]
 pub fn gecko_profiler_end_marker(name: &str) {
     use gecko_profiler::{gecko_profiler_category, MarkerOptions, MarkerTiming, ProfilerTime, Tracing};
     gecko_profiler::add_marker(
@@ -911,6 +1054,7 @@ pub fn gecko_profiler_end_marker(name: &str) {
     );
 }
 
+#[cfg(feature = "gecko")]
 pub fn gecko_profiler_event_marker(name: &str) {
     use gecko_profiler::{gecko_profiler_category, Tracing};
     gecko_profiler::add_marker(
@@ -921,6 +1065,7 @@ pub fn gecko_profiler_event_marker(name: &str) {
     );
 }
 
+#[cfg(feature = "gecko")]
 pub fn gecko_profiler_add_text_marker(name: &str, text: &str, microseconds: f64) {
     use gecko_profiler::{gecko_profiler_category, MarkerOptions, MarkerTiming, ProfilerTime};
     if !gecko_profiler::can_accept_markers() {
@@ -940,16 +1085,31 @@ pub fn gecko_profiler_add_text_marker(name: &str, text: &str, microseconds: f64)
     );
 }
 
+// Without Gecko, there is no profiler to add markers to.
+#[cfg(not(feature = "gecko"))]
+pub fn gecko_profiler_start_marker(_name: &str) {}
+#[cfg(not(feature = "gecko"))]
+pub fn gecko_profiler_end_marker(_name: &str) {}
+#[cfg(not(feature = "gecko"))]
+pub fn gecko_profiler_event_marker(_name: &str) {}
+#[cfg(not(feature = "gecko"))]
+pub fn gecko_profiler_add_text_marker(_name: &str, _text: &str, _microseconds: f64) {}
+
 /// Simple implementation of the WR ProfilerHooks trait to allow profile
 /// markers to be seen in the Gecko profiler.
 struct GeckoProfilerHooks;
 
 impl ProfilerHooks for GeckoProfilerHooks {
+    #[cfg(feature = "gecko")]
     fn register_thread(&self, thread_name: &str) {
         gecko_profiler::register_thread(thread_name);
     }
 
+    #[cfg(not(feature = "gecko"))]
+    fn register_thread(&self, _thread_name: &str) {}
+
     fn unregister_thread(&self) {
+        #[cfg(feature = "gecko")]
         gecko_profiler::unregister_thread();
     }
 
@@ -971,33 +1131,12 @@ impl ProfilerHooks for GeckoProfilerHooks {
     }
 
     fn thread_is_being_profiled(&self) -> bool {
-        unsafe { gecko_profiler_thread_is_being_profiled() }
+        host().thread_is_being_profiled()
     }
 }
 
 static PROFILER_HOOKS: GeckoProfilerHooks = GeckoProfilerHooks {};
 
-#[allow(improper_ctypes)] // this is needed so that rustc doesn't complain about passing the &mut Transaction to an extern function
-extern "C" {
-    // These callbacks are invoked from the scene builder thread (aka the APZ
-    // updater thread)
-    fn apz_register_updater(window_id: WrWindowId);
-    fn apz_pre_scene_swap(window_id: WrWindowId);
-    fn apz_post_scene_swap(window_id: WrWindowId, pipeline_info: &WrPipelineInfo);
-    fn apz_run_updater(window_id: WrWindowId);
-    fn apz_deregister_updater(window_id: WrWindowId);
-
-    // These callbacks are invoked from the render backend thread (aka the APZ
-    // sampler thread)
-    fn apz_register_sampler(window_id: WrWindowId);
-    fn apz_sample_transforms(window_id: WrWindowId, generated_frame_id: *const u64, transaction: &mut Transaction);
-    fn apz_deregister_sampler(window_id: WrWindowId);
-
-    fn omta_register_sampler(window_id: WrWindowId);
-    fn omta_sample(window_id: WrWindowId, transaction: &mut Transaction);
-    fn omta_deregister_sampler(window_id: WrWindowId);
-}
-
 struct APZCallbacks {
     window_id: WrWindowId,
 }
@@ -1010,12 +1149,11 @@ impl APZCallbacks {
 
 impl SceneBuilderHooks for APZCallbacks {
     fn register(&self) {
-        unsafe {
-            if static_prefs::pref!("gfx.webrender.scene-builder-thread-local-arena") {
-                wr_register_thread_local_arena();
-            }
-            apz_register_updater(self.window_id);
+        let host = host();
+        if host.use_thread_local_arena(ArenaThread::SceneBuilder) {
+            host.register_thread_local_arena();
         }
+        host.apz_register_updater(self.window_id);
     }
 
     fn pre_scene_build(&self) {
@@ -1023,26 +1161,22 @@ impl SceneBuilderHooks for APZCallbacks {
     }
 
     fn pre_scene_swap(&self) {
-        unsafe {
-            apz_pre_scene_swap(self.window_id);
-        }
+        host().apz_pre_scene_swap(self.window_id);
     }
 
     fn post_scene_swap(&self, _document_ids: &Vec<DocumentId>, info: PipelineInfo) {
         let mut info = WrPipelineInfo::new(&info);
-        unsafe {
-            apz_post_scene_swap(self.window_id, &info);
-        }
+        host().apz_post_scene_swap(self.window_id, &info);
 
         // After a scene swap we should schedule a render for the next vsync,
         // otherwise there's no guarantee that the new scene will get rendered
         // anytime soon
-        unsafe { wr_finished_scene_build(self.window_id, &mut info) }
+        host().finished_scene_build(self.window_id, &mut info);
         gecko_profiler_end_marker("SceneBuilding");
     }
 
     fn post_resource_update(&self, _document_ids: &Vec<DocumentId>) {
-        unsafe { wr_schedule_render(self.window_id, RenderReasons::POST_RESOURCE_UPDATES_HOOK) }
+        host().schedule_render(self.window_id, RenderReasons::POST_RESOURCE_UPDATES_HOOK);
         gecko_profiler_end_marker("SceneBuilding");
     }
 
@@ -1051,11 +1185,11 @@ impl SceneBuilderHooks for APZCallbacks {
     }
 
     fn poke(&self) {
-        unsafe { apz_run_updater(self.window_id) }
+        host().apz_run_updater(self.window_id)
     }
 
     fn deregister(&self) {
-        unsafe { apz_deregister_updater(self.window_id) }
+        host().apz_deregister_updater(self.window_id)
     }
 }
 
@@ -1063,8 +1197,9 @@ struct RenderBackendCallbacks;
 
 impl RenderBackendHooks for RenderBackendCallbacks {
     fn init_thread(&self) {
-        if static_prefs::pref!("gfx.webrender.frame-builder-thread-local-arena") {
-            unsafe { wr_register_thread_local_arena() };
+        let host = host();
+        if host.use_thread_local_arena(ArenaThread::FrameBuilder) {
+            host.register_thread_local_arena();
         }
     }
 }
@@ -1081,44 +1216,29 @@ impl SamplerCallback {
 
 impl AsyncPropertySampler for SamplerCallback {
     fn register(&self) {
-        unsafe {
-            apz_register_sampler(self.window_id);
-            omta_register_sampler(self.window_id);
-        }
+        let host = host();
+        host.apz_register_sampler(self.window_id);
+        host.omta_register_sampler(self.window_id);
     }
 
     fn sample(&self, _document_id: DocumentId, generated_frame_id: Option<u64>) -> Vec<FrameMsg> {
-        let generated_frame_id_value;
-        let generated_frame_id: *const u64 = match generated_frame_id {
-            Some(id) => {
-                generated_frame_id_value = id;
-                &generated_frame_id_value
-            },
-            None => ptr::null_mut(),
-        };
         let mut transaction = Transaction::new();
         // Reset the pending properties first because omta_sample and apz_sample_transforms
         // may be failed to reset them due to null samplers.
         transaction.reset_dynamic_properties();
-        unsafe {
-            apz_sample_transforms(self.window_id, generated_frame_id, &mut transaction);
-            omta_sample(self.window_id, &mut transaction);
-        };
+        let host = host();
+        host.apz_sample_transforms(self.window_id, generated_frame_id, &mut transaction);
+        host.omta_sample(self.window_id, &mut transaction);
         transaction.get_frame_ops()
     }
 
     fn deregister(&self) {
-        unsafe {
-            apz_deregister_sampler(self.window_id);
-            omta_deregister_sampler(self.window_id);
-        }
+        let host = host();
+        host.apz_deregister_sampler(self.window_id);
+        host.omta_deregister_sampler(self.window_id);
     }
 }
 
-extern "C" {
-    fn wr_register_thread_local_arena();
-}
-
 pub struct WrThreadPool(Arc<rayon::ThreadPool>);
 
 #[no_mangle]
@@ -1134,20 +1254,22 @@ pub extern "C" fn wr_thread_pool_new(low_priority: bool) -> *mut WrThreadPool {
 
     let priority_tag = if low_priority { "LP" } else { "" };
 
-    let use_thread_local_arena = static_prefs::pref!("gfx.webrender.worker-thread-local-arena");
+    let use_thread_local_arena = host().use_thread_local_arena(ArenaThread::Worker);
 
     let worker = rayon::ThreadPoolBuilder::new()
         .thread_name(move |idx| format!("WRWorker{}#{}", priority_tag, idx))
         .num_threads(num_threads)
         .start_handler(move |idx| {
             if use_thread_local_arena {
-                unsafe { wr_register_thread_local_arena(); }
+                host().register_thread_local_arena();
             }
             let name = format!("WRWorker{}#{}", priority_tag, idx);
             register_thread_with_profiler(name.clone());
+            #[cfg(feature = "gecko")]
             gecko_profiler::register_thread(&name);
         })
         .exit_handler(|_idx| {
+            #[cfg(feature = "gecko")]
             gecko_profiler::unregister_thread();
         })
         .build();
@@ -1179,13 +1301,47 @@ pub unsafe extern "C" fn wr_chunk_pool_purge(pool: &WrChunkPool) {
     pool.0.purge_all_chunks();
 }
 
+// Call MakeCurrent before this.
+#[cfg(feature = "gecko")]
+fn wr_device_fingerprint(swgl_context: *mut c_void, gl_context: *mut c_void) -> WrDeviceFingerprint {
+    let software = !swgl_context.is_null();
+    let gl = if software {
+        Rc::new(swgl::Context::from(swgl_context)) as Rc<dyn gl::Gl>
+    } else if gl_context.is_null() {
+        return WrDeviceFingerprint {
+            gl_vendor: String::new(),
+            gl_renderer: String::new(),
+            gl_version: String::new(),
+            is_software: false,
+        };
+    } else if host().is_glcontext_gles(gl_context) {
+        unsafe { gl::GlesFns::load_with(|symbol| get_proc_address(gl_context, symbol)) }
+    } else {
+        unsafe { gl::GlFns::load_with(|symbol| get_proc_address(gl_context, symbol)) }
+    };
+
+    WrDeviceFingerprint {
+        gl_vendor: gl.get_string(gl::VENDOR),
+        gl_renderer: gl.get_string(gl::RENDERER),
+        gl_version: gl.get_string(gl::VERSION),
+        is_software: software,
+    }
+}
+
+// Call MakeCurrent before this.
+#[cfg(feature = "gecko")]
 #[no_mangle]
 pub unsafe extern "C" fn wr_program_cache_new(
     prof_path: &nsAString,
     thread_pool: *mut WrThreadPool,
+    use_archive: bool,
+    swgl_context: *mut c_void,
+    gl_context: *mut c_void,
 ) -> *mut WrProgramCache {
     let workers = &(*thread_pool).0;
-    let program_cache = WrProgramCache::new(prof_path, workers);
+    let fingerprint = wr_device_fingerprint(swgl_context, gl_context);
+    let cache_path = get_cache_path_from_prof_path(prof_path);
+    let program_cache = WrProgramCache::new(cache_path, workers, use_archive, &fingerprint);
     Box::into_raw(Box::new(program_cache))
 }
 
@@ -1199,9 +1355,32 @@ pub unsafe extern "C" fn wr_try_load_startup_shaders_from_disk(program_cache: *m
     (*program_cache).try_load_startup_shaders_from_disk();
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn wr_program_cache_set_max_disk_size(program_cache: *mut WrProgramCache, max_size: u64) {
+    (*program_cache).set_max_disk_size(max_size);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wr_program_cache_get_disk_usage(
+    program_cache: *const WrProgramCache,
+    out_usage: &mut WrProgramCacheDiskUsage,
+) {
+    *out_usage = (*program_cache).disk_usage();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wr_program_cache_get_quarantined_count(program_cache: *const WrProgramCache) -> usize {
+    (*program_cache).quarantined_count()
+}
+
+#[cfg(feature = "gecko")]
 #[no_mangle]
 pub unsafe extern "C" fn remove_program_binary_disk_cache(prof_path: &nsAString) -> bool {
-    match remove_disk_cache(prof_path) {
+    let cache_path = match get_cache_path_from_prof_path(prof_path) {
+        Some(cache_path) => cache_path,
+        None => return true,
+    };
+    match remove_disk_cache(&cache_path) {
         Ok(_) => true,
         Err(_) => {
             error!("Failed to remove program binary disk cache");
@@ -1211,16 +1390,16 @@ pub unsafe extern "C" fn remove_program_binary_disk_cache(prof_path: &nsAString)
 }
 
 // This matches IsEnvSet in gfxEnv.h
-fn env_var_to_bool(key: &'static str) -> bool {
+pub(crate) fn env_var_to_bool(key: &'static str) -> bool {
     env::var(key).ok().map_or(false, |v| !v.is_empty())
 }
 
 // Call MakeCurrent before this.
 fn wr_device_new(gl_context: *mut c_void, pc: Option<&mut WrProgramCache>) -> Device {
-    assert!(unsafe { is_in_render_thread() });
+    assert!(is_in_render_thread());
 
     let gl;
-    if unsafe { is_glcontext_gles(gl_context) } {
+    if host().is_glcontext_gles(gl_context) {
         gl = unsafe { gl::GlesFns::load_with(|symbol| get_proc_address(gl_context, symbol)) };
     } else {
         gl = unsafe { gl::GlFns::load_with(|symbol| get_proc_address(gl_context, symbol)) };
@@ -1230,25 +1409,15 @@ fn wr_device_new(gl_context: *mut c_void, pc: Option<&mut WrProgramCache>) -> De
 
     info!("WebRender - OpenGL version new {}", version);
 
-    let upload_method = if unsafe { is_glcontext_angle(gl_context) } {
+    let upload_method = if host().is_glcontext_angle(gl_context) {
         UploadMethod::Immediate
     } else {
         UploadMethod::PixelBuffer(ONE_TIME_USAGE_HINT)
     };
 
-    let resource_override_path = unsafe {
-        let override_charptr = gfx_wr_resource_path_override();
-        if override_charptr.is_null() {
-            None
-        } else {
-            match CStr::from_ptr(override_charptr).to_str() {
-                Ok(override_str) => Some(PathBuf::from(override_str)),
-                _ => None,
-            }
-        }
-    };
+    let resource_override_path = host().resource_path_override();
 
-    let use_optimized_shaders = unsafe { gfx_wr_use_optimized_shaders() };
+    let use_optimized_shaders = host().use_optimized_shaders();
 
     let cached_programs = pc.map(|cached_programs| Rc::clone(cached_programs.rc_get()));
 
@@ -1268,71 +1437,6 @@ fn wr_device_new(gl_context: *mut c_void, pc: Option<&mut WrProgramCache>) -> De
     )
 }
 
-extern "C" {
-    fn wr_compositor_create_surface(
-        compositor: *mut c_void,
-        id: NativeSurfaceId,
-        virtual_offset: DeviceIntPoint,
-        tile_size: DeviceIntSize,
-        is_opaque: bool,
-    );
-    fn wr_compositor_create_external_surface(compositor: *mut c_void, id: NativeSurfaceId, is_opaque: bool);
-    fn wr_compositor_create_backdrop_surface(compositor: *mut c_void, id: NativeSurfaceId, color: ColorF);
-    fn wr_compositor_destroy_surface(compositor: *mut c_void, id: NativeSurfaceId);
-    fn wr_compositor_create_tile(compositor: *mut c_void, id: NativeSurfaceId, x: i32, y: i32);
-    fn wr_compositor_destroy_tile(compositor: *mut c_void, id: NativeSurfaceId, x: i32, y: i32);
-    fn wr_compositor_attach_external_image(
-        compositor: *mut c_void,
-        id: NativeSurfaceId,
-        external_image: ExternalImageId,
-    );
-    fn wr_compositor_bind(
-        compositor: *mut c_void,
-        id: NativeTileId,
-        offset: &mut DeviceIntPoint,
-        fbo_id: &mut u32,
-        dirty_rect: DeviceIntRect,
-        valid_rect: DeviceIntRect,
-    );
-    fn wr_compositor_unbind(compositor: *mut c_void);
-    fn wr_compositor_begin_frame(compositor: *mut c_void);
-    fn wr_compositor_add_surface(
-        compositor: *mut c_void,
-        id: NativeSurfaceId,
-        transform: &CompositorSurfaceTransform,
-        clip_rect: DeviceIntRect,
-        image_rendering: ImageRendering,
-    );
-    fn wr_compositor_start_compositing(
-        compositor: *mut c_void,
-        clear_color: ColorF,
-        dirty_rects: *const DeviceIntRect,
-        num_dirty_rects: usize,
-        opaque_rects: *const DeviceIntRect,
-        num_opaque_rects: usize,
-    );
-    fn wr_compositor_end_frame(compositor: *mut c_void);
-    fn wr_compositor_enable_native_compositor(compositor: *mut c_void, enable: bool);
-    fn wr_compositor_deinit(compositor: *mut c_void);
-    fn wr_compositor_get_capabilities(compositor: *mut c_void, caps: *mut CompositorCapabilities);
-    fn wr_compositor_get_window_visibility(compositor: *mut c_void, caps: *mut WindowVisibility);
-    fn wr_compositor_map_tile(
-        compositor: *mut c_void,
-        id: NativeTileId,
-        dirty_rect: DeviceIntRect,
-        valid_rect: DeviceIntRect,
-        data: &mut *mut c_void,
-        stride: &mut i32,
-    );
-    fn wr_compositor_unmap_tile(compositor: *mut c_void);
-
-    fn wr_partial_present_compositor_set_buffer_damage_region(
-        compositor: *mut c_void,
-        rects: *const DeviceIntRect,
-        n_rects: usize,
-    );
-}
-
 pub struct WrCompositor(*mut c_void);
 
 impl Compositor for WrCompositor {
@@ -1344,45 +1448,31 @@ impl Compositor for WrCompositor {
         tile_size: DeviceIntSize,
         is_opaque: bool,
     ) {
-        unsafe {
-            wr_compositor_create_surface(self.0, id, virtual_offset, tile_size, is_opaque);
-        }
+        host().compositor_create_surface(self.0, id, virtual_offset, tile_size, is_opaque);
     }
 
     fn create_external_surface(&mut self, _device: &mut Device, id: NativeSurfaceId, is_opaque: bool) {
-        unsafe {
-            wr_compositor_create_external_surface(self.0, id, is_opaque);
-        }
+        host().compositor_create_external_surface(self.0, id, is_opaque);
     }
 
     fn create_backdrop_surface(&mut self, _device: &mut Device, id: NativeSurfaceId, color: ColorF) {
-        unsafe {
-            wr_compositor_create_backdrop_surface(self.0, id, color);
-        }
+        host().compositor_create_backdrop_surface(self.0, id, color);
     }
 
     fn destroy_surface(&mut self, _device: &mut Device, id: NativeSurfaceId) {
-        unsafe {
-            wr_compositor_destroy_surface(self.0, id);
-        }
+        host().compositor_destroy_surface(self.0, id);
     }
 
     fn create_tile(&mut self, _device: &mut Device, id: NativeTileId) {
-        unsafe {
-            wr_compositor_create_tile(self.0, id.surface_id, id.x, id.y);
-        }
+        host().compositor_create_tile(self.0, id.surface_id, id.x, id.y);
     }
 
     fn destroy_tile(&mut self, _device: &mut Device, id: NativeTileId) {
-        unsafe {
-            wr_compositor_destroy_tile(self.0, id.surface_id, id.x, id.y);
-        }
+        host().compositor_destroy_tile(self.0, id.surface_id, id.x, id.y);
     }
 
     fn attach_external_image(&mut self, _device: &mut Device, id: NativeSurfaceId, external_image: ExternalImageId) {
-        unsafe {
-            wr_compositor_attach_external_image(self.0, id, external_image);
-        }
+        host().compositor_attach_external_image(self.0, id, external_image);
     }
 
     fn bind(
@@ -1397,30 +1487,24 @@ impl Compositor for WrCompositor {
             fbo_id: 0,
         };
 
-        unsafe {
-            wr_compositor_bind(
-                self.0,
-                id,
-                &mut surface_info.origin,
-                &mut surface_info.fbo_id,
-                dirty_rect,
-                valid_rect,
-            );
-        }
+        host().compositor_bind(
+            self.0,
+            id,
+            &mut surface_info.origin,
+            &mut surface_info.fbo_id,
+            dirty_rect,
+            valid_rect,
+        );
 
         surface_info
     }
 
     fn unbind(&mut self, _device: &mut Device) {
-        unsafe {
-            wr_compositor_unbind(self.0);
-        }
+        host().compositor_unbind(self.0);
     }
 
     fn begin_frame(&mut self, _device: &mut Device) {
-        unsafe {
-            wr_compositor_begin_frame(self.0);
-        }
+        host().compositor_begin_frame(self.0);
     }
 
     fn add_surface(
@@ -1431,9 +1515,7 @@ impl Compositor for WrCompositor {
         clip_rect: DeviceIntRect,
         image_rendering: ImageRendering,
     ) {
-        unsafe {
-            wr_compositor_add_surface(self.0, id, &transform, clip_rect, image_rendering);
-        }
+        host().compositor_add_surface(self.0, id, &transform, clip_rect, image_rendering);
     }
 
     fn start_compositing(
@@ -1443,126 +1525,509 @@ impl Compositor for WrCompositor {
         dirty_rects: &[DeviceIntRect],
         opaque_rects: &[DeviceIntRect],
     ) {
-        unsafe {
-            wr_compositor_start_compositing(
-                self.0,
-                clear_color,
-                dirty_rects.as_ptr(),
-                dirty_rects.len(),
-                opaque_rects.as_ptr(),
-                opaque_rects.len(),
-            );
-        }
+        host().compositor_start_compositing(self.0, clear_color, dirty_rects, opaque_rects);
     }
 
     fn end_frame(&mut self, _device: &mut Device) {
-        unsafe {
-            wr_compositor_end_frame(self.0);
-        }
+        host().compositor_end_frame(self.0);
     }
 
     fn enable_native_compositor(&mut self, _device: &mut Device, enable: bool) {
-        unsafe {
-            wr_compositor_enable_native_compositor(self.0, enable);
-        }
+        host().compositor_enable_native_compositor(self.0, enable);
     }
 
     fn deinit(&mut self, _device: &mut Device) {
-        unsafe {
-            wr_compositor_deinit(self.0);
-        }
+        host().compositor_deinit(self.0);
     }
 
     fn get_capabilities(&self, _device: &mut Device) -> CompositorCapabilities {
-        unsafe {
-            let mut caps: CompositorCapabilities = Default::default();
-            wr_compositor_get_capabilities(self.0, &mut caps);
-            caps
-        }
+        let mut caps: CompositorCapabilities = Default::default();
+        host().compositor_get_capabilities(self.0, &mut caps);
+        caps
     }
 
     fn get_window_visibility(&self, _device: &mut Device) -> WindowVisibility {
-        unsafe {
-            let mut visibility: WindowVisibility = Default::default();
-            wr_compositor_get_window_visibility(self.0, &mut visibility);
-            visibility
-        }
+        let mut visibility: WindowVisibility = Default::default();
+        host().compositor_get_window_visibility(self.0, &mut visibility);
+        visibility
     }
 }
 
-extern "C" {
-    fn wr_swgl_lock_composite_surface(
+impl MappableCompositor for WrCompositor {
+    /// Map a tile's underlying buffer so it can be used as the backing for
+    /// a SWGL framebuffer. This is intended to be a replacement for 'bind'
+    /// in any compositors that intend to directly interoperate with SWGL
+    /// while supporting some form of native layers.
+    fn map_tile(
+        &mut self,
+        _device: &mut Device,
+        id: NativeTileId,
+        dirty_rect: DeviceIntRect,
+        valid_rect: DeviceIntRect,
+    ) -> Option<MappedTileInfo> {
+        let mut tile_info = MappedTileInfo {
+            data: ptr::null_mut(),
+            stride: 0,
+        };
+
+        host().compositor_map_tile(
+            self.0,
+            id,
+            dirty_rect,
+            valid_rect,
+            &mut tile_info.data,
+            &mut tile_info.stride,
+        );
+
+        if !tile_info.data.is_null() && tile_info.stride != 0 {
+            Some(tile_info)
+        } else {
+            None
+        }
+    }
+
+    /// Unmap a tile that was was previously mapped via map_tile to signal
+    /// that SWGL is done rendering to the buffer.
+    fn unmap_tile(&mut self, _device: &mut Device) {
+        host().compositor_unmap_tile(self.0);
+    }
+
+    fn lock_composite_surface(
+        &mut self,
+        _device: &mut Device,
         ctx: *mut c_void,
         external_image_id: ExternalImageId,
         composite_info: *mut SWGLCompositeSurfaceInfo,
-    ) -> bool;
-    fn wr_swgl_unlock_composite_surface(ctx: *mut c_void, external_image_id: ExternalImageId);
+    ) -> bool {
+        host().swgl_lock_composite_surface(ctx, external_image_id, composite_info)
+    }
+    fn unlock_composite_surface(&mut self, _device: &mut Device, ctx: *mut c_void, external_image_id: ExternalImageId) {
+        host().swgl_unlock_composite_surface(ctx, external_image_id)
+    }
+}
+
+pub struct WrPartialPresentCompositor(*mut c_void);
+
+impl PartialPresentCompositor for WrPartialPresentCompositor {
+    fn set_buffer_damage_region(&mut self, rects: &[DeviceIntRect]) {
+        host().compositor_set_buffer_damage_region(self.0, rects);
+    }
+}
+
+/// A call made to a native compositor, as recorded by a `RecordingCompositor`.
+///
+/// Surfaces, tiles and external images are identified by their raw ids. Tile
+/// contents aren't recorded.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CompositorCall {
+    CreateSurface {
+        id: u64,
+        virtual_offset: DeviceIntPoint,
+        tile_size: DeviceIntSize,
+        is_opaque: bool,
+    },
+    CreateExternalSurface {
+        id: u64,
+        is_opaque: bool,
+    },
+    CreateBackdropSurface {
+        id: u64,
+        color: ColorF,
+    },
+    DestroySurface {
+        id: u64,
+    },
+    CreateTile {
+        id: (u64, i32, i32),
+    },
+    DestroyTile {
+        id: (u64, i32, i32),
+    },
+    AttachExternalImage {
+        id: u64,
+        external_image: u64,
+    },
+    Bind {
+        id: (u64, i32, i32),
+        dirty_rect: DeviceIntRect,
+        valid_rect: DeviceIntRect,
+    },
+    Unbind,
+    MapTile {
+        id: (u64, i32, i32),
+        dirty_rect: DeviceIntRect,
+        valid_rect: DeviceIntRect,
+    },
+    UnmapTile,
+    BeginFrame,
+    AddSurface {
+        id: u64,
+        /// The scale and then the offset of the transform.
+        transform: (f32, f32, f32, f32),
+        clip_rect: DeviceIntRect,
+        image_rendering: ImageRendering,
+    },
+    StartCompositing {
+        clear_color: ColorF,
+        dirty_rects: Vec<DeviceIntRect>,
+        opaque_rects: Vec<DeviceIntRect>,
+    },
+    EndFrame,
+    EnableNativeCompositor {
+        enable: bool,
+    },
+    Deinit,
+}
+
+fn tile_id(id: NativeTileId) -> (u64, i32, i32) {
+    (id.surface_id.0, id.x, id.y)
+}
+
+fn native_tile_id((surface_id, x, y): (u64, i32, i32)) -> NativeTileId {
+    NativeTileId {
+        surface_id: NativeSurfaceId(surface_id),
+        x,
+        y,
+    }
+}
+
+impl CompositorCall {
+    /// Makes the call on a compositor. Tiles can only be mapped by a
+    /// `MappableCompositor`, so `MapTile` calls are skipped, see `replay_mapped`.
+    pub fn replay(&self, compositor: &mut dyn Compositor, device: &mut Device) {
+        self.replay_unmapped(compositor, device);
+    }
+
+    /// Makes the call on a compositor whose tiles can be mapped. Mapped tiles are
+    /// unmapped right away, as their contents weren't recorded.
+    pub fn replay_mapped(&self, compositor: &mut dyn MappableCompositor, device: &mut Device) {
+        match *self {
+            CompositorCall::MapTile {
+                id,
+                dirty_rect,
+                valid_rect,
+            } => {
+                if compositor
+                    .map_tile(device, native_tile_id(id), dirty_rect, valid_rect)
+                    .is_some()
+                {
+                    compositor.unmap_tile(device);
+                }
+            },
+            _ => self.replay_unmapped(compositor, device),
+        }
+    }
+
+    fn replay_unmapped<C: Compositor + ?Sized>(&self, compositor: &mut C, device: &mut Device) {
+        use self::CompositorCall::*;
+        match *self {
+            CreateSurface {
+                id,
+                virtual_offset,
+                tile_size,
+                is_opaque,
+            } => compositor.create_surface(device, NativeSurfaceId(id), virtual_offset, tile_size, is_opaque),
+            CreateExternalSurface { id, is_opaque } => {
+                compositor.create_external_surface(device, NativeSurfaceId(id), is_opaque)
+            },
+            CreateBackdropSurface { id, color } => {
+                compositor.create_backdrop_surface(device, NativeSurfaceId(id), color)
+            },
+            DestroySurface { id } => compositor.destroy_surface(device, NativeSurfaceId(id)),
+            CreateTile { id } => compositor.create_tile(device, native_tile_id(id)),
+            DestroyTile { id } => compositor.destroy_tile(device, native_tile_id(id)),
+            AttachExternalImage { id, external_image } => {
+                compositor.attach_external_image(device, NativeSurfaceId(id), ExternalImageId(external_image))
+            },
+            Bind {
+                id,
+                dirty_rect,
+                valid_rect,
+            } => {
+                compositor.bind(device, native_tile_id(id), dirty_rect, valid_rect);
+            },
+            Unbind => compositor.unbind(device),
+            // Tiles are unmapped as soon as they are mapped, if they can be.
+            MapTile { .. } | UnmapTile => {},
+            BeginFrame => compositor.begin_frame(device),
+            AddSurface {
+                id,
+                transform: (scale_x, scale_y, offset_x, offset_y),
+                clip_rect,
+                image_rendering,
+            } => {
+                let mut transform = CompositorSurfaceTransform::identity();
+                transform.scale.x = scale_x;
+                transform.scale.y = scale_y;
+                transform.offset.x = offset_x;
+                transform.offset.y = offset_y;
+                compositor.add_surface(device, NativeSurfaceId(id), transform, clip_rect, image_rendering)
+            },
+            StartCompositing {
+                clear_color,
+                ref dirty_rects,
+                ref opaque_rects,
+            } => compositor.start_compositing(device, clear_color, dirty_rects, opaque_rects),
+            EndFrame => compositor.end_frame(device),
+            EnableNativeCompositor { enable } => compositor.enable_native_compositor(device, enable),
+            Deinit => compositor.deinit(device),
+        }
+    }
+}
+
+/// A compositor that records the calls made to another one in a directory, so that
+/// they can be read back with `read_compositor_recording` and replayed into another
+/// compositor.
+///
+/// The calls of every frame are written to their own file when the frame ends, named
+/// after its sequence number. Calls made between frames belong to the next one.
+pub struct RecordingCompositor<C> {
+    compositor: C,
+    dir: PathBuf,
+    next_frame: u64,
+    /// The calls of the current frame.
+    calls: Vec<CompositorCall>,
+}
+
+impl<C: Compositor> RecordingCompositor<C> {
+    /// Creates a compositor that records the calls made to `compositor` in the given
+    /// directory, which must exist.
+    pub fn new(compositor: C, dir: PathBuf) -> Self {
+        RecordingCompositor {
+            compositor,
+            dir,
+            next_frame: 0,
+            calls: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, call: CompositorCall) {
+        self.calls.push(call);
+    }
+
+    /// Writes the calls of the current frame. Failures are logged, as recording is
+    /// only a debugging aid.
+    fn write_frame(&mut self) {
+        if self.calls.is_empty() {
+            return;
+        }
+        let path = self.dir.join(format!("{:08}.bin", self.next_frame));
+        self.next_frame += 1;
+        let written = bincode::serialize(&self.calls)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            .and_then(|frame| fs::write(&path, frame));
+        if let Err(err) = written {
+            warn!("Failed to record compositor frame {:?}: {:?}", path, err);
+        }
+        self.calls.clear();
+    }
+}
+
+/// Reads the calls recorded by a `RecordingCompositor`, frame by frame.
+pub fn read_compositor_recording(dir: &Path) -> io::Result<Vec<Vec<CompositorCall>>> {
+    let mut frames: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    frames.sort();
+
+    frames
+        .iter()
+        .map(|path| {
+            bincode::deserialize(&fs::read(path)?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        })
+        .collect()
+}
+
+impl<C: Compositor> Compositor for RecordingCompositor<C> {
+    fn create_surface(
+        &mut self,
+        device: &mut Device,
+        id: NativeSurfaceId,
+        virtual_offset: DeviceIntPoint,
+        tile_size: DeviceIntSize,
+        is_opaque: bool,
+    ) {
+        self.record(CompositorCall::CreateSurface {
+            id: id.0,
+            virtual_offset,
+            tile_size,
+            is_opaque,
+        });
+        self.compositor
+            .create_surface(device, id, virtual_offset, tile_size, is_opaque);
+    }
+
+    fn create_external_surface(&mut self, device: &mut Device, id: NativeSurfaceId, is_opaque: bool) {
+        self.record(CompositorCall::CreateExternalSurface { id: id.0, is_opaque });
+        self.compositor.create_external_surface(device, id, is_opaque);
+    }
+
+    fn create_backdrop_surface(&mut self, device: &mut Device, id: NativeSurfaceId, color: ColorF) {
+        self.record(CompositorCall::CreateBackdropSurface { id: id.0, color });
+        self.compositor.create_backdrop_surface(device, id, color);
+    }
+
+    fn destroy_surface(&mut self, device: &mut Device, id: NativeSurfaceId) {
+        self.record(CompositorCall::DestroySurface { id: id.0 });
+        self.compositor.destroy_surface(device, id);
+    }
+
+    fn create_tile(&mut self, device: &mut Device, id: NativeTileId) {
+        self.record(CompositorCall::CreateTile { id: tile_id(id) });
+        self.compositor.create_tile(device, id);
+    }
+
+    fn destroy_tile(&mut self, device: &mut Device, id: NativeTileId) {
+        self.record(CompositorCall::DestroyTile { id: tile_id(id) });
+        self.compositor.destroy_tile(device, id);
+    }
+
+    fn attach_external_image(&mut self, device: &mut Device, id: NativeSurfaceId, external_image: ExternalImageId) {
+        self.record(CompositorCall::AttachExternalImage {
+            id: id.0,
+            external_image: external_image.0,
+        });
+        self.compositor.attach_external_image(device, id, external_image);
+    }
+
+    fn bind(
+        &mut self,
+        device: &mut Device,
+        id: NativeTileId,
+        dirty_rect: DeviceIntRect,
+        valid_rect: DeviceIntRect,
+    ) -> NativeSurfaceInfo {
+        self.record(CompositorCall::Bind {
+            id: tile_id(id),
+            dirty_rect,
+            valid_rect,
+        });
+        self.compositor.bind(device, id, dirty_rect, valid_rect)
+    }
+
+    fn unbind(&mut self, device: &mut Device) {
+        self.record(CompositorCall::Unbind);
+        self.compositor.unbind(device);
+    }
+
+    fn begin_frame(&mut self, device: &mut Device) {
+        self.record(CompositorCall::BeginFrame);
+        self.compositor.begin_frame(device);
+    }
+
+    fn add_surface(
+        &mut self,
+        device: &mut Device,
+        id: NativeSurfaceId,
+        transform: CompositorSurfaceTransform,
+        clip_rect: DeviceIntRect,
+        image_rendering: ImageRendering,
+    ) {
+        self.record(CompositorCall::AddSurface {
+            id: id.0,
+            transform: (
+                transform.scale.x,
+                transform.scale.y,
+                transform.offset.x,
+                transform.offset.y,
+            ),
+            clip_rect,
+            image_rendering,
+        });
+        self.compositor
+            .add_surface(device, id, transform, clip_rect, image_rendering);
+    }
+
+    fn start_compositing(
+        &mut self,
+        device: &mut Device,
+        clear_color: ColorF,
+        dirty_rects: &[DeviceIntRect],
+        opaque_rects: &[DeviceIntRect],
+    ) {
+        self.record(CompositorCall::StartCompositing {
+            clear_color,
+            dirty_rects: dirty_rects.to_vec(),
+            opaque_rects: opaque_rects.to_vec(),
+        });
+        self.compositor
+            .start_compositing(device, clear_color, dirty_rects, opaque_rects);
+    }
+
+    fn end_frame(&mut self, device: &mut Device) {
+        self.record(CompositorCall::EndFrame);
+        self.write_frame();
+        self.compositor.end_frame(device);
+    }
+
+    fn enable_native_compositor(&mut self, device: &mut Device, enable: bool) {
+        self.record(CompositorCall::EnableNativeCompositor { enable });
+        self.compositor.enable_native_compositor(device, enable);
+    }
+
+    fn deinit(&mut self, device: &mut Device) {
+        self.record(CompositorCall::Deinit);
+        self.write_frame();
+        self.compositor.deinit(device);
+    }
+
+    fn get_capabilities(&self, device: &mut Device) -> CompositorCapabilities {
+        self.compositor.get_capabilities(device)
+    }
+
+    fn get_window_visibility(&self, device: &mut Device) -> WindowVisibility {
+        self.compositor.get_window_visibility(device)
+    }
 }
 
-impl MappableCompositor for WrCompositor {
-    /// Map a tile's underlying buffer so it can be used as the backing for
-    /// a SWGL framebuffer. This is intended to be a replacement for 'bind'
-    /// in any compositors that intend to directly interoperate with SWGL
-    /// while supporting some form of native layers.
+impl<C: MappableCompositor> MappableCompositor for RecordingCompositor<C> {
     fn map_tile(
         &mut self,
-        _device: &mut Device,
+        device: &mut Device,
         id: NativeTileId,
         dirty_rect: DeviceIntRect,
         valid_rect: DeviceIntRect,
     ) -> Option<MappedTileInfo> {
-        let mut tile_info = MappedTileInfo {
-            data: ptr::null_mut(),
-            stride: 0,
-        };
-
-        unsafe {
-            wr_compositor_map_tile(
-                self.0,
-                id,
-                dirty_rect,
-                valid_rect,
-                &mut tile_info.data,
-                &mut tile_info.stride,
-            );
-        }
-
-        if !tile_info.data.is_null() && tile_info.stride != 0 {
-            Some(tile_info)
-        } else {
-            None
-        }
+        self.record(CompositorCall::MapTile {
+            id: tile_id(id),
+            dirty_rect,
+            valid_rect,
+        });
+        self.compositor.map_tile(device, id, dirty_rect, valid_rect)
     }
 
-    /// Unmap a tile that was was previously mapped via map_tile to signal
-    /// that SWGL is done rendering to the buffer.
-    fn unmap_tile(&mut self, _device: &mut Device) {
-        unsafe {
-            wr_compositor_unmap_tile(self.0);
-        }
+    fn unmap_tile(&mut self, device: &mut Device) {
+        self.record(CompositorCall::UnmapTile);
+        self.compositor.unmap_tile(device);
     }
 
     fn lock_composite_surface(
         &mut self,
-        _device: &mut Device,
+        device: &mut Device,
         ctx: *mut c_void,
         external_image_id: ExternalImageId,
         composite_info: *mut SWGLCompositeSurfaceInfo,
     ) -> bool {
-        unsafe { wr_swgl_lock_composite_surface(ctx, external_image_id, composite_info) }
+        self.compositor
+            .lock_composite_surface(device, ctx, external_image_id, composite_info)
     }
-    fn unlock_composite_surface(&mut self, _device: &mut Device, ctx: *mut c_void, external_image_id: ExternalImageId) {
-        unsafe { wr_swgl_unlock_composite_surface(ctx, external_image_id) }
+
+    fn unlock_composite_surface(&mut self, device: &mut Device, ctx: *mut c_void, external_image_id: ExternalImageId) {
+        self.compositor.unlock_composite_surface(device, ctx, external_image_id)
     }
 }
 
-pub struct WrPartialPresentCompositor(*mut c_void);
-
-impl PartialPresentCompositor for WrPartialPresentCompositor {
-    fn set_buffer_damage_region(&mut self, rects: &[DeviceIntRect]) {
-        unsafe {
-            wr_partial_present_compositor_set_buffer_damage_region(self.0, rects.as_ptr(), rects.len());
-        }
+/// Returns where to record the calls made to the native compositor of a window, if
+/// MOZ_WR_COMPOSITOR_RECORDING_DIR is set, creating the directory.
+fn compositor_recording_dir(window_id: WrWindowId) -> Option<PathBuf> {
+    let dir = env::var_os("MOZ_WR_COMPOSITOR_RECORDING_DIR")?;
+    let dir = PathBuf::from(dir).join(format!("{}-{}", std::process::id(), window_id.0));
+    match fs::create_dir_all(&dir) {
+        Ok(()) => Some(dir),
+        Err(err) => {
+            warn!("Failed to start recording compositor calls: {:?}", err);
+            None
+        },
     }
 }
 
@@ -1575,9 +2040,11 @@ pub struct WrGlyphRasterThread(GlyphRasterThread);
 pub extern "C" fn wr_glyph_raster_thread_new() -> *mut WrGlyphRasterThread {
     let thread = GlyphRasterThread::new(
         || {
+            #[cfg(feature = "gecko")]
             gecko_profiler::register_thread("WrGlyphRasterizer");
         },
         || {
+            #[cfg(feature = "gecko")]
             gecko_profiler::unregister_thread();
         },
     );
@@ -1639,192 +2106,176 @@ pub extern "C" fn wr_window_new(
     max_shared_surface_size: i32,
     enable_subpixel_aa: bool,
 ) -> bool {
-    assert!(unsafe { is_in_render_thread() });
+    assert!(is_in_render_thread());
 
     // Ensure the WR profiler callbacks are hooked up to the Gecko profiler.
     set_profiler_hooks(Some(&PROFILER_HOOKS));
 
-    let software = !swgl_context.is_null();
-    let (gl, sw_gl) = if software {
-        let ctx = swgl::Context::from(swgl_context);
-        ctx.make_current();
-        (Rc::new(ctx) as Rc<dyn gl::Gl>, Some(ctx))
-    } else {
-        let gl = unsafe {
-            if gl_context.is_null() {
-                panic!("Native GL context required when not using SWGL!");
-            } else if is_glcontext_gles(gl_context) {
-                gl::GlesFns::load_with(|symbol| get_proc_address(gl_context, symbol))
-            } else {
-                gl::GlFns::load_with(|symbol| get_proc_address(gl_context, symbol))
-            }
-        };
-        (gl, None)
-    };
-
-    let version = gl.get_string(gl::VERSION);
-
-    info!("WebRender - OpenGL version new {}", version);
-
+    let window_size = DeviceIntSize::new(window_width, window_height);
+    let notifier = Box::new(CppNotifier { window_id });
     let workers = unsafe { Arc::clone(&(*thread_pool).0) };
-    let workers_low_priority = unsafe {
-        if support_low_priority_threadpool {
-            Arc::clone(&(*thread_pool_low_priority).0)
-        } else {
-            Arc::clone(&(*thread_pool).0)
-        }
-    };
-
-    let upload_method = if !gl_context.is_null() && unsafe { is_glcontext_angle(gl_context) } {
-        UploadMethod::Immediate
-    } else {
-        UploadMethod::PixelBuffer(ONE_TIME_USAGE_HINT)
-    };
-
-    let precache_flags = if env_var_to_bool("MOZ_WR_PRECACHE_SHADERS") {
-        ShaderPrecacheFlags::FULL_COMPILE
+    let sw_gl = if swgl_context.is_null() {
+        None
     } else {
-        ShaderPrecacheFlags::empty()
+        Some(swgl::Context::from(swgl_context))
     };
-
-    let cached_programs = program_cache.map(|program_cache| Rc::clone(&program_cache.rc_get()));
-
-    let color = if cfg!(target_os = "android") {
-        // The color is for avoiding black flash before receiving display list.
-        ColorF::new(1.0, 1.0, 1.0, 1.0)
-    } else {
-        ColorF::new(0.0, 0.0, 0.0, 0.0)
+    let mut builder = match sw_gl {
+        Some(ctx) => WindowBuilder::new_software(window_id, window_size, ctx, notifier, workers),
+        None => WindowBuilder::new(window_id, window_size, unsafe { load_gl(gl_context) }, notifier, workers),
     };
 
-    let compositor_config = if software {
-        CompositorConfig::Native {
-            compositor: Box::new(SwCompositor::new(
-                sw_gl.unwrap(),
-                Box::new(WrCompositor(compositor)),
-                use_native_compositor,
-            )),
-        }
-    } else if use_native_compositor {
-        CompositorConfig::Native {
-            compositor: Box::new(WrCompositor(compositor)),
-        }
-    } else {
-        CompositorConfig::Draw {
+    builder = builder
+        .document_id(document_id)
+        .chunk_pool(chunk_pool.0.clone())
+        .size_of_ops(size_of_op, enclosing_size_of_op)
+        .crash_annotator(Box::new(MozCrashAnnotator))
+        .scene_builder_hooks(Box::new(APZCallbacks::new(window_id)))
+        .render_backend_hooks(Box::new(RenderBackendCallbacks))
+        .sampler(Box::new(SamplerCallback::new(window_id)))
+        .compositor_config(compositor_config(
+            window_id,
+            sw_gl,
+            compositor,
+            use_native_compositor,
+            use_partial_present,
             max_partial_present_rects,
             draw_previous_partial_present_regions,
-            partial_present: if use_partial_present {
-                Some(Box::new(WrPartialPresentCompositor(compositor)))
-            } else {
-                None
-            },
-        }
-    };
+        ));
+
+    if support_low_priority_threadpool {
+        builder = builder.low_priority_workers(unsafe { Arc::clone(&(*thread_pool_low_priority).0) });
+    }
+    if !gl_context.is_null() && host().is_glcontext_angle(gl_context) {
+        builder = builder.upload_method(UploadMethod::Immediate);
+    }
+    if let Some(program_cache) = program_cache {
+        builder = builder.program_cache(Rc::clone(&program_cache.rc_get()));
+    }
+    if let Some(shaders) = shaders {
+        builder = builder.shaders(shaders.0.clone());
+    }
+    if let Some(glyph_raster_thread) = glyph_raster_thread {
+        builder = builder.glyph_raster_thread(glyph_raster_thread.0.clone());
+    }
 
     let picture_tile_size = if picture_tile_width > 0 && picture_tile_height > 0 {
         Some(DeviceIntSize::new(picture_tile_width, picture_tile_height))
     } else {
         None
     };
-
-    let texture_cache_config = if is_main_window {
-        TextureCacheConfig::DEFAULT
-    } else {
-        TextureCacheConfig {
-            color8_linear_texture_size: 512,
-            color8_nearest_texture_size: 512,
-            color8_glyph_texture_size: 512,
-            alpha8_texture_size: 512,
-            alpha8_glyph_texture_size: 512,
-            alpha16_texture_size: 512,
-        }
-    };
-
-    let opts = WebRenderOptions {
-        enable_aa: true,
-        enable_subpixel_aa,
+    let options = WindowOptions {
+        is_main_window,
         support_low_priority_transactions,
         allow_texture_swizzling,
-        blob_image_handler: Some(Box::new(Moz2dBlobImageHandler::new(
-            workers.clone(),
-            workers_low_priority,
-        ))),
-        crash_annotator: Some(Box::new(MozCrashAnnotator)),
-        workers: Some(workers),
-        chunk_pool: Some(chunk_pool.0.clone()),
-        dedicated_glyph_raster_thread: glyph_raster_thread.map(|grt| grt.0.clone()),
-        size_of_op: Some(size_of_op),
-        enclosing_size_of_op: Some(enclosing_size_of_op),
-        cached_programs,
-        resource_override_path: unsafe {
-            let override_charptr = gfx_wr_resource_path_override();
-            if override_charptr.is_null() {
-                None
-            } else {
-                match CStr::from_ptr(override_charptr).to_str() {
-                    Ok(override_str) => Some(PathBuf::from(override_str)),
-                    _ => None,
-                }
-            }
-        },
-        use_optimized_shaders: unsafe { gfx_wr_use_optimized_shaders() },
-        renderer_id: Some(window_id.0),
-        upload_method,
-        scene_builder_hooks: Some(Box::new(APZCallbacks::new(window_id))),
-        render_backend_hooks: Some(Box::new(RenderBackendCallbacks)),
-        sampler: Some(Box::new(SamplerCallback::new(window_id))),
-        max_internal_texture_size: Some(8192), // We want to tile if larger than this
-        clear_color: color,
-        precache_flags,
-        namespace_alloc_by_client: true,
-        // Font namespace must be allocated by the client
-        shared_font_namespace: Some(next_namespace_id()),
-        // SWGL doesn't support the GL_ALWAYS depth comparison function used by
-        // `clear_caches_with_quads`, but scissored clears work well.
-        clear_caches_with_quads: !software && !allow_scissored_cache_clears,
-        // SWGL supports KHR_blend_equation_advanced safely, but we haven't yet
-        // tested other HW platforms determine if it is safe to allow them.
-        allow_advanced_blend_equation: software,
+        allow_scissored_cache_clears,
         surface_origin_is_top_left,
-        compositor_config,
         enable_gpu_markers,
         panic_on_gl_error,
         picture_tile_size,
-        texture_cache_config,
         reject_software_rasterizer,
         low_quality_pinch_zoom,
         max_shared_surface_size,
-        ..Default::default()
+        enable_subpixel_aa,
+        resource_override_path: host().resource_path_override(),
+        use_optimized_shaders: host().use_optimized_shaders(),
+        ..blob_options_from_host(window_id)
     };
 
-    let window_size = DeviceIntSize::new(window_width, window_height);
-    let notifier = Box::new(CppNotifier { window_id });
-    let (renderer, sender) = match create_webrender_instance(gl, notifier, opts, shaders.map(|sh| &sh.0)) {
-        Ok((renderer, sender)) => (renderer, sender),
+    let window = match builder.options(options).build() {
+        Ok(window) => window,
         Err(e) => {
             warn!(" Failed to create a Renderer: {:?}", e);
-            let msg = CString::new(format!("wr_window_new: {:?}", e)).unwrap();
-            unsafe {
-                gfx_critical_note(msg.as_ptr());
-            }
-            *out_err = msg.into_raw();
+            let msg = format!("wr_window_new: {}", e);
+            host().critical_note(&msg);
+            *out_err = CString::new(msg).unwrap().into_raw();
             return false;
         },
     };
 
     unsafe {
-        *out_max_texture_size = renderer.get_max_texture_size();
+        *out_max_texture_size = window.max_texture_size();
     }
-    *out_handle = Box::into_raw(Box::new(DocumentHandle::new(
-        sender.create_api_by_client(next_namespace_id()),
-        None,
-        window_size,
-        document_id,
-    )));
+    let (document, renderer) = window.into_raw_parts();
+    *out_handle = Box::into_raw(Box::new(document));
     *out_renderer = Box::into_raw(Box::new(renderer));
 
     true
 }
 
+/// Loads the functions of a native GL context.
+unsafe fn load_gl(gl_context: *mut c_void) -> Rc<dyn gl::Gl> {
+    if gl_context.is_null() {
+        panic!("Native GL context required when not using SWGL!");
+    } else if host().is_glcontext_gles(gl_context) {
+        gl::GlesFns::load_with(|symbol| get_proc_address(gl_context, symbol))
+    } else {
+        gl::GlFns::load_with(|symbol| get_proc_address(gl_context, symbol))
+    }
+}
+
+/// Returns how the frames of a window are composited through Gecko's compositor, which
+/// SWGL windows always render into.
+fn compositor_config(
+    window_id: WrWindowId,
+    sw_gl: Option<swgl::Context>,
+    compositor: *mut c_void,
+    use_native_compositor: bool,
+    use_partial_present: bool,
+    max_partial_present_rects: usize,
+    draw_previous_partial_present_regions: bool,
+) -> CompositorConfig {
+    let recording_dir = if use_native_compositor {
+        compositor_recording_dir(window_id)
+    } else {
+        None
+    };
+    if let Some(sw_gl) = sw_gl {
+        let native_compositor: Box<dyn MappableCompositor> = match recording_dir {
+            Some(dir) => Box::new(RecordingCompositor::new(WrCompositor(compositor), dir)),
+            None => Box::new(WrCompositor(compositor)),
+        };
+        CompositorConfig::Native {
+            compositor: Box::new(SwCompositor::new(sw_gl, native_compositor, use_native_compositor)),
+        }
+    } else if use_native_compositor {
+        let native_compositor: Box<dyn Compositor> = match recording_dir {
+            Some(dir) => Box::new(RecordingCompositor::new(WrCompositor(compositor), dir)),
+            None => Box::new(WrCompositor(compositor)),
+        };
+        CompositorConfig::Native {
+            compositor: native_compositor,
+        }
+    } else {
+        CompositorConfig::Draw {
+            max_partial_present_rects,
+            draw_previous_partial_present_regions,
+            partial_present: if use_partial_present {
+                Some(Box::new(WrPartialPresentCompositor(compositor)))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Returns the default window options, with the blob options set by the host.
+fn blob_options_from_host(window_id: WrWindowId) -> WindowOptions {
+    let blob_replay_fallback = host().blob_replay_fallback();
+    let blob_tile_cache_size = match host().blob_tile_cache_size() {
+        0 => None,
+        size => Some(size),
+    };
+    let blob_recording_dir = host()
+        .blob_recording_dir()
+        .map(|dir| dir.join(format!("{}-{}", std::process::id(), window_id.0)));
+    WindowOptions {
+        blob_replay_fallback,
+        blob_tile_cache_size,
+        blob_recording_dir,
+        ..WindowOptions::default()
+    }
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn wr_api_free_error_msg(msg: *mut c_char) {
     if !msg.is_null() {
@@ -1832,24 +2283,69 @@ pub unsafe extern "C" fn wr_api_free_error_msg(msg: *mut c_char) {
     }
 }
 
+/// Creates a window that renders with SWGL into memory, without a GL context or
+/// compositor, whose frames are composited by a `MemoryCompositor`. The calling thread
+/// becomes its render thread.
+#[no_mangle]
+pub unsafe extern "C" fn wr_headless_window_new(
+    window_id: WrWindowId,
+    width: i32,
+    height: i32,
+    thread_pool: *mut WrThreadPool,
+    out_window: &mut *mut HeadlessWindow,
+) -> bool {
+    let workers = Arc::clone(&(*thread_pool).0);
+    let size = DeviceIntSize::new(width, height);
+    match HeadlessWindow::with_native_compositor(window_id, size, workers, WindowOptions::default()) {
+        Ok(window) => {
+            *out_window = Box::into_raw(Box::new(window));
+            true
+        },
+        Err(e) => {
+            host().critical_note(&format!("wr_headless_window_new: {}", e));
+            false
+        },
+    }
+}
+
+/// The document of a headless window, which stays owned by the window.
+#[no_mangle]
+pub extern "C" fn wr_headless_window_document(window: &mut HeadlessWindow) -> &mut DocumentHandle {
+    window.document().handle_mut()
+}
+
+/// Waits for the next frame of a headless window and renders it into `out_pixels`, as
+/// RGBA8 rows from the top.
+#[no_mangle]
+pub extern "C" fn wr_headless_window_render(window: &mut HeadlessWindow, out_pixels: &mut ThinVec<u8>) -> bool {
+    match window.render() {
+        Ok(frame) => {
+            out_pixels.clear();
+            out_pixels.extend(frame.pixels);
+            true
+        },
+        Err(e) => {
+            warn!("wr_headless_window_render: {}", e);
+            false
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wr_headless_window_delete(window: *mut HeadlessWindow) {
+    mem::drop(Box::from_raw(window));
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn wr_api_delete_document(dh: &mut DocumentHandle) {
-    dh.api.delete_document(dh.document_id);
+    dh.delete_document();
 }
 
 #[no_mangle]
 pub extern "C" fn wr_api_clone(dh: &mut DocumentHandle, out_handle: &mut *mut DocumentHandle) {
-    assert!(unsafe { is_in_compositor_thread() });
-
-    let hit_tester = dh.ensure_hit_tester().clone();
+    assert!(is_in_compositor_thread());
 
-    let handle = DocumentHandle {
-        api: dh.api.create_sender().create_api_by_client(next_namespace_id()),
-        document_id: dh.document_id,
-        hit_tester: Some(hit_tester),
-        hit_tester_request: None,
-    };
-    *out_handle = Box::into_raw(Box::new(handle));
+    *out_handle = Box::into_raw(Box::new(dh.create_similar()));
 }
 
 #[no_mangle]
@@ -1864,17 +2360,17 @@ pub unsafe extern "C" fn wr_api_stop_render_backend(dh: &mut DocumentHandle) {
 
 #[no_mangle]
 pub unsafe extern "C" fn wr_api_shut_down(dh: &mut DocumentHandle) {
-    dh.api.shut_down(true);
+    dh.shut_down();
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn wr_api_notify_memory_pressure(dh: &mut DocumentHandle) {
-    dh.api.notify_memory_pressure();
+    dh.notify_memory_pressure();
 }
 
 #[no_mangle]
 pub extern "C" fn wr_api_set_debug_flags(dh: &mut DocumentHandle, flags: DebugFlags) {
-    dh.api.set_debug_flags(flags);
+    dh.set_debug_flags(flags);
 }
 
 #[no_mangle]
@@ -1901,7 +2397,10 @@ pub unsafe extern "C" fn wr_api_accumulate_memory_report(
     size_of_op: unsafe extern "C" fn(ptr: *const c_void) -> usize,
     enclosing_size_of_op: Option<unsafe extern "C" fn(ptr: *const c_void) -> usize>,
 ) {
-    let ops = MallocSizeOfOps::new(size_of_op, enclosing_size_of_op);
+    let mut ops = MallocSizeOfOps::new(size_of_op, enclosing_size_of_op);
+    if let Some(ref cache) = dh.blob_tile_cache {
+        report.rasterized_blobs += cache.report_memory(&mut ops);
+    }
     *report += dh.api.report_memory(ops);
 }
 
@@ -1920,7 +2419,9 @@ pub unsafe extern "C" fn wr_api_set_batching_lookback(dh: &mut DocumentHandle, c
     dh.api.send_debug_cmd(DebugCommand::SetBatchingLookback(count));
 }
 
-fn make_transaction(do_async: bool) -> Transaction {
+/// Creates a transaction, which is built on the scene builder thread if `do_async` is
+/// set.
+pub fn make_transaction(do_async: bool) -> Transaction {
     let mut transaction = Transaction::new();
     // Ensure that we either use async scene building or not based on the
     // gecko pref, regardless of what the default is. We can remove this once
@@ -1970,9 +2471,7 @@ pub extern "C" fn wr_transaction_notify(txn: &mut Transaction, when: Checkpoint,
     struct GeckoNotification(usize);
     impl NotificationHandler for GeckoNotification {
         fn notify(&self, when: Checkpoint) {
-            unsafe {
-                wr_transaction_notification_notified(self.0, when);
-            }
+            host().transaction_notification_notified(self.0, when);
         }
     }
 
@@ -2311,14 +2810,83 @@ pub extern "C" fn wr_resource_updates_delete_blob_image(txn: &mut Transaction, k
     txn.delete_blob_image(key);
 }
 
+/// Describes the entries of a blob image as JSON, see `inspect_blob_json`.
+#[cfg(feature = "gecko")]
+#[no_mangle]
+pub extern "C" fn wr_blob_image_inspect(blob: ByteSlice, out_json: &mut nsACString) {
+    out_json.assign(&inspect_blob_json(blob.as_slice()));
+}
+
+/// Moves the blob tile timings of the document's window recorded since the last call
+/// into `out_timings`. Timings are only recorded when the gfx.webrender.blob.raster-timings
+/// pref is set.
+#[no_mangle]
+pub extern "C" fn wr_take_blob_raster_timings(dh: &DocumentHandle, out_timings: &mut ThinVec<BlobRasterTiming>) {
+    if let Some(ref timings) = dh.blob_raster_timings {
+        out_timings.extend(timings.take());
+    }
+}
+
+/// A font used by blob images that couldn't be registered.
+#[repr(C)]
+pub struct WrFailedBlobFont {
+    key: WrFontKey,
+    error: BlobFontError,
+}
+
+/// Appends the fonts used by the blob images of the document's window that couldn't be
+/// registered to `out_fonts`. The blob items that use them aren't drawn.
+#[no_mangle]
+pub extern "C" fn wr_api_get_failed_blob_fonts(dh: &DocumentHandle, out_fonts: &mut ThinVec<WrFailedBlobFont>) {
+    if let Some(ref failed_fonts) = dh.failed_blob_fonts {
+        out_fonts.extend(
+            failed_fonts
+                .list()
+                .into_iter()
+                .map(|(key, error)| WrFailedBlobFont { key, error }),
+        );
+    }
+}
+
+/// Sets the font that replaces the native fonts of blob images that can't be loaded, for
+/// the windows created from now on. This is a file path, except on macOS where it is the
+/// name of the font. `path` may be null to go back to the fallback font of the platform.
+#[no_mangle]
+pub unsafe extern "C" fn wr_set_blob_fallback_font(path: *const c_char, index: u32) {
+    if path.is_null() {
+        set_blob_fallback_font(None);
+        return;
+    }
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(_) => {
+            warn!("Ignoring a fallback font path that isn't UTF-8");
+            return;
+        },
+    };
+    set_blob_fallback_font(Some(native_font_handle(path, index)));
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn native_font_handle(name: &str, _index: u32) -> NativeFontHandle {
+    NativeFontHandle { name: name.to_string() }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+fn native_font_handle(path: &str, index: u32) -> NativeFontHandle {
+    NativeFontHandle {
+        path: PathBuf::from(path),
+        index,
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn wr_api_send_transaction(dh: &mut DocumentHandle, transaction: &mut Transaction, is_async: bool) {
     if transaction.is_empty() {
         return;
     }
     let new_txn = make_transaction(is_async);
-    let txn = mem::replace(transaction, new_txn);
-    dh.api.send_transaction(dh.document_id, txn);
+    dh.send_transaction(mem::replace(transaction, new_txn));
 }
 
 #[no_mangle]
@@ -2335,7 +2903,7 @@ pub unsafe extern "C" fn wr_transaction_clear_display_list(
 
 #[no_mangle]
 pub extern "C" fn wr_api_send_external_event(dh: &mut DocumentHandle, evt: usize) {
-    assert!(unsafe { !is_in_render_thread() });
+    assert!(is_off_render_thread());
 
     dh.api.send_external_event(ExternalEvent::from_raw(evt));
 }
@@ -2534,7 +3102,7 @@ pub unsafe extern "C" fn wr_api_wake_scene_builder(dh: &mut DocumentHandle) {
 
 #[no_mangle]
 pub unsafe extern "C" fn wr_api_flush_scene_builder(dh: &mut DocumentHandle) {
-    dh.api.flush_scene_builder();
+    dh.flush_scene_builder();
 }
 
 // RenderThread WIP notes:
@@ -2562,26 +3130,73 @@ impl WebRenderFrameBuilder {
     }
 }
 
+/// Builds the display lists of a pipeline.
 pub struct WrState {
     pipeline_id: WrPipelineId,
     frame_builder: WebRenderFrameBuilder,
 }
 
+impl WrState {
+    /// Creates a builder for the display lists of the given pipeline.
+    pub fn new(pipeline_id: WrPipelineId) -> WrState {
+        WrState {
+            pipeline_id,
+            frame_builder: WebRenderFrameBuilder::new(pipeline_id),
+        }
+    }
+
+    /// Returns the pipeline the display lists are built for.
+    pub fn pipeline_id(&self) -> WrPipelineId {
+        self.pipeline_id
+    }
+
+    /// Starts building a display list.
+    pub fn begin(&mut self) {
+        self.frame_builder.dl_builder.begin();
+    }
+
+    /// Finishes building the display list.
+    pub fn end(&mut self) -> BuiltDisplayList {
+        let (_, dl) = self.frame_builder.dl_builder.end();
+        dl
+    }
+
+    /// Pushes a rectangle of the given color.
+    pub(crate) fn push_rect(&mut self, properties: &CommonItemProperties, rect: LayoutRect, color: ColorF) {
+        self.frame_builder.dl_builder.push_rect(properties, rect, color);
+    }
+
+    /// Pushes a rectangle which clears what is below it to transparent.
+    pub(crate) fn push_clear_rect(&mut self, properties: &CommonItemProperties, rect: LayoutRect) {
+        self.frame_builder.dl_builder.push_clear_rect(properties, rect);
+    }
+
+    /// Pushes an image, stretched to `bounds`.
+    pub(crate) fn push_image(
+        &mut self,
+        properties: &CommonItemProperties,
+        bounds: LayoutRect,
+        image_rendering: ImageRendering,
+        alpha_type: AlphaType,
+        key: ImageKey,
+        color: ColorF,
+    ) {
+        self.frame_builder
+            .dl_builder
+            .push_image(properties, bounds, image_rendering, alpha_type, key, color);
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn wr_state_new(pipeline_id: WrPipelineId) -> *mut WrState {
-    assert!(unsafe { !is_in_render_thread() });
-
-    let state = Box::new(WrState {
-        pipeline_id,
-        frame_builder: WebRenderFrameBuilder::new(pipeline_id),
-    });
+    assert!(is_off_render_thread());
 
-    Box::into_raw(state)
+    Box::into_raw(Box::new(WrState::new(pipeline_id)))
 }
 
 #[no_mangle]
 pub extern "C" fn wr_state_delete(state: *mut WrState) {
-    assert!(unsafe { !is_in_render_thread() });
+    assert!(is_off_render_thread());
 
     unsafe {
         mem::drop(Box::from_raw(state));
@@ -2651,7 +3266,7 @@ pub extern "C" fn wr_dp_push_stacking_context(
     filter_datas_count: usize,
     glyph_raster_space: RasterSpace,
 ) -> WrSpatialId {
-    debug_assert!(unsafe { !is_in_render_thread() });
+    debug_assert!(is_off_render_thread());
 
     let c_filters = unsafe { make_slice(filters, filter_count) };
     let mut filters: Vec<FilterOp> = c_filters.iter().copied().collect();
@@ -2796,7 +3411,7 @@ pub extern "C" fn wr_dp_push_stacking_context(
 
 #[no_mangle]
 pub extern "C" fn wr_dp_pop_stacking_context(state: &mut WrState, is_reference_frame: bool) {
-    debug_assert!(unsafe { !is_in_render_thread() });
+    debug_assert!(is_off_render_thread());
     state.frame_builder.dl_builder.pop_stacking_context();
     if is_reference_frame {
         state.frame_builder.dl_builder.pop_reference_frame();
@@ -2810,7 +3425,7 @@ pub extern "C" fn wr_dp_define_clipchain(
     clips: *const WrClipId,
     clips_count: usize,
 ) -> u64 {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
     let parent = unsafe { parent_clipchain_id.as_ref() }.map(|id| ClipChainId(*id, state.pipeline_id));
 
     let pipeline_id = state.pipeline_id;
@@ -2832,7 +3447,7 @@ pub extern "C" fn wr_dp_define_image_mask_clip_with_parent_clip_chain(
     point_count: usize,
     fill_rule: FillRule,
 ) -> WrClipId {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let c_points = unsafe { make_slice(points, point_count) };
     let points: Vec<LayoutPoint> = c_points.iter().copied().collect();
@@ -2852,7 +3467,7 @@ pub extern "C" fn wr_dp_define_rounded_rect_clip(
     space: WrSpatialId,
     complex: ComplexClipRegion,
 ) -> WrClipId {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let clip_id = state
         .frame_builder
@@ -2863,7 +3478,7 @@ pub extern "C" fn wr_dp_define_rounded_rect_clip(
 
 #[no_mangle]
 pub extern "C" fn wr_dp_define_rect_clip(state: &mut WrState, space: WrSpatialId, clip_rect: LayoutRect) -> WrClipId {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let clip_id = state
         .frame_builder
@@ -2887,7 +3502,7 @@ pub extern "C" fn wr_dp_define_sticky_frame(
     key: SpatialTreeItemKey,
     animation: *const WrAnimationProperty,
 ) -> WrSpatialId {
-    assert!(unsafe { is_in_main_thread() });
+    assert!(is_in_main_thread());
     let anim = unsafe { animation.as_ref() };
     let transform = anim.map(|anim| {
         debug_assert!(anim.id > 0);
@@ -2929,7 +3544,7 @@ pub extern "C" fn wr_dp_define_scroll_layer(
     has_scroll_linked_effect: HasScrollLinkedEffect,
     key: SpatialTreeItemKey,
 ) -> WrSpatialId {
-    assert!(unsafe { is_in_main_thread() });
+    assert!(is_in_main_thread());
 
     let space_and_clip = state.frame_builder.dl_builder.define_scroll_frame(
         parent.to_webrender(state.pipeline_id),
@@ -2955,7 +3570,7 @@ pub extern "C" fn wr_dp_push_iframe(
     pipeline_id: WrPipelineId,
     ignore_missing_pipeline: bool,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     state.frame_builder.dl_builder.push_iframe(
         rect,
@@ -3025,7 +3640,7 @@ pub extern "C" fn wr_dp_push_rect(
     parent: &WrSpaceAndClipChain,
     color: ColorF,
 ) {
-    debug_assert!(unsafe { !is_in_render_thread() });
+    debug_assert!(is_off_render_thread());
 
     let mut prim_info = common_item_properties_for_rect(state, clip, is_backface_visible, parent);
     if force_antialiasing {
@@ -3035,7 +3650,7 @@ pub extern "C" fn wr_dp_push_rect(
         prim_info.flags |= PrimitiveFlags::CHECKERBOARD_BACKGROUND;
     }
 
-    state.frame_builder.dl_builder.push_rect(&prim_info, rect, color);
+    state.push_rect(&prim_info, rect, color);
 }
 
 #[no_mangle]
@@ -3048,7 +3663,7 @@ pub extern "C" fn wr_dp_push_rect_with_animation(
     color: ColorF,
     animation: *const WrAnimationProperty,
 ) {
-    debug_assert!(unsafe { !is_in_render_thread() });
+    debug_assert!(is_off_render_thread());
 
     let prim_info = common_item_properties_for_rect(state, clip, is_backface_visible, parent);
 
@@ -3078,7 +3693,7 @@ pub extern "C" fn wr_dp_push_backdrop_filter(
     filter_datas: *const WrFilterData,
     filter_datas_count: usize,
 ) {
-    debug_assert!(unsafe { !is_in_render_thread() });
+    debug_assert!(is_off_render_thread());
 
     let c_filters = unsafe { make_slice(filters, filter_count) };
     let filters: Vec<FilterOp> = c_filters.iter().copied().collect();
@@ -3125,7 +3740,7 @@ pub extern "C" fn wr_dp_push_clear_rect(
     clip_rect: LayoutRect,
     parent: &WrSpaceAndClipChain,
 ) {
-    debug_assert!(unsafe { !is_in_render_thread() });
+    debug_assert!(is_off_render_thread());
 
     let space_and_clip = parent.to_webrender(state.pipeline_id);
 
@@ -3136,7 +3751,7 @@ pub extern "C" fn wr_dp_push_clear_rect(
         flags: prim_flags(true, /* prefer_compositor_surface */ false),
     };
 
-    state.frame_builder.dl_builder.push_clear_rect(&prim_info, rect);
+    state.push_clear_rect(&prim_info, rect);
 }
 
 #[no_mangle]
@@ -3149,7 +3764,7 @@ pub extern "C" fn wr_dp_push_hit_test(
     scroll_id: u64,
     hit_info: u16,
 ) {
-    debug_assert!(unsafe { !is_in_render_thread() });
+    debug_assert!(is_off_render_thread());
 
     let clip_rect = clip.intersection(&rect);
     if clip_rect.is_none() {
@@ -3189,7 +3804,7 @@ pub extern "C" fn wr_dp_push_image(
     prefer_compositor_surface: bool,
     supports_external_compositing: bool,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() || is_in_compositor_thread() });
+    debug_assert!(is_in_main_thread() || is_in_compositor_thread());
 
     let space_and_clip = parent.to_webrender(state.pipeline_id);
 
@@ -3216,10 +3831,7 @@ pub extern "C" fn wr_dp_push_image(
         AlphaType::Alpha
     };
 
-    state
-        .frame_builder
-        .dl_builder
-        .push_image(&prim_info, bounds, image_rendering, alpha_type, key, color);
+    state.push_image(&prim_info, bounds, image_rendering, alpha_type, key, color);
 }
 
 #[no_mangle]
@@ -3236,7 +3848,7 @@ pub extern "C" fn wr_dp_push_repeating_image(
     premultiplied_alpha: bool,
     color: ColorF,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() || is_in_compositor_thread() });
+    debug_assert!(is_in_main_thread() || is_in_compositor_thread());
 
     let space_and_clip = parent.to_webrender(state.pipeline_id);
 
@@ -3283,7 +3895,7 @@ pub extern "C" fn wr_dp_push_yuv_planar_image(
     prefer_compositor_surface: bool,
     supports_external_compositing: bool,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() || is_in_compositor_thread() });
+    debug_assert!(is_in_main_thread() || is_in_compositor_thread());
 
     let space_and_clip = parent.to_webrender(state.pipeline_id);
 
@@ -3326,7 +3938,7 @@ pub extern "C" fn wr_dp_push_yuv_NV12_image(
     prefer_compositor_surface: bool,
     supports_external_compositing: bool,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() || is_in_compositor_thread() });
+    debug_assert!(is_in_main_thread() || is_in_compositor_thread());
 
     let space_and_clip = parent.to_webrender(state.pipeline_id);
 
@@ -3369,7 +3981,7 @@ pub extern "C" fn wr_dp_push_yuv_P010_image(
     prefer_compositor_surface: bool,
     supports_external_compositing: bool,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() || is_in_compositor_thread() });
+    debug_assert!(is_in_main_thread() || is_in_compositor_thread());
 
     let space_and_clip = parent.to_webrender(state.pipeline_id);
 
@@ -3412,7 +4024,7 @@ pub extern "C" fn wr_dp_push_yuv_NV16_image(
     prefer_compositor_surface: bool,
     supports_external_compositing: bool,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() || is_in_compositor_thread() });
+    debug_assert!(is_in_main_thread() || is_in_compositor_thread());
 
     let space_and_clip = parent.to_webrender(state.pipeline_id);
 
@@ -3454,7 +4066,7 @@ pub extern "C" fn wr_dp_push_yuv_interleaved_image(
     prefer_compositor_surface: bool,
     supports_external_compositing: bool,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() || is_in_compositor_thread() });
+    debug_assert!(is_in_main_thread() || is_in_compositor_thread());
 
     let space_and_clip = parent.to_webrender(state.pipeline_id);
 
@@ -3493,7 +4105,7 @@ pub extern "C" fn wr_dp_push_text(
     glyph_count: u32,
     glyph_options: *const GlyphOptions,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let glyph_slice = unsafe { make_slice(glyphs, glyph_count as usize) };
 
@@ -3524,7 +4136,7 @@ pub extern "C" fn wr_dp_push_shadow(
     shadow: Shadow,
     should_inflate: bool,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     state
         .frame_builder
@@ -3534,7 +4146,7 @@ pub extern "C" fn wr_dp_push_shadow(
 
 #[no_mangle]
 pub extern "C" fn wr_dp_pop_all_shadows(state: &mut WrState) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     state.frame_builder.dl_builder.pop_all_shadows();
 }
@@ -3551,7 +4163,7 @@ pub extern "C" fn wr_dp_push_line(
     color: &ColorF,
     style: LineStyle,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let space_and_clip = parent.to_webrender(state.pipeline_id);
 
@@ -3583,7 +4195,7 @@ pub extern "C" fn wr_dp_push_border(
     left: BorderSide,
     radius: BorderRadius,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let border_details = BorderDetails::Normal(NormalBorder {
         left,
@@ -3631,7 +4243,7 @@ pub extern "C" fn wr_dp_push_border_image(
     parent: &WrSpaceAndClipChain,
     params: &WrBorderImage,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
     let border_details = BorderDetails::NinePatch(NinePatchBorder {
         source: NinePatchBorderSource::Image(params.image, params.image_rendering),
         width: params.width,
@@ -3674,7 +4286,7 @@ pub extern "C" fn wr_dp_push_border_gradient(
     stops_count: usize,
     extend_mode: ExtendMode,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let stops_slice = unsafe { make_slice(stops, stops_count) };
     let stops_vector = stops_slice.to_owned();
@@ -3724,7 +4336,7 @@ pub extern "C" fn wr_dp_push_border_radial_gradient(
     stops_count: usize,
     extend_mode: ExtendMode,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let stops_slice = unsafe { make_slice(stops, stops_count) };
     let stops_vector = stops_slice.to_owned();
@@ -3781,7 +4393,7 @@ pub extern "C" fn wr_dp_push_border_conic_gradient(
     stops_count: usize,
     extend_mode: ExtendMode,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let stops_slice = unsafe { make_slice(stops, stops_count) };
     let stops_vector = stops_slice.to_owned();
@@ -3838,7 +4450,7 @@ pub extern "C" fn wr_dp_push_linear_gradient(
     tile_size: LayoutSize,
     tile_spacing: LayoutSize,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let stops_slice = unsafe { make_slice(stops, stops_count) };
     let stops_vector = stops_slice.to_owned();
@@ -3878,7 +4490,7 @@ pub extern "C" fn wr_dp_push_radial_gradient(
     tile_size: LayoutSize,
     tile_spacing: LayoutSize,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let stops_slice = unsafe { make_slice(stops, stops_count) };
     let stops_vector = stops_slice.to_owned();
@@ -3918,7 +4530,7 @@ pub extern "C" fn wr_dp_push_conic_gradient(
     tile_size: LayoutSize,
     tile_spacing: LayoutSize,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let stops_slice = unsafe { make_slice(stops, stops_count) };
     let stops_vector = stops_slice.to_owned();
@@ -3958,7 +4570,7 @@ pub extern "C" fn wr_dp_push_box_shadow(
     border_radius: BorderRadius,
     clip_mode: BoxShadowClipMode,
 ) {
-    debug_assert!(unsafe { is_in_main_thread() });
+    debug_assert!(is_in_main_thread());
 
     let space_and_clip = parent.to_webrender(state.pipeline_id);
 
@@ -4045,7 +4657,7 @@ pub extern "C" fn wr_dump_serialized_display_list(state: &mut WrState) {
 
 #[no_mangle]
 pub unsafe extern "C" fn wr_api_begin_builder(state: &mut WrState) {
-    state.frame_builder.dl_builder.begin();
+    state.begin();
 }
 
 #[no_mangle]
@@ -4056,8 +4668,7 @@ pub unsafe extern "C" fn wr_api_end_builder(
     dl_cache_data: &mut WrVecU8,
     dl_spatial_tree: &mut WrVecU8,
 ) {
-    let (_, dl) = state.frame_builder.dl_builder.end();
-    let (payload, descriptor) = dl.into_data();
+    let (payload, descriptor) = state.end().into_data();
     *dl_items_data = WrVecU8::from_vec(payload.items_data);
     *dl_cache_data = WrVecU8::from_vec(payload.cache_data);
     *dl_spatial_tree = WrVecU8::from_vec(payload.spatial_tree);
@@ -4074,7 +4685,7 @@ pub struct HitResult {
 
 #[no_mangle]
 pub extern "C" fn wr_api_hit_test(dh: &mut DocumentHandle, point: WorldPoint, out_results: &mut ThinVec<HitResult>) {
-    let result = dh.ensure_hit_tester().hit_test(point);
+    let result = dh.hit_test(point);
     for item in &result.items {
         out_results.push(HitResult {
             pipeline_id: item.pipeline,
@@ -4101,10 +4712,22 @@ pub unsafe extern "C" fn wr_dec_ref_arc(arc: *const VecU8) {
 // TODO: nical
 // Update for the new blob image interface changes.
 //
+/// A display item of a blob image that intersects the tile given to `wr_moz2d_render_cb`.
+#[repr(C)]
+pub struct WrBlobItem<'a> {
+    /// The bounds of the item.
+    pub bounds: DeviceIntRect,
+    /// The recorded drawing commands of the item.
+    pub commands: ByteSlice<'a>,
+    /// The list of fonts the item uses.
+    pub extra_data: ByteSlice<'a>,
+}
+
 extern "C" {
     // TODO: figure out the API for tiled blob images.
     pub fn wr_moz2d_render_cb(
-        blob: ByteSlice,
+        items: *const WrBlobItem,
+        item_count: usize,
         format: ImageFormat,
         render_rect: &LayoutIntRect,
         visible_rect: &DeviceIntRect,
@@ -4195,10 +4818,7 @@ pub extern "C" fn wr_shaders_new(
         Ok(shaders) => shaders,
         Err(e) => {
             warn!(" Failed to create a Shaders: {:?}", e);
-            let msg = CString::new(format!("wr_shaders_new: {:?}", e)).unwrap();
-            unsafe {
-                gfx_critical_note(msg.as_ptr());
-            }
+            host().critical_note(&format!("wr_shaders_new: {:?}", e));
             return ptr::null_mut();
         },
     }));
@@ -4226,3 +4846,115 @@ pub unsafe extern "C" fn wr_program_cache_report_memory(
 ) -> usize {
     (*cache).program_cache.report_memory(size_of_op)
 }
+
+#[no_mangle]
+pub unsafe extern "C" fn wr_program_cache_get_stats(cache: *const WrProgramCache, out_stats: &mut WrProgramCacheStats) {
+    *out_stats = (*cache).stats();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory_compositor::MemoryCompositor;
+
+    // Creates a device rendering with a new SWGL context, which is made current.
+    fn swgl_device() -> (swgl::Context, Device) {
+        let context = swgl::Context::create();
+        context.make_current();
+        let device = Device::new(
+            Rc::new(context),
+            None,
+            None,
+            true,
+            UploadMethod::Immediate,
+            512 * 512,
+            None,
+            true,
+            true,
+            None,
+            false,
+            false,
+        );
+        (context, device)
+    }
+
+    fn rect(x0: i32, y0: i32, x1: i32, y1: i32) -> DeviceIntRect {
+        DeviceIntRect::new(DeviceIntPoint::new(x0, y0), DeviceIntPoint::new(x1, y1))
+    }
+
+    #[test]
+    fn empty_frames_cant_be_encoded() {
+        assert!(raw_frame(0, 4, ImageFormat::BGRA8).is_none());
+        assert!(raw_frame(4, -1, ImageFormat::BGRA8).is_none());
+        let frame = raw_frame(3, 2, ImageFormat::RGBA8).unwrap();
+        assert_eq!((frame.stride, frame.pixels.len()), (12, 24));
+    }
+
+    #[test]
+    fn compositor_calls_round_trip() {
+        let calls = vec![
+            CompositorCall::CreateSurface {
+                id: 1,
+                virtual_offset: DeviceIntPoint::new(-2, 3),
+                tile_size: DeviceIntSize::new(256, 256),
+                is_opaque: true,
+            },
+            CompositorCall::CreateTile { id: (1, 0, -1) },
+            CompositorCall::MapTile {
+                id: (1, 0, -1),
+                dirty_rect: rect(0, 0, 8, 8),
+                valid_rect: rect(0, 0, 16, 8),
+            },
+            CompositorCall::UnmapTile,
+            CompositorCall::BeginFrame,
+            CompositorCall::AddSurface {
+                id: 1,
+                transform: (1.0, -1.0, 0.0, 64.0),
+                clip_rect: rect(0, 0, 64, 64),
+                image_rendering: ImageRendering::Pixelated,
+            },
+            CompositorCall::StartCompositing {
+                clear_color: ColorF::WHITE,
+                dirty_rects: vec![rect(0, 0, 8, 8)],
+                opaque_rects: Vec::new(),
+            },
+            CompositorCall::EndFrame,
+        ];
+        let encoded = bincode::serialize(&calls).unwrap();
+        assert_eq!(bincode::deserialize::<Vec<CompositorCall>>(&encoded).unwrap(), calls);
+    }
+
+    #[test]
+    fn recorded_frames_replay_into_memory_compositors() {
+        let dir = env::temp_dir().join(format!("wr-compositor-recording-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (context, mut device) = swgl_device();
+        let size = DeviceIntSize::new(4, 4);
+
+        let mut recorder = RecordingCompositor::new(MemoryCompositor::new(context, size), dir.clone());
+        let recorded = recorder.compositor.output();
+        let id = NativeSurfaceId(1);
+        let transform = CompositorSurfaceTransform::identity();
+        recorder.create_backdrop_surface(&mut device, id, ColorF::new(1.0, 0.0, 0.0, 1.0));
+        recorder.begin_frame(&mut device);
+        recorder.add_surface(&mut device, id, transform, rect(1, 1, 3, 3), ImageRendering::Auto);
+        recorder.start_compositing(&mut device, ColorF::TRANSPARENT, &[], &[]);
+        recorder.end_frame(&mut device);
+
+        let frames = read_compositor_recording(&dir).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].len(), 5);
+
+        let mut replayed = MemoryCompositor::new(context, size);
+        for call in &frames[0] {
+            call.replay(&mut replayed, &mut device);
+        }
+        let frame = replayed.output().read();
+        assert_eq!(frame, recorded.read());
+        assert_eq!(frame.pixels[(4 + 1) * 4..][..4], [255, 0, 0, 255]);
+        assert_eq!(frame.pixels[..4], [0, 0, 0, 0]);
+
+        fs::remove_dir_all(&dir).unwrap();
+        context.destroy();
+    }
+}
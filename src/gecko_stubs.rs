@@ -0,0 +1,76 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Stand-ins for the Gecko callbacks used by blob image playback.
+//!
+//! These let the crate be tested and fuzzed without linking against the C++ side,
+//! together with the default host callbacks (see `host`). They are only built for
+//! tests, and for the fuzz targets when the `gecko` feature is off, so that they never
+//! clash with the real functions. Other embedders that play back blob images provide
+//! their own. Playback draws nothing, and font registration is ignored.
+
+use std::os::raw::c_void;
+
+use bindings::{ArcVecU8, MutByteSlice, WrBlobItem, WrFontInstanceKey, WrFontKey, WrIdNamespace};
+use webrender::api::units::{DeviceIntRect, LayoutIntRect};
+use webrender::api::{FontInstanceOptions, FontInstancePlatformOptions, FontVariation, ImageFormat, TileOffset};
+
+#[no_mangle]
+pub extern "C" fn wr_moz2d_render_cb(
+    _items: *const WrBlobItem,
+    _item_count: usize,
+    _format: ImageFormat,
+    _render_rect: &LayoutIntRect,
+    _visible_rect: &DeviceIntRect,
+    _tile_size: u16,
+    _tile_offset: &TileOffset,
+    _dirty_rect: Option<&LayoutIntRect>,
+    mut output: MutByteSlice,
+) -> bool {
+    // Produce a transparent tile.
+    for byte in output.as_mut_slice() {
+        *byte = 0;
+    }
+    true
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn HasFontData(_key: WrFontKey) -> bool {
+    true
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn AddFontData(_key: WrFontKey, _data: *const u8, _size: usize, _index: u32, _vec: &ArcVecU8) {}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn AddNativeFontHandle(_key: WrFontKey, _handle: *mut c_void, _index: u32) {}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn DeleteFontData(_key: WrFontKey) {}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn AddBlobFont(
+    _instance_key: WrFontInstanceKey,
+    _font_key: WrFontKey,
+    _size: f32,
+    _options: Option<&FontInstanceOptions>,
+    _platform_options: Option<&FontInstancePlatformOptions>,
+    _variations: *const FontVariation,
+    _num_variations: usize,
+) {
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn DeleteBlobFont(_key: WrFontInstanceKey) {}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn ClearBlobImageResources(_namespace: WrIdNamespace) {}
@@ -0,0 +1,1259 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The callbacks into the embedder that the bindings depend on.
+//!
+//! In Firefox these are C functions implemented by Gecko and linked into libxul,
+//! which is what the builtin callbacks call. Other embedders register their own with
+//! `set_host_callbacks`, or with `wr_register_host_callbacks` from C, before the
+//! callbacks are first used. Builds without the
+//! `gecko` feature don't reference Gecko at all, and use the defaults of
+//! `HostCallbacks` until something is registered: thread checks pass, GL contexts are
+//! desktop GL, notifications and native compositor calls are dropped, and critical
+//! notes are logged.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::OnceLock;
+
+use bindings::{WrExternalImage, WrExternalImageType, WrPipelineInfo, WrWindowId};
+use moz2d_renderer::{BlobReplayFallback, BlobSchedulingPolicy};
+use webrender::api::units::*;
+use webrender::api::*;
+use webrender::render_api::Transaction;
+use webrender::{
+    CompositorCapabilities, CompositorSurfaceTransform, NativeSurfaceId, NativeTileId, SWGLCompositeSurfaceInfo,
+    WindowVisibility,
+};
+
+/// The threads that can use a thread-local memory arena, if the host enables it.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArenaThread {
+    /// The scene builder thread.
+    SceneBuilder,
+    /// The render backend (frame builder) thread.
+    FrameBuilder,
+    /// The threads of a worker pool.
+    Worker,
+}
+
+/// Everything the bindings need from the embedder.
+///
+/// Every method has a default, so implementations only override what they support.
+/// The `*mut c_void` arguments are the embedder's own objects (GL contexts,
+/// compositors and external image hosts) that it passed to the bindings.
+pub trait HostCallbacks: Send + Sync {
+    /// Whether the caller is on the compositor thread, or `None` if the host doesn't
+    /// track threads.
+    fn is_in_compositor_thread(&self) -> Option<bool> {
+        None
+    }
+
+    /// Whether the caller is on the render thread, or `None` if the host doesn't track
+    /// threads.
+    fn is_in_render_thread(&self) -> Option<bool> {
+        None
+    }
+
+    /// Whether the caller is on the main thread, or `None` if the host doesn't track
+    /// threads.
+    fn is_in_main_thread(&self) -> Option<bool> {
+        None
+    }
+
+    /// Whether a native GL context is a GLES one.
+    fn is_glcontext_gles(&self, _gl_context: *mut c_void) -> bool {
+        false
+    }
+
+    /// Whether a native GL context is backed by ANGLE.
+    fn is_glcontext_angle(&self, _gl_context: *mut c_void) -> bool {
+        false
+    }
+
+    /// Looks up a GL function of a native GL context.
+    fn get_proc_address(&self, _gl_context: *mut c_void, _name: &CStr) -> *const c_void {
+        ptr::null()
+    }
+
+    /// A directory to load shaders and other resources from instead of the builtin ones.
+    fn resource_path_override(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Whether to use the optimized versions of the shaders.
+    fn use_optimized_shaders(&self) -> bool {
+        true
+    }
+
+    /// Reports an unexpected failure that doesn't prevent rendering.
+    fn critical_note(&self, msg: &str) {
+        warn!("{}", msg);
+    }
+
+    /// Sets an annotation for crash reports.
+    fn set_crash_annotation(&self, _annotation: CrashAnnotation, _value: &CStr) {}
+
+    /// Clears an annotation set with `set_crash_annotation`.
+    fn clear_crash_annotation(&self, _annotation: CrashAnnotation) {}
+
+    /// Whether the calling thread is being profiled.
+    fn thread_is_being_profiled(&self) -> bool {
+        false
+    }
+
+    /// Whether a thread should register a thread-local memory arena when it starts.
+    fn use_thread_local_arena(&self, _thread: ArenaThread) -> bool {
+        false
+    }
+
+    /// Registers a thread-local memory arena for the calling thread.
+    fn register_thread_local_arena(&self) {}
+
+    /// How blob images are rasterized. This is read whenever a batch of blob tiles is
+    /// about to be rasterized, so it can change at any time.
+    fn blob_scheduling_policy(&self) -> BlobSchedulingPolicy {
+        BlobSchedulingPolicy::DEFAULT
+    }
+
+    /// What new windows produce for the tiles of blob images that can't be replayed.
+    fn blob_replay_fallback(&self) -> BlobReplayFallback {
+        BlobReplayFallback::Error
+    }
+
+    /// The size in bytes of the cache of rasterized blob tiles of new windows, or 0 to
+    /// not cache them.
+    fn blob_tile_cache_size(&self) -> usize {
+        0
+    }
+
+    /// A directory in which new windows record the blobs they are given, see
+    /// `BlobRecorder`.
+    fn blob_recording_dir(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// A frame needs to be rendered, or composited if `composite_needed`.
+    fn notifier_wake_up(&self, _window_id: WrWindowId, _composite_needed: bool) {}
+
+    /// A new frame was built.
+    fn notifier_new_frame_ready(&self, _window_id: WrWindowId, _composite_needed: bool, _publish_id: FramePublishId) {}
+
+    /// An event sent with `Transaction::notify` or `RenderApi::send_external_event` was
+    /// handled.
+    fn notifier_external_event(&self, _window_id: WrWindowId, _raw_event: usize) {}
+
+    /// A frame should be rendered at the next opportunity.
+    fn schedule_render(&self, _window_id: WrWindowId, _reasons: RenderReasons) {}
+
+    /// A new scene was swapped in. The host may take the contents of `pipeline_info`.
+    fn finished_scene_build(&self, _window_id: WrWindowId, _pipeline_info: &mut WrPipelineInfo) {}
+
+    /// A notification requested with `wr_transaction_notify` reached `when`.
+    fn transaction_notification_notified(&self, _handler: usize, _when: Checkpoint) {}
+
+    /// The scene builder thread of a window started.
+    fn apz_register_updater(&self, _window_id: WrWindowId) {}
+
+    /// A new scene is about to be swapped in.
+    fn apz_pre_scene_swap(&self, _window_id: WrWindowId) {}
+
+    /// A new scene was swapped in.
+    fn apz_post_scene_swap(&self, _window_id: WrWindowId, _pipeline_info: &WrPipelineInfo) {}
+
+    /// The scene builder thread was poked to run pending APZ updates.
+    fn apz_run_updater(&self, _window_id: WrWindowId) {}
+
+    /// The scene builder thread of a window is shutting down.
+    fn apz_deregister_updater(&self, _window_id: WrWindowId) {}
+
+    /// The render backend thread of a window started.
+    fn apz_register_sampler(&self, _window_id: WrWindowId) {}
+
+    /// Adds the async scroll transforms of the frame being built to `transaction`.
+    fn apz_sample_transforms(
+        &self,
+        _window_id: WrWindowId,
+        _generated_frame_id: Option<u64>,
+        _transaction: &mut Transaction,
+    ) {
+    }
+
+    /// The render backend thread of a window is shutting down.
+    fn apz_deregister_sampler(&self, _window_id: WrWindowId) {}
+
+    /// The render backend thread of a window started.
+    fn omta_register_sampler(&self, _window_id: WrWindowId) {}
+
+    /// Adds the animated properties of the frame being built to `transaction`.
+    fn omta_sample(&self, _window_id: WrWindowId, _transaction: &mut Transaction) {}
+
+    /// The render backend thread of a window is shutting down.
+    fn omta_deregister_sampler(&self, _window_id: WrWindowId) {}
+
+    /// Locks an external image for the renderer.
+    fn lock_external_image(
+        &self,
+        _renderer: *mut c_void,
+        _external_image_id: ExternalImageId,
+        _channel_index: u8,
+    ) -> WrExternalImage {
+        WrExternalImage {
+            image_type: WrExternalImageType::Invalid,
+            handle: 0,
+            u0: 0.0,
+            v0: 0.0,
+            u1: 0.0,
+            v1: 0.0,
+            buff: ptr::null(),
+            size: 0,
+        }
+    }
+
+    /// Unlocks an external image locked with `lock_external_image`.
+    fn unlock_external_image(&self, _renderer: *mut c_void, _external_image_id: ExternalImageId, _channel_index: u8) {}
+
+    /// See `Compositor::create_surface`.
+    fn compositor_create_surface(
+        &self,
+        _compositor: *mut c_void,
+        _id: NativeSurfaceId,
+        _virtual_offset: DeviceIntPoint,
+        _tile_size: DeviceIntSize,
+        _is_opaque: bool,
+    ) {
+    }
+
+    /// See `Compositor::create_external_surface`.
+    fn compositor_create_external_surface(&self, _compositor: *mut c_void, _id: NativeSurfaceId, _is_opaque: bool) {}
+
+    /// See `Compositor::create_backdrop_surface`.
+    fn compositor_create_backdrop_surface(&self, _compositor: *mut c_void, _id: NativeSurfaceId, _color: ColorF) {}
+
+    /// See `Compositor::destroy_surface`.
+    fn compositor_destroy_surface(&self, _compositor: *mut c_void, _id: NativeSurfaceId) {}
+
+    /// See `Compositor::create_tile`.
+    fn compositor_create_tile(&self, _compositor: *mut c_void, _id: NativeSurfaceId, _x: i32, _y: i32) {}
+
+    /// See `Compositor::destroy_tile`.
+    fn compositor_destroy_tile(&self, _compositor: *mut c_void, _id: NativeSurfaceId, _x: i32, _y: i32) {}
+
+    /// See `Compositor::attach_external_image`.
+    fn compositor_attach_external_image(
+        &self,
+        _compositor: *mut c_void,
+        _id: NativeSurfaceId,
+        _external_image: ExternalImageId,
+    ) {
+    }
+
+    /// See `Compositor::bind`. Leaving `fbo_id` alone means the tile can't be drawn to.
+    fn compositor_bind(
+        &self,
+        _compositor: *mut c_void,
+        _id: NativeTileId,
+        _offset: &mut DeviceIntPoint,
+        _fbo_id: &mut u32,
+        _dirty_rect: DeviceIntRect,
+        _valid_rect: DeviceIntRect,
+    ) {
+    }
+
+    /// See `Compositor::unbind`.
+    fn compositor_unbind(&self, _compositor: *mut c_void) {}
+
+    /// See `Compositor::begin_frame`.
+    fn compositor_begin_frame(&self, _compositor: *mut c_void) {}
+
+    /// See `Compositor::add_surface`.
+    fn compositor_add_surface(
+        &self,
+        _compositor: *mut c_void,
+        _id: NativeSurfaceId,
+        _transform: &CompositorSurfaceTransform,
+        _clip_rect: DeviceIntRect,
+        _image_rendering: ImageRendering,
+    ) {
+    }
+
+    /// See `Compositor::start_compositing`.
+    fn compositor_start_compositing(
+        &self,
+        _compositor: *mut c_void,
+        _clear_color: ColorF,
+        _dirty_rects: &[DeviceIntRect],
+        _opaque_rects: &[DeviceIntRect],
+    ) {
+    }
+
+    /// See `Compositor::end_frame`.
+    fn compositor_end_frame(&self, _compositor: *mut c_void) {}
+
+    /// See `Compositor::enable_native_compositor`.
+    fn compositor_enable_native_compositor(&self, _compositor: *mut c_void, _enable: bool) {}
+
+    /// See `Compositor::deinit`.
+    fn compositor_deinit(&self, _compositor: *mut c_void) {}
+
+    /// See `Compositor::get_capabilities`.
+    fn compositor_get_capabilities(&self, _compositor: *mut c_void, _caps: &mut CompositorCapabilities) {}
+
+    /// See `Compositor::get_window_visibility`.
+    fn compositor_get_window_visibility(&self, _compositor: *mut c_void, _visibility: &mut WindowVisibility) {}
+
+    /// See `MappableCompositor::map_tile`. Leaving `data` null means the tile couldn't be
+    /// mapped.
+    fn compositor_map_tile(
+        &self,
+        _compositor: *mut c_void,
+        _id: NativeTileId,
+        _dirty_rect: DeviceIntRect,
+        _valid_rect: DeviceIntRect,
+        _data: &mut *mut c_void,
+        _stride: &mut i32,
+    ) {
+    }
+
+    /// See `MappableCompositor::unmap_tile`.
+    fn compositor_unmap_tile(&self, _compositor: *mut c_void) {}
+
+    /// See `PartialPresentCompositor::set_buffer_damage_region`.
+    fn compositor_set_buffer_damage_region(&self, _compositor: *mut c_void, _rects: &[DeviceIntRect]) {}
+
+    /// See `MappableCompositor::lock_composite_surface`.
+    fn swgl_lock_composite_surface(
+        &self,
+        _ctx: *mut c_void,
+        _external_image_id: ExternalImageId,
+        _composite_info: *mut SWGLCompositeSurfaceInfo,
+    ) -> bool {
+        false
+    }
+
+    /// See `MappableCompositor::unlock_composite_surface`.
+    fn swgl_unlock_composite_surface(&self, _ctx: *mut c_void, _external_image_id: ExternalImageId) {}
+}
+
+/// The host callbacks that only use the defaults.
+pub struct DefaultHostCallbacks;
+
+impl HostCallbacks for DefaultHostCallbacks {}
+
+#[cfg(all(feature = "gecko", not(test)))]
+use self::gecko::GECKO_HOST_CALLBACKS as BUILTIN_HOST_CALLBACKS;
+#[cfg(any(test, not(feature = "gecko")))]
+static BUILTIN_HOST_CALLBACKS: DefaultHostCallbacks = DefaultHostCallbacks;
+
+static HOST_CALLBACKS: OnceLock<&'static dyn HostCallbacks> = OnceLock::new();
+
+/// Registers the callbacks of the embedder.
+///
+/// This can only be done once, before the callbacks are first used, which creating a
+/// window does. Returns false, dropping the callbacks, if it is too late.
+pub fn set_host_callbacks(callbacks: Box<dyn HostCallbacks>) -> bool {
+    let mut callbacks = Some(callbacks);
+    HOST_CALLBACKS.get_or_init(|| Box::leak(callbacks.take().unwrap()));
+    callbacks.is_none()
+}
+
+/// The registered callbacks, or the builtin ones, which can't be replaced once this was
+/// called.
+pub fn host() -> &'static dyn HostCallbacks {
+    *HOST_CALLBACKS.get_or_init(|| &BUILTIN_HOST_CALLBACKS)
+}
+
+// The thread checks are only used in assertions, which pass if the host doesn't know.
+
+pub(crate) fn is_in_compositor_thread() -> bool {
+    host().is_in_compositor_thread().unwrap_or(true)
+}
+
+pub(crate) fn is_in_render_thread() -> bool {
+    host().is_in_render_thread().unwrap_or(true)
+}
+
+pub(crate) fn is_off_render_thread() -> bool {
+    !host().is_in_render_thread().unwrap_or(false)
+}
+
+pub(crate) fn is_in_main_thread() -> bool {
+    host().is_in_main_thread().unwrap_or(true)
+}
+
+/// The host callbacks as a table of C functions, for embedders that aren't written in
+/// Rust.
+///
+/// The functions have the signatures of the ones Gecko implements, and a null entry
+/// falls back to the default of `HostCallbacks`.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+#[allow(improper_ctypes_definitions)] // for the &mut Transaction of the samplers
+pub struct WrHostCallbacks {
+    pub is_in_compositor_thread: Option<unsafe extern "C" fn() -> bool>,
+    pub is_in_render_thread: Option<unsafe extern "C" fn() -> bool>,
+    pub is_in_main_thread: Option<unsafe extern "C" fn() -> bool>,
+    pub is_glcontext_gles: Option<unsafe extern "C" fn(gl_context: *mut c_void) -> bool>,
+    pub is_glcontext_angle: Option<unsafe extern "C" fn(gl_context: *mut c_void) -> bool>,
+    pub get_proc_address_from_glcontext:
+        Option<unsafe extern "C" fn(gl_context: *mut c_void, procname: *const c_char) -> *const c_void>,
+    pub gfx_wr_resource_path_override: Option<unsafe extern "C" fn() -> *const c_char>,
+    pub gfx_wr_use_optimized_shaders: Option<unsafe extern "C" fn() -> bool>,
+    pub gfx_critical_note: Option<unsafe extern "C" fn(msg: *const c_char)>,
+    pub gfx_wr_set_crash_annotation: Option<unsafe extern "C" fn(annotation: CrashAnnotation, value: *const c_char)>,
+    pub gfx_wr_clear_crash_annotation: Option<unsafe extern "C" fn(annotation: CrashAnnotation)>,
+    pub gecko_profiler_thread_is_being_profiled: Option<unsafe extern "C" fn() -> bool>,
+    pub use_thread_local_arena: Option<unsafe extern "C" fn(thread: ArenaThread) -> bool>,
+    pub wr_register_thread_local_arena: Option<unsafe extern "C" fn()>,
+    pub blob_scheduling_policy: Option<unsafe extern "C" fn() -> BlobSchedulingPolicy>,
+    pub blob_replay_fallback: Option<unsafe extern "C" fn() -> BlobReplayFallback>,
+    pub blob_tile_cache_size: Option<unsafe extern "C" fn() -> usize>,
+    pub gfx_wr_blob_recording_dir: Option<unsafe extern "C" fn() -> *const c_char>,
+    pub wr_notifier_wake_up: Option<unsafe extern "C" fn(window_id: WrWindowId, composite_needed: bool)>,
+    pub wr_notifier_new_frame_ready:
+        Option<unsafe extern "C" fn(window_id: WrWindowId, composite_needed: bool, publish_id: FramePublishId)>,
+    pub wr_notifier_external_event: Option<unsafe extern "C" fn(window_id: WrWindowId, raw_event: usize)>,
+    pub wr_schedule_render: Option<unsafe extern "C" fn(window_id: WrWindowId, reasons: RenderReasons)>,
+    pub wr_finished_scene_build:
+        Option<unsafe extern "C" fn(window_id: WrWindowId, pipeline_info: &mut WrPipelineInfo)>,
+    pub wr_transaction_notification_notified: Option<unsafe extern "C" fn(handler: usize, when: Checkpoint)>,
+    pub apz_register_updater: Option<unsafe extern "C" fn(window_id: WrWindowId)>,
+    pub apz_pre_scene_swap: Option<unsafe extern "C" fn(window_id: WrWindowId)>,
+    pub apz_post_scene_swap: Option<unsafe extern "C" fn(window_id: WrWindowId, pipeline_info: &WrPipelineInfo)>,
+    pub apz_run_updater: Option<unsafe extern "C" fn(window_id: WrWindowId)>,
+    pub apz_deregister_updater: Option<unsafe extern "C" fn(window_id: WrWindowId)>,
+    pub apz_register_sampler: Option<unsafe extern "C" fn(window_id: WrWindowId)>,
+    pub apz_sample_transforms: Option<
+        unsafe extern "C" fn(window_id: WrWindowId, generated_frame_id: *const u64, transaction: &mut Transaction),
+    >,
+    pub apz_deregister_sampler: Option<unsafe extern "C" fn(window_id: WrWindowId)>,
+    pub omta_register_sampler: Option<unsafe extern "C" fn(window_id: WrWindowId)>,
+    pub omta_sample: Option<unsafe extern "C" fn(window_id: WrWindowId, transaction: &mut Transaction)>,
+    pub omta_deregister_sampler: Option<unsafe extern "C" fn(window_id: WrWindowId)>,
+    pub wr_renderer_lock_external_image: Option<
+        unsafe extern "C" fn(
+            renderer: *mut c_void,
+            external_image_id: ExternalImageId,
+            channel_index: u8,
+        ) -> WrExternalImage,
+    >,
+    pub wr_renderer_unlock_external_image:
+        Option<unsafe extern "C" fn(renderer: *mut c_void, external_image_id: ExternalImageId, channel_index: u8)>,
+    pub wr_compositor_create_surface: Option<
+        unsafe extern "C" fn(
+            compositor: *mut c_void,
+            id: NativeSurfaceId,
+            virtual_offset: DeviceIntPoint,
+            tile_size: DeviceIntSize,
+            is_opaque: bool,
+        ),
+    >,
+    pub wr_compositor_create_external_surface:
+        Option<unsafe extern "C" fn(compositor: *mut c_void, id: NativeSurfaceId, is_opaque: bool)>,
+    pub wr_compositor_create_backdrop_surface:
+        Option<unsafe extern "C" fn(compositor: *mut c_void, id: NativeSurfaceId, color: ColorF)>,
+    pub wr_compositor_destroy_surface: Option<unsafe extern "C" fn(compositor: *mut c_void, id: NativeSurfaceId)>,
+    pub wr_compositor_create_tile:
+        Option<unsafe extern "C" fn(compositor: *mut c_void, id: NativeSurfaceId, x: i32, y: i32)>,
+    pub wr_compositor_destroy_tile:
+        Option<unsafe extern "C" fn(compositor: *mut c_void, id: NativeSurfaceId, x: i32, y: i32)>,
+    pub wr_compositor_attach_external_image:
+        Option<unsafe extern "C" fn(compositor: *mut c_void, id: NativeSurfaceId, external_image: ExternalImageId)>,
+    pub wr_compositor_bind: Option<
+        unsafe extern "C" fn(
+            compositor: *mut c_void,
+            id: NativeTileId,
+            offset: &mut DeviceIntPoint,
+            fbo_id: &mut u32,
+            dirty_rect: DeviceIntRect,
+            valid_rect: DeviceIntRect,
+        ),
+    >,
+    pub wr_compositor_unbind: Option<unsafe extern "C" fn(compositor: *mut c_void)>,
+    pub wr_compositor_begin_frame: Option<unsafe extern "C" fn(compositor: *mut c_void)>,
+    pub wr_compositor_add_surface: Option<
+        unsafe extern "C" fn(
+            compositor: *mut c_void,
+            id: NativeSurfaceId,
+            transform: &CompositorSurfaceTransform,
+            clip_rect: DeviceIntRect,
+            image_rendering: ImageRendering,
+        ),
+    >,
+    pub wr_compositor_start_compositing: Option<
+        unsafe extern "C" fn(
+            compositor: *mut c_void,
+            clear_color: ColorF,
+            dirty_rects: *const DeviceIntRect,
+            num_dirty_rects: usize,
+            opaque_rects: *const DeviceIntRect,
+            num_opaque_rects: usize,
+        ),
+    >,
+    pub wr_compositor_end_frame: Option<unsafe extern "C" fn(compositor: *mut c_void)>,
+    pub wr_compositor_enable_native_compositor: Option<unsafe extern "C" fn(compositor: *mut c_void, enable: bool)>,
+    pub wr_compositor_deinit: Option<unsafe extern "C" fn(compositor: *mut c_void)>,
+    pub wr_compositor_get_capabilities:
+        Option<unsafe extern "C" fn(compositor: *mut c_void, caps: *mut CompositorCapabilities)>,
+    pub wr_compositor_get_window_visibility:
+        Option<unsafe extern "C" fn(compositor: *mut c_void, visibility: *mut WindowVisibility)>,
+    pub wr_compositor_map_tile: Option<
+        unsafe extern "C" fn(
+            compositor: *mut c_void,
+            id: NativeTileId,
+            dirty_rect: DeviceIntRect,
+            valid_rect: DeviceIntRect,
+            data: &mut *mut c_void,
+            stride: &mut i32,
+        ),
+    >,
+    pub wr_compositor_unmap_tile: Option<unsafe extern "C" fn(compositor: *mut c_void)>,
+    pub wr_partial_present_compositor_set_buffer_damage_region:
+        Option<unsafe extern "C" fn(compositor: *mut c_void, rects: *const DeviceIntRect, n_rects: usize)>,
+    pub wr_swgl_lock_composite_surface: Option<
+        unsafe extern "C" fn(
+            ctx: *mut c_void,
+            external_image_id: ExternalImageId,
+            composite_info: *mut SWGLCompositeSurfaceInfo,
+        ) -> bool,
+    >,
+    pub wr_swgl_unlock_composite_surface:
+        Option<unsafe extern "C" fn(ctx: *mut c_void, external_image_id: ExternalImageId)>,
+}
+
+/// Calls the entry of a `WrHostCallbacks` if it is set, and the default otherwise.
+macro_rules! call_or_default {
+    ($table:expr, $entry:ident($($arg:expr),*), $default:expr) => {
+        match $table.$entry {
+            Some(f) => unsafe { f($($arg),*) },
+            None => $default,
+        }
+    };
+}
+
+fn frame_id_ptr(generated_frame_id: &Option<u64>) -> *const u64 {
+    match *generated_frame_id {
+        Some(ref id) => id,
+        None => ptr::null(),
+    }
+}
+
+impl HostCallbacks for WrHostCallbacks {
+    fn is_in_compositor_thread(&self) -> Option<bool> {
+        self.is_in_compositor_thread.map(|f| unsafe { f() })
+    }
+
+    fn is_in_render_thread(&self) -> Option<bool> {
+        self.is_in_render_thread.map(|f| unsafe { f() })
+    }
+
+    fn is_in_main_thread(&self) -> Option<bool> {
+        self.is_in_main_thread.map(|f| unsafe { f() })
+    }
+
+    fn is_glcontext_gles(&self, gl_context: *mut c_void) -> bool {
+        call_or_default!(
+            self,
+            is_glcontext_gles(gl_context),
+            DefaultHostCallbacks.is_glcontext_gles(gl_context)
+        )
+    }
+
+    fn is_glcontext_angle(&self, gl_context: *mut c_void) -> bool {
+        call_or_default!(
+            self,
+            is_glcontext_angle(gl_context),
+            DefaultHostCallbacks.is_glcontext_angle(gl_context)
+        )
+    }
+
+    fn get_proc_address(&self, gl_context: *mut c_void, name: &CStr) -> *const c_void {
+        call_or_default!(
+            self,
+            get_proc_address_from_glcontext(gl_context, name.as_ptr()),
+            DefaultHostCallbacks.get_proc_address(gl_context, name)
+        )
+    }
+
+    fn resource_path_override(&self) -> Option<PathBuf> {
+        match self.gfx_wr_resource_path_override {
+            Some(f) => unsafe { path_from_c_str(f()) },
+            None => DefaultHostCallbacks.resource_path_override(),
+        }
+    }
+
+    fn use_optimized_shaders(&self) -> bool {
+        call_or_default!(
+            self,
+            gfx_wr_use_optimized_shaders(),
+            DefaultHostCallbacks.use_optimized_shaders()
+        )
+    }
+
+    fn critical_note(&self, msg: &str) {
+        match self.gfx_critical_note {
+            Some(f) => {
+                // Messages may quote data with NUL bytes, which C strings can't hold.
+                let msg = CString::new(msg.replace('\0', "\u{FFFD}")).unwrap();
+                unsafe { f(msg.as_ptr()) }
+            },
+            None => DefaultHostCallbacks.critical_note(msg),
+        }
+    }
+
+    fn set_crash_annotation(&self, annotation: CrashAnnotation, value: &CStr) {
+        call_or_default!(self, gfx_wr_set_crash_annotation(annotation, value.as_ptr()), ())
+    }
+
+    fn clear_crash_annotation(&self, annotation: CrashAnnotation) {
+        call_or_default!(self, gfx_wr_clear_crash_annotation(annotation), ())
+    }
+
+    fn thread_is_being_profiled(&self) -> bool {
+        call_or_default!(
+            self,
+            gecko_profiler_thread_is_being_profiled(),
+            DefaultHostCallbacks.thread_is_being_profiled()
+        )
+    }
+
+    fn use_thread_local_arena(&self, thread: ArenaThread) -> bool {
+        call_or_default!(
+            self,
+            use_thread_local_arena(thread),
+            DefaultHostCallbacks.use_thread_local_arena(thread)
+        )
+    }
+
+    fn register_thread_local_arena(&self) {
+        call_or_default!(self, wr_register_thread_local_arena(), ())
+    }
+
+    fn blob_scheduling_policy(&self) -> BlobSchedulingPolicy {
+        call_or_default!(
+            self,
+            blob_scheduling_policy(),
+            DefaultHostCallbacks.blob_scheduling_policy()
+        )
+    }
+
+    fn blob_replay_fallback(&self) -> BlobReplayFallback {
+        call_or_default!(self, blob_replay_fallback(), DefaultHostCallbacks.blob_replay_fallback())
+    }
+
+    fn blob_tile_cache_size(&self) -> usize {
+        call_or_default!(self, blob_tile_cache_size(), DefaultHostCallbacks.blob_tile_cache_size())
+    }
+
+    fn blob_recording_dir(&self) -> Option<PathBuf> {
+        match self.gfx_wr_blob_recording_dir {
+            Some(f) => unsafe { path_from_c_str(f()) },
+            None => DefaultHostCallbacks.blob_recording_dir(),
+        }
+    }
+
+    fn notifier_wake_up(&self, window_id: WrWindowId, composite_needed: bool) {
+        call_or_default!(self, wr_notifier_wake_up(window_id, composite_needed), ())
+    }
+
+    fn notifier_new_frame_ready(&self, window_id: WrWindowId, composite_needed: bool, publish_id: FramePublishId) {
+        call_or_default!(
+            self,
+            wr_notifier_new_frame_ready(window_id, composite_needed, publish_id),
+            ()
+        )
+    }
+
+    fn notifier_external_event(&self, window_id: WrWindowId, raw_event: usize) {
+        call_or_default!(self, wr_notifier_external_event(window_id, raw_event), ())
+    }
+
+    fn schedule_render(&self, window_id: WrWindowId, reasons: RenderReasons) {
+        call_or_default!(self, wr_schedule_render(window_id, reasons), ())
+    }
+
+    fn finished_scene_build(&self, window_id: WrWindowId, pipeline_info: &mut WrPipelineInfo) {
+        call_or_default!(self, wr_finished_scene_build(window_id, pipeline_info), ())
+    }
+
+    fn transaction_notification_notified(&self, handler: usize, when: Checkpoint) {
+        call_or_default!(self, wr_transaction_notification_notified(handler, when), ())
+    }
+
+    fn apz_register_updater(&self, window_id: WrWindowId) {
+        call_or_default!(self, apz_register_updater(window_id), ())
+    }
+
+    fn apz_pre_scene_swap(&self, window_id: WrWindowId) {
+        call_or_default!(self, apz_pre_scene_swap(window_id), ())
+    }
+
+    fn apz_post_scene_swap(&self, window_id: WrWindowId, pipeline_info: &WrPipelineInfo) {
+        call_or_default!(self, apz_post_scene_swap(window_id, pipeline_info), ())
+    }
+
+    fn apz_run_updater(&self, window_id: WrWindowId) {
+        call_or_default!(self, apz_run_updater(window_id), ())
+    }
+
+    fn apz_deregister_updater(&self, window_id: WrWindowId) {
+        call_or_default!(self, apz_deregister_updater(window_id), ())
+    }
+
+    fn apz_register_sampler(&self, window_id: WrWindowId) {
+        call_or_default!(self, apz_register_sampler(window_id), ())
+    }
+
+    fn apz_sample_transforms(
+        &self,
+        window_id: WrWindowId,
+        generated_frame_id: Option<u64>,
+        transaction: &mut Transaction,
+    ) {
+        let generated_frame_id = frame_id_ptr(&generated_frame_id);
+        call_or_default!(
+            self,
+            apz_sample_transforms(window_id, generated_frame_id, transaction),
+            ()
+        )
+    }
+
+    fn apz_deregister_sampler(&self, window_id: WrWindowId) {
+        call_or_default!(self, apz_deregister_sampler(window_id), ())
+    }
+
+    fn omta_register_sampler(&self, window_id: WrWindowId) {
+        call_or_default!(self, omta_register_sampler(window_id), ())
+    }
+
+    fn omta_sample(&self, window_id: WrWindowId, transaction: &mut Transaction) {
+        call_or_default!(self, omta_sample(window_id, transaction), ())
+    }
+
+    fn omta_deregister_sampler(&self, window_id: WrWindowId) {
+        call_or_default!(self, omta_deregister_sampler(window_id), ())
+    }
+
+    fn lock_external_image(
+        &self,
+        renderer: *mut c_void,
+        external_image_id: ExternalImageId,
+        channel_index: u8,
+    ) -> WrExternalImage {
+        call_or_default!(
+            self,
+            wr_renderer_lock_external_image(renderer, external_image_id, channel_index),
+            DefaultHostCallbacks.lock_external_image(renderer, external_image_id, channel_index)
+        )
+    }
+
+    fn unlock_external_image(&self, renderer: *mut c_void, external_image_id: ExternalImageId, channel_index: u8) {
+        call_or_default!(
+            self,
+            wr_renderer_unlock_external_image(renderer, external_image_id, channel_index),
+            ()
+        )
+    }
+
+    fn compositor_create_surface(
+        &self,
+        compositor: *mut c_void,
+        id: NativeSurfaceId,
+        virtual_offset: DeviceIntPoint,
+        tile_size: DeviceIntSize,
+        is_opaque: bool,
+    ) {
+        call_or_default!(
+            self,
+            wr_compositor_create_surface(compositor, id, virtual_offset, tile_size, is_opaque),
+            ()
+        )
+    }
+
+    fn compositor_create_external_surface(&self, compositor: *mut c_void, id: NativeSurfaceId, is_opaque: bool) {
+        call_or_default!(
+            self,
+            wr_compositor_create_external_surface(compositor, id, is_opaque),
+            ()
+        )
+    }
+
+    fn compositor_create_backdrop_surface(&self, compositor: *mut c_void, id: NativeSurfaceId, color: ColorF) {
+        call_or_default!(self, wr_compositor_create_backdrop_surface(compositor, id, color), ())
+    }
+
+    fn compositor_destroy_surface(&self, compositor: *mut c_void, id: NativeSurfaceId) {
+        call_or_default!(self, wr_compositor_destroy_surface(compositor, id), ())
+    }
+
+    fn compositor_create_tile(&self, compositor: *mut c_void, id: NativeSurfaceId, x: i32, y: i32) {
+        call_or_default!(self, wr_compositor_create_tile(compositor, id, x, y), ())
+    }
+
+    fn compositor_destroy_tile(&self, compositor: *mut c_void, id: NativeSurfaceId, x: i32, y: i32) {
+        call_or_default!(self, wr_compositor_destroy_tile(compositor, id, x, y), ())
+    }
+
+    fn compositor_attach_external_image(
+        &self,
+        compositor: *mut c_void,
+        id: NativeSurfaceId,
+        external_image: ExternalImageId,
+    ) {
+        call_or_default!(
+            self,
+            wr_compositor_attach_external_image(compositor, id, external_image),
+            ()
+        )
+    }
+
+    fn compositor_bind(
+        &self,
+        compositor: *mut c_void,
+        id: NativeTileId,
+        offset: &mut DeviceIntPoint,
+        fbo_id: &mut u32,
+        dirty_rect: DeviceIntRect,
+        valid_rect: DeviceIntRect,
+    ) {
+        call_or_default!(
+            self,
+            wr_compositor_bind(compositor, id, offset, fbo_id, dirty_rect, valid_rect),
+            ()
+        )
+    }
+
+    fn compositor_unbind(&self, compositor: *mut c_void) {
+        call_or_default!(self, wr_compositor_unbind(compositor), ())
+    }
+
+    fn compositor_begin_frame(&self, compositor: *mut c_void) {
+        call_or_default!(self, wr_compositor_begin_frame(compositor), ())
+    }
+
+    fn compositor_add_surface(
+        &self,
+        compositor: *mut c_void,
+        id: NativeSurfaceId,
+        transform: &CompositorSurfaceTransform,
+        clip_rect: DeviceIntRect,
+        image_rendering: ImageRendering,
+    ) {
+        call_or_default!(
+            self,
+            wr_compositor_add_surface(compositor, id, transform, clip_rect, image_rendering),
+            ()
+        )
+    }
+
+    fn compositor_start_compositing(
+        &self,
+        compositor: *mut c_void,
+        clear_color: ColorF,
+        dirty_rects: &[DeviceIntRect],
+        opaque_rects: &[DeviceIntRect],
+    ) {
+        call_or_default!(
+            self,
+            wr_compositor_start_compositing(
+                compositor,
+                clear_color,
+                dirty_rects.as_ptr(),
+                dirty_rects.len(),
+                opaque_rects.as_ptr(),
+                opaque_rects.len()
+            ),
+            ()
+        )
+    }
+
+    fn compositor_end_frame(&self, compositor: *mut c_void) {
+        call_or_default!(self, wr_compositor_end_frame(compositor), ())
+    }
+
+    fn compositor_enable_native_compositor(&self, compositor: *mut c_void, enable: bool) {
+        call_or_default!(self, wr_compositor_enable_native_compositor(compositor, enable), ())
+    }
+
+    fn compositor_deinit(&self, compositor: *mut c_void) {
+        call_or_default!(self, wr_compositor_deinit(compositor), ())
+    }
+
+    fn compositor_get_capabilities(&self, compositor: *mut c_void, caps: &mut CompositorCapabilities) {
+        call_or_default!(self, wr_compositor_get_capabilities(compositor, caps), ())
+    }
+
+    fn compositor_get_window_visibility(&self, compositor: *mut c_void, visibility: &mut WindowVisibility) {
+        call_or_default!(self, wr_compositor_get_window_visibility(compositor, visibility), ())
+    }
+
+    fn compositor_map_tile(
+        &self,
+        compositor: *mut c_void,
+        id: NativeTileId,
+        dirty_rect: DeviceIntRect,
+        valid_rect: DeviceIntRect,
+        data: &mut *mut c_void,
+        stride: &mut i32,
+    ) {
+        call_or_default!(
+            self,
+            wr_compositor_map_tile(compositor, id, dirty_rect, valid_rect, data, stride),
+            ()
+        )
+    }
+
+    fn compositor_unmap_tile(&self, compositor: *mut c_void) {
+        call_or_default!(self, wr_compositor_unmap_tile(compositor), ())
+    }
+
+    fn compositor_set_buffer_damage_region(&self, compositor: *mut c_void, rects: &[DeviceIntRect]) {
+        call_or_default!(
+            self,
+            wr_partial_present_compositor_set_buffer_damage_region(compositor, rects.as_ptr(), rects.len()),
+            ()
+        )
+    }
+
+    fn swgl_lock_composite_surface(
+        &self,
+        ctx: *mut c_void,
+        external_image_id: ExternalImageId,
+        composite_info: *mut SWGLCompositeSurfaceInfo,
+    ) -> bool {
+        call_or_default!(
+            self,
+            wr_swgl_lock_composite_surface(ctx, external_image_id, composite_info),
+            DefaultHostCallbacks.swgl_lock_composite_surface(ctx, external_image_id, composite_info)
+        )
+    }
+
+    fn swgl_unlock_composite_surface(&self, ctx: *mut c_void, external_image_id: ExternalImageId) {
+        call_or_default!(self, wr_swgl_unlock_composite_surface(ctx, external_image_id), ())
+    }
+}
+
+unsafe fn path_from_c_str(path: *const c_char) -> Option<PathBuf> {
+    if path.is_null() {
+        return None;
+    }
+    CStr::from_ptr(path).to_str().ok().map(PathBuf::from)
+}
+
+/// Registers a table of host callbacks, see `set_host_callbacks`. Returns false if it
+/// is too late to.
+#[no_mangle]
+#[allow(improper_ctypes_definitions)]
+pub extern "C" fn wr_register_host_callbacks(callbacks: &WrHostCallbacks) -> bool {
+    set_host_callbacks(Box::new(*callbacks))
+}
+
+/// The callbacks implemented by Gecko.
+#[cfg(all(feature = "gecko", not(test)))]
+mod gecko {
+    use super::*;
+
+    extern "C" {
+        fn is_in_compositor_thread() -> bool;
+        fn is_in_render_thread() -> bool;
+        fn is_in_main_thread() -> bool;
+        fn is_glcontext_gles(glcontext_ptr: *mut c_void) -> bool;
+        fn is_glcontext_angle(glcontext_ptr: *mut c_void) -> bool;
+        fn get_proc_address_from_glcontext(glcontext_ptr: *mut c_void, procname: *const c_char) -> *const c_void;
+        fn gfx_wr_resource_path_override() -> *const c_char;
+        fn gfx_wr_use_optimized_shaders() -> bool;
+        fn gfx_wr_blob_recording_dir() -> *const c_char;
+        // TODO: make gfx_critical_error() work.
+        // We still have problem to pass the error message from render/render_backend
+        // thread to main thread now.
+        #[allow(dead_code)]
+        fn gfx_critical_error(msg: *const c_char);
+        fn gfx_critical_note(msg: *const c_char);
+        fn gfx_wr_set_crash_annotation(annotation: CrashAnnotation, value: *const c_char);
+        fn gfx_wr_clear_crash_annotation(annotation: CrashAnnotation);
+        fn gecko_profiler_thread_is_being_profiled() -> bool;
+        fn wr_register_thread_local_arena();
+    }
+
+    extern "C" {
+        fn wr_notifier_wake_up(window_id: WrWindowId, composite_needed: bool);
+        fn wr_notifier_new_frame_ready(window_id: WrWindowId, composite_needed: bool, publish_id: FramePublishId);
+        fn wr_notifier_external_event(window_id: WrWindowId, raw_event: usize);
+        fn wr_schedule_render(window_id: WrWindowId, reasons: RenderReasons);
+        // NOTE: This moves away from pipeline_info.
+        fn wr_finished_scene_build(window_id: WrWindowId, pipeline_info: &mut WrPipelineInfo);
+
+        fn wr_transaction_notification_notified(handler: usize, when: Checkpoint);
+    }
+
+    #[allow(improper_ctypes)] // this is needed so that rustc doesn't complain about passing the &mut Transaction to an extern function
+    extern "C" {
+        // These callbacks are invoked from the scene builder thread (aka the APZ
+        // updater thread)
+        fn apz_register_updater(window_id: WrWindowId);
+        fn apz_pre_scene_swap(window_id: WrWindowId);
+        fn apz_post_scene_swap(window_id: WrWindowId, pipeline_info: &WrPipelineInfo);
+        fn apz_run_updater(window_id: WrWindowId);
+        fn apz_deregister_updater(window_id: WrWindowId);
+
+        // These callbacks are invoked from the render backend thread (aka the APZ
+        // sampler thread)
+        fn apz_register_sampler(window_id: WrWindowId);
+        fn apz_sample_transforms(window_id: WrWindowId, generated_frame_id: *const u64, transaction: &mut Transaction);
+        fn apz_deregister_sampler(window_id: WrWindowId);
+
+        fn omta_register_sampler(window_id: WrWindowId);
+        fn omta_sample(window_id: WrWindowId, transaction: &mut Transaction);
+        fn omta_deregister_sampler(window_id: WrWindowId);
+    }
+
+    extern "C" {
+        fn wr_renderer_lock_external_image(
+            renderer: *mut c_void,
+            external_image_id: ExternalImageId,
+            channel_index: u8,
+        ) -> WrExternalImage;
+        fn wr_renderer_unlock_external_image(
+            renderer: *mut c_void,
+            external_image_id: ExternalImageId,
+            channel_index: u8,
+        );
+    }
+
+    extern "C" {
+        fn wr_compositor_create_surface(
+            compositor: *mut c_void,
+            id: NativeSurfaceId,
+            virtual_offset: DeviceIntPoint,
+            tile_size: DeviceIntSize,
+            is_opaque: bool,
+        );
+        fn wr_compositor_create_external_surface(compositor: *mut c_void, id: NativeSurfaceId, is_opaque: bool);
+        fn wr_compositor_create_backdrop_surface(compositor: *mut c_void, id: NativeSurfaceId, color: ColorF);
+        fn wr_compositor_destroy_surface(compositor: *mut c_void, id: NativeSurfaceId);
+        fn wr_compositor_create_tile(compositor: *mut c_void, id: NativeSurfaceId, x: i32, y: i32);
+        fn wr_compositor_destroy_tile(compositor: *mut c_void, id: NativeSurfaceId, x: i32, y: i32);
+        fn wr_compositor_attach_external_image(
+            compositor: *mut c_void,
+            id: NativeSurfaceId,
+            external_image: ExternalImageId,
+        );
+        fn wr_compositor_bind(
+            compositor: *mut c_void,
+            id: NativeTileId,
+            offset: &mut DeviceIntPoint,
+            fbo_id: &mut u32,
+            dirty_rect: DeviceIntRect,
+            valid_rect: DeviceIntRect,
+        );
+        fn wr_compositor_unbind(compositor: *mut c_void);
+        fn wr_compositor_begin_frame(compositor: *mut c_void);
+        fn wr_compositor_add_surface(
+            compositor: *mut c_void,
+            id: NativeSurfaceId,
+            transform: &CompositorSurfaceTransform,
+            clip_rect: DeviceIntRect,
+            image_rendering: ImageRendering,
+        );
+        fn wr_compositor_start_compositing(
+            compositor: *mut c_void,
+            clear_color: ColorF,
+            dirty_rects: *const DeviceIntRect,
+            num_dirty_rects: usize,
+            opaque_rects: *const DeviceIntRect,
+            num_opaque_rects: usize,
+        );
+        fn wr_compositor_end_frame(compositor: *mut c_void);
+        fn wr_compositor_enable_native_compositor(compositor: *mut c_void, enable: bool);
+        fn wr_compositor_deinit(compositor: *mut c_void);
+        fn wr_compositor_get_capabilities(compositor: *mut c_void, caps: *mut CompositorCapabilities);
+        fn wr_compositor_get_window_visibility(compositor: *mut c_void, caps: *mut WindowVisibility);
+        fn wr_compositor_map_tile(
+            compositor: *mut c_void,
+            id: NativeTileId,
+            dirty_rect: DeviceIntRect,
+            valid_rect: DeviceIntRect,
+            data: &mut *mut c_void,
+            stride: &mut i32,
+        );
+        fn wr_compositor_unmap_tile(compositor: *mut c_void);
+
+        fn wr_partial_present_compositor_set_buffer_damage_region(
+            compositor: *mut c_void,
+            rects: *const DeviceIntRect,
+            n_rects: usize,
+        );
+    }
+
+    extern "C" {
+        fn wr_swgl_lock_composite_surface(
+            ctx: *mut c_void,
+            external_image_id: ExternalImageId,
+            composite_info: *mut SWGLCompositeSurfaceInfo,
+        ) -> bool;
+        fn wr_swgl_unlock_composite_surface(ctx: *mut c_void, external_image_id: ExternalImageId);
+    }
+
+    /// Gecko's callbacks. The thread-local arena and blob prefs are read here rather than
+    /// by Gecko.
+    pub static GECKO_HOST_CALLBACKS: WrHostCallbacks = WrHostCallbacks {
+        is_in_compositor_thread: Some(is_in_compositor_thread),
+        is_in_render_thread: Some(is_in_render_thread),
+        is_in_main_thread: Some(is_in_main_thread),
+        is_glcontext_gles: Some(is_glcontext_gles),
+        is_glcontext_angle: Some(is_glcontext_angle),
+        get_proc_address_from_glcontext: Some(get_proc_address_from_glcontext),
+        gfx_wr_resource_path_override: Some(gfx_wr_resource_path_override),
+        gfx_wr_use_optimized_shaders: Some(gfx_wr_use_optimized_shaders),
+        gfx_critical_note: Some(gfx_critical_note),
+        gfx_wr_set_crash_annotation: Some(gfx_wr_set_crash_annotation),
+        gfx_wr_clear_crash_annotation: Some(gfx_wr_clear_crash_annotation),
+        gecko_profiler_thread_is_being_profiled: Some(gecko_profiler_thread_is_being_profiled),
+        use_thread_local_arena: Some(use_thread_local_arena),
+        wr_register_thread_local_arena: Some(wr_register_thread_local_arena),
+        blob_scheduling_policy: Some(blob_scheduling_policy),
+        blob_replay_fallback: Some(blob_replay_fallback),
+        blob_tile_cache_size: Some(blob_tile_cache_size),
+        gfx_wr_blob_recording_dir: Some(gfx_wr_blob_recording_dir),
+        wr_notifier_wake_up: Some(wr_notifier_wake_up),
+        wr_notifier_new_frame_ready: Some(wr_notifier_new_frame_ready),
+        wr_notifier_external_event: Some(wr_notifier_external_event),
+        wr_schedule_render: Some(wr_schedule_render),
+        wr_finished_scene_build: Some(wr_finished_scene_build),
+        wr_transaction_notification_notified: Some(wr_transaction_notification_notified),
+        apz_register_updater: Some(apz_register_updater),
+        apz_pre_scene_swap: Some(apz_pre_scene_swap),
+        apz_post_scene_swap: Some(apz_post_scene_swap),
+        apz_run_updater: Some(apz_run_updater),
+        apz_deregister_updater: Some(apz_deregister_updater),
+        apz_register_sampler: Some(apz_register_sampler),
+        apz_sample_transforms: Some(apz_sample_transforms),
+        apz_deregister_sampler: Some(apz_deregister_sampler),
+        omta_register_sampler: Some(omta_register_sampler),
+        omta_sample: Some(omta_sample),
+        omta_deregister_sampler: Some(omta_deregister_sampler),
+        wr_renderer_lock_external_image: Some(wr_renderer_lock_external_image),
+        wr_renderer_unlock_external_image: Some(wr_renderer_unlock_external_image),
+        wr_compositor_create_surface: Some(wr_compositor_create_surface),
+        wr_compositor_create_external_surface: Some(wr_compositor_create_external_surface),
+        wr_compositor_create_backdrop_surface: Some(wr_compositor_create_backdrop_surface),
+        wr_compositor_destroy_surface: Some(wr_compositor_destroy_surface),
+        wr_compositor_create_tile: Some(wr_compositor_create_tile),
+        wr_compositor_destroy_tile: Some(wr_compositor_destroy_tile),
+        wr_compositor_attach_external_image: Some(wr_compositor_attach_external_image),
+        wr_compositor_bind: Some(wr_compositor_bind),
+        wr_compositor_unbind: Some(wr_compositor_unbind),
+        wr_compositor_begin_frame: Some(wr_compositor_begin_frame),
+        wr_compositor_add_surface: Some(wr_compositor_add_surface),
+        wr_compositor_start_compositing: Some(wr_compositor_start_compositing),
+        wr_compositor_end_frame: Some(wr_compositor_end_frame),
+        wr_compositor_enable_native_compositor: Some(wr_compositor_enable_native_compositor),
+        wr_compositor_deinit: Some(wr_compositor_deinit),
+        wr_compositor_get_capabilities: Some(wr_compositor_get_capabilities),
+        wr_compositor_get_window_visibility: Some(wr_compositor_get_window_visibility),
+        wr_compositor_map_tile: Some(wr_compositor_map_tile),
+        wr_compositor_unmap_tile: Some(wr_compositor_unmap_tile),
+        wr_partial_present_compositor_set_buffer_damage_region: Some(
+            wr_partial_present_compositor_set_buffer_damage_region,
+        ),
+        wr_swgl_lock_composite_surface: Some(wr_swgl_lock_composite_surface),
+        wr_swgl_unlock_composite_surface: Some(wr_swgl_unlock_composite_surface),
+    };
+
+    unsafe extern "C" fn use_thread_local_arena(thread: ArenaThread) -> bool {
+        match thread {
+            ArenaThread::SceneBuilder => static_prefs::pref!("gfx.webrender.scene-builder-thread-local-arena"),
+            ArenaThread::FrameBuilder => static_prefs::pref!("gfx.webrender.frame-builder-thread-local-arena"),
+            ArenaThread::Worker => static_prefs::pref!("gfx.webrender.worker-thread-local-arena"),
+        }
+    }
+
+    unsafe extern "C" fn blob_scheduling_policy() -> BlobSchedulingPolicy {
+        BlobSchedulingPolicy {
+            low_priority_parallel_threshold: static_prefs::pref!(
+                "gfx.webrender.blob.low-priority-parallel-threshold"
+            ) as usize,
+            high_priority_parallel_threshold: static_prefs::pref!(
+                "gfx.webrender.blob.high-priority-parallel-threshold"
+            ) as usize,
+            min_parallel_cost: static_prefs::pref!("gfx.webrender.blob.min-parallel-cost") as u64,
+            low_priority_on_main_pool: static_prefs::pref!("gfx.webrender.blob.low-priority-on-main-pool"),
+            split_across_pools: static_prefs::pref!("gfx.webrender.blob.split-across-pools"),
+            record_timings: static_prefs::pref!("gfx.webrender.blob.raster-timings"),
+        }
+    }
+
+    unsafe extern "C" fn blob_replay_fallback() -> BlobReplayFallback {
+        if static_prefs::pref!("gfx.webrender.blob.failure-checkerboard") {
+            BlobReplayFallback::Checkerboard
+        } else {
+            BlobReplayFallback::Error
+        }
+    }
+
+    unsafe extern "C" fn blob_tile_cache_size() -> usize {
+        static_prefs::pref!("gfx.webrender.blob.tile-cache-size-kb") as usize * 1024
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    unsafe extern "C" fn yes() -> bool {
+        true
+    }
+
+    unsafe extern "C" fn is_gles(_gl_context: *mut c_void) -> bool {
+        true
+    }
+
+    #[test]
+    fn missing_table_entries_use_the_defaults() {
+        let table = WrHostCallbacks {
+            is_in_render_thread: Some(yes),
+            is_glcontext_gles: Some(is_gles),
+            ..Default::default()
+        };
+        assert_eq!(table.is_in_render_thread(), Some(true));
+        assert_eq!(table.is_in_main_thread(), None);
+        assert!(table.is_glcontext_gles(ptr::null_mut()));
+        assert!(!table.is_glcontext_angle(ptr::null_mut()));
+        assert!(table.use_optimized_shaders());
+        assert_eq!(table.resource_path_override(), None);
+        assert_eq!(table.blob_replay_fallback(), BlobReplayFallback::Error);
+        assert_eq!(table.blob_tile_cache_size(), 0);
+        assert_eq!(table.blob_recording_dir(), None);
+
+        // Without registered callbacks, thread checks pass.
+        assert!(is_in_main_thread() && is_in_render_thread() && is_off_render_thread());
+    }
+
+    #[test]
+    fn callbacks_cant_be_replaced_once_used() {
+        host().critical_note("using the builtin callbacks");
+        assert!(!set_host_callbacks(Box::new(DefaultHostCallbacks)));
+        assert!(!wr_register_host_callbacks(&WrHostCallbacks::default()));
+    }
+
+    static NOTES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    unsafe extern "C" fn note(msg: *const c_char) {
+        NOTES.lock().unwrap().push(CStr::from_ptr(msg).to_str().unwrap().to_owned());
+    }
+
+    #[test]
+    fn critical_notes_keep_nul_bytes_visible() {
+        let table = WrHostCallbacks {
+            gfx_critical_note: Some(note),
+            ..Default::default()
+        };
+        table.critical_note("bad font name: a\0b");
+        assert_eq!(*NOTES.lock().unwrap(), ["bad font name: a\u{FFFD}b"]);
+    }
+}
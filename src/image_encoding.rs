@@ -0,0 +1,408 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Encoding of raw frames, such as async screenshots and frames recorded for the
+//! composition recorder, into PNG or QOI images.
+//!
+//! Frames are converted to RGBA8, optionally downscaled to fit a maximum size with a
+//! box filter, and optionally converted to straight alpha, before being encoded.
+//! `EncodeTask` does all of this on a thread pool, off the render thread.
+
+use std::error;
+use std::fmt;
+use std::sync::mpsc;
+
+use rayon::ThreadPool;
+use webrender::api::units::DeviceIntSize;
+use webrender::api::ImageFormat;
+
+/// The formats frames can be encoded to.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageEncoding {
+    /// PNG, compressed with the default settings.
+    Png,
+    /// The Quite OK Image format, which is much faster to encode than PNG, for
+    /// consumers that can decode it.
+    Qoi,
+}
+
+/// How to encode a frame.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EncodeOptions {
+    /// The format to encode to.
+    pub encoding: ImageEncoding,
+    /// The maximum width of the image, or 0 for no maximum. Wider frames are
+    /// downscaled, keeping their aspect ratio.
+    pub max_width: i32,
+    /// The maximum height of the image, or 0 for no maximum.
+    pub max_height: i32,
+    /// Whether to convert premultiplied colors to straight alpha, as most image
+    /// viewers expect.
+    pub unpremultiply: bool,
+}
+
+/// A frame to encode.
+#[derive(Clone, Debug)]
+pub struct RawFrame {
+    /// The size of the frame, in pixels.
+    pub size: DeviceIntSize,
+    /// The format of the pixels. Only BGRA8 and RGBA8 can be encoded.
+    pub format: ImageFormat,
+    /// The number of bytes from one row to the next.
+    pub stride: usize,
+    /// The pixels, row by row from the top.
+    pub pixels: Vec<u8>,
+}
+
+/// The ways in which encoding a frame can fail.
+#[derive(Debug)]
+pub enum EncodeError {
+    /// The pixels of the frame aren't BGRA8 or RGBA8.
+    UnsupportedFormat(ImageFormat),
+    /// There are fewer pixels than the size and stride of the frame require.
+    TruncatedFrame,
+    /// The frame couldn't be encoded as a PNG.
+    Png(png::EncodingError),
+    /// The encoding task stopped without a result.
+    Cancelled,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::UnsupportedFormat(format) => write!(f, "can't encode {:?} frames", format),
+            EncodeError::TruncatedFrame => write!(f, "the frame is missing pixels"),
+            EncodeError::Png(ref err) => write!(f, "failed to encode a PNG: {}", err),
+            EncodeError::Cancelled => write!(f, "the encoding was cancelled"),
+        }
+    }
+}
+
+impl error::Error for EncodeError {}
+
+/// Converts the rows of a frame to tightly packed RGBA8 pixels.
+fn to_rgba(frame: &RawFrame) -> Result<Vec<u8>, EncodeError> {
+    let swap_red_blue = match frame.format {
+        ImageFormat::BGRA8 => true,
+        ImageFormat::RGBA8 => false,
+        format => return Err(EncodeError::UnsupportedFormat(format)),
+    };
+    let row_size = frame.size.width as usize * 4;
+    let height = frame.size.height as usize;
+    if height > 0 && (frame.stride < row_size || frame.pixels.len() < frame.stride * (height - 1) + row_size) {
+        return Err(EncodeError::TruncatedFrame);
+    }
+
+    let mut pixels = Vec::with_capacity(row_size * height);
+    for y in 0..height {
+        let row = &frame.pixels[y * frame.stride..][..row_size];
+        if swap_red_blue {
+            for pixel in row.chunks(4) {
+                pixels.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+            }
+        } else {
+            pixels.extend_from_slice(row);
+        }
+    }
+    Ok(pixels)
+}
+
+/// Returns the size a frame is downscaled to, to fit the maximum size of the options.
+fn scaled_size(size: DeviceIntSize, options: &EncodeOptions) -> DeviceIntSize {
+    let mut scale = 1.0f64;
+    if options.max_width > 0 && size.width > options.max_width {
+        scale = scale.min(options.max_width as f64 / size.width as f64);
+    }
+    if options.max_height > 0 && size.height > options.max_height {
+        scale = scale.min(options.max_height as f64 / size.height as f64);
+    }
+    DeviceIntSize::new(
+        ((size.width as f64 * scale).round() as i32).max(1).min(size.width),
+        ((size.height as f64 * scale).round() as i32).max(1).min(size.height),
+    )
+}
+
+/// Downscales RGBA8 pixels, averaging the pixels each destination pixel covers.
+fn downscale(pixels: &[u8], size: DeviceIntSize, scaled: DeviceIntSize) -> Vec<u8> {
+    let (width, height) = (size.width as usize, size.height as usize);
+    let (scaled_width, scaled_height) = (scaled.width as usize, scaled.height as usize);
+    let mut result = Vec::with_capacity(scaled_width * scaled_height * 4);
+    for y in 0..scaled_height {
+        let y0 = y * height / scaled_height;
+        let y1 = ((y + 1) * height / scaled_height).max(y0 + 1);
+        for x in 0..scaled_width {
+            let x0 = x * width / scaled_width;
+            let x1 = ((x + 1) * width / scaled_width).max(x0 + 1);
+            let mut sum = [0u32; 4];
+            for src_y in y0..y1 {
+                for pixel in pixels[(src_y * width + x0) * 4..(src_y * width + x1) * 4].chunks(4) {
+                    for (sum, &channel) in sum.iter_mut().zip(pixel) {
+                        *sum += channel as u32;
+                    }
+                }
+            }
+            let count = ((y1 - y0) * (x1 - x0)) as u32;
+            result.extend(sum.iter().map(|sum| ((sum + count / 2) / count) as u8));
+        }
+    }
+    result
+}
+
+/// Premultiplies RGBA8 pixels with straight alpha.
+pub(crate) fn premultiply(pixels: &mut [u8]) {
+    for pixel in pixels.chunks_mut(4) {
+        let alpha = pixel[3] as u32;
+        for channel in &mut pixel[..3] {
+            *channel = ((*channel as u32 * alpha + 127) / 255) as u8;
+        }
+    }
+}
+
+/// Converts premultiplied RGBA8 pixels to straight alpha.
+pub(crate) fn unpremultiply(pixels: &mut [u8]) {
+    for pixel in pixels.chunks_mut(4) {
+        let alpha = pixel[3] as u32;
+        if alpha == 0 {
+            continue;
+        }
+        for channel in &mut pixel[..3] {
+            *channel = ((*channel as u32 * 255 + alpha / 2) / alpha).min(255) as u8;
+        }
+    }
+}
+
+/// Encodes tightly packed RGBA8 pixels as a PNG.
+pub(crate) fn encode_png(size: DeviceIntSize, pixels: &[u8]) -> Result<Vec<u8>, png::EncodingError> {
+    let mut data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut data, size.width as u32, size.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(pixels)?;
+        writer.finish()?;
+    }
+    Ok(data)
+}
+
+/// Encodes tightly packed RGBA8 pixels as a QOI image, following
+/// https://qoiformat.org/qoi-specification.pdf.
+fn encode_qoi(size: DeviceIntSize, pixels: &[u8]) -> Vec<u8> {
+    const QOI_OP_DIFF: u8 = 0x40;
+    const QOI_OP_LUMA: u8 = 0x80;
+    const QOI_OP_RUN: u8 = 0xc0;
+    const QOI_OP_RGB: u8 = 0xfe;
+    const QOI_OP_RGBA: u8 = 0xff;
+    const MAX_RUN: u8 = 62;
+
+    let mut data = Vec::with_capacity(14 + pixels.len() + 8);
+    data.extend_from_slice(b"qoif");
+    data.extend_from_slice(&(size.width as u32).to_be_bytes());
+    data.extend_from_slice(&(size.height as u32).to_be_bytes());
+    // Four channels, in sRGB with linear alpha.
+    data.extend_from_slice(&[4, 0]);
+
+    let mut index = [[0u8; 4]; 64];
+    let mut previous = [0, 0, 0, 255];
+    let mut run = 0;
+    let num_pixels = pixels.len() / 4;
+    for (i, pixel) in pixels.chunks(4).enumerate() {
+        let pixel = [pixel[0], pixel[1], pixel[2], pixel[3]];
+        if pixel == previous {
+            run += 1;
+            if run == MAX_RUN || i + 1 == num_pixels {
+                data.push(QOI_OP_RUN | (run - 1));
+                run = 0;
+            }
+            continue;
+        }
+        if run > 0 {
+            data.push(QOI_OP_RUN | (run - 1));
+            run = 0;
+        }
+
+        let hash =
+            (pixel[0] as usize * 3 + pixel[1] as usize * 5 + pixel[2] as usize * 7 + pixel[3] as usize * 11) % 64;
+        if index[hash] == pixel {
+            data.push(hash as u8);
+        } else {
+            index[hash] = pixel;
+            if pixel[3] == previous[3] {
+                let dr = pixel[0].wrapping_sub(previous[0]) as i8;
+                let dg = pixel[1].wrapping_sub(previous[1]) as i8;
+                let db = pixel[2].wrapping_sub(previous[2]) as i8;
+                let dr_dg = dr.wrapping_sub(dg);
+                let db_dg = db.wrapping_sub(dg);
+                if (-2..2).contains(&dr) && (-2..2).contains(&dg) && (-2..2).contains(&db) {
+                    data.push(QOI_OP_DIFF | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8);
+                } else if (-8..8).contains(&dr_dg) && (-32..32).contains(&dg) && (-8..8).contains(&db_dg) {
+                    data.push(QOI_OP_LUMA | (dg + 32) as u8);
+                    data.push(((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8);
+                } else {
+                    data.extend_from_slice(&[QOI_OP_RGB, pixel[0], pixel[1], pixel[2]]);
+                }
+            } else {
+                data.extend_from_slice(&[QOI_OP_RGBA, pixel[0], pixel[1], pixel[2], pixel[3]]);
+            }
+        }
+        previous = pixel;
+    }
+    data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+    data
+}
+
+/// Encodes a frame.
+pub fn encode_frame(frame: &RawFrame, options: &EncodeOptions) -> Result<Vec<u8>, EncodeError> {
+    let mut pixels = to_rgba(frame)?;
+    let size = scaled_size(frame.size, options);
+    if size != frame.size {
+        // Premultiplied colors are averaged correctly.
+        pixels = downscale(&pixels, frame.size, size);
+    }
+    if options.unpremultiply {
+        unpremultiply(&mut pixels);
+    }
+    match options.encoding {
+        ImageEncoding::Png => encode_png(size, &pixels).map_err(EncodeError::Png),
+        ImageEncoding::Qoi => Ok(encode_qoi(size, &pixels)),
+    }
+}
+
+/// The state of an `EncodeTask`, as polled from C.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncodeStatus {
+    /// The image isn't encoded yet.
+    Pending,
+    /// The image was encoded.
+    Finished,
+    /// The image couldn't be encoded.
+    Failed,
+}
+
+/// A frame being encoded on a thread pool.
+pub struct EncodeTask {
+    result: mpsc::Receiver<Result<Vec<u8>, EncodeError>>,
+}
+
+impl EncodeTask {
+    /// Starts encoding a frame on the given thread pool.
+    pub fn spawn(workers: &ThreadPool, frame: RawFrame, options: EncodeOptions) -> EncodeTask {
+        let (sender, result) = mpsc::channel();
+        workers.spawn(move || {
+            // The task may have been dropped.
+            let _ = sender.send(encode_frame(&frame, &options));
+        });
+        EncodeTask { result }
+    }
+
+    /// Waits for the encoded image.
+    pub fn wait(self) -> Result<Vec<u8>, EncodeError> {
+        self.result.recv().unwrap_or(Err(EncodeError::Cancelled))
+    }
+
+    /// Returns the encoded image if it is ready, without waiting for it. It can only be
+    /// taken once, after which the task is cancelled.
+    pub fn try_take(&mut self) -> Option<Result<Vec<u8>, EncodeError>> {
+        match self.result.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(EncodeError::Cancelled)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QOI_END: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+
+    fn options(encoding: ImageEncoding) -> EncodeOptions {
+        EncodeOptions {
+            encoding,
+            max_width: 0,
+            max_height: 0,
+            unpremultiply: false,
+        }
+    }
+
+    #[test]
+    fn qoi_uses_diffs_runs_and_the_index() {
+        let frame = RawFrame {
+            size: DeviceIntSize::new(4, 1),
+            format: ImageFormat::RGBA8,
+            stride: 16,
+            pixels: vec![1, 0, 0, 255, 1, 0, 0, 255, 0, 0, 0, 255, 1, 0, 0, 255],
+        };
+        let data = encode_frame(&frame, &options(ImageEncoding::Qoi)).unwrap();
+        assert_eq!(&data[..14], b"qoif\0\0\0\x04\0\0\0\x01\x04\0");
+        // A diff from the initial black, a run of one, a diff back to black, and the
+        // index of the first pixel.
+        assert_eq!(&data[14..data.len() - 8], &[0x7a, 0xc0, 0x5a, 56]);
+        assert_eq!(&data[data.len() - 8..], &QOI_END);
+    }
+
+    #[test]
+    fn frames_are_converted_and_downscaled() {
+        // Two rows of two premultiplied BGRA8 pixels, with padding after each row.
+        let frame = RawFrame {
+            size: DeviceIntSize::new(2, 2),
+            format: ImageFormat::BGRA8,
+            stride: 12,
+            pixels: vec![
+                0, 0, 128, 128, 0, 0, 128, 128, 9, 9, 9, 9, //
+                0, 0, 0, 0, 0, 0, 0, 0, 9, 9, 9, 9,
+            ],
+        };
+        let options = EncodeOptions {
+            max_width: 1,
+            unpremultiply: true,
+            ..options(ImageEncoding::Png)
+        };
+        let data = encode_frame(&frame, &options).unwrap();
+
+        let mut reader = png::Decoder::new(&data[..]).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (1, 1));
+        // Half transparent red, averaged with transparent pixels.
+        assert_eq!(&pixels[..4], &[255, 0, 0, 64]);
+    }
+
+    #[test]
+    fn premultiplied_alpha_round_trips() {
+        let mut pixels = [255, 128, 0, 128, 10, 20, 30, 0, 7, 8, 9, 255];
+        premultiply(&mut pixels);
+        assert_eq!(pixels, [128, 64, 0, 128, 0, 0, 0, 0, 7, 8, 9, 255]);
+        unpremultiply(&mut pixels);
+        assert_eq!(pixels, [255, 128, 0, 128, 0, 0, 0, 0, 7, 8, 9, 255]);
+    }
+
+    #[test]
+    fn tasks_can_be_polled() {
+        let workers = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let frame = RawFrame {
+            size: DeviceIntSize::new(1, 1),
+            format: ImageFormat::RGBA8,
+            stride: 4,
+            pixels: vec![1, 2, 3, 255],
+        };
+        let mut task = EncodeTask::spawn(&workers, frame, options(ImageEncoding::Qoi));
+        let data = loop {
+            match task.try_take() {
+                Some(result) => break result.unwrap(),
+                None => std::thread::yield_now(),
+            }
+        };
+        assert_eq!(&data[..4], b"qoif");
+        // The image was taken.
+        match task.try_take() {
+            Some(Err(EncodeError::Cancelled)) => {},
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -8,11 +8,12 @@ extern crate app_units;
 extern crate bincode;
 extern crate euclid;
 extern crate fxhash;
-extern crate gecko_profiler;
 extern crate gleam;
-extern crate nsstring;
+extern crate memmap2;
 extern crate num_cpus;
+extern crate png;
 extern crate rayon;
+extern crate serde;
 extern crate swgl;
 extern crate thin_vec;
 extern crate tracy_rs;
@@ -20,6 +21,14 @@ extern crate uuid;
 extern crate webrender;
 extern crate wr_malloc_size_of;
 
+#[cfg(feature = "gecko")]
+extern crate gecko_profiler;
+#[cfg(feature = "gecko")]
+extern crate nsstring;
+
+#[cfg(not(any(test, feature = "gecko", feature = "standalone")))]
+compile_error!("webrender_bindings needs either the gecko or the standalone feature");
+
 #[macro_use]
 extern crate log;
 
@@ -35,9 +44,18 @@ extern crate core_graphics;
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 extern crate foreign_types;
 
-mod program_cache;
+pub mod program_cache;
 
+pub mod api;
 #[allow(non_snake_case)]
 pub mod bindings;
+pub mod host;
+pub mod image_encoding;
+pub mod memory_compositor;
 pub mod moz2d_renderer;
+pub mod reftest;
 mod swgl_bindings;
+
+/// cbindgen:ignore
+#[cfg(any(test, all(feature = "fuzzing", not(feature = "gecko"))))]
+mod gecko_stubs;
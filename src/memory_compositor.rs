@@ -0,0 +1,617 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A native compositor that keeps its surfaces in memory.
+//!
+//! It stands in for the OS compositors behind `WrCompositor` (DirectComposition,
+//! CoreAnimation and Wayland): WebRender renders picture cache tiles into its
+//! buffers with SWGL, and it composites the surfaces added to a frame into an
+//! output buffer the way `RenderCompositorNative` lays out native layers. Comparing
+//! its output with that of the draw compositor validates the native-compositor
+//! paths without a window system.
+//!
+//! External surfaces aren't supported, and are composited as nothing.
+
+use std::collections::HashMap;
+use std::os::raw::c_void;
+use std::sync::{Arc, Mutex};
+
+use api::RgbaFrame;
+use gleam::gl::{self, Gl};
+use webrender::api::units::*;
+use webrender::api::{ColorF, ExternalImageId, ImageRendering};
+use webrender::{
+    Compositor, CompositorCapabilities, CompositorSurfaceTransform, Device, MappableCompositor, MappedTileInfo,
+    NativeSurfaceId, NativeSurfaceInfo, NativeTileId, SWGLCompositeSurfaceInfo, WindowVisibility,
+};
+
+/// A pixel buffer with a SWGL texture using it as storage.
+struct Buffer {
+    size: DeviceIntSize,
+    pixels: Vec<u32>,
+    texture: u32,
+}
+
+impl Buffer {
+    fn new(context: swgl::Context, size: DeviceIntSize) -> Buffer {
+        let mut pixels = vec![0; size.area().max(0) as usize];
+        let texture = context.gen_textures(1)[0];
+        // The buffer is never resized, so the texture can keep pointing into it.
+        context.set_texture_buffer(
+            texture,
+            gl::RGBA8,
+            size.width,
+            size.height,
+            size.width * 4,
+            pixels.as_mut_ptr() as *mut c_void,
+            size.width,
+            size.height,
+        );
+        Buffer { size, pixels, texture }
+    }
+
+    fn delete(self, context: swgl::Context) {
+        context.delete_textures(&[self.texture]);
+    }
+}
+
+struct Tile {
+    buffer: Buffer,
+    // The part of the tile that has content, in tile space.
+    valid_rect: DeviceIntRect,
+}
+
+enum SurfaceContent {
+    Tiles {
+        tile_size: DeviceIntSize,
+        tiles: HashMap<(i32, i32), Tile>,
+    },
+    External,
+    Backdrop(ColorF),
+}
+
+struct Surface {
+    content: SurfaceContent,
+    is_opaque: bool,
+}
+
+/// A surface added to the frame being composited.
+struct Layer {
+    id: NativeSurfaceId,
+    transform: CompositorSurfaceTransform,
+    clip_rect: DeviceIntRect,
+    image_rendering: ImageRendering,
+}
+
+/// The output of a `MemoryCompositor`, which can be read from other threads.
+#[derive(Clone)]
+pub struct MemoryCompositorOutput {
+    size: DeviceIntSize,
+    // BGRA8 pixels from the top row, locked while a frame is composited.
+    pixels: Arc<Mutex<Buffer>>,
+}
+
+impl MemoryCompositorOutput {
+    /// Returns the last composited frame.
+    pub fn read(&self) -> RgbaFrame {
+        RgbaFrame::from_bgra(self.size, &self.pixels.lock().unwrap().pixels)
+    }
+}
+
+/// A native compositor which composites in memory with SWGL.
+pub struct MemoryCompositor {
+    context: swgl::Context,
+    output: MemoryCompositorOutput,
+    surfaces: HashMap<NativeSurfaceId, Surface>,
+    layers: Vec<Layer>,
+    clear_color: ColorF,
+    dirty_rects: Vec<DeviceIntRect>,
+    opaque_rects: Vec<DeviceIntRect>,
+}
+
+impl MemoryCompositor {
+    /// Creates a compositor which composites frames of the given size with the given
+    /// context.
+    pub fn new(context: swgl::Context, size: DeviceIntSize) -> MemoryCompositor {
+        let output = MemoryCompositorOutput {
+            size,
+            pixels: Arc::new(Mutex::new(Buffer::new(context, size))),
+        };
+        MemoryCompositor {
+            context,
+            output,
+            surfaces: HashMap::new(),
+            layers: Vec::new(),
+            clear_color: ColorF::TRANSPARENT,
+            dirty_rects: Vec::new(),
+            opaque_rects: Vec::new(),
+        }
+    }
+
+    /// Returns a handle to read the composited frames.
+    pub fn output(&self) -> MemoryCompositorOutput {
+        self.output.clone()
+    }
+
+    fn tile_mut(&mut self, id: NativeTileId) -> Option<&mut Tile> {
+        match self.surfaces.get_mut(&id.surface_id)?.content {
+            SurfaceContent::Tiles { ref mut tiles, .. } => tiles.get_mut(&(id.x, id.y)),
+            _ => None,
+        }
+    }
+
+    /// Composites the layers added since `begin_frame` into the output.
+    fn composite(&self) {
+        let mut output = self.output.pixels.lock().unwrap();
+        let bounds = DeviceIntRect::from_size(self.output.size);
+        // Without dirty rects, the whole frame is composited.
+        let dirty_rects = if self.dirty_rects.is_empty() {
+            vec![bounds]
+        } else {
+            self.dirty_rects
+                .iter()
+                .filter_map(|rect| rect.intersection(&bounds))
+                .collect()
+        };
+
+        // Opaque layers cover the opaque rects, so they don't need clearing.
+        let clear_color = to_bgra(self.clear_color.premultiplied());
+        for dirty_rect in &dirty_rects {
+            for rect in subtract_rects(*dirty_rect, &self.opaque_rects) {
+                fill_rect(&mut output.pixels, self.output.size, &rect, clear_color, false);
+            }
+        }
+
+        let locked_output = match self.context.lock_texture(output.texture) {
+            Some(locked) => locked,
+            None => return,
+        };
+        for layer in &self.layers {
+            let surface = match self.surfaces.get(&layer.id) {
+                Some(surface) => surface,
+                None => continue,
+            };
+            for dirty_rect in &dirty_rects {
+                let clip_rect = match layer.clip_rect.intersection(dirty_rect) {
+                    Some(rect) => rect,
+                    None => continue,
+                };
+                match surface.content {
+                    SurfaceContent::Tiles { tile_size, ref tiles } => {
+                        for (&(x, y), tile) in tiles {
+                            if tile.valid_rect.is_empty() {
+                                continue;
+                            }
+                            let locked_tile = match self.context.lock_texture(tile.buffer.texture) {
+                                Some(locked) => locked,
+                                None => continue,
+                            };
+                            // Tiles are laid out from the origin of the surface.
+                            let origin = DeviceIntVector::new(tile_size.width * x, tile_size.height * y);
+                            let (dest, flip_x, flip_y) =
+                                transform_rect(&layer.transform, &tile.valid_rect.translate(origin));
+                            let src = tile.valid_rect;
+                            let filter = match layer.image_rendering {
+                                ImageRendering::Auto => gl::LINEAR,
+                                _ => gl::NEAREST,
+                            };
+                            locked_output.composite(
+                                &locked_tile,
+                                src.min.x,
+                                src.min.y,
+                                src.width(),
+                                src.height(),
+                                dest.min.x,
+                                dest.min.y,
+                                dest.width(),
+                                dest.height(),
+                                surface.is_opaque,
+                                flip_x,
+                                flip_y,
+                                filter,
+                                clip_rect.min.x,
+                                clip_rect.min.y,
+                                clip_rect.width(),
+                                clip_rect.height(),
+                            );
+                        }
+                    },
+                    SurfaceContent::Backdrop(color) => {
+                        // Backdrops fill their whole clip rect.
+                        let color = to_bgra(color.premultiplied());
+                        fill_rect(&mut output.pixels, self.output.size, &clip_rect, color, true);
+                    },
+                    SurfaceContent::External => {},
+                }
+            }
+        }
+    }
+}
+
+/// Maps a rect through a surface transform, rounding it out to device pixels, and
+/// returns whether it is flipped horizontally and vertically.
+fn transform_rect(transform: &CompositorSurfaceTransform, rect: &DeviceIntRect) -> (DeviceIntRect, bool, bool) {
+    let map = |value: i32, scale: f32, offset: f32| value as f32 * scale + offset;
+    let x0 = map(rect.min.x, transform.scale.x, transform.offset.x);
+    let x1 = map(rect.max.x, transform.scale.x, transform.offset.x);
+    let y0 = map(rect.min.y, transform.scale.y, transform.offset.y);
+    let y1 = map(rect.max.y, transform.scale.y, transform.offset.y);
+    let dest = DeviceIntRect::new(
+        DeviceIntPoint::new(x0.min(x1).round() as i32, y0.min(y1).round() as i32),
+        DeviceIntPoint::new(x0.max(x1).round() as i32, y0.max(y1).round() as i32),
+    );
+    (dest, x1 < x0, y1 < y0)
+}
+
+/// Converts a premultiplied color to a BGRA8 pixel.
+fn to_bgra(color: ColorF) -> u32 {
+    let [r, g, b, a] = color.to_array();
+    let channel = |value: f32| (value.max(0.0).min(1.0) * 255.0).round() as u32;
+    channel(a) << 24 | channel(r) << 16 | channel(g) << 8 | channel(b)
+}
+
+/// Returns the parts of a rect that none of `holes` cover.
+fn subtract_rects(rect: DeviceIntRect, holes: &[DeviceIntRect]) -> Vec<DeviceIntRect> {
+    let mut parts = vec![rect];
+    for hole in holes {
+        let mut remaining = Vec::with_capacity(parts.len());
+        for part in parts {
+            let hole = match part.intersection(hole) {
+                Some(hole) => hole,
+                None => {
+                    remaining.push(part);
+                    continue;
+                },
+            };
+            // What is above and below the hole, then what is on its sides.
+            let pieces = [
+                DeviceIntRect::new(part.min, DeviceIntPoint::new(part.max.x, hole.min.y)),
+                DeviceIntRect::new(DeviceIntPoint::new(part.min.x, hole.max.y), part.max),
+                DeviceIntRect::new(
+                    DeviceIntPoint::new(part.min.x, hole.min.y),
+                    DeviceIntPoint::new(hole.min.x, hole.max.y),
+                ),
+                DeviceIntRect::new(
+                    DeviceIntPoint::new(hole.max.x, hole.min.y),
+                    DeviceIntPoint::new(part.max.x, hole.max.y),
+                ),
+            ];
+            remaining.extend(pieces.iter().filter(|piece| !piece.is_empty()));
+        }
+        parts = remaining;
+    }
+    parts
+}
+
+/// Fills a rect of a buffer with a premultiplied BGRA8 color, blending it over what is
+/// there if `blend` is set.
+fn fill_rect(pixels: &mut [u32], size: DeviceIntSize, rect: &DeviceIntRect, color: u32, blend: bool) {
+    let rect = match rect.intersection(&DeviceIntRect::from_size(size)) {
+        Some(rect) => rect,
+        None => return,
+    };
+    let inv_alpha = if blend { 255 - (color >> 24) } else { 0 };
+    for y in rect.min.y..rect.max.y {
+        for x in rect.min.x..rect.max.x {
+            let pixel = &mut pixels[(y * size.width + x) as usize];
+            *pixel = if inv_alpha == 0 {
+                color
+            } else {
+                let mut blended = 0;
+                for shift in &[0, 8, 16, 24] {
+                    let src = (color >> shift) & 0xff;
+                    let dst = (*pixel >> shift) & 0xff;
+                    blended |= (src + (dst * inv_alpha + 127) / 255).min(255) << shift;
+                }
+                blended
+            };
+        }
+    }
+}
+
+impl Compositor for MemoryCompositor {
+    fn create_surface(
+        &mut self,
+        _device: &mut Device,
+        id: NativeSurfaceId,
+        _virtual_offset: DeviceIntPoint,
+        tile_size: DeviceIntSize,
+        is_opaque: bool,
+    ) {
+        let content = SurfaceContent::Tiles {
+            tile_size,
+            tiles: HashMap::new(),
+        };
+        self.surfaces.insert(id, Surface { content, is_opaque });
+    }
+
+    fn create_external_surface(&mut self, _device: &mut Device, id: NativeSurfaceId, is_opaque: bool) {
+        let content = SurfaceContent::External;
+        self.surfaces.insert(id, Surface { content, is_opaque });
+    }
+
+    fn create_backdrop_surface(&mut self, _device: &mut Device, id: NativeSurfaceId, color: ColorF) {
+        let content = SurfaceContent::Backdrop(color);
+        let is_opaque = color.a >= 1.0;
+        self.surfaces.insert(id, Surface { content, is_opaque });
+    }
+
+    fn destroy_surface(&mut self, _device: &mut Device, id: NativeSurfaceId) {
+        if let Some(Surface {
+            content: SurfaceContent::Tiles { tiles, .. },
+            ..
+        }) = self.surfaces.remove(&id)
+        {
+            for (_, tile) in tiles {
+                tile.buffer.delete(self.context);
+            }
+        }
+    }
+
+    fn create_tile(&mut self, _device: &mut Device, id: NativeTileId) {
+        let context = self.context;
+        if let Some(&mut Surface {
+            content: SurfaceContent::Tiles {
+                tile_size,
+                ref mut tiles,
+            },
+            ..
+        }) = self.surfaces.get_mut(&id.surface_id)
+        {
+            let tile = Tile {
+                buffer: Buffer::new(context, tile_size),
+                valid_rect: DeviceIntRect::zero(),
+            };
+            if let Some(old) = tiles.insert((id.x, id.y), tile) {
+                old.buffer.delete(context);
+            }
+        }
+    }
+
+    fn destroy_tile(&mut self, _device: &mut Device, id: NativeTileId) {
+        if let Some(&mut Surface {
+            content: SurfaceContent::Tiles { ref mut tiles, .. },
+            ..
+        }) = self.surfaces.get_mut(&id.surface_id)
+        {
+            if let Some(tile) = tiles.remove(&(id.x, id.y)) {
+                tile.buffer.delete(self.context);
+            }
+        }
+    }
+
+    fn attach_external_image(&mut self, _device: &mut Device, _id: NativeSurfaceId, _external_image: ExternalImageId) {}
+
+    fn bind(
+        &mut self,
+        _device: &mut Device,
+        _id: NativeTileId,
+        _dirty_rect: DeviceIntRect,
+        _valid_rect: DeviceIntRect,
+    ) -> NativeSurfaceInfo {
+        // Tiles are rendered to with SWGL through `map_tile`, there are no framebuffers
+        // to bind.
+        warn!("MemoryCompositor tiles can't be bound");
+        NativeSurfaceInfo {
+            origin: DeviceIntPoint::zero(),
+            fbo_id: 0,
+        }
+    }
+
+    fn unbind(&mut self, _device: &mut Device) {}
+
+    fn begin_frame(&mut self, _device: &mut Device) {
+        self.layers.clear();
+    }
+
+    fn add_surface(
+        &mut self,
+        _device: &mut Device,
+        id: NativeSurfaceId,
+        transform: CompositorSurfaceTransform,
+        clip_rect: DeviceIntRect,
+        image_rendering: ImageRendering,
+    ) {
+        self.layers.push(Layer {
+            id,
+            transform,
+            clip_rect,
+            image_rendering,
+        });
+    }
+
+    fn start_compositing(
+        &mut self,
+        _device: &mut Device,
+        clear_color: ColorF,
+        dirty_rects: &[DeviceIntRect],
+        opaque_rects: &[DeviceIntRect],
+    ) {
+        self.clear_color = clear_color;
+        self.dirty_rects = dirty_rects.to_vec();
+        self.opaque_rects = opaque_rects.to_vec();
+    }
+
+    fn end_frame(&mut self, _device: &mut Device) {
+        self.composite();
+    }
+
+    fn enable_native_compositor(&mut self, _device: &mut Device, _enable: bool) {}
+
+    fn deinit(&mut self, device: &mut Device) {
+        let ids: Vec<_> = self.surfaces.keys().cloned().collect();
+        for id in ids {
+            self.destroy_surface(device, id);
+        }
+        let output = self.output.pixels.lock().unwrap();
+        self.context.delete_textures(&[output.texture]);
+    }
+
+    fn get_capabilities(&self, _device: &mut Device) -> CompositorCapabilities {
+        CompositorCapabilities::default()
+    }
+
+    fn get_window_visibility(&self, _device: &mut Device) -> WindowVisibility {
+        WindowVisibility::default()
+    }
+}
+
+impl MappableCompositor for MemoryCompositor {
+    fn map_tile(
+        &mut self,
+        _device: &mut Device,
+        id: NativeTileId,
+        _dirty_rect: DeviceIntRect,
+        valid_rect: DeviceIntRect,
+    ) -> Option<MappedTileInfo> {
+        let tile = self.tile_mut(id)?;
+        let size = tile.buffer.size;
+        if valid_rect.is_empty() || !DeviceIntRect::from_size(size).contains_box(&valid_rect) {
+            return None;
+        }
+        tile.valid_rect = valid_rect;
+        // Like native layers, the data starts at the valid rect.
+        let offset = (valid_rect.min.y * size.width + valid_rect.min.x) as usize;
+        Some(MappedTileInfo {
+            data: tile.buffer.pixels[offset..].as_mut_ptr() as *mut c_void,
+            stride: size.width * 4,
+        })
+    }
+
+    fn unmap_tile(&mut self, _device: &mut Device) {}
+
+    fn lock_composite_surface(
+        &mut self,
+        _device: &mut Device,
+        _ctx: *mut c_void,
+        _external_image_id: ExternalImageId,
+        _composite_info: *mut SWGLCompositeSurfaceInfo,
+    ) -> bool {
+        false
+    }
+
+    fn unlock_composite_surface(
+        &mut self,
+        _device: &mut Device,
+        _ctx: *mut c_void,
+        _external_image_id: ExternalImageId,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use euclid::vec2;
+
+    const GREEN: ColorF = ColorF {
+        r: 0.0,
+        g: 1.0,
+        b: 0.0,
+        a: 1.0,
+    };
+
+    // A compositor of 4x4 frames, whose output starts out green.
+    fn compositor() -> MemoryCompositor {
+        let context = swgl::Context::create();
+        context.make_current();
+        let compositor = MemoryCompositor::new(context, DeviceIntSize::new(4, 4));
+        for pixel in &mut compositor.output.pixels.lock().unwrap().pixels {
+            *pixel = to_bgra(GREEN);
+        }
+        compositor
+    }
+
+    fn rect(x0: i32, y0: i32, x1: i32, y1: i32) -> DeviceIntRect {
+        DeviceIntRect::new(DeviceIntPoint::new(x0, y0), DeviceIntPoint::new(x1, y1))
+    }
+
+    // Returns the pixels of the output, as rows of RGBA8 pixels.
+    fn read(compositor: &MemoryCompositor) -> Vec<Vec<[u8; 4]>> {
+        let frame = compositor.output().read();
+        frame
+            .pixels
+            .chunks(4 * 4)
+            .map(|row| row.chunks(4).map(|pixel| [pixel[0], pixel[1], pixel[2], pixel[3]]).collect())
+            .collect()
+    }
+
+    #[test]
+    fn transform_rect_flips_and_scales() {
+        let transform = CompositorSurfaceTransform {
+            offset: vec2(10.0, 20.0),
+            scale: vec2(2.0, -1.0),
+        };
+        assert_eq!(transform_rect(&transform, &rect(1, 2, 3, 4)), (rect(12, 16, 16, 18), false, true));
+
+        let transform = CompositorSurfaceTransform {
+            offset: vec2(0.0, 0.0),
+            scale: vec2(-1.0, 0.5),
+        };
+        assert_eq!(transform_rect(&transform, &rect(1, 0, 3, 3)), (rect(-3, 0, -1, 2), true, false));
+    }
+
+    #[test]
+    fn backdrops_fill_their_clip_rect() {
+        let mut compositor = compositor();
+        let id = NativeSurfaceId(1);
+        compositor.surfaces.insert(
+            id,
+            Surface {
+                content: SurfaceContent::Backdrop(ColorF::new(1.0, 0.0, 0.0, 0.5)),
+                is_opaque: false,
+            },
+        );
+        compositor.layers.push(Layer {
+            id,
+            transform: CompositorSurfaceTransform::identity(),
+            clip_rect: rect(1, 1, 3, 3),
+            image_rendering: ImageRendering::Auto,
+        });
+        compositor.clear_color = ColorF::new(0.0, 0.0, 1.0, 1.0);
+        compositor.composite();
+
+        let pixels = read(&compositor);
+        assert_eq!(pixels[0][0], [0, 0, 255, 255]);
+        assert_eq!(pixels[3][3], [0, 0, 255, 255]);
+        // Half transparent red, blended over the blue clear color.
+        assert_eq!(pixels[1][1], [128, 0, 127, 255]);
+        assert_eq!(pixels[2][2], [128, 0, 127, 255]);
+        compositor.context.destroy();
+    }
+
+    #[test]
+    fn only_dirty_rects_are_composited() {
+        let mut compositor = compositor();
+        compositor.dirty_rects = vec![rect(0, 0, 2, 4), rect(3, 3, 8, 8)];
+        compositor.composite();
+
+        let pixels = read(&compositor);
+        // The clear color replaces what was there, even though it is transparent.
+        assert_eq!(pixels[0][0], [0, 0, 0, 0]);
+        assert_eq!(pixels[3][1], [0, 0, 0, 0]);
+        assert_eq!(pixels[3][3], [0, 0, 0, 0]);
+        assert_eq!(pixels[0][2], [0, 255, 0, 255]);
+        assert_eq!(pixels[2][3], [0, 255, 0, 255]);
+        compositor.context.destroy();
+    }
+
+    #[test]
+    fn opaque_rects_are_not_cleared() {
+        let mut compositor = compositor();
+        compositor.opaque_rects = vec![rect(1, 1, 3, 3), rect(2, 0, 4, 2)];
+        compositor.composite();
+
+        let pixels = read(&compositor);
+        for (y, row) in pixels.iter().enumerate() {
+            for (x, pixel) in row.iter().enumerate() {
+                let point = DeviceIntPoint::new(x as i32, y as i32);
+                let opaque = compositor.opaque_rects.iter().any(|rect| rect.contains(point));
+                let expected = if opaque { [0, 255, 0, 255] } else { [0, 0, 0, 0] };
+                assert_eq!(*pixel, expected, "at {:?}", point);
+            }
+        }
+        compositor.context.destroy();
+    }
+}
@@ -9,24 +9,36 @@
 //! it also handles merging "partial" blob images (see `merge_blob_images`) and
 //! registering fonts found in the blob (see `prepare_request`).
 
-use bindings::{wr_moz2d_render_cb, ArcVecU8, ByteSlice, MutByteSlice};
+use bindings::{wr_moz2d_render_cb, ArcVecU8, ByteSlice, MutByteSlice, WrBlobItem};
+#[cfg(feature = "gecko")]
 use gecko_profiler::gecko_profiler_label;
+#[cfg(feature = "gecko")]
 use gecko_profiler::auto_profiler_marker_tracing;
+use host::host;
 use rayon::prelude::*;
 use rayon::ThreadPool;
-use webrender::api::units::{BlobDirtyRect, BlobToDeviceTranslation, DeviceIntRect};
+use webrender::api::units::{BlobDirtyRect, BlobToDeviceTranslation, DeviceIntRect, LayoutIntRect};
 use webrender::api::*;
 
 use euclid::point2;
+use std::cmp::Reverse;
 use std::collections::btree_map::BTreeMap;
-use std::collections::hash_map;
-use std::collections::hash_map::HashMap;
+use std::collections::hash_map::{DefaultHasher, HashMap};
 use std::collections::Bound::Included;
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::hash::{Hash, Hasher};
 use std::i32;
+use std::io;
 use std::mem;
 use std::os::raw::c_void;
-use std::ptr;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, Weak};
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows", target_os = "android")))]
+use std::sync::OnceLock;
+use std::time::Instant;
+use wr_malloc_size_of::MallocSizeOfOps;
 
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 use core_foundation::string::CFString;
@@ -48,9 +60,7 @@ macro_rules! dlog {
 
 /// Debug prints a blob's item bounds, indicating whether the bounds are dirty or not.
 fn dump_bounds(blob: &[u8], dirty_rect: DeviceIntRect) {
-    let mut index = BlobReader::new(blob);
-    while index.reader.has_more() {
-        let e = index.read_entry();
+    for e in inspect_blob(blob).unwrap_or_default() {
         dlog!(
             "  {:?} {}",
             e.bounds,
@@ -61,13 +71,11 @@ fn dump_bounds(blob: &[u8], dirty_rect: DeviceIntRect) {
 
 /// Debug prints a blob's metadata.
 fn dump_index(blob: &[u8]) {
-    let mut index = BlobReader::new(blob);
     // we might get an empty result here because sub groups are not tightly bound
     // and we'll sometimes have display items that end up with empty bounds in
     // the blob image.
-    while index.reader.has_more() {
-        let e = index.read_entry();
-        dlog!("result bounds: {} {} {:?}", e.end, e.extra_end, e.bounds);
+    for e in inspect_blob(blob).unwrap_or_default() {
+        dlog!("result bounds: {} {} {:?}", e.data_len, e.extra_len, e.bounds);
     }
 }
 
@@ -75,16 +83,392 @@ fn dump_index(blob: &[u8]) {
 pub struct Moz2dBlobImageHandler {
     workers: Arc<ThreadPool>,
     workers_low_priority: Arc<ThreadPool>,
-    blob_commands: HashMap<BlobImageKey, BlobCommand>,
+    /// The blobs, shared with the rasterizers until the next change. Changing a blob
+    /// only copies the map's pointers, and the blob's own command.
+    blob_commands: Arc<HashMap<BlobImageKey, Arc<BlobCommand>>>,
+    store: ItemStore,
+    /// The number of rasterizers created so far.
+    rasterizer_count: u64,
     enable_multithreading: bool,
+    backend: Arc<dyn BlobRenderBackend>,
+    fonts: Arc<dyn BlobFontRegistry>,
+    fallback: BlobReplayFallback,
+    failures: Arc<ReplayFailures>,
+    tile_cache: Option<Arc<BlobTileCache>>,
+    recorder: Option<Arc<BlobRecorder>>,
+    /// The scheduling policy, or `None` to use the host's, see `set_scheduling_policy`.
+    scheduling_policy: Option<BlobSchedulingPolicy>,
+    /// The timings recorded by the rasterizers, when the policy asks for them.
+    raster_timings: Arc<BlobRasterTimings>,
+    /// The fonts that couldn't be registered, shared with the handlers created by
+    /// `create_similar`.
+    failed_fonts: Arc<FailedBlobFonts>,
+}
+
+/// What to produce for a tile of a blob that can't be replayed.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlobReplayFallback {
+    /// Report an error to WebRender, which leaves the tile out.
+    Error,
+    /// Produce a transparent tile.
+    Transparent,
+    /// Produce a gray checkerboard tile, to make failures easy to spot.
+    Checkerboard,
+}
+
+/// Keeps track of the blobs that failed to replay.
+///
+/// This is shared between the handler and its rasterizers, which run on snapshots of it.
+#[derive(Default)]
+struct ReplayFailures {
+    /// Blobs that failed to replay since they were last added or updated. Their tiles
+    /// aren't replayed again, as they would most likely fail again.
+    quarantined: Mutex<HashSet<BlobImageKey>>,
+    /// The number of replay failures so far.
+    count: AtomicUsize,
+}
+
+impl ReplayFailures {
+    /// Records a replay failure, and quarantines its blob.
+    fn record(&self, key: BlobImageKey) {
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        self.quarantined.lock().unwrap().insert(key);
+        host().critical_note(&format!(
+            "Blob replay failure for {:?} ({} so far), quarantining it",
+            key, count
+        ));
+    }
+
+    /// Gives a blob another chance after it changed.
+    fn release(&self, key: BlobImageKey) {
+        self.quarantined.lock().unwrap().remove(&key);
+    }
+}
+
+/// Identifies the content of a rasterized tile.
+struct TileCacheKey {
+    /// The items that intersect the tile, see `BlobTileItems`. They are hashed by the
+    /// content hash of their payloads, and compared in full, so that tiles are never
+    /// shared between blobs with different content.
+    items: Vec<StoredItem>,
+    /// The area of the blob covered by the tile.
+    render_rect: LayoutIntRect,
+    /// The format of the tile.
+    format: ImageFormat,
+    /// The visible rect of the blob, which tiles are clipped to.
+    visible_rect: DeviceIntRect,
+}
+
+impl TileCacheKey {
+    /// The size of the key in a `BlobTileCache`. The payloads of the items are shared with
+    /// the handlers, so only the list of items is counted.
+    fn size(&self) -> usize {
+        self.items.len() * mem::size_of::<StoredItem>()
+    }
+}
+
+impl PartialEq for TileCacheKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.render_rect == other.render_rect
+            && self.format == other.format
+            && self.visible_rect == other.visible_rect
+            && self.items.len() == other.items.len()
+            && self.items.iter().zip(other.items.iter()).all(|(a, b)| {
+                a.bounds == b.bounds
+                    && a.hash == b.hash
+                    && a.extra_begin == b.extra_begin
+                    && (Arc::ptr_eq(&a.payload, &b.payload) || a.payload == b.payload)
+            })
+    }
+}
+
+impl Eq for TileCacheKey {}
+
+impl Hash for TileCacheKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for item in &self.items {
+            item.bounds.hash(state);
+            item.hash.hash(state);
+        }
+        self.render_rect.hash(state);
+        self.format.hash(state);
+        self.visible_rect.hash(state);
+    }
+}
+
+/// A rasterized tile in the `BlobTileCache`.
+struct CachedTile {
+    /// The pixels of the tile.
+    data: Arc<Vec<u8>>,
+    /// When the tile was last used, in `BlobTileCache` lookups.
+    last_used: u64,
+}
+
+/// The state of a `BlobTileCache`.
+#[derive(Default)]
+struct TileCacheState {
+    /// The cached tiles.
+    tiles: HashMap<Arc<TileCacheKey>, CachedTile>,
+    /// The keys of the cached tiles by when they were last used, oldest first.
+    lru: BTreeMap<u64, Arc<TileCacheKey>>,
+    /// The total size of the cached tiles and of their keys, in bytes.
+    size: usize,
+    /// The number of lookups so far, used to find the least recently used tile.
+    clock: u64,
+}
+
+/// A bounded cache of rasterized blob tiles, keyed by their content.
+///
+/// Tiles that are made of the same items as a tile that was already rasterized are
+/// reused instead of being replayed, whatever their blob image key. This is the case of
+/// an icon used in several places, or of a blob that is deleted and added again.
+///
+/// Only fully rasterized tiles are cached, and the least recently used ones are evicted
+/// once the cache is over its size. The cache can be shared between handlers.
+pub struct BlobTileCache {
+    /// The state of the cache, which is shared between rasterization threads.
+    state: Mutex<TileCacheState>,
+    /// The size the cached tiles may take, in bytes.
+    max_size: usize,
+}
+
+impl BlobTileCache {
+    /// Creates an empty cache, which keeps up to `max_size` bytes of tiles.
+    pub fn new(max_size: usize) -> Self {
+        BlobTileCache {
+            state: Mutex::new(TileCacheState::default()),
+            max_size,
+        }
+    }
+
+    /// Returns the pixels of a tile with the given content, if there is one.
+    fn get(&self, key: &TileCacheKey) -> Option<Arc<Vec<u8>>> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.clock += 1;
+        let tile = state.tiles.get_mut(key)?;
+        let key = state.lru.remove(&tile.last_used).unwrap();
+        tile.last_used = state.clock;
+        state.lru.insert(state.clock, key);
+        Some(Arc::clone(&tile.data))
+    }
+
+    /// Adds a rasterized tile, evicting the least recently used ones if needed.
+    fn insert(&self, key: TileCacheKey, data: Arc<Vec<u8>>) {
+        let key_size = key.size();
+        if data.len() + key_size > self.max_size {
+            return;
+        }
+        let key = Arc::new(key);
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.clock += 1;
+        let last_used = state.clock;
+        state.size += data.len() + key_size;
+        state.lru.insert(last_used, Arc::clone(&key));
+        if let Some(old) = state.tiles.insert(key, CachedTile { data, last_used }) {
+            // The key was kept, so only the old tile is gone.
+            state.lru.remove(&old.last_used);
+            state.size -= old.data.len() + key_size;
+        }
+        while state.size > self.max_size {
+            let oldest = match state.lru.keys().next() {
+                Some(&last_used) => state.lru.remove(&last_used).unwrap(),
+                None => break,
+            };
+            let evicted = state.tiles.remove(&*oldest).unwrap();
+            state.size -= evicted.data.len() + oldest.size();
+        }
+    }
+
+    /// Removes all the tiles.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.tiles.clear();
+        state.lru.clear();
+        state.size = 0;
+    }
+
+    /// Returns the number of cached tiles.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().tiles.len()
+    }
+
+    /// Returns whether the cache has no tiles.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Measures the heap memory used by the cached tiles.
+    ///
+    /// Tiles that are also still in use by WebRender are counted here too, but the item
+    /// payloads of the keys, which the handlers own, are not.
+    pub fn report_memory(&self, ops: &mut MallocSizeOfOps) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .tiles
+            .iter()
+            .map(|(key, tile)| unsafe {
+                // Tiles without items have keys that don't own an allocation.
+                let key_size = if key.items.capacity() > 0 { ops.malloc_size_of(key.items.as_ptr()) } else { 0 };
+                key_size + ops.malloc_size_of(tile.data.as_ptr())
+            })
+            .sum()
+    }
+}
+
+/// The items of a blob that intersect a tile, in drawing order, see
+/// `BlobRenderBackend::render_tile`.
+///
+/// This borrows the items of the blob, so that tiles are replayed without copying them.
+#[derive(Copy, Clone)]
+pub struct BlobTileItems<'a> {
+    items: &'a [StoredItem],
+    render_rect: DeviceIntRect,
+}
+
+impl<'a> BlobTileItems<'a> {
+    fn new(items: &'a [StoredItem], render_rect: &LayoutIntRect) -> Self {
+        BlobTileItems {
+            items,
+            render_rect: render_rect.cast_unit(),
+        }
+    }
+
+    /// Iterates over the items.
+    pub fn iter(&self) -> impl Iterator<Item = BlobItem<'a>> + 'a {
+        self.stored_items().map(|item| BlobItem {
+            bounds: item.bounds,
+            commands: &item.payload[..item.extra_begin],
+            extra_data: &item.payload[item.extra_begin..],
+        })
+    }
+
+    /// Iterates over the stored items.
+    fn stored_items(&self) -> impl Iterator<Item = &'a StoredItem> + 'a {
+        let render_rect = self.render_rect;
+        self.items.iter().filter(move |item| item.bounds.intersects(&render_rect))
+    }
 }
 
-/// Transmute some bytes into a value.
+/// Records the blobs given to a handler in a directory, along with the fonts they use
+/// and the tiles that are requested, so that they can be replayed offline with
+/// `Moz2dBlobImageHandler::replay_recording`.
 ///
-/// FIXME: kill this with fire and/or do a super robust security audit
-unsafe fn convert_from_bytes<T: Copy>(slice: &[u8]) -> T {
-    assert!(mem::size_of::<T>() <= slice.len());
-    ptr::read_unaligned(slice.as_ptr() as *const T)
+/// Every event is written to its own file, named after its sequence number and kind.
+/// Added and updated blobs are recorded as they are received, before being merged.
+/// Fonts and font instances are recorded once, the first time they are used. Native
+/// font handles only make sense on the machine they were recorded on, so only a
+/// placeholder is recorded for them, and their fonts fail to load when replaying.
+pub struct BlobRecorder {
+    /// Where the events are written.
+    dir: PathBuf,
+    state: Mutex<RecorderState>,
+}
+
+#[derive(Default)]
+struct RecorderState {
+    /// The sequence number of the next event.
+    next_event: u64,
+    /// The fonts whose data, or placeholder, was already recorded.
+    recorded_fonts: HashSet<FontKey>,
+    /// The font instances that were already recorded.
+    recorded_font_instances: HashSet<FontInstanceKey>,
+}
+
+impl BlobRecorder {
+    /// Creates a recorder that writes to the given directory, creating it if needed.
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(BlobRecorder {
+            dir,
+            state: Mutex::new(RecorderState::default()),
+        })
+    }
+
+    /// Returns the directory the events are written to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes an event. Failures are logged, as recording is only a debugging aid.
+    fn write(&self, kind: &str, event: bincode::Result<Vec<u8>>) {
+        let sequence = {
+            let mut state = self.state.lock().unwrap();
+            state.next_event += 1;
+            state.next_event - 1
+        };
+        let path = self.dir.join(format!("{:08}-{}.bin", sequence, kind));
+        let written = event
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            .and_then(|event| fs::write(&path, event));
+        if let Err(err) = written {
+            warn!("Failed to record blob event {:?}: {:?}", path, err);
+        }
+    }
+
+    fn record_add(&self, key: BlobImageKey, data: &[u8], visible_rect: &DeviceIntRect, tile_size: TileSize) {
+        self.write("add", bincode::serialize(&(key, visible_rect, tile_size, data)));
+    }
+
+    fn record_update(&self, key: BlobImageKey, data: &[u8], visible_rect: &DeviceIntRect, dirty_rect: &BlobDirtyRect) {
+        self.write("update", bincode::serialize(&(key, visible_rect, dirty_rect, data)));
+    }
+
+    fn record_delete(&self, key: BlobImageKey) {
+        self.write("delete", bincode::serialize(&key));
+    }
+
+    /// Records the data of a font, unless it was already recorded.
+    fn record_font_data(&self, key: FontKey, data: &[u8], index: u32) {
+        if !self.state.lock().unwrap().recorded_fonts.insert(key) {
+            return;
+        }
+        self.write("font-data", bincode::serialize(&(key, data, index)));
+    }
+
+    /// Records a placeholder for a native font, unless it was already recorded.
+    fn record_native_font(&self, key: FontKey) {
+        if !self.state.lock().unwrap().recorded_fonts.insert(key) {
+            return;
+        }
+        self.write("font-native", bincode::serialize(&key));
+    }
+
+    /// Records a font instance, unless it was already recorded.
+    fn record_font_instance(&self, key: FontInstanceKey, instance: &FontInstanceData) {
+        if !self.state.lock().unwrap().recorded_font_instances.insert(key) {
+            return;
+        }
+        self.write(
+            "font-instance",
+            bincode::serialize(&(
+                key,
+                instance.font_key,
+                instance.size,
+                &instance.options,
+                &instance.platform_options,
+                &instance.variations,
+            )),
+        );
+    }
+
+    fn record_raster(&self, requests: &[BlobImageParams]) {
+        let requests: Vec<_> = requests
+            .iter()
+            .map(|params| {
+                (
+                    params.request.key,
+                    params.request.tile,
+                    params.descriptor.rect,
+                    params.descriptor.format,
+                    params.dirty_rect,
+                )
+            })
+            .collect();
+        self.write("raster", bincode::serialize(&requests));
+    }
 }
 
 /// Transmute a value into some bytes.
@@ -96,7 +480,34 @@ fn convert_to_bytes<T>(x: &T) -> &[u8] {
     }
 }
 
-/// A simple helper for deserializing a bunch of transmuted POD data from bytes.
+/// The ways in which a blob image can be malformed.
+///
+/// Blobs come from a content process, so they are validated before being merged or
+/// rasterized rather than trusted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlobError {
+    /// The buffer is too short to hold the offset of the index.
+    TooShort,
+    /// The offset of the index points outside of the buffer.
+    BadIndexOffset,
+    /// The index ends in the middle of an entry.
+    TruncatedIndex,
+    /// An entry's `end` or `extra_end` is before the end of the previous item, or
+    /// past the end of the data.
+    NonMonotonicOffsets,
+    /// An item's extra data is too short for the fonts it declares.
+    TruncatedFonts,
+    /// An entry's bounds have a minimum greater than their maximum.
+    InvertedBounds,
+    /// A partial blob refers to an item that is missing from the blob it is merged into.
+    MissingItem,
+}
+
+/// A simple helper for deserializing a bunch of POD data from bytes.
+///
+/// The values are stored in native endianness, as they were written by the C++
+/// side in the same process. Every read is bounds checked, and yields `None` if the
+/// buffer is too short.
 struct BufReader<'a> {
     /// The buffer to read from.
     buf: &'a [u8],
@@ -110,31 +521,62 @@ impl<'a> BufReader<'a> {
         BufReader { buf, pos: 0 }
     }
 
-    /// Transmute-deserializes a value of type T from the stream.
-    ///
-    /// !!! SUPER DANGEROUS !!!
-    ///
-    /// To limit the scope of this unsafety, please don't call this directly.
-    /// Make a helper method for each whitelisted type.
-    unsafe fn read<T: Copy>(&mut self) -> T {
-        let ret = convert_from_bytes(&self.buf[self.pos..]);
-        self.pos += mem::size_of::<T>();
-        ret
+    /// Reads the next `len` bytes from the stream.
+    fn read_bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
     }
 
-    /// Deserializes a BlobFont.
-    fn read_blob_font(&mut self) -> BlobFont {
-        unsafe { self.read::<BlobFont>() }
+    /// Deserializes a u32.
+    fn read_u32(&mut self) -> Option<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.read_bytes(4)?);
+        Some(u32::from_ne_bytes(bytes))
+    }
+
+    /// Deserializes an i32.
+    fn read_i32(&mut self) -> Option<i32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.read_bytes(4)?);
+        Some(i32::from_ne_bytes(bytes))
+    }
+
+    /// Deserializes a u64.
+    fn read_u64(&mut self) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+        Some(u64::from_ne_bytes(bytes))
+    }
+
+    /// Deserializes a BlobFont, following its `repr(C)` layout.
+    fn read_blob_font(&mut self) -> Option<BlobFont> {
+        let namespace = self.read_u32()?;
+        let key = self.read_u32()?;
+        let scaled_font_ptr = self.read_u64()?;
+        Some(BlobFont {
+            font_instance_key: FontInstanceKey::new(IdNamespace(namespace), key),
+            scaled_font_ptr,
+        })
     }
 
     /// Deserializes a usize.
-    fn read_usize(&mut self) -> usize {
-        unsafe { self.read::<usize>() }
+    fn read_usize(&mut self) -> Option<usize> {
+        let mut bytes = [0; mem::size_of::<usize>()];
+        bytes.copy_from_slice(self.read_bytes(mem::size_of::<usize>())?);
+        Some(usize::from_ne_bytes(bytes))
     }
 
     /// Deserializes a rectangle.
-    fn read_box(&mut self) -> DeviceIntRect {
-        unsafe { self.read::<DeviceIntRect>() }
+    fn read_box(&mut self) -> Option<DeviceIntRect> {
+        let min_x = self.read_i32()?;
+        let min_y = self.read_i32()?;
+        let max_x = self.read_i32()?;
+        let max_y = self.read_i32()?;
+        Some(DeviceIntRect {
+            min: point2(min_x, min_y),
+            max: point2(max_x, max_y),
+        })
     }
 
     /// Returns whether the buffer has more data to deserialize.
@@ -175,11 +617,14 @@ struct BlobReader<'a> {
     reader: BufReader<'a>,
     /// Where the buffer head is.
     begin: usize,
+    /// The length of the items' data, which is also where the index starts.
+    data_len: usize,
 }
 
 /// The metadata for each display item in a blob image (doesn't match the serialized layout).
 ///
 /// See BlobReader above for detailed docs of the blob image format.
+#[derive(Copy, Clone, Debug)]
 struct Entry {
     /// The bounds of the display item.
     bounds: DeviceIntRect,
@@ -193,23 +638,39 @@ struct Entry {
 
 impl<'a> BlobReader<'a> {
     /// Creates a new BlobReader for the given buffer.
-    fn new(buf: &'a [u8]) -> BlobReader<'a> {
+    fn new(buf: &'a [u8]) -> Result<BlobReader<'a>, BlobError> {
         // The offset of the index is at the end of the buffer.
-        let index_offset_pos = buf.len() - mem::size_of::<usize>();
-        assert!(index_offset_pos < buf.len());
-        let index_offset = unsafe { convert_from_bytes::<usize>(&buf[index_offset_pos..]) };
+        let index_offset_pos = buf
+            .len()
+            .checked_sub(mem::size_of::<usize>())
+            .ok_or(BlobError::TooShort)?;
+        let index_offset = BufReader::new(&buf[index_offset_pos..])
+            .read_usize()
+            .ok_or(BlobError::TooShort)?;
+        if index_offset > index_offset_pos {
+            return Err(BlobError::BadIndexOffset);
+        }
 
-        BlobReader {
+        Ok(BlobReader {
             reader: BufReader::new(&buf[index_offset..index_offset_pos]),
             begin: 0,
-        }
+            data_len: index_offset,
+        })
     }
 
     /// Reads the next display item's metadata.
-    fn read_entry(&mut self) -> Entry {
-        let end = self.reader.read_usize();
-        let extra_end = self.reader.read_usize();
-        let bounds = self.reader.read_box();
+    ///
+    /// The returned offsets are guaranteed to be valid, monotonic indices into the data.
+    fn read_entry(&mut self) -> Result<Entry, BlobError> {
+        let end = self.reader.read_usize().ok_or(BlobError::TruncatedIndex)?;
+        let extra_end = self.reader.read_usize().ok_or(BlobError::TruncatedIndex)?;
+        let bounds = self.reader.read_box().ok_or(BlobError::TruncatedIndex)?;
+        if end < self.begin || extra_end < end || extra_end > self.data_len {
+            return Err(BlobError::NonMonotonicOffsets);
+        }
+        if bounds.min.x > bounds.max.x || bounds.min.y > bounds.max.y {
+            return Err(BlobError::InvertedBounds);
+        }
         let ret = Entry {
             begin: self.begin,
             end,
@@ -217,8 +678,140 @@ impl<'a> BlobReader<'a> {
             bounds,
         };
         self.begin = extra_end;
-        ret
+        Ok(ret)
+    }
+}
+
+/// Reads the list of fonts used by an item from its extra data.
+fn read_blob_fonts(extra_data: &[u8]) -> Result<Vec<BlobFont>, BlobError> {
+    // The items of a partial blob that aren't recorded have no extra data at all.
+    if extra_data.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut reader = BufReader::new(extra_data);
+    let font_count = reader.read_usize().ok_or(BlobError::TruncatedFonts)?;
+    (0..font_count)
+        .map(|_| reader.read_blob_font().ok_or(BlobError::TruncatedFonts))
+        .collect()
+}
+
+/// Reads the index of a blob image, checking that the blob is well-formed, so that
+/// merging, font registration and rasterization can rely on it.
+fn read_blob_index(blob: &[u8]) -> Result<Vec<Entry>, BlobError> {
+    let mut index = BlobReader::new(blob)?;
+    let mut entries = Vec::new();
+    while index.reader.has_more() {
+        let e = index.read_entry()?;
+        read_blob_fonts(&blob[e.end..e.extra_end])?;
+        entries.push(e);
+    }
+    Ok(entries)
+}
+
+/// Checks that a blob image is well-formed, see `read_blob_index`.
+#[cfg(any(test, feature = "fuzzing"))]
+fn validate_blob(blob: &[u8]) -> Result<(), BlobError> {
+    read_blob_index(blob).map(|_| ())
+}
+
+/// A display item of a blob image, as seen by a `BlobRenderBackend`.
+pub struct BlobItem<'a> {
+    /// The bounds of the item.
+    pub bounds: DeviceIntRect,
+    /// The recorded drawing commands of the item.
+    pub commands: &'a [u8],
+    /// The extra data of the item, which lists the fonts it uses, see `inspect_blob`.
+    pub extra_data: &'a [u8],
+}
+
+/// Iterates over the display items of a blob image, in drawing order.
+///
+/// The blobs accepted by handlers have been validated, so this yields all of their items.
+pub fn blob_items<'a>(blob: &'a [u8]) -> impl Iterator<Item = BlobItem<'a>> + 'a {
+    let mut index = BlobReader::new(blob).ok();
+    std::iter::from_fn(move || {
+        let index = index.as_mut()?;
+        if !index.reader.has_more() {
+            return None;
+        }
+        let e = index.read_entry().ok()?;
+        Some(BlobItem {
+            bounds: e.bounds,
+            commands: &blob[e.begin..e.end],
+            extra_data: &blob[e.end..e.extra_end],
+        })
+    })
+}
+
+/// An entry of a blob image's index, as reported by `inspect_blob`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlobEntryInfo {
+    /// The bounds of the item.
+    pub bounds: DeviceIntRect,
+    /// The length of the item's recorded drawing commands, in bytes.
+    pub data_len: usize,
+    /// The length of the item's extra data, in bytes.
+    pub extra_len: usize,
+    /// The fonts the item uses, decoded from its extra data.
+    pub fonts: Vec<BlobFont>,
+}
+
+/// Lists the entries of a blob image, in drawing order.
+///
+/// Unlike `blob_items`, this doesn't expect the blob to be valid, and reports why it
+/// isn't.
+pub fn inspect_blob(blob: &[u8]) -> Result<Vec<BlobEntryInfo>, BlobError> {
+    let mut index = BlobReader::new(blob)?;
+    let mut entries = Vec::new();
+    while index.reader.has_more() {
+        let e = index.read_entry()?;
+        entries.push(BlobEntryInfo {
+            bounds: e.bounds,
+            data_len: e.end - e.begin,
+            extra_len: e.extra_end - e.end,
+            fonts: read_blob_fonts(&blob[e.end..e.extra_end])?,
+        });
     }
+    Ok(entries)
+}
+
+/// Describes a blob image as JSON, for tools.
+///
+/// This is either `{"items": [...]}`, with an object per entry as reported by
+/// `inspect_blob`, or `{"error": "..."}` if the blob is malformed.
+pub fn inspect_blob_json(blob: &[u8]) -> String {
+    let entries = match inspect_blob(blob) {
+        Ok(entries) => entries,
+        Err(err) => return format!("{{\"error\":\"{:?}\"}}", err),
+    };
+    let items: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let fonts: Vec<String> = entry
+                .fonts
+                .iter()
+                .map(|font| {
+                    format!(
+                        "{{\"instance_key\":[{},{}],\"scaled_font\":\"{:#x}\"}}",
+                        (font.font_instance_key.0).0,
+                        font.font_instance_key.1,
+                        font.scaled_font_ptr
+                    )
+                })
+                .collect();
+            format!(
+                "{{\"bounds\":[{},{},{},{}],\"data_len\":{},\"extra_len\":{},\"fonts\":[{}]}}",
+                entry.bounds.min.x,
+                entry.bounds.min.y,
+                entry.bounds.max.x,
+                entry.bounds.max.y,
+                entry.data_len,
+                entry.extra_len,
+                fonts.join(",")
+            )
+        })
+        .collect();
+    format!("{{\"items\":[{}]}}", items.join(","))
 }
 
 /// Writes new blob images.
@@ -288,35 +881,39 @@ impl CacheKey {
     }
 }
 
-/// Provides an API for looking up the display items in a blob image by bounds, yielding items
+/// Provides an API for looking up the display items of a list by bounds, yielding items
 /// with equal bounds in their original relative ordering.
 ///
-/// This is used to implement `merge_blobs_images`.
+/// This is used to implement `merge_items`.
 ///
 /// We use a BTree as a kind of multi-map, by appending an integer "cache_order" to the key.
 /// This lets us use multiple items with matching bounds in the map and allows
 /// us to fetch and remove them while retaining the ordering of the original list.
-struct CachedReader<'a> {
-    /// Wrapped reader.
-    reader: BlobReader<'a>,
-    /// Cached entries that have been read but not yet requested by our consumer.
-    cache: BTreeMap<CacheKey, Entry>,
+struct CachedReader<I: Iterator<Item = DeviceIntRect>> {
+    /// The bounds of the items that haven't been read yet, along with their index.
+    items: std::iter::Enumerate<I>,
+    /// Cached items that have been read but not yet requested by our consumer, with
+    /// their index and bounds.
+    cache: BTreeMap<CacheKey, (usize, DeviceIntRect)>,
     /// The current number of internally read display items, used to preserve list order.
     cache_index_counter: u32,
+    /// The union of the bounds of the dirty items that were skipped over.
+    dirty_bounds: DeviceIntRect,
 }
 
-impl<'a> CachedReader<'a> {
-    /// Creates a new CachedReader.
-    pub fn new(buf: &'a [u8]) -> Self {
+impl<I: Iterator<Item = DeviceIntRect>> CachedReader<I> {
+    /// Creates a new CachedReader over the bounds of the items of a list.
+    pub fn new(items: I) -> Self {
         CachedReader {
-            reader: BlobReader::new(buf),
+            items: items.enumerate(),
             cache: BTreeMap::new(),
             cache_index_counter: 0,
+            dirty_bounds: DeviceIntRect::zero(),
         }
     }
 
     /// Tries to find the given bounds in the cache of internally read items, removing it if found.
-    fn take_entry_with_bounds_from_cache(&mut self, bounds: &DeviceIntRect) -> Option<Entry> {
+    fn take_item_with_bounds_from_cache(&mut self, bounds: &DeviceIntRect) -> Option<usize> {
         if self.cache.is_empty() {
             return None;
         }
@@ -333,33 +930,41 @@ impl<'a> CachedReader<'a> {
             None => return None,
         };
 
-        Some(
-            self.cache
-                .remove(&key_to_delete)
-                .expect("We just got this key from range, it needs to be present"),
-        )
+        let (index, _) = self
+            .cache
+            .remove(&key_to_delete)
+            .expect("We just got this key from range, it needs to be present");
+        Some(index)
     }
 
-    /// Yields the next item in the blob image with the given bounds.
+    /// Returns the index of the next item in the list with the given bounds.
     ///
-    /// If the given bounds aren't found in the blob, this panics. `merge_blob_images` should
-    /// avoid this by construction if the blob images are well-formed.
-    pub fn next_entry_with_bounds(&mut self, bounds: &DeviceIntRect, ignore_rect: &DeviceIntRect) -> Entry {
-        if let Some(entry) = self.take_entry_with_bounds_from_cache(bounds) {
-            return entry;
-        }
-
-        loop {
-            // This will panic if we run through the whole list without finding our bounds.
-            let old = self.reader.read_entry();
-            if old.bounds == *bounds {
-                return old;
-            } else if !ignore_rect.contains_box(&old.bounds) {
+    /// Items for which `is_dirty` returns true are skipped, as their recording is never taken
+    /// from this list.
+    ///
+    /// If the given bounds aren't found in the list, this returns `BlobError::MissingItem`.
+    /// `merge_items` avoids this by construction if the lists are consistent.
+    pub fn next_item_with_bounds(
+        &mut self,
+        bounds: &DeviceIntRect,
+        is_dirty: &dyn Fn(&DeviceIntRect) -> bool,
+    ) -> Result<usize, BlobError> {
+        if let Some(index) = self.take_item_with_bounds_from_cache(bounds) {
+            return Ok(index);
+        }
+
+        for (index, old_bounds) in &mut self.items {
+            if old_bounds == *bounds {
+                return Ok(index);
+            } else if is_dirty(&old_bounds) {
+                self.dirty_bounds = union_rects(&self.dirty_bounds, &old_bounds);
+            } else {
                 self.cache
-                    .insert(CacheKey::new(old.bounds, self.cache_index_counter), old);
+                    .insert(CacheKey::new(old_bounds, self.cache_index_counter), (index, old_bounds));
                 self.cache_index_counter += 1;
             }
         }
+        Err(BlobError::MissingItem)
     }
 }
 
@@ -398,14 +1003,29 @@ impl<'a> CachedReader<'a> {
 /// the first not-yet-copied item with those bounds in the old list and copy that.
 /// Any items found in the old list but not the new one can be safely assumed to
 /// have been deleted.
-fn merge_blob_images(
+///
+/// Only the parts of the items that are visible both before and after the update matter
+/// here. Items that are outside of the old or the new visible rect are always recorded
+/// in the new list, so items that scroll into or out of view can be inserted or deleted
+/// without the dirty rect covering them.
+///
+/// Along with the merged blob, this returns the part of the new visible rect that may
+/// have changed: the bounds of the dirty items of both lists, clipped to the dirty rect,
+/// plus the area that was scrolled into view. This is usually much smaller than the dirty
+/// rect, which also covers the items that changed out of view.
+///
+/// An error is returned if either blob is malformed, or if the new blob refers to items
+/// that the old one doesn't have.
+///
+/// Handlers merge the items they stored instead, see `merge_items`, so that unchanged
+/// items aren't copied. This works on whole blobs, for tools and fuzzing.
+pub fn merge_blob_images(
     old_buf: &[u8],
     new_buf: &[u8],
     dirty_rect: DeviceIntRect,
     old_visible_rect: DeviceIntRect,
     new_visible_rect: DeviceIntRect,
-) -> Vec<u8> {
-    let mut result = BlobWriter::new();
+) -> Result<(Vec<u8>, DeviceIntRect), BlobError> {
     dlog!("dirty rect: {:?}", dirty_rect);
     dlog!("old:");
     dump_bounds(old_buf, dirty_rect);
@@ -414,78 +1034,294 @@ fn merge_blob_images(
     dlog!("old visibile rect: {:?}", old_visible_rect);
     dlog!("new visibile rect: {:?}", new_visible_rect);
 
-    let mut old_reader = CachedReader::new(old_buf);
-    let mut new_reader = BlobReader::new(new_buf);
+    let old_index = read_blob_index(old_buf)?;
+    let new_index = read_blob_index(new_buf)?;
+    let (merged, changed_rect) = merge_items(
+        old_index.iter().map(|e| e.bounds),
+        new_index.iter().map(|e| e.bounds),
+        dirty_rect,
+        old_visible_rect,
+        new_visible_rect,
+    )?;
+
+    let mut result = BlobWriter::new();
+    for item in merged {
+        let (buf, e) = match item {
+            MergedItem::Old(i) => (old_buf, &old_index[i]),
+            MergedItem::New(i) => (new_buf, &new_index[i]),
+        };
+        result.new_entry(e.extra_end - e.end, e.bounds, &buf[e.begin..e.extra_end]);
+    }
+    let result = result.finish();
+    dump_index(&result);
+    Ok((result, changed_rect))
+}
+
+/// Where an item of a merged list comes from, see `merge_items`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum MergedItem {
+    /// The item at this index of the old list.
+    Old(usize),
+    /// The item at this index of the new list.
+    New(usize),
+}
+
+/// Merges the items of a new partial blob image into the items of an existing complete
+/// one, given their bounds, as described in `merge_blob_images`.
+///
+/// Returns where each item of the merged list comes from, along with the part of the new
+/// visible rect that may have changed. This lets handlers merge the items they stored
+/// without writing them back into a blob.
+fn merge_items(
+    old_bounds: impl Iterator<Item = DeviceIntRect>,
+    new_bounds: impl Iterator<Item = DeviceIntRect>,
+    dirty_rect: DeviceIntRect,
+    old_visible_rect: DeviceIntRect,
+    new_visible_rect: DeviceIntRect,
+) -> Result<(Vec<MergedItem>, DeviceIntRect), BlobError> {
+    let mut result = Vec::new();
+    let mut old_reader = CachedReader::new(old_bounds);
     let preserved_rect = old_visible_rect.intersection_unchecked(&new_visible_rect);
+    // An item is dirty if the part of it that stays visible is in the dirty rect. This
+    // only depends on the bounds, so items with equal bounds are all dirty or all clean.
+    let is_dirty = |bounds: &DeviceIntRect| dirty_rect.contains_box(&bounds.intersection_unchecked(&preserved_rect));
+    let mut changed_bounds = DeviceIntRect::zero();
 
     // Loop over both new and old entries merging them.
     // Both new and old must have the same number of entries that
-    // overlap but are not contained by the dirty rect, and they
-    // must be in the same order.
-    while new_reader.reader.has_more() {
-        let new = new_reader.read_entry();
-        dlog!("bounds: {} {} {:?}", new.end, new.extra_end, new.bounds);
-        let preserved_bounds = new.bounds.intersection_unchecked(&preserved_rect);
-        if dirty_rect.contains_box(&preserved_bounds) {
-            result.new_entry(new.extra_end - new.end, new.bounds, &new_buf[new.begin..new.extra_end]);
+    // are not dirty, and they must be in the same order.
+    for (i, bounds) in new_bounds.enumerate() {
+        dlog!("bounds: {} {:?}", i, bounds);
+        if is_dirty(&bounds) {
+            changed_bounds = union_rects(&changed_bounds, &bounds);
+            result.push(MergedItem::New(i));
+        } else {
+            result.push(MergedItem::Old(old_reader.next_item_with_bounds(&bounds, &is_dirty)?));
+        }
+    }
+    changed_bounds = union_rects(&changed_bounds, &old_reader.dirty_bounds);
+
+    // The remaining items have been deleted. Dirty ones are accounted for by the dirty rect,
+    // but if the dirty rect misses a deletion, count the item as changed rather than trusting it.
+    let mut missed_bounds = DeviceIntRect::zero();
+    for (_, bounds) in old_reader.items {
+        dlog!("old bounds: {:?}", bounds);
+        if is_dirty(&bounds) {
+            changed_bounds = union_rects(&changed_bounds, &bounds);
         } else {
-            let old = old_reader.next_entry_with_bounds(&new.bounds, &dirty_rect);
-            result.new_entry(old.extra_end - old.end, new.bounds, &old_buf[old.begin..old.extra_end])
+            missed_bounds = union_rects(&missed_bounds, &bounds);
         }
     }
+    for &(_, bounds) in old_reader.cache.values() {
+        missed_bounds = union_rects(&missed_bounds, &bounds);
+    }
 
-    // XXX: future work: ensure that items that have been deleted but aren't in the blob's visible
-    // rect don't affect the dirty rect -- this allows us to scroll content out of view while only
-    // updating the areas where items have been scrolled *into* view. This is very important for
-    // the performance of blobs that are larger than the viewport. When this is done this
-    // assertion will need to be modified to factor in the visible rect, or removed.
+    let changed_rect = union_rects(&changed_bounds.intersection_unchecked(&dirty_rect), &missed_bounds);
+    let changed_rect = union_rects(
+        &changed_rect.intersection_unchecked(&new_visible_rect),
+        &exposed_rect(&old_visible_rect, &new_visible_rect),
+    );
+    dlog!("changed rect: {:?}", changed_rect);
+    Ok((result, changed_rect))
+}
 
-    // Ensure all remaining items will be discarded
-    while old_reader.reader.reader.has_more() {
-        let old = old_reader.reader.read_entry();
-        dlog!("new bounds: {} {} {:?}", old.end, old.extra_end, old.bounds);
-        //assert!(dirty_rect.contains_box(&old.bounds));
+/// Returns the smallest rect containing both rects, ignoring empty ones.
+fn union_rects(a: &DeviceIntRect, b: &DeviceIntRect) -> DeviceIntRect {
+    if a.is_empty() {
+        *b
+    } else if b.is_empty() {
+        *a
+    } else {
+        a.union(b)
     }
+}
 
-    //assert!(old_reader.cache.is_empty());
+/// Returns the bounding box of the part of the new visible rect that wasn't visible before.
+fn exposed_rect(old_visible_rect: &DeviceIntRect, new_visible_rect: &DeviceIntRect) -> DeviceIntRect {
+    let overlap = match old_visible_rect.intersection(new_visible_rect) {
+        Some(overlap) => overlap,
+        None => return *new_visible_rect,
+    };
+    let mut exposed = *new_visible_rect;
+    if overlap.min.x == exposed.min.x && overlap.max.x == exposed.max.x {
+        // Scrolled vertically: only rows were exposed.
+        if overlap.min.y == exposed.min.y {
+            exposed.min.y = overlap.max.y;
+        } else if overlap.max.y == exposed.max.y {
+            exposed.max.y = overlap.min.y;
+        }
+    } else if overlap.min.y == exposed.min.y && overlap.max.y == exposed.max.y {
+        // Scrolled horizontally: only columns were exposed.
+        if overlap.min.x == exposed.min.x {
+            exposed.min.x = overlap.max.x;
+        } else if overlap.max.x == exposed.max.x {
+            exposed.max.x = overlap.min.x;
+        }
+    }
+    exposed
+}
 
-    let result = result.finish();
-    dump_index(&result);
-    result
+/// Fuzzing entry point for blob image validation and merging.
+///
+/// The input starts with the dirty rect, the old visible rect and the new visible rect
+/// (four native-endian i32s each), followed by the length of the old blob as a u32, the
+/// old blob, and the new blob.
+#[cfg(feature = "fuzzing")]
+pub fn fuzz_merge_blob_images(data: &[u8]) {
+    let mut reader = BufReader::new(data);
+    let (dirty_rect, old_visible_rect, new_visible_rect, old_len) = match (
+        reader.read_box(),
+        reader.read_box(),
+        reader.read_box(),
+        reader.read_u32(),
+    ) {
+        (Some(dirty), Some(old_visible), Some(new_visible), Some(old_len)) => {
+            (dirty, old_visible, new_visible, old_len as usize)
+        },
+        _ => return,
+    };
+    let blobs = &data[reader.pos..];
+    if old_len > blobs.len() {
+        return;
+    }
+    let (old_buf, new_buf) = blobs.split_at(old_len);
+    if validate_blob(old_buf).is_err() || validate_blob(new_buf).is_err() {
+        return;
+    }
+    if let Ok((merged, changed_rect)) =
+        merge_blob_images(old_buf, new_buf, dirty_rect, old_visible_rect, new_visible_rect)
+    {
+        assert_eq!(validate_blob(&merged), Ok(()));
+        assert!(changed_rect.is_empty() || new_visible_rect.contains_box(&changed_rect));
+    }
 }
 
 /// A font used by a blob image.
 #[repr(C)]
-#[derive(Copy, Clone)]
-struct BlobFont {
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BlobFont {
     /// The font key.
-    font_instance_key: FontInstanceKey,
+    pub font_instance_key: FontInstanceKey,
     /// A pointer to the scaled font.
-    scaled_font_ptr: u64,
+    pub scaled_font_ptr: u64,
+}
+
+/// An item of a blob image, as stored by a handler.
+#[derive(Clone)]
+struct StoredItem {
+    /// The bounds of the item.
+    bounds: DeviceIntRect,
+    /// The recording of the item followed by its extra data. Items with the same content
+    /// share it, across blobs and across the versions of a blob, see `ItemStore`.
+    payload: Arc<Vec<u8>>,
+    /// The hash of the payload.
+    hash: u64,
+    /// Where the extra data starts in the payload.
+    extra_begin: usize,
 }
 
 /// A blob image and extra data provided by webrender on how to rasterize it.
 #[derive(Clone)]
 struct BlobCommand {
-    /// The blob.
-    data: Arc<BlobImageData>,
+    /// The items of the blob, in order.
+    items: Arc<Vec<StoredItem>>,
     /// What part of the blob should be rasterized (visible_rect's top-left corresponds to
     /// (0,0) in the blob's rasterization)
     visible_rect: DeviceIntRect,
     /// The size of the tiles to use in rasterization.
     tile_size: TileSize,
+    /// The part of the visible rect that changed since the last rasterizer was created,
+    /// accumulated over the updates in between. Partially dirty tiles are only repainted
+    /// within it.
+    ///
+    /// Rasterizers are run as soon as they are created, and repaint every tile that an
+    /// update made dirty, so the tiles requested from the next one were dirty in at most
+    /// these updates. Parts that leave the visible rect are dropped: they come back into
+    /// view as exposed areas, which are changed areas.
+    changed_rect: DeviceIntRect,
+    /// The handler's `rasterizer_count` when `changed_rect` was last extended.
+    changed_rect_rasterizer: u64,
 }
 
 struct Job {
+    /// The estimated cost of the job, see `estimate_cost`, or 0 if it isn't needed.
+    cost: u64,
+    /// When the batch of the job started, if its timing is recorded.
+    batch_start: Option<Instant>,
+    /// Where the timing of the job is recorded, if it is.
+    raster_timings: Option<Arc<BlobRasterTimings>>,
+    low_priority: bool,
+    backend: Arc<dyn BlobRenderBackend>,
+    fallback: BlobReplayFallback,
+    failures: Arc<ReplayFailures>,
+    /// Whether the blob failed to replay before, in which case it isn't replayed.
+    quarantined: bool,
+    tile_cache: Option<Arc<BlobTileCache>>,
     request: BlobImageRequest,
     descriptor: BlobImageDescriptor,
-    commands: Arc<BlobImageData>,
+    items: Arc<Vec<StoredItem>>,
     dirty_rect: BlobDirtyRect,
     visible_rect: DeviceIntRect,
     tile_size: TileSize,
     output: MutableTileBuffer,
 }
 
+/// Deduplicates the items of blob images, so that items with the same content share
+/// their payload.
+///
+/// This is the case of the items that an update doesn't change, which are shared by the
+/// versions of the blob before and after the merge, of blobs that are added again under
+/// another key, and of identical items such as an icon used in several places.
+#[derive(Default)]
+struct ItemStore {
+    /// The payloads by the hash of their content. Payloads are dropped once no handler
+    /// or rasterizer uses them anymore.
+    payloads: HashMap<u64, Vec<Weak<Vec<u8>>>>,
+    /// The number of hashes that were left the last time dropped payloads were forgotten.
+    live_payloads: usize,
+}
+
+impl ItemStore {
+    /// Returns a payload with the given content and hash, sharing it if possible.
+    fn intern(&mut self, payload: &[u8], hash: u64) -> Arc<Vec<u8>> {
+        if self.payloads.len() > 2 * self.live_payloads + 64 {
+            self.payloads.retain(|_, bucket| {
+                bucket.retain(|payload| payload.strong_count() > 0);
+                !bucket.is_empty()
+            });
+            self.live_payloads = self.payloads.len();
+        }
+
+        let bucket = self.payloads.entry(hash).or_insert_with(Vec::new);
+        bucket.retain(|payload| payload.strong_count() > 0);
+        if let Some(existing) = bucket.iter().filter_map(Weak::upgrade).find(|existing| **existing == payload) {
+            return existing;
+        }
+        let payload = Arc::new(payload.to_vec());
+        bucket.push(Arc::downgrade(&payload));
+        payload
+    }
+
+    /// Stores an item of a blob, given its entry in the index, see `read_blob_index`.
+    fn store_item(&mut self, blob: &[u8], e: &Entry) -> StoredItem {
+        let payload = &blob[e.begin..e.extra_end];
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        let hash = hasher.finish();
+        StoredItem {
+            bounds: e.bounds,
+            payload: self.intern(payload, hash),
+            hash,
+            extra_begin: e.end - e.begin,
+        }
+    }
+
+    /// Stores the items of a blob, given its index.
+    fn store_blob(&mut self, blob: &[u8], index: &[Entry]) -> Vec<StoredItem> {
+        index.iter().map(|e| self.store_item(blob, e)).collect()
+    }
+}
+
 /// Rasterizes gecko blob images.
 struct Moz2dBlobRasterizer {
     /// Pool of rasterizers.
@@ -493,9 +1329,21 @@ struct Moz2dBlobRasterizer {
     /// Pool of low priority rasterizers.
     workers_low_priority: Arc<ThreadPool>,
     /// Blobs to rasterize.
-    blob_commands: HashMap<BlobImageKey, BlobCommand>,
+    blob_commands: Arc<HashMap<BlobImageKey, Arc<BlobCommand>>>,
     ///
     enable_multithreading: bool,
+    /// Replays the blobs into tiles.
+    backend: Arc<dyn BlobRenderBackend>,
+    /// What to produce for tiles that fail to replay.
+    fallback: BlobReplayFallback,
+    /// The blobs that failed to replay.
+    failures: Arc<ReplayFailures>,
+    /// Tiles that were already rasterized.
+    tile_cache: Option<Arc<BlobTileCache>>,
+    /// How the tiles are spread over the workers.
+    policy: BlobSchedulingPolicy,
+    /// Where the timings are recorded, if the policy asks for them.
+    raster_timings: Option<Arc<BlobRasterTimings>>,
 }
 
 impl AsyncBlobImageRasterizer for Moz2dBlobRasterizer {
@@ -506,7 +1354,9 @@ impl AsyncBlobImageRasterizer for Moz2dBlobRasterizer {
         tile_pool: &mut BlobTilePool,
     ) -> Vec<(BlobImageRequest, BlobImageResult)> {
         // All we do here is spin up our workers to callback into gecko to replay the drawing commands.
+        #[cfg(feature = "gecko")]
         gecko_profiler_label!(Graphics, Rasterization);
+        #[cfg(feature = "gecko")]
         auto_profiler_marker_tracing!(
             "BlobRasterization",
             gecko_profiler::gecko_profiler_category!(Graphics),
@@ -514,75 +1364,246 @@ impl AsyncBlobImageRasterizer for Moz2dBlobRasterizer {
             "Webrender".into()
         );
 
+        let policy = self.policy;
+        let batch_start = self.raster_timings.as_ref().map(|_| Instant::now());
+        // Costs are only needed to pick a thread or a pool for the batch, or to time it.
+        let estimate_costs = policy.min_parallel_cost > 0 || policy.split_across_pools || batch_start.is_some();
+
+        // Blobs that were rejected as malformed have no commands.
+        let mut rejected = Vec::new();
+        let quarantined = self.failures.quarantined.lock().unwrap().clone();
         let requests: Vec<Job> = requests
             .iter()
-            .map(|params| {
-                let command = &self.blob_commands[&params.request.key];
-                let blob = Arc::clone(&command.data);
+            .filter_map(|params| {
+                let command = match self.blob_commands.get(&params.request.key) {
+                    Some(command) => command,
+                    None => {
+                        rejected.push((params.request, Err(BlobImageError::InvalidKey)));
+                        return None;
+                    },
+                };
                 assert!(!params.descriptor.rect.is_empty());
 
+                // Only repaint what actually changed. If nothing did, the tile is still
+                // repainted as requested, as there is no way to report an empty result.
+                let dirty_rect = match params.dirty_rect {
+                    DirtyRect::Partial(rect) => match rect.intersection(&command.changed_rect.cast_unit()) {
+                        Some(changed) => DirtyRect::Partial(changed),
+                        None => params.dirty_rect,
+                    },
+                    DirtyRect::All => DirtyRect::All,
+                };
+
                 let buf_size = (params.descriptor.rect.area() * params.descriptor.format.bytes_per_pixel()) as usize;
 
-                Job {
+                Some(Job {
+                    cost: if estimate_costs {
+                        estimate_cost(&command.items, &params.descriptor.rect)
+                    } else {
+                        0
+                    },
+                    batch_start,
+                    raster_timings: self.raster_timings.clone(),
+                    low_priority,
+                    backend: Arc::clone(&self.backend),
+                    fallback: self.fallback,
+                    failures: Arc::clone(&self.failures),
+                    quarantined: quarantined.contains(&params.request.key),
+                    tile_cache: self.tile_cache.clone(),
                     request: params.request,
                     descriptor: params.descriptor,
-                    commands: blob,
+                    items: Arc::clone(&command.items),
                     visible_rect: command.visible_rect,
-                    dirty_rect: params.dirty_rect,
+                    dirty_rect,
                     tile_size: command.tile_size,
                     output: tile_pool.get_buffer(buf_size),
-                }
+                })
             })
             .collect();
 
         // If we don't have a lot of blobs it is probably not worth the initial cost
         // of installing work on rayon's thread pool so we do it serially on this thread.
-        let should_parallelize = if !self.enable_multithreading {
-            false
-        } else if low_priority {
-            requests.len() > 2
+        // For high priority requests we don't "risk" the potential priority inversion of
+        // dispatching to a thread pool full of low priority jobs unless it is really
+        // appealing, hence the higher default threshold.
+        let parallel_threshold = if low_priority {
+            policy.low_priority_parallel_threshold
         } else {
-            // For high priority requests we don't "risk" the potential priority inversion of
-            // dispatching to a thread pool full of low priority jobs unless it is really
-            // appealing.
-            requests.len() > 4
+            policy.high_priority_parallel_threshold
         };
+        let total_cost: u64 = requests.iter().map(|job| job.cost).sum();
+        let should_parallelize =
+            self.enable_multithreading && requests.len() > parallel_threshold && total_cost >= policy.min_parallel_cost;
 
-        let result = if should_parallelize {
+        let (primary_pool, secondary_pool) = if low_priority && !policy.low_priority_on_main_pool {
+            (&self.workers_low_priority, &self.workers)
+        } else {
+            (&self.workers, &self.workers_low_priority)
+        };
+
+        let mut result: Vec<(BlobImageRequest, BlobImageResult)> = if !should_parallelize {
+            requests.into_iter().map(rasterize_blob).collect()
+        } else if policy.split_across_pools {
+            // Both pools work on their share of the batch at the same time, and this
+            // thread is blocked until they are both done.
+            let (primary_jobs, secondary_jobs) = split_by_cost(
+                requests,
+                primary_pool.current_num_threads(),
+                secondary_pool.current_num_threads(),
+            );
+            let (sender, receiver) = mpsc::channel();
+            if !secondary_jobs.is_empty() {
+                secondary_pool.spawn(move || {
+                    let results: Vec<_> = secondary_jobs.into_par_iter().map(rasterize_blob).collect();
+                    let _ = sender.send(results);
+                });
+            }
+            let mut results: Vec<_> =
+                primary_pool.install(|| primary_jobs.into_par_iter().map(rasterize_blob).collect());
+            results.extend(receiver.recv().unwrap_or_default());
+            results
+        } else {
             // Parallel version synchronously installs a job on the thread pool which will
             // try to do the work in parallel.
             // This thread is blocked until the thread pool is done doing the work.
-            let lambda = || requests.into_par_iter().map(rasterize_blob).collect();
-            if low_priority {
-                //TODO --bpe runtime flag to A/B test these two
-                self.workers_low_priority.install(lambda)
-            //self.workers.install(lambda)
-            } else {
-                self.workers.install(lambda)
-            }
-        } else {
-            requests.into_iter().map(rasterize_blob).collect()
+            primary_pool.install(|| requests.into_par_iter().map(rasterize_blob).collect())
         };
 
+        result.extend(rejected);
         result
     }
 }
 
-// a cross platform wrapper that creates an autorelease pool
-// on macOS
-fn autoreleasepool<T, F: FnOnce() -> T>(f: F) -> T {
-    #[cfg(target_os = "macos")]
-    {
-        objc::rc::autoreleasepool(f)
-    }
-    #[cfg(not(target_os = "macos"))]
-    {
-        f()
-    }
+/// The parameters of a tile to rasterize, see `BlobRenderBackend::render_tile`.
+#[derive(Copy, Clone, Debug)]
+pub struct BlobTileParams {
+    /// The format of the output.
+    pub format: ImageFormat,
+    /// The area of the blob covered by the tile.
+    pub render_rect: LayoutIntRect,
+    /// The visible rect of the blob, whose top-left corner is the origin of the tiles.
+    pub visible_rect: DeviceIntRect,
+    /// The size of the blob's tiles.
+    pub tile_size: TileSize,
+    /// Which tile this is.
+    pub tile: TileOffset,
+    /// The part of the blob to repaint, or `None` to repaint the whole tile.
+    ///
+    /// The rest of the output may be left as is, it won't be used.
+    pub dirty_rect: Option<LayoutIntRect>,
 }
 
-fn rasterize_blob(mut job: Job) -> (BlobImageRequest, BlobImageResult) {
-    gecko_profiler_label!(Graphics, Rasterization);
+/// Replays the drawing commands of blob images into tiles.
+///
+/// This lets embedders provide their own recording format and rasterizer. The recording
+/// of each item is opaque to WebRender, which gives backends the items of the blob that
+/// intersect each tile, see `BlobTileItems`.
+pub trait BlobRenderBackend: Send + Sync {
+    /// Rasterizes the given tile of a blob into `output`, which has the size of
+    /// `params.render_rect` with rows of `params.format` pixels.
+    ///
+    /// `items` only holds the items of the blob that intersect the tile, in order.
+    ///
+    /// Returns whether the blob could be replayed. This is called from the rasterization
+    /// worker threads.
+    fn render_tile(&self, items: BlobTileItems, params: &BlobTileParams, output: &mut [u8]) -> bool;
+}
+
+/// Why a font used by a blob image couldn't be registered.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlobFontError {
+    /// WebRender has no data for the font.
+    MissingFontData,
+    /// The font isn't installed or can't be loaded, and neither can the fallback font.
+    Unloadable,
+}
+
+/// The fonts used by blob images that couldn't be registered, and why.
+///
+/// They aren't retried until they are deleted, and the blob items that use them fail to
+/// replay.
+#[derive(Default)]
+pub struct FailedBlobFonts {
+    fonts: Mutex<HashMap<FontKey, BlobFontError>>,
+}
+
+impl FailedBlobFonts {
+    /// Returns the fonts that couldn't be registered, and why.
+    pub fn list(&self) -> Vec<(FontKey, BlobFontError)> {
+        self.fonts.lock().unwrap().iter().map(|(&key, &err)| (key, err)).collect()
+    }
+}
+
+/// Registers the fonts that blob images use with the rasterizer, before they are
+/// rasterized.
+///
+/// Keys are registered once, and deleted when WebRender deletes them.
+pub trait BlobFontRegistry: Send + Sync {
+    /// Returns whether the data of the given font was already registered.
+    fn has_font_data(&self, key: FontKey) -> bool;
+    /// Registers a font from its raw data.
+    fn add_font_data(&self, key: FontKey, data: &Arc<Vec<u8>>, index: u32);
+    /// Registers a font from a handle to a font installed on the system.
+    fn add_native_font(&self, key: FontKey, handle: &NativeFontHandle) -> Result<(), BlobFontError>;
+    /// Unregisters the data of a font.
+    fn delete_font_data(&self, key: FontKey);
+    /// Registers an instance of a font at a given size, with the given options.
+    fn add_font_instance(
+        &self,
+        key: FontInstanceKey,
+        font_key: FontKey,
+        size: f32,
+        options: Option<&FontInstanceOptions>,
+        platform_options: Option<&FontInstancePlatformOptions>,
+        variations: &[FontVariation],
+    );
+    /// Unregisters a font instance.
+    fn delete_font_instance(&self, key: FontInstanceKey);
+    /// Unregisters all fonts and font instances of a namespace.
+    fn clear_namespace(&self, namespace: IdNamespace);
+}
+
+// a cross platform wrapper that creates an autorelease pool
+// on macOS
+fn autoreleasepool<T, F: FnOnce() -> T>(f: F) -> T {
+    #[cfg(target_os = "macos")]
+    {
+        objc::rc::autoreleasepool(f)
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        f()
+    }
+}
+
+fn rasterize_blob(mut job: Job) -> (BlobImageRequest, BlobImageResult) {
+    let start = job.batch_start.map(|_| Instant::now());
+    let request = job.request;
+    let (cost, batch_start, low_priority) = (job.cost, job.batch_start, job.low_priority);
+    let raster_timings = job.raster_timings.take();
+
+    let result = rasterize_job(job);
+
+    if let (Some(raster_timings), Some(batch_start), Some(start)) = (raster_timings, batch_start, start) {
+        let end = Instant::now();
+        raster_timings.record(BlobRasterTiming {
+            key: request.key,
+            tile: request.tile,
+            cost,
+            low_priority,
+            queue_time_us: start.duration_since(batch_start).as_micros() as u64,
+            raster_time_us: end.duration_since(start).as_micros() as u64,
+        });
+    }
+
+    (request, result)
+}
+
+/// Rasterizes a tile, see `rasterize_blob`.
+fn rasterize_job(mut job: Job) -> BlobImageResult {
+    #[cfg(feature = "gecko")]
+    gecko_profiler_label!(Graphics, Rasterization);
     let descriptor = job.descriptor;
 
     let dirty_rect = match job.dirty_rect {
@@ -592,58 +1613,257 @@ fn rasterize_blob(mut job: Job) -> (BlobImageRequest, BlobImageResult) {
     assert!(!descriptor.rect.is_empty());
 
     let request = job.request;
+    let params = BlobTileParams {
+        format: descriptor.format,
+        render_rect: descriptor.rect,
+        visible_rect: job.visible_rect,
+        tile_size: job.tile_size,
+        tile: request.tile,
+        dirty_rect,
+    };
 
-    let result = autoreleasepool(|| {
-        unsafe {
-            if wr_moz2d_render_cb(
-                ByteSlice::new(&job.commands[..]),
-                descriptor.format,
-                &descriptor.rect,
-                &job.visible_rect,
-                job.tile_size,
-                &request.tile,
-                dirty_rect.as_ref(),
-                MutByteSlice::new(job.output.as_mut_slice()),
-            ) {
-                // We want the dirty rect local to the tile rather than the whole image.
-                // TODO(nical): move that up and avoid recomupting the tile bounds in the callback
-                let dirty_rect = job.dirty_rect.to_subrect_of(&descriptor.rect);
-                let tx: BlobToDeviceTranslation = (-descriptor.rect.min.to_vector()).into();
-                let rasterized_rect = tx.transform_box(&dirty_rect);
-
-                Ok(RasterizedBlobImage {
-                    rasterized_rect,
-                    data: job.output.into_arc(),
-                })
-            } else {
-                panic!("Moz2D replay problem");
-            }
-        }
+    // We want the dirty rect local to the tile rather than the whole image.
+    // TODO(nical): move that up and avoid recomupting the tile bounds in the callback
+    let tile_dirty_rect = job.dirty_rect.to_subrect_of(&descriptor.rect);
+    let tx: BlobToDeviceTranslation = (-descriptor.rect.min.to_vector()).into();
+    let rasterized_rect = tx.transform_box(&tile_dirty_rect);
+
+    let items = BlobTileItems::new(&job.items, &descriptor.rect);
+    let tile_cache = if job.quarantined { None } else { job.tile_cache.as_ref() };
+    let cache_key = tile_cache.map(|_| TileCacheKey {
+        items: items.stored_items().cloned().collect(),
+        render_rect: descriptor.rect,
+        format: descriptor.format,
+        visible_rect: job.visible_rect,
     });
+    if let (Some(cache), Some(key)) = (tile_cache, cache_key.as_ref()) {
+        if let Some(data) = cache.get(key) {
+            return Ok(RasterizedBlobImage { rasterized_rect, data });
+        }
+    }
 
-    (request, result)
+    let replayed = !job.quarantined
+        && job
+            .backend
+            .render_tile(items, &params, job.output.as_mut_slice());
+    if !replayed && !job.quarantined {
+        job.failures.record(request.key);
+    }
+
+    if replayed || fill_fallback_tile(job.fallback, &params, job.output.as_mut_slice()) {
+        let data = job.output.into_arc();
+        // Only tiles that were painted entirely can be reused for other requests.
+        if let (true, None, Some(cache), Some(key)) = (replayed, dirty_rect, tile_cache, cache_key) {
+            cache.insert(key, Arc::clone(&data));
+        }
+
+        Ok(RasterizedBlobImage { rasterized_rect, data })
+    } else {
+        Err(BlobImageError::Other("Blob replay failure".to_string()))
+    }
+}
+
+/// How blob rasterization work is spread over the worker threads.
+///
+/// Handlers use the policy of the host, see `HostCallbacks::blob_scheduling_policy`,
+/// unless they are given one with `Moz2dBlobImageHandler::set_scheduling_policy`. It
+/// applies from the next rasterizer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BlobSchedulingPolicy {
+    /// Low priority batches with more requests than this are rasterized in parallel.
+    pub low_priority_parallel_threshold: usize,
+    /// High priority batches with more requests than this are rasterized in parallel.
+    pub high_priority_parallel_threshold: usize,
+    /// Batches whose total cost is lower than this are rasterized on the calling
+    /// thread, whatever their number of requests. See `estimate_cost`.
+    pub min_parallel_cost: u64,
+    /// Whether low priority batches are rasterized by the high priority pool rather than
+    /// the low priority one.
+    pub low_priority_on_main_pool: bool,
+    /// Whether parallel batches are split across both pools, in proportion to their
+    /// number of threads.
+    pub split_across_pools: bool,
+    /// Whether to record the timing of each request, see `BlobRasterTimings`.
+    pub record_timings: bool,
+}
+
+impl BlobSchedulingPolicy {
+    /// The policy that is used until another one is set.
+    pub const DEFAULT: BlobSchedulingPolicy = BlobSchedulingPolicy {
+        low_priority_parallel_threshold: 2,
+        high_priority_parallel_threshold: 4,
+        min_parallel_cost: 0,
+        low_priority_on_main_pool: false,
+        split_across_pools: false,
+        record_timings: false,
+    };
+}
+
+impl Default for BlobSchedulingPolicy {
+    fn default() -> Self {
+        BlobSchedulingPolicy::DEFAULT
+    }
+}
+
+/// The timing of the rasterization of a blob tile.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct BlobRasterTiming {
+    /// The blob the tile belongs to.
+    pub key: BlobImageKey,
+    /// Which tile this is.
+    pub tile: TileOffset,
+    /// The estimated cost of the tile, see `estimate_cost`.
+    pub cost: u64,
+    /// Whether the tile was part of a low priority batch.
+    pub low_priority: bool,
+    /// How long the tile waited for a worker since its batch started, in microseconds.
+    pub queue_time_us: u64,
+    /// How long the tile took to rasterize, in microseconds.
+    pub raster_time_us: u64,
+}
+
+/// How many timings are kept until they are taken. Older ones are dropped.
+const MAX_RECORDED_TIMINGS: usize = 1024;
+
+/// The timings of the tiles rasterized for a handler and the handlers created from it,
+/// when their scheduling policy asks for them.
+#[derive(Default)]
+pub struct BlobRasterTimings {
+    /// The timings recorded since they were last taken.
+    timings: Mutex<VecDeque<BlobRasterTiming>>,
+}
+
+impl BlobRasterTimings {
+    /// Returns the timings recorded since the last call, oldest first.
+    pub fn take(&self) -> Vec<BlobRasterTiming> {
+        self.timings.lock().unwrap().drain(..).collect()
+    }
+
+    /// Records the timing of a tile.
+    fn record(&self, timing: BlobRasterTiming) {
+        let mut timings = self.timings.lock().unwrap();
+        if timings.len() == MAX_RECORDED_TIMINGS {
+            timings.pop_front();
+        }
+        timings.push_back(timing);
+    }
+
+    /// Drops the timings that weren't taken.
+    fn clear(&self) {
+        self.timings.lock().unwrap().clear();
+    }
+}
+
+/// Estimates the cost of rasterizing a tile of a blob, in arbitrary units.
+///
+/// Every item drawn in the tile is assumed to cover it, so the cost is the area of the
+/// tile multiplied by the number of items that intersect it, plus one for clearing it.
+fn estimate_cost(items: &[StoredItem], render_rect: &LayoutIntRect) -> u64 {
+    let render_rect = render_rect.cast_unit();
+    let item_count = items.iter().filter(|item| item.bounds.intersects(&render_rect)).count() as u64;
+    (render_rect.area() as u64).saturating_mul(item_count + 1)
+}
+
+/// Splits a batch of jobs between two pools, balancing their cost per thread.
+fn split_by_cost(mut jobs: Vec<Job>, primary_threads: usize, secondary_threads: usize) -> (Vec<Job>, Vec<Job>) {
+    // Placing the most expensive jobs first gives a better balance.
+    jobs.sort_by_key(|job| Reverse(job.cost));
+    let (primary_threads, secondary_threads) = (primary_threads.max(1) as u64, secondary_threads.max(1) as u64);
+    let (mut primary, mut secondary) = (Vec::new(), Vec::new());
+    let (mut primary_cost, mut secondary_cost) = (0u64, 0u64);
+    for job in jobs {
+        // Compare primary_cost / primary_threads to secondary_cost / secondary_threads.
+        let primary_load = primary_cost.saturating_add(job.cost).saturating_mul(secondary_threads);
+        let secondary_load = secondary_cost.saturating_add(job.cost).saturating_mul(primary_threads);
+        if primary_load <= secondary_load {
+            primary_cost = primary_cost.saturating_add(job.cost);
+            primary.push(job);
+        } else {
+            secondary_cost = secondary_cost.saturating_add(job.cost);
+            secondary.push(job);
+        }
+    }
+    (primary, secondary)
+}
+
+/// Fills a tile that couldn't be replayed according to the fallback.
+///
+/// Returns false if the fallback is to report an error.
+fn fill_fallback_tile(fallback: BlobReplayFallback, params: &BlobTileParams, output: &mut [u8]) -> bool {
+    match fallback {
+        BlobReplayFallback::Error => false,
+        BlobReplayFallback::Transparent => {
+            for byte in output {
+                *byte = 0;
+            }
+            true
+        },
+        BlobReplayFallback::Checkerboard => {
+            // Use blob coordinates so that the squares line up across tiles.
+            const SQUARE_SIZE: i32 = 8;
+            let bytes_per_pixel = params.format.bytes_per_pixel() as usize;
+            let width = params.render_rect.width().max(1) as usize;
+            for (i, pixel) in output.chunks_mut(bytes_per_pixel).enumerate() {
+                let x = params.render_rect.min.x + (i % width) as i32;
+                let y = params.render_rect.min.y + (i / width) as i32;
+                let light = (x.div_euclid(SQUARE_SIZE) + y.div_euclid(SQUARE_SIZE)) % 2 == 0;
+                for byte in pixel.iter_mut() {
+                    *byte = if light { 0xCC } else { 0x88 };
+                }
+                if bytes_per_pixel == 4 {
+                    // Make BGRA8 and RGBA8 tiles opaque.
+                    pixel[3] = 0xFF;
+                }
+            }
+            true
+        },
+    }
 }
 
 impl BlobImageHandler for Moz2dBlobImageHandler {
     fn create_similar(&self) -> Box<dyn BlobImageHandler> {
-        Box::new(Self::new(
+        let mut handler = Self::with_backend(
             Arc::clone(&self.workers),
             Arc::clone(&self.workers_low_priority),
-        ))
+            Arc::clone(&self.backend),
+            Arc::clone(&self.fonts),
+        );
+        handler.set_replay_fallback(self.fallback);
+        handler.set_tile_cache(self.tile_cache.clone());
+        handler.set_recorder(self.recorder.clone());
+        handler.set_scheduling_policy(self.scheduling_policy);
+        handler.raster_timings = Arc::clone(&self.raster_timings);
+        handler.failed_fonts = Arc::clone(&self.failed_fonts);
+        Box::new(handler)
     }
 
     fn add(&mut self, key: BlobImageKey, data: Arc<BlobImageData>, visible_rect: &DeviceIntRect, tile_size: TileSize) {
-        {
-            let index = BlobReader::new(&data);
-            assert!(index.reader.has_more());
+        if let Some(ref recorder) = self.recorder {
+            recorder.record_add(key, &data, visible_rect, tile_size);
         }
-        self.blob_commands.insert(
+        self.failures.release(key);
+        let index = match read_blob_index(&data) {
+            Ok(index) => index,
+            Err(err) => {
+                error!("Rejecting malformed blob image {:?}: {:?}", key, err);
+                if self.blob_commands.contains_key(&key) {
+                    Arc::make_mut(&mut self.blob_commands).remove(&key);
+                }
+                return;
+            },
+        };
+        let items = self.store.store_blob(&data, &index);
+        Arc::make_mut(&mut self.blob_commands).insert(
             key,
-            BlobCommand {
-                data: Arc::clone(&data),
+            Arc::new(BlobCommand {
+                items: Arc::new(items),
                 visible_rect: *visible_rect,
                 tile_size,
-            },
+                changed_rect: DeviceIntRect::zero(),
+                changed_rect_rasterizer: self.rasterizer_count,
+            }),
         );
     }
 
@@ -654,68 +1874,124 @@ impl BlobImageHandler for Moz2dBlobImageHandler {
         visible_rect: &DeviceIntRect,
         dirty_rect: &BlobDirtyRect,
     ) {
-        match self.blob_commands.entry(key) {
-            hash_map::Entry::Occupied(mut e) => {
-                let command = e.get_mut();
-                let dirty_rect = if let DirtyRect::Partial(rect) = *dirty_rect {
-                    rect.cast_unit()
+        if let Some(ref recorder) = self.recorder {
+            recorder.record_update(key, &data, visible_rect, dirty_rect);
+        }
+        let command = match self.blob_commands.get(&key) {
+            Some(command) => command,
+            None => {
+                // This blob was rejected as malformed when it was added.
+                error!("Ignoring update of missing blob image {:?}", key);
+                return;
+            },
+        };
+        let dirty_rect = if let DirtyRect::Partial(rect) = *dirty_rect {
+            rect.cast_unit()
+        } else {
+            DeviceIntRect {
+                min: point2(i32::MIN, i32::MIN),
+                max: point2(i32::MAX, i32::MAX),
+            }
+        };
+        // The unchanged items keep their payloads, only the ones recorded in the new blob
+        // are stored.
+        let store = &mut self.store;
+        let merged = read_blob_index(&data).and_then(|index| {
+            let (merged, changed_rect) = merge_items(
+                command.items.iter().map(|item| item.bounds),
+                index.iter().map(|e| e.bounds),
+                dirty_rect,
+                command.visible_rect,
+                *visible_rect,
+            )?;
+            let items = merged
+                .into_iter()
+                .map(|item| match item {
+                    MergedItem::Old(i) => command.items[i].clone(),
+                    MergedItem::New(i) => store.store_item(&data, &index[i]),
+                })
+                .collect::<Vec<_>>();
+            Ok((items, changed_rect))
+        });
+        match merged {
+            Ok((items, changed_rect)) => {
+                self.failures.release(key);
+                // Rasterizers keep the version of the blobs they were created with.
+                let command = Arc::make_mut(Arc::make_mut(&mut self.blob_commands).get_mut(&key).unwrap());
+                command.items = Arc::new(items);
+                command.visible_rect = *visible_rect;
+                // The changes that a rasterizer was created after have been repainted.
+                let previous_changes = if command.changed_rect_rasterizer == self.rasterizer_count {
+                    command.changed_rect.intersection_unchecked(visible_rect)
                 } else {
-                    DeviceIntRect {
-                        min: point2(i32::MIN, i32::MIN),
-                        max: point2(i32::MAX, i32::MAX),
-                    }
+                    DeviceIntRect::zero()
                 };
-                command.data = Arc::new(merge_blob_images(
-                    &command.data,
-                    &data,
-                    dirty_rect,
-                    command.visible_rect,
-                    *visible_rect,
-                ));
-                command.visible_rect = *visible_rect;
+                command.changed_rect = union_rects(&previous_changes, &changed_rect);
+                command.changed_rect_rasterizer = self.rasterizer_count;
             },
-            _ => {
-                panic!("missing image key");
+            Err(err) => {
+                // Keep the previous, consistent version of the blob.
+                error!("Rejecting malformed blob image update {:?}: {:?}", key, err);
             },
         }
     }
 
     fn delete(&mut self, key: BlobImageKey) {
-        self.blob_commands.remove(&key);
+        if let Some(ref recorder) = self.recorder {
+            recorder.record_delete(key);
+        }
+        if self.blob_commands.contains_key(&key) {
+            Arc::make_mut(&mut self.blob_commands).remove(&key);
+        }
+        self.failures.release(key);
     }
 
     fn create_blob_rasterizer(&mut self) -> Box<dyn AsyncBlobImageRasterizer> {
+        self.rasterizer_count += 1;
+        let policy = self.scheduling_policy.unwrap_or_else(|| host().blob_scheduling_policy());
+        let raster_timings = if policy.record_timings {
+            Some(Arc::clone(&self.raster_timings))
+        } else {
+            self.raster_timings.clear();
+            None
+        };
         Box::new(Moz2dBlobRasterizer {
             workers: Arc::clone(&self.workers),
             workers_low_priority: Arc::clone(&self.workers_low_priority),
-            blob_commands: self.blob_commands.clone(),
+            blob_commands: Arc::clone(&self.blob_commands),
             enable_multithreading: self.enable_multithreading,
+            backend: Arc::clone(&self.backend),
+            fallback: self.fallback,
+            failures: Arc::clone(&self.failures),
+            tile_cache: self.tile_cache.clone(),
+            policy,
+            raster_timings,
         })
     }
 
     fn delete_font(&mut self, font: FontKey) {
-        unsafe {
-            DeleteFontData(font);
-        }
+        self.failed_fonts.fonts.lock().unwrap().remove(&font);
+        self.fonts.delete_font_data(font);
     }
 
     fn delete_font_instance(&mut self, key: FontInstanceKey) {
-        unsafe {
-            DeleteBlobFont(key);
-        }
+        self.fonts.delete_font_instance(key);
     }
 
     fn clear_namespace(&mut self, namespace: IdNamespace) {
-        unsafe {
-            ClearBlobImageResources(namespace);
-        }
+        self.failed_fonts.fonts.lock().unwrap().retain(|key, _| key.0 != namespace);
+        self.fonts.clear_namespace(namespace);
     }
 
     fn prepare_resources(&mut self, resources: &dyn BlobImageResources, requests: &[BlobImageParams]) {
         for params in requests {
-            let commands = &self.blob_commands[&params.request.key];
-            let blob = Arc::clone(&commands.data);
-            self.prepare_request(&blob, resources);
+            if let Some(command) = self.blob_commands.get(&params.request.key) {
+                let items = Arc::clone(&command.items);
+                self.prepare_request(&items, resources);
+            }
+        }
+        if let Some(ref recorder) = self.recorder {
+            recorder.record_raster(requests);
         }
     }
 
@@ -746,62 +2022,500 @@ extern "C" {
 
 }
 
+/// Replays blobs recorded by Gecko's DrawTargetRecording, with Moz2D (see Moz2DImageRenderer.cpp).
+pub struct Moz2dRenderBackend;
+
+impl BlobRenderBackend for Moz2dRenderBackend {
+    fn render_tile(&self, items: BlobTileItems, params: &BlobTileParams, output: &mut [u8]) -> bool {
+        let items: Vec<WrBlobItem> = items
+            .iter()
+            .map(|item| WrBlobItem {
+                bounds: item.bounds,
+                commands: ByteSlice::new(item.commands),
+                extra_data: ByteSlice::new(item.extra_data),
+            })
+            .collect();
+        autoreleasepool(|| unsafe {
+            wr_moz2d_render_cb(
+                items.as_ptr(),
+                items.len(),
+                params.format,
+                &params.render_rect,
+                &params.visible_rect,
+                params.tile_size,
+                &params.tile,
+                params.dirty_rect.as_ref(),
+                MutByteSlice::new(output),
+            )
+        })
+    }
+}
+
+/// Registers fonts with Moz2D (see Moz2DImageRenderer.cpp).
+///
+/// Native fonts that can't be loaded, for example because they were uninstalled, are
+/// replaced with a fallback font so that blobs using them still render.
+pub struct Moz2dFontRegistry {
+    fallback: Option<NativeFontHandle>,
+}
+
+impl Moz2dFontRegistry {
+    /// Creates a registry which replaces the native fonts that can't be loaded with the
+    /// given font. Without one, these fonts fail to register.
+    pub fn with_fallback_font(fallback: Option<NativeFontHandle>) -> Self {
+        Moz2dFontRegistry { fallback }
+    }
+
+    /// Loads the given native font, or the fallback font if it can't be loaded.
+    fn load_native_font<T>(
+        &self,
+        key: FontKey,
+        handle: &NativeFontHandle,
+        load: impl Fn(&NativeFontHandle) -> Option<T>,
+    ) -> Result<T, BlobFontError> {
+        if let Some(font) = load(handle) {
+            return Ok(font);
+        }
+        warn!("Failed to load the native font of {:?}, using the fallback font", key);
+        self.fallback
+            .as_ref()
+            .and_then(|fallback| load(fallback))
+            .ok_or(BlobFontError::Unloadable)
+    }
+}
+
+impl Default for Moz2dFontRegistry {
+    /// Creates a registry with the fallback font set with `set_blob_fallback_font`, or
+    /// the one of the platform.
+    fn default() -> Self {
+        Self::with_fallback_font(default_fallback_font())
+    }
+}
+
+/// The fallback font set with `set_blob_fallback_font`, if any.
+static FALLBACK_FONT: Mutex<Option<NativeFontHandle>> = Mutex::new(None);
+
+/// Sets the font that replaces the native fonts that can't be loaded in the font
+/// registries created from now on, instead of the one of the platform. `None` goes back
+/// to the font of the platform.
+pub fn set_blob_fallback_font(font: Option<NativeFontHandle>) {
+    *FALLBACK_FONT.lock().unwrap() = font;
+}
+
+/// Returns the fallback font set with `set_blob_fallback_font`, or the one of the
+/// platform.
+fn default_fallback_font() -> Option<NativeFontHandle> {
+    if let Some(ref font) = *FALLBACK_FONT.lock().unwrap() {
+        return Some(font.clone());
+    }
+    platform_fallback_font()
+}
+
+/// Returns the font that Gecko falls back to on this platform.
+#[cfg(target_os = "windows")]
+fn platform_fallback_font() -> Option<NativeFontHandle> {
+    let windows_dir = std::env::var_os("SystemRoot")?;
+    Some(NativeFontHandle {
+        path: PathBuf::from(windows_dir).join("Fonts").join("arial.ttf"),
+        index: 0,
+    })
+}
+
+/// Returns the font that Gecko falls back to on this platform.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn platform_fallback_font() -> Option<NativeFontHandle> {
+    Some(NativeFontHandle {
+        name: "Lucida Grande".to_string(),
+    })
+}
+
+/// Returns the font that Gecko falls back to on this platform.
+#[cfg(target_os = "android")]
+fn platform_fallback_font() -> Option<NativeFontHandle> {
+    Some(NativeFontHandle {
+        path: PathBuf::from("/system/fonts/Roboto-Regular.ttf"),
+        index: 0,
+    })
+}
+
+/// Returns the font that fontconfig picks for sans-serif text, which is the one Gecko
+/// falls back to on this platform. It is only looked up once.
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows", target_os = "android")))]
+fn platform_fallback_font() -> Option<NativeFontHandle> {
+    static FONT: OnceLock<Option<NativeFontHandle>> = OnceLock::new();
+    FONT.get_or_init(|| {
+        let font = fontconfig::match_font(b"sans-serif\0");
+        if font.is_none() {
+            warn!("fontconfig has no sans-serif font to fall back to");
+        }
+        font
+    })
+    .clone()
+}
+
+/// The few fontconfig functions needed to find the fallback font. Gecko already links
+/// fontconfig, other builds link it here.
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows", target_os = "android")))]
+mod fontconfig {
+    use std::ffi::{CStr, OsStr};
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+    use std::ptr;
+    use webrender::api::NativeFontHandle;
+
+    type FcPattern = c_void;
+    type FcConfig = c_void;
+
+    /// `FcMatchPattern`, of `FcMatchKind`.
+    const FC_MATCH_PATTERN: c_int = 0;
+    /// `FcResultMatch`, of `FcResult`.
+    const FC_RESULT_MATCH: c_int = 0;
+    /// The properties of the matched font that are read.
+    const FC_FILE: &[u8] = b"file\0";
+    const FC_INDEX: &[u8] = b"index\0";
+
+    #[cfg_attr(not(feature = "gecko"), link(name = "fontconfig"))]
+    extern "C" {
+        fn FcNameParse(name: *const u8) -> *mut FcPattern;
+        fn FcConfigSubstitute(config: *mut FcConfig, pattern: *mut FcPattern, kind: c_int) -> c_int;
+        fn FcDefaultSubstitute(pattern: *mut FcPattern);
+        fn FcFontMatch(config: *mut FcConfig, pattern: *mut FcPattern, result: *mut c_int) -> *mut FcPattern;
+        fn FcPatternGetString(
+            pattern: *mut FcPattern,
+            object: *const c_char,
+            n: c_int,
+            value: *mut *const u8,
+        ) -> c_int;
+        fn FcPatternGetInteger(pattern: *mut FcPattern, object: *const c_char, n: c_int, value: *mut c_int) -> c_int;
+        fn FcPatternDestroy(pattern: *mut FcPattern);
+    }
+
+    /// Returns the file of the font that best matches a fontconfig pattern, given as a
+    /// NUL-terminated string.
+    pub fn match_font(pattern: &[u8]) -> Option<NativeFontHandle> {
+        debug_assert_eq!(pattern.last(), Some(&0));
+        unsafe {
+            let pattern = FcNameParse(pattern.as_ptr());
+            if pattern.is_null() {
+                return None;
+            }
+            FcConfigSubstitute(ptr::null_mut(), pattern, FC_MATCH_PATTERN);
+            FcDefaultSubstitute(pattern);
+            let mut result = 0;
+            let font = FcFontMatch(ptr::null_mut(), pattern, &mut result);
+            FcPatternDestroy(pattern);
+            if font.is_null() {
+                return None;
+            }
+
+            let mut file = ptr::null();
+            let mut index = 0;
+            let has_file = FcPatternGetString(font, FC_FILE.as_ptr() as *const c_char, 0, &mut file) == FC_RESULT_MATCH;
+            let handle = if has_file {
+                // Fonts that aren't in a collection have no index.
+                FcPatternGetInteger(font, FC_INDEX.as_ptr() as *const c_char, 0, &mut index);
+                let path = CStr::from_ptr(file as *const c_char).to_bytes();
+                Some(NativeFontHandle {
+                    path: PathBuf::from(OsStr::from_bytes(path)),
+                    index: index as u32,
+                })
+            } else {
+                None
+            };
+            FcPatternDestroy(font);
+            handle
+        }
+    }
+}
+
+impl BlobFontRegistry for Moz2dFontRegistry {
+    fn has_font_data(&self, key: FontKey) -> bool {
+        unsafe { HasFontData(key) }
+    }
+
+    fn add_font_data(&self, key: FontKey, data: &Arc<Vec<u8>>, index: u32) {
+        unsafe { AddFontData(key, data.as_ptr(), data.len(), index, data) };
+    }
+
+    #[cfg(target_os = "windows")]
+    fn add_native_font(&self, key: FontKey, handle: &NativeFontHandle) -> Result<(), BlobFontError> {
+        let face = self.load_native_font(key, handle, |handle| {
+            let file = dwrote::FontFile::new_from_path(&handle.path)?;
+            file.create_face(handle.index, dwrote::DWRITE_FONT_SIMULATIONS_NONE)
+                .ok()
+        })?;
+        unsafe { AddNativeFontHandle(key, face.as_ptr() as *mut c_void, 0) };
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    fn add_native_font(&self, key: FontKey, handle: &NativeFontHandle) -> Result<(), BlobFontError> {
+        // Lucida Grande is the fallback font in Gecko, so it is the default fallback here.
+        let font = self.load_native_font(key, handle, |handle| {
+            CGFont::from_name(&CFString::new(&handle.name)).ok()
+        })?;
+        unsafe { AddNativeFontHandle(key, font.as_ptr() as *mut c_void, 0) };
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
+    fn add_native_font(&self, key: FontKey, handle: &NativeFontHandle) -> Result<(), BlobFontError> {
+        // The font is only opened when it is first used, so check that it is there.
+        let (path, index) = self.load_native_font(key, handle, |handle| {
+            if !handle.path.is_file() {
+                return None;
+            }
+            Some((CString::new(handle.path.as_os_str().as_bytes()).ok()?, handle.index))
+        })?;
+        unsafe { AddNativeFontHandle(key, path.as_ptr() as *mut c_void, index) };
+        Ok(())
+    }
+
+    fn delete_font_data(&self, key: FontKey) {
+        unsafe { DeleteFontData(key) };
+    }
+
+    fn add_font_instance(
+        &self,
+        key: FontInstanceKey,
+        font_key: FontKey,
+        size: f32,
+        options: Option<&FontInstanceOptions>,
+        platform_options: Option<&FontInstancePlatformOptions>,
+        variations: &[FontVariation],
+    ) {
+        unsafe {
+            AddBlobFont(
+                key,
+                font_key,
+                size,
+                options,
+                platform_options,
+                variations.as_ptr(),
+                variations.len(),
+            );
+        }
+    }
+
+    fn delete_font_instance(&self, key: FontInstanceKey) {
+        unsafe { DeleteBlobFont(key) };
+    }
+
+    fn clear_namespace(&self, namespace: IdNamespace) {
+        unsafe { ClearBlobImageResources(namespace) };
+    }
+}
+
 impl Moz2dBlobImageHandler {
-    /// Create a new BlobImageHandler with the given thread pool.
+    /// Create a new BlobImageHandler with the given thread pool, which rasterizes with Moz2D.
     pub fn new(workers: Arc<ThreadPool>, workers_low_priority: Arc<ThreadPool>) -> Self {
+        Self::with_backend(
+            workers,
+            workers_low_priority,
+            Arc::new(Moz2dRenderBackend),
+            Arc::new(Moz2dFontRegistry::default()),
+        )
+    }
+
+    /// Create a new BlobImageHandler with the given thread pool, which rasterizes with
+    /// the given backend and registers fonts with the given registry.
+    pub fn with_backend(
+        workers: Arc<ThreadPool>,
+        workers_low_priority: Arc<ThreadPool>,
+        backend: Arc<dyn BlobRenderBackend>,
+        fonts: Arc<dyn BlobFontRegistry>,
+    ) -> Self {
         Moz2dBlobImageHandler {
-            blob_commands: HashMap::new(),
+            blob_commands: Arc::new(HashMap::new()),
+            store: ItemStore::default(),
+            rasterizer_count: 0,
             workers,
             workers_low_priority,
             enable_multithreading: true,
+            backend,
+            fonts,
+            fallback: BlobReplayFallback::Error,
+            failures: Arc::new(ReplayFailures::default()),
+            tile_cache: None,
+            recorder: None,
+            scheduling_policy: None,
+            raster_timings: Arc::new(BlobRasterTimings::default()),
+            failed_fonts: Arc::new(FailedBlobFonts::default()),
         }
     }
 
-    /// Does early preprocessing of a blob's resources.
+    /// Sets the recorder of the blobs given to this handler, if any. There is none by
+    /// default.
+    pub fn set_recorder(&mut self, recorder: Option<Arc<BlobRecorder>>) {
+        self.recorder = recorder;
+    }
+
+    /// Replays a recording made by a `BlobRecorder`.
     ///
-    /// Currently just sets up fonts found in the blob.
-    fn prepare_request(&self, blob: &[u8], resources: &dyn BlobImageResources) {
-        #[cfg(target_os = "windows")]
-        fn process_native_font_handle(key: FontKey, handle: &NativeFontHandle) {
-            let file = dwrote::FontFile::new_from_path(&handle.path).unwrap();
-            let face = file
-                .create_face(handle.index, dwrote::DWRITE_FONT_SIMULATIONS_NONE)
-                .unwrap();
-            unsafe { AddNativeFontHandle(key, face.as_ptr() as *mut c_void, 0) };
-        }
-
-        #[cfg(any(target_os = "macos", target_os = "ios"))]
-        fn process_native_font_handle(key: FontKey, handle: &NativeFontHandle) {
-            let font = match CGFont::from_name(&CFString::new(&handle.name)) {
-                Ok(font) => font,
-                Err(_) => {
-                    // If for some reason we failed to load a font descriptor, then our
-                    // only options are to either abort or substitute a fallback font.
-                    // It is preferable to use a fallback font instead so that rendering
-                    // can at least still proceed in some fashion without erroring.
-                    // Lucida Grande is the fallback font in Gecko, so use that here.
-                    CGFont::from_name(&CFString::from_static_string("Lucida Grande"))
-                        .expect("Failed reading font descriptor and could not load fallback font")
-                },
+    /// Blobs are added, merged and deleted in the recorded order, recorded fonts are
+    /// registered, and every recorded batch of tiles is rasterized. Returns the results
+    /// of all the batches, in order.
+    pub fn replay_recording(&mut self, dir: &Path) -> io::Result<Vec<(BlobImageRequest, BlobImageResult)>> {
+        fn decode<T>(event: bincode::Result<T>) -> io::Result<T> {
+            event.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        }
+
+        let mut events: Vec<PathBuf> = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<_>>()?;
+        events.sort();
+
+        let mut results = Vec::new();
+        for path in events {
+            let kind = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(stem) => stem.splitn(2, '-').nth(1).unwrap_or("").to_string(),
+                None => continue,
             };
-            unsafe { AddNativeFontHandle(key, font.as_ptr() as *mut c_void, 0) };
+            let event = fs::read(&path)?;
+            match &kind[..] {
+                "add" => {
+                    let (key, visible_rect, tile_size, data): (BlobImageKey, DeviceIntRect, TileSize, Vec<u8>) =
+                        decode(bincode::deserialize(&event))?;
+                    self.add(key, Arc::new(data), &visible_rect, tile_size);
+                },
+                "update" => {
+                    let (key, visible_rect, dirty_rect, data): (BlobImageKey, DeviceIntRect, BlobDirtyRect, Vec<u8>) =
+                        decode(bincode::deserialize(&event))?;
+                    self.update(key, Arc::new(data), &visible_rect, &dirty_rect);
+                },
+                "delete" => {
+                    let key: BlobImageKey = decode(bincode::deserialize(&event))?;
+                    self.delete(key);
+                },
+                "font-data" => {
+                    let (key, data, index): (FontKey, Vec<u8>, u32) = decode(bincode::deserialize(&event))?;
+                    if !self.fonts.has_font_data(key) {
+                        self.fonts.add_font_data(key, &Arc::new(data), index);
+                    }
+                },
+                "font-native" => {
+                    let key: FontKey = decode(bincode::deserialize(&event))?;
+                    // The items using the font fail to replay, as they would if it was
+                    // missing from the system, and the font is reported by `failed_fonts`.
+                    warn!("Blob recording {:?} uses native font {:?}, which can't be replayed", dir, key);
+                    self.failed_fonts.fonts.lock().unwrap().insert(key, BlobFontError::MissingFontData);
+                },
+                "font-instance" => {
+                    let (key, font_key, size, options, platform_options, variations): (
+                        FontInstanceKey,
+                        FontKey,
+                        f32,
+                        Option<FontInstanceOptions>,
+                        Option<FontInstancePlatformOptions>,
+                        Vec<FontVariation>,
+                    ) = decode(bincode::deserialize(&event))?;
+                    if self.failed_fonts.fonts.lock().unwrap().contains_key(&font_key) {
+                        continue;
+                    }
+                    self.fonts.add_font_instance(
+                        key,
+                        font_key,
+                        size,
+                        options.as_ref(),
+                        platform_options.as_ref(),
+                        &variations,
+                    );
+                },
+                "raster" => {
+                    let requests: Vec<(BlobImageKey, TileOffset, LayoutIntRect, ImageFormat, BlobDirtyRect)> =
+                        decode(bincode::deserialize(&event))?;
+                    let requests: Vec<_> = requests
+                        .into_iter()
+                        .map(|(key, tile, rect, format, dirty_rect)| BlobImageParams {
+                            request: BlobImageRequest { key, tile },
+                            descriptor: BlobImageDescriptor { rect, format },
+                            dirty_rect,
+                        })
+                        .collect();
+                    let mut rasterizer = self.create_blob_rasterizer();
+                    results.extend(rasterizer.rasterize(&requests, false, &mut BlobTilePool::new()));
+                },
+                _ => warn!("Skipping unknown blob event {:?}", path),
+            }
         }
+        Ok(results)
+    }
 
-        #[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows")))]
-        fn process_native_font_handle(key: FontKey, handle: &NativeFontHandle) {
-            let cstr = CString::new(handle.path.as_os_str().as_bytes()).unwrap();
-            unsafe { AddNativeFontHandle(key, cstr.as_ptr() as *mut c_void, handle.index) };
+    /// Sets the cache of rasterized tiles to use, if any. There is none by default.
+    pub fn set_tile_cache(&mut self, tile_cache: Option<Arc<BlobTileCache>>) {
+        self.tile_cache = tile_cache;
+    }
+
+    /// Sets the scheduling policy of the rasterizers, or `None` to use the host's, which
+    /// is the default.
+    pub fn set_scheduling_policy(&mut self, policy: Option<BlobSchedulingPolicy>) {
+        self.scheduling_policy = policy;
+    }
+
+    /// Returns where the tile timings are recorded, when the scheduling policy asks for
+    /// them. They are shared with the handlers created by `create_similar`.
+    pub fn raster_timings(&self) -> Arc<BlobRasterTimings> {
+        Arc::clone(&self.raster_timings)
+    }
+
+    /// Sets what to produce for the tiles of blobs that fail to replay.
+    pub fn set_replay_fallback(&mut self, fallback: BlobReplayFallback) {
+        self.fallback = fallback;
+    }
+
+    /// Returns where the fonts used by blobs that couldn't be registered are kept. They
+    /// are shared with the handlers created by `create_similar`.
+    pub fn failed_fonts(&self) -> Arc<FailedBlobFonts> {
+        Arc::clone(&self.failed_fonts)
+    }
+
+    /// Returns the number of tiles that failed to replay so far.
+    pub fn replay_failure_count(&self) -> usize {
+        self.failures.count.load(Ordering::Relaxed)
+    }
+
+    /// Does early preprocessing of a blob's resources.
+    ///
+    /// Currently just sets up fonts found in the blob.
+    fn prepare_request(&mut self, items: &[StoredItem], resources: &dyn BlobImageResources) {
+        /// Registers the data of a font, unless it already is.
+        fn register_font(
+            key: FontKey,
+            registry: &dyn BlobFontRegistry,
+            recorder: Option<&BlobRecorder>,
+            resources: &dyn BlobImageResources,
+        ) -> Result<(), BlobFontError> {
+            let template = resources.get_font_data(key);
+            match (recorder, template.as_ref()) {
+                (Some(recorder), Some(&FontTemplate::Raw(ref data, index))) => {
+                    recorder.record_font_data(key, data, index)
+                },
+                (Some(recorder), Some(&FontTemplate::Native(_))) => recorder.record_native_font(key),
+                _ => {},
+            }
+            if registry.has_font_data(key) {
+                return Ok(());
+            }
+            match template.ok_or(BlobFontError::MissingFontData)? {
+                FontTemplate::Raw(ref data, index) => {
+                    registry.add_font_data(key, data, index);
+                    Ok(())
+                },
+                FontTemplate::Native(ref handle) => registry.add_native_font(key, handle),
+            }
         }
 
         fn process_fonts(
-            mut extra_data: BufReader,
+            fonts: Vec<BlobFont>,
+            registry: &dyn BlobFontRegistry,
+            recorder: Option<&BlobRecorder>,
             resources: &dyn BlobImageResources,
+            failed_fonts: &mut HashMap<FontKey, BlobFontError>,
             unscaled_fonts: &mut Vec<FontKey>,
             scaled_fonts: &mut Vec<FontInstanceKey>,
         ) {
-            let font_count = extra_data.read_usize();
-            for _ in 0..font_count {
-                let font = extra_data.read_blob_font();
+            for font in fonts {
                 if scaled_fonts.contains(&font.font_instance_key) {
                     continue;
                 }
@@ -809,46 +2523,1295 @@ impl Moz2dBlobImageHandler {
                 if let Some(instance) = resources.get_font_instance_data(font.font_instance_key) {
                     if !unscaled_fonts.contains(&instance.font_key) {
                         unscaled_fonts.push(instance.font_key);
-                        if !unsafe { HasFontData(instance.font_key) } {
-                            let template = resources.get_font_data(instance.font_key).unwrap();
-                            match template {
-                                FontTemplate::Raw(ref data, ref index) => unsafe {
-                                    AddFontData(instance.font_key, data.as_ptr(), data.len(), *index, data);
-                                },
-                                FontTemplate::Native(ref handle) => {
-                                    process_native_font_handle(instance.font_key, handle);
-                                },
+                        if !failed_fonts.contains_key(&instance.font_key) {
+                            if let Err(err) = register_font(instance.font_key, registry, recorder, resources) {
+                                host().critical_note(&format!(
+                                    "Failed to register font {:?} for blob images: {:?}",
+                                    instance.font_key, err
+                                ));
+                                failed_fonts.insert(instance.font_key, err);
                             }
                         }
                     }
-                    unsafe {
-                        AddBlobFont(
-                            font.font_instance_key,
-                            instance.font_key,
-                            instance.size,
-                            instance.options.as_ref(),
-                            instance.platform_options.as_ref(),
-                            instance.variations.as_ptr(),
-                            instance.variations.len(),
-                        );
+                    // The items using instances of fonts that failed fail to replay,
+                    // rather than crash.
+                    if failed_fonts.contains_key(&instance.font_key) {
+                        continue;
+                    }
+                    if let Some(recorder) = recorder {
+                        recorder.record_font_instance(font.font_instance_key, &instance);
                     }
+                    registry.add_font_instance(
+                        font.font_instance_key,
+                        instance.font_key,
+                        instance.size,
+                        instance.options.as_ref(),
+                        instance.platform_options.as_ref(),
+                        &instance.variations,
+                    );
                 }
             }
         }
 
-        {
-            let mut index = BlobReader::new(blob);
+        // The blob was validated when it was added or updated, so errors here can
+        // only be reached by a bug in the merging code. Just stop processing fonts.
+        let fonts = &*self.fonts;
+        let recorder = self.recorder.as_ref().map(|recorder| &**recorder);
+        let mut failed_fonts = self.failed_fonts.fonts.lock().unwrap();
+        let _ = (|| -> Result<(), BlobError> {
             let mut unscaled_fonts = Vec::new();
             let mut scaled_fonts = Vec::new();
-            while index.reader.pos < index.reader.buf.len() {
-                let e = index.read_entry();
+            for item in items {
                 process_fonts(
-                    BufReader::new(&blob[e.end..e.extra_end]),
+                    read_blob_fonts(&item.payload[item.extra_begin..])?,
+                    fonts,
+                    recorder,
                     resources,
+                    &mut failed_fonts,
                     &mut unscaled_fonts,
                     &mut scaled_fonts,
                 );
             }
+            Ok(())
+        })();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A xorshift generator, so that failures can be reproduced from their seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn new(seed: u64) -> Rng {
+            Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+        }
+
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
         }
     }
+
+    /// A display item, as far as blob merging is concerned.
+    #[derive(Clone, Debug)]
+    struct Item {
+        /// Identifies the item's recording, so that misplaced items are detected.
+        id: u32,
+        bounds: DeviceIntRect,
+        font_count: usize,
+    }
+
+    /// Makes up bounds on a coarse grid, so that items often share bounds or are empty.
+    fn random_bounds(rng: &mut Rng) -> DeviceIntRect {
+        let x = rng.below(8) as i32 * 16;
+        let y = rng.below(8) as i32 * 16;
+        let width = rng.below(4) as i32 * 16;
+        let height = rng.below(4) as i32 * 16;
+        DeviceIntRect {
+            min: point2(x, y),
+            max: point2(x + width, y + height),
+        }
+    }
+
+    fn random_item(rng: &mut Rng, id: u32) -> Item {
+        Item {
+            id,
+            bounds: random_bounds(rng),
+            font_count: rng.below(3),
+        }
+    }
+
+    /// Writes an item's recording, returning its size along with the size of its extra data.
+    fn record_item(item: &Item, data: &mut Vec<u8>) -> usize {
+        for _ in 0..(item.id % 4 + 1) {
+            data.extend_from_slice(&item.id.to_ne_bytes());
+        }
+        let extra_begin = data.len();
+        data.extend_from_slice(convert_to_bytes(&item.font_count));
+        for i in 0..item.font_count {
+            let font = BlobFont {
+                font_instance_key: FontInstanceKey::new(IdNamespace(1), item.id * 4 + i as u32),
+                scaled_font_ptr: u64::from(item.id),
+            };
+            data.extend_from_slice(convert_to_bytes(&font));
+        }
+        data.len() - extra_begin
+    }
+
+    /// Writes a blob in which only the items accepted by `recorded` have recordings.
+    fn write_blob(items: &[Item], mut recorded: impl FnMut(&Item) -> bool) -> Vec<u8> {
+        let mut writer = BlobWriter::new();
+        for item in items {
+            if recorded(item) {
+                let mut data = Vec::new();
+                let extra_size = record_item(item, &mut data);
+                writer.new_entry(extra_size, item.bounds, &data);
+            } else {
+                writer.new_entry(0, item.bounds, &[]);
+            }
+        }
+        writer.finish()
+    }
+
+    /// Writes stored items back into a blob.
+    fn write_stored_blob(items: &[StoredItem]) -> Vec<u8> {
+        let mut writer = BlobWriter::new();
+        for item in items {
+            writer.new_entry(item.payload.len() - item.extra_begin, item.bounds, &item.payload);
+        }
+        writer.finish()
+    }
+
+    /// Checks `merge_blob_images` against a model of the retained display list: the old list
+    /// is edited by inserting, removing and replacing items, the dirty rect covers the edits
+    /// that stay in view, and the merge must produce the new list with every item recorded.
+    /// The reported changed rect must cover every visible change.
+    #[test]
+    fn merge_matches_reference() {
+        for seed in 0..4000 {
+            let mut rng = Rng::new(seed);
+            let mut next_id = 0;
+            let mut old_items = Vec::new();
+            for _ in 0..rng.below(12) {
+                old_items.push(random_item(&mut rng, next_id));
+                next_id += 1;
+            }
+
+            let old_visible_rect = random_bounds(&mut rng).union(&random_bounds(&mut rng));
+            let new_visible_rect = random_bounds(&mut rng).union(&random_bounds(&mut rng));
+            let preserved_rect = old_visible_rect.intersection_unchecked(&new_visible_rect);
+
+            let mut new_items = old_items.clone();
+            let mut changed_items = Vec::new();
+            for _ in 0..rng.below(4) {
+                match rng.below(3) {
+                    0 if !new_items.is_empty() => {
+                        changed_items.push(new_items.remove(rng.below(new_items.len())));
+                    },
+                    1 if !new_items.is_empty() => {
+                        let index = rng.below(new_items.len());
+                        new_items[index].id = next_id;
+                        changed_items.push(new_items[index].clone());
+                    },
+                    _ => {
+                        let item = random_item(&mut rng, next_id);
+                        changed_items.push(item.clone());
+                        new_items.insert(rng.below(new_items.len() + 1), item);
+                    },
+                }
+                next_id += 1;
+            }
+            // Edits out of view don't need to be in the dirty rect.
+            let dirty_rect = changed_items
+                .iter()
+                .map(|item| item.bounds)
+                .filter(|bounds| !bounds.intersection_unchecked(&preserved_rect).is_empty())
+                .fold(DeviceIntRect::zero(), |dirty, bounds| union_rects(&dirty, &bounds));
+
+            let old_blob = write_blob(&old_items, |_| true);
+            let partial_blob = write_blob(&new_items, |item| {
+                dirty_rect.contains_box(&item.bounds.intersection_unchecked(&preserved_rect))
+            });
+            assert_eq!(validate_blob(&partial_blob), Ok(()), "seed {}", seed);
+
+            let (merged, changed_rect) =
+                merge_blob_images(&old_blob, &partial_blob, dirty_rect, old_visible_rect, new_visible_rect).unwrap();
+            assert_eq!(merged, write_blob(&new_items, |_| true), "seed {}", seed);
+            for item in &changed_items {
+                let visible_bounds = item.bounds.intersection_unchecked(&new_visible_rect);
+                assert!(
+                    visible_bounds.is_empty() || changed_rect.contains_box(&visible_bounds),
+                    "seed {}",
+                    seed
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn offscreen_changes_are_not_repainted() {
+        let visible_rect = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(100, 100),
+        };
+        let onscreen = Item {
+            id: 0,
+            bounds: DeviceIntRect {
+                min: point2(10, 10),
+                max: point2(20, 20),
+            },
+            font_count: 0,
+        };
+        let offscreen = Item {
+            id: 1,
+            bounds: DeviceIntRect {
+                min: point2(200, 200),
+                max: point2(300, 300),
+            },
+            font_count: 0,
+        };
+        let old_blob = write_blob(&[onscreen.clone(), offscreen], |_| true);
+        let partial_blob = write_blob(&[onscreen.clone()], |_| false);
+        let (merged, changed_rect) = merge_blob_images(
+            &old_blob,
+            &partial_blob,
+            DeviceIntRect::zero(),
+            visible_rect,
+            visible_rect,
+        )
+        .unwrap();
+        assert_eq!(merged, write_blob(&[onscreen], |_| true));
+        assert!(changed_rect.is_empty());
+    }
+
+    #[test]
+    fn exposed_rects() {
+        let rect = |x0, y0, x1, y1| DeviceIntRect {
+            min: point2(x0, y0),
+            max: point2(x1, y1),
+        };
+        // Scrolling down exposes the bottom rows.
+        assert_eq!(
+            exposed_rect(&rect(0, 0, 100, 100), &rect(0, 30, 100, 130)),
+            rect(0, 100, 100, 130)
+        );
+        // Scrolling left exposes the left columns.
+        assert_eq!(
+            exposed_rect(&rect(0, 0, 100, 100), &rect(-20, 0, 80, 100)),
+            rect(-20, 0, 0, 100)
+        );
+        // Scrolling diagonally exposes an L shape.
+        assert_eq!(
+            exposed_rect(&rect(0, 0, 100, 100), &rect(10, 10, 110, 110)),
+            rect(10, 10, 110, 110)
+        );
+        // Jumping away exposes everything, and shrinking exposes nothing.
+        assert_eq!(
+            exposed_rect(&rect(0, 0, 100, 100), &rect(200, 0, 300, 100)),
+            rect(200, 0, 300, 100)
+        );
+        assert!(exposed_rect(&rect(0, 0, 100, 100), &rect(10, 10, 90, 90)).is_empty());
+    }
+
+    #[test]
+    fn merge_reports_missing_items() {
+        let mut rng = Rng::new(0);
+        let item = random_item(&mut rng, 0);
+        let other = Item {
+            bounds: DeviceIntRect {
+                min: point2(200, 200),
+                max: point2(300, 300),
+            },
+            ..item.clone()
+        };
+        let everything = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(1000, 1000),
+        };
+        let old_blob = write_blob(&[item], |_| true);
+        let partial_blob = write_blob(&[other], |_| false);
+        assert_eq!(
+            merge_blob_images(&old_blob, &partial_blob, DeviceIntRect::zero(), everything, everything),
+            Err(BlobError::MissingItem)
+        );
+    }
+
+    #[test]
+    fn read_fonts() {
+        let item = Item {
+            id: 7,
+            bounds: DeviceIntRect::zero(),
+            font_count: 2,
+        };
+        let mut data = Vec::new();
+        let extra_size = record_item(&item, &mut data);
+        let fonts = read_blob_fonts(&data[data.len() - extra_size..]).unwrap();
+        assert_eq!(fonts.len(), 2);
+        assert_eq!(fonts[1].font_instance_key, FontInstanceKey::new(IdNamespace(1), 29));
+        assert_eq!(fonts[1].scaled_font_ptr, 7);
+
+        assert_eq!(
+            read_blob_fonts(&data[data.len() - extra_size..data.len() - 1]).err(),
+            Some(BlobError::TruncatedFonts)
+        );
+
+        // Items that aren't recorded in a partial blob have no extra data.
+        assert!(read_blob_fonts(&[]).unwrap().is_empty());
+        let partial_blob = write_blob(&[item.clone()], |_| false);
+        assert_eq!(validate_blob(&partial_blob), Ok(()));
+    }
+
+    #[test]
+    fn unknown_keys_are_ignored() {
+        let workers = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let mut handler = Moz2dBlobImageHandler::with_backend(
+            Arc::clone(&workers),
+            workers,
+            Arc::new(TestBackend {
+                items: std::sync::Mutex::new(Vec::new()),
+            }),
+            Arc::new(Moz2dFontRegistry::default()),
+        );
+        let key = BlobImageKey(ImageKey::new(IdNamespace(1), 1));
+        let visible_rect = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(64, 64),
+        };
+        let item = Item {
+            id: 1,
+            bounds: visible_rect,
+            font_count: 0,
+        };
+        let blob = Arc::new(write_blob(&[item], |_| true));
+        handler.update(key, Arc::clone(&blob), &visible_rect, &DirtyRect::All);
+        assert!(handler.blob_commands.is_empty());
+        handler.delete(key);
+
+        handler.add(key, blob, &visible_rect, 64);
+        handler.delete(key);
+        assert!(handler.blob_commands.is_empty());
+    }
+
+    #[test]
+    fn inspect_entries() {
+        let item = Item {
+            id: 7,
+            bounds: DeviceIntRect {
+                min: point2(1, 2),
+                max: point2(3, 4),
+            },
+            font_count: 2,
+        };
+        let blob = write_blob(&[item.clone()], |_| true);
+        let entries = inspect_blob(&blob).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].bounds, item.bounds);
+        assert_eq!(entries[0].data_len, 4 * mem::size_of::<u32>());
+        assert_eq!(
+            entries[0].extra_len,
+            mem::size_of::<usize>() + 2 * mem::size_of::<BlobFont>()
+        );
+        assert_eq!(entries[0].fonts.len(), 2);
+        assert_eq!(
+            entries[0].fonts[1].font_instance_key,
+            FontInstanceKey::new(IdNamespace(1), 29)
+        );
+
+        let json = inspect_blob_json(&blob);
+        assert!(json.starts_with("{\"items\":[{\"bounds\":[1,2,3,4],\"data_len\":16,"));
+        assert!(json.contains("{\"instance_key\":[1,29],\"scaled_font\":\"0x7\"}"));
+        assert_eq!(inspect_blob_json(&[0; 2]), "{\"error\":\"TooShort\"}");
+    }
+
+    #[test]
+    fn validate_rejects_malformed_blobs() {
+        let usize_size = mem::size_of::<usize>();
+        let bounds = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(10, 10),
+        };
+        let item = Item {
+            id: 1,
+            bounds,
+            font_count: 1,
+        };
+        let blob = write_blob(&[item.clone(), item], |_| true);
+        let index_offset = blob.len() - usize_size;
+        let entry_size = 2 * usize_size + 16;
+        assert_eq!(validate_blob(&blob), Ok(()));
+
+        assert_eq!(validate_blob(&blob[..usize_size - 1]), Err(BlobError::TooShort));
+
+        let mut bad = blob.clone();
+        bad[index_offset..].copy_from_slice(convert_to_bytes(&(index_offset + 1)));
+        assert_eq!(validate_blob(&bad), Err(BlobError::BadIndexOffset));
+
+        let mut bad = blob[..index_offset - 1].to_vec();
+        bad.extend_from_slice(&blob[index_offset..]);
+        assert_eq!(validate_blob(&bad), Err(BlobError::TruncatedIndex));
+
+        // Make the second item end before the first one.
+        let second_entry = index_offset - entry_size;
+        let mut bad = blob.clone();
+        bad[second_entry..second_entry + usize_size].copy_from_slice(convert_to_bytes(&0usize));
+        assert_eq!(validate_blob(&bad), Err(BlobError::NonMonotonicOffsets));
+
+        // Make the first item's extra data run past the data.
+        let first_entry = index_offset - 2 * entry_size;
+        let mut bad = blob.clone();
+        bad[first_entry + usize_size..first_entry + 2 * usize_size]
+            .copy_from_slice(convert_to_bytes(&(index_offset + 1)));
+        assert_eq!(validate_blob(&bad), Err(BlobError::NonMonotonicOffsets));
+
+        // Swap min.x and max.x.
+        let mut bad = blob.clone();
+        bad[first_entry + 2 * usize_size..first_entry + 2 * usize_size + 4].copy_from_slice(&11i32.to_ne_bytes());
+        assert_eq!(validate_blob(&bad), Err(BlobError::InvertedBounds));
+
+        // Claim more fonts than the extra data holds.
+        let mut reader = BlobReader::new(&blob).unwrap();
+        let entry = reader.read_entry().unwrap();
+        let mut bad = blob.clone();
+        bad[entry.end..entry.end + usize_size].copy_from_slice(convert_to_bytes(&2usize));
+        assert_eq!(validate_blob(&bad), Err(BlobError::TruncatedFonts));
+    }
+
+    /// Corrupts valid blobs at random and checks that validating and merging them never panics.
+    #[test]
+    fn corrupted_blobs_do_not_panic() {
+        let everything = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(1000, 1000),
+        };
+        for seed in 0..2000 {
+            let mut rng = Rng::new(seed);
+            let items: Vec<Item> = (0..rng.below(6) as u32).map(|id| random_item(&mut rng, id)).collect();
+            let old_blob = write_blob(&items, |_| true);
+            let mut new_blob = write_blob(&items, |_| rng.below(2) == 0);
+            for _ in 0..(rng.below(4) + 1) {
+                let index = rng.below(new_blob.len());
+                new_blob[index] = rng.next() as u8;
+            }
+            if rng.below(4) == 0 {
+                new_blob.truncate(rng.below(new_blob.len()));
+            }
+            if validate_blob(&new_blob).is_ok() {
+                let dirty_rect = random_bounds(&mut rng);
+                if let Ok((merged, _)) = merge_blob_images(&old_blob, &new_blob, dirty_rect, everything, everything) {
+                    assert_eq!(validate_blob(&merged), Ok(()), "seed {}", seed);
+                }
+            }
+        }
+    }
+
+    /// A backend that paints every tile white, and remembers which items it saw.
+    struct TestBackend {
+        items: std::sync::Mutex<Vec<DeviceIntRect>>,
+    }
+
+    impl BlobRenderBackend for TestBackend {
+        fn render_tile(&self, items: BlobTileItems, _params: &BlobTileParams, output: &mut [u8]) -> bool {
+            self.items
+                .lock()
+                .unwrap()
+                .extend(items.iter().map(|item| item.bounds));
+            for byte in output {
+                *byte = 0xFF;
+            }
+            true
+        }
+    }
+
+    #[test]
+    fn handler_rasterizes_with_backend() {
+        let workers = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let backend = Arc::new(TestBackend {
+            items: std::sync::Mutex::new(Vec::new()),
+        });
+        let mut handler = Moz2dBlobImageHandler::with_backend(
+            Arc::clone(&workers),
+            workers,
+            Arc::clone(&backend) as Arc<dyn BlobRenderBackend>,
+            Arc::new(Moz2dFontRegistry::default()),
+        );
+
+        let item = Item {
+            id: 1,
+            bounds: DeviceIntRect {
+                min: point2(0, 0),
+                max: point2(16, 16),
+            },
+            font_count: 0,
+        };
+        let key = BlobImageKey(ImageKey::new(IdNamespace(1), 1));
+        let visible_rect = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(64, 64),
+        };
+        handler.add(key, Arc::new(write_blob(&[item.clone()], |_| true)), &visible_rect, 64);
+
+        let params = |key| BlobImageParams {
+            request: BlobImageRequest {
+                key,
+                tile: point2(0, 0),
+            },
+            descriptor: BlobImageDescriptor {
+                rect: visible_rect.cast_unit(),
+                format: ImageFormat::BGRA8,
+            },
+            dirty_rect: DirtyRect::All,
+        };
+        let unknown_key = BlobImageKey(ImageKey::new(IdNamespace(1), 2));
+        let mut rasterizer = handler.create_blob_rasterizer();
+        let results = rasterizer.rasterize(&[params(key), params(unknown_key)], false, &mut BlobTilePool::new());
+
+        assert_eq!(results.len(), 2);
+        for (request, result) in results {
+            if request.key == key {
+                let image = result.unwrap();
+                assert!(image.data.iter().all(|&byte| byte == 0xFF));
+            } else {
+                assert!(matches!(result, Err(BlobImageError::InvalidKey)));
+            }
+        }
+        assert_eq!(*backend.items.lock().unwrap(), vec![item.bounds]);
+    }
+
+    /// A backend that always fails to replay, and counts its attempts.
+    struct FailingBackend {
+        attempts: AtomicUsize,
+    }
+
+    impl BlobRenderBackend for FailingBackend {
+        fn render_tile(&self, _items: BlobTileItems, _params: &BlobTileParams, _output: &mut [u8]) -> bool {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    #[test]
+    fn replay_failures_are_quarantined() {
+        let workers = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let backend = Arc::new(FailingBackend {
+            attempts: AtomicUsize::new(0),
+        });
+        let mut handler = Moz2dBlobImageHandler::with_backend(
+            Arc::clone(&workers),
+            workers,
+            Arc::clone(&backend) as Arc<dyn BlobRenderBackend>,
+            Arc::new(Moz2dFontRegistry::default()),
+        );
+        handler.set_replay_fallback(BlobReplayFallback::Checkerboard);
+
+        let item = Item {
+            id: 1,
+            bounds: DeviceIntRect {
+                min: point2(0, 0),
+                max: point2(16, 16),
+            },
+            font_count: 0,
+        };
+        let blob = Arc::new(write_blob(&[item], |_| true));
+        let key = BlobImageKey(ImageKey::new(IdNamespace(1), 1));
+        let visible_rect = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(16, 16),
+        };
+        handler.add(key, Arc::clone(&blob), &visible_rect, 16);
+
+        let params = BlobImageParams {
+            request: BlobImageRequest {
+                key,
+                tile: point2(0, 0),
+            },
+            descriptor: BlobImageDescriptor {
+                rect: visible_rect.cast_unit(),
+                format: ImageFormat::BGRA8,
+            },
+            dirty_rect: DirtyRect::All,
+        };
+        for _ in 0..3 {
+            let results = handler
+                .create_blob_rasterizer()
+                .rasterize(&[params], false, &mut BlobTilePool::new());
+            let image = results.into_iter().next().unwrap().1.unwrap();
+            // The first pixel is light gray, the one 8 pixels to the right is dark gray.
+            assert_eq!(&image.data[..4], &[0xCC, 0xCC, 0xCC, 0xFF]);
+            assert_eq!(&image.data[32..36], &[0x88, 0x88, 0x88, 0xFF]);
+        }
+        assert_eq!(backend.attempts.load(Ordering::Relaxed), 1);
+        assert_eq!(handler.replay_failure_count(), 1);
+
+        // Updating the blob gives it another chance.
+        handler.update(key, blob, &visible_rect, &DirtyRect::All);
+        handler
+            .create_blob_rasterizer()
+            .rasterize(&[params], false, &mut BlobTilePool::new());
+        assert_eq!(backend.attempts.load(Ordering::Relaxed), 2);
+
+        handler.set_replay_fallback(BlobReplayFallback::Error);
+        handler.delete(key);
+        handler.add(key, Arc::new(write_blob(&[], |_| true)), &visible_rect, 16);
+        let results = handler
+            .create_blob_rasterizer()
+            .rasterize(&[params], false, &mut BlobTilePool::new());
+        assert!(results[0].1.is_err());
+    }
+
+    #[test]
+    fn tile_cache_reuses_identical_tiles() {
+        let workers = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let backend = Arc::new(TestBackend {
+            items: std::sync::Mutex::new(Vec::new()),
+        });
+        let tile_cache = Arc::new(BlobTileCache::new(1024 * 1024));
+        let mut handler = Moz2dBlobImageHandler::with_backend(
+            Arc::clone(&workers),
+            workers,
+            Arc::clone(&backend) as Arc<dyn BlobRenderBackend>,
+            Arc::new(Moz2dFontRegistry::default()),
+        );
+        handler.set_tile_cache(Some(Arc::clone(&tile_cache)));
+
+        let item = |id, x| Item {
+            id,
+            bounds: DeviceIntRect {
+                min: point2(x, 0),
+                max: point2(x + 16, 16),
+            },
+            font_count: 0,
+        };
+        let visible_rect = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(64, 16),
+        };
+        // The same icon under two keys, and a blob that differs only outside of the first tile.
+        let icon = Arc::new(write_blob(&[item(1, 0)], |_| true));
+        let other = Arc::new(write_blob(&[item(1, 0), item(2, 32)], |_| true));
+        let keys: Vec<BlobImageKey> = (1..4).map(|i| BlobImageKey(ImageKey::new(IdNamespace(1), i))).collect();
+        handler.add(keys[0], Arc::clone(&icon), &visible_rect, 32);
+        handler.add(keys[1], icon, &visible_rect, 32);
+        handler.add(keys[2], other, &visible_rect, 32);
+
+        let params = |key, x| BlobImageParams {
+            request: BlobImageRequest {
+                key,
+                tile: point2(x / 32, 0),
+            },
+            descriptor: BlobImageDescriptor {
+                rect: LayoutIntRect {
+                    min: point2(x, 0),
+                    max: point2(x + 32, 16),
+                },
+                format: ImageFormat::BGRA8,
+            },
+            dirty_rect: DirtyRect::All,
+        };
+        for key in &keys {
+            handler
+                .create_blob_rasterizer()
+                .rasterize(&[params(*key, 0)], false, &mut BlobTilePool::new());
+        }
+        assert_eq!(backend.items.lock().unwrap().len(), 1);
+        assert_eq!(tile_cache.len(), 1);
+
+        handler
+            .create_blob_rasterizer()
+            .rasterize(&[params(keys[2], 32)], false, &mut BlobTilePool::new());
+        // Tiles are replayed from the items they contain only.
+        assert_eq!(backend.items.lock().unwrap().len(), 2);
+        assert_eq!(tile_cache.len(), 2);
+
+        // A cache that fits two tiles evicts the least recently used one.
+        let small_cache = BlobTileCache::new(2 * 32 * 16 * 4);
+        let key = |x| TileCacheKey {
+            items: Vec::new(),
+            render_rect: LayoutIntRect {
+                min: point2(x, 0),
+                max: point2(x + 32, 16),
+            },
+            format: ImageFormat::BGRA8,
+            visible_rect,
+        };
+        small_cache.insert(key(0), Arc::new(vec![0; 32 * 16 * 4]));
+        small_cache.insert(key(32), Arc::new(vec![0; 32 * 16 * 4]));
+        assert!(small_cache.get(&key(0)).is_some());
+        small_cache.insert(key(64), Arc::new(vec![0; 32 * 16 * 4]));
+        assert_eq!(small_cache.len(), 2);
+        assert!(small_cache.get(&key(0)).is_some());
+        assert!(small_cache.get(&key(32)).is_none());
+        assert!(small_cache.get(&key(64)).is_some());
+    }
+
+    #[test]
+    fn cost_grows_with_area_and_items() {
+        let item = |id, x| Item {
+            id,
+            bounds: DeviceIntRect {
+                min: point2(x, 0),
+                max: point2(x + 16, 16),
+            },
+            font_count: 0,
+        };
+        let blob = write_blob(&[item(1, 0), item(2, 0), item(3, 64)], |_| true);
+        let tile = |size| LayoutIntRect {
+            min: point2(0, 0),
+            max: point2(size, size),
+        };
+
+        assert_eq!(estimate_cost(&[], &tile(32)), 32 * 32);
+        let items = ItemStore::default().store_blob(&blob, &read_blob_index(&blob).unwrap());
+        assert_eq!(estimate_cost(&items, &tile(32)), 32 * 32 * 3);
+        assert_eq!(estimate_cost(&items, &tile(128)), 128 * 128 * 4);
+    }
+
+    #[test]
+    fn tile_keys_hold_the_items_in_the_tile() {
+        let item = |id, x, font_count| Item {
+            id,
+            bounds: DeviceIntRect {
+                min: point2(x, 0),
+                max: point2(x + 16, 16),
+            },
+            font_count,
+        };
+        let tile = LayoutIntRect {
+            min: point2(0, 0),
+            max: point2(32, 16),
+        };
+        // Each blob is stored separately, so that their items don't share payloads.
+        let key = |blob: &[u8]| {
+            let stored = ItemStore::default().store_blob(blob, &read_blob_index(blob).unwrap());
+            TileCacheKey {
+                items: BlobTileItems::new(&stored, &tile).stored_items().cloned().collect(),
+                render_rect: tile,
+                format: ImageFormat::BGRA8,
+                visible_rect: tile.cast_unit(),
+            }
+        };
+        let hash = |key: &TileCacheKey| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        let blob = write_blob(&[item(1, 0, 1), item(2, 64, 0)], |_| true);
+        let tile_items = key(&blob).items;
+        assert_eq!(tile_items.len(), 1);
+        assert_eq!(*tile_items[0].payload, blob[..tile_items[0].payload.len()]);
+        let same = key(&write_blob(&[item(1, 0, 1), item(3, 64, 0)], |_| true));
+        assert!(key(&blob) == same);
+        assert_eq!(hash(&key(&blob)), hash(&same));
+        // Same bounds, but a different recording or different fonts.
+        assert!(key(&blob) != key(&write_blob(&[item(5, 0, 1)], |_| true)));
+        assert!(key(&blob) != key(&write_blob(&[item(1, 0, 2)], |_| true)));
+        assert!(key(&blob) != key(&write_blob(&[item(1, 16, 1)], |_| true)));
+    }
+
+    #[test]
+    fn tiles_are_split_across_pools_and_timed() {
+        let workers = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap());
+        let workers_low_priority = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let mut handler = Moz2dBlobImageHandler::new(workers, workers_low_priority);
+
+        let key = BlobImageKey(ImageKey::new(IdNamespace(2), 1));
+        let visible_rect = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(128, 128),
+        };
+        let item = Item {
+            id: 1,
+            bounds: visible_rect,
+            font_count: 0,
+        };
+        handler.add(key, Arc::new(write_blob(&[item], |_| true)), &visible_rect, 32);
+
+        let tiles: Vec<_> = (0..16)
+            .map(|i| BlobImageParams {
+                request: BlobImageRequest {
+                    key,
+                    tile: point2(i % 4, i / 4),
+                },
+                descriptor: BlobImageDescriptor {
+                    rect: LayoutIntRect {
+                        min: point2((i % 4) as i32 * 32, (i / 4) as i32 * 32),
+                        max: point2((i % 4) as i32 * 32 + 32, (i / 4) as i32 * 32 + 32),
+                    },
+                    format: ImageFormat::BGRA8,
+                },
+                dirty_rect: DirtyRect::All,
+            })
+            .collect();
+
+        handler.set_scheduling_policy(Some(BlobSchedulingPolicy {
+            split_across_pools: true,
+            record_timings: true,
+            ..BlobSchedulingPolicy::DEFAULT
+        }));
+        let mut rasterizer = handler.create_blob_rasterizer();
+        let results = rasterizer.rasterize(&tiles, true, &mut BlobTilePool::new());
+        let timings = handler.raster_timings().take();
+
+        assert_eq!(results.len(), tiles.len());
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        let mut timed_tiles: Vec<_> = timings
+            .iter()
+            .map(|timing| {
+                assert!(timing.low_priority);
+                assert_eq!(timing.cost, 32 * 32 * 2);
+                (timing.tile.x, timing.tile.y)
+            })
+            .collect();
+        timed_tiles.sort();
+        let mut expected_tiles: Vec<_> = tiles
+            .iter()
+            .map(|tile| (tile.request.tile.x, tile.request.tile.y))
+            .collect();
+        expected_tiles.sort();
+        assert_eq!(timed_tiles, expected_tiles);
+
+        // Timings that weren't taken are dropped once they aren't recorded anymore.
+        handler.create_blob_rasterizer().rasterize(&tiles[..1], true, &mut BlobTilePool::new());
+        handler.set_scheduling_policy(Some(BlobSchedulingPolicy::DEFAULT));
+        handler.create_blob_rasterizer().rasterize(&tiles[..1], true, &mut BlobTilePool::new());
+        assert!(handler.raster_timings().take().is_empty());
+    }
+
+    /// Resources without any font, for blobs that don't use any.
+    struct NoResources;
+
+    impl BlobImageResources for NoResources {
+        fn get_font_data(&self, _key: FontKey) -> Option<FontTemplate> {
+            None
+        }
+
+        fn get_font_instance_data(&self, _key: FontInstanceKey) -> Option<FontInstanceData> {
+            None
+        }
+    }
+
+    #[test]
+    fn recordings_replay_merges_and_tiles() {
+        let dir = std::env::temp_dir().join(format!("blob-recording-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let workers = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let handler_with_backend = || {
+            let backend = Arc::new(TestBackend {
+                items: std::sync::Mutex::new(Vec::new()),
+            });
+            let handler = Moz2dBlobImageHandler::with_backend(
+                Arc::clone(&workers),
+                Arc::clone(&workers),
+                Arc::clone(&backend) as Arc<dyn BlobRenderBackend>,
+                Arc::new(Moz2dFontRegistry::default()),
+            );
+            (handler, backend)
+        };
+
+        let key = BlobImageKey(ImageKey::new(IdNamespace(3), 1));
+        let visible_rect = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(64, 64),
+        };
+        let items: Vec<_> = (0..4)
+            .map(|id| Item {
+                id,
+                bounds: DeviceIntRect {
+                    min: point2(id as i32 * 16, 0),
+                    max: point2(id as i32 * 16 + 16, 16),
+                },
+                font_count: 0,
+            })
+            .collect();
+        let params = [BlobImageParams {
+            request: BlobImageRequest {
+                key,
+                tile: point2(0, 0),
+            },
+            descriptor: BlobImageDescriptor {
+                rect: visible_rect.cast_unit(),
+                format: ImageFormat::BGRA8,
+            },
+            dirty_rect: DirtyRect::All,
+        }];
+
+        let (mut recorded, recorded_backend) = handler_with_backend();
+        recorded.set_recorder(Some(Arc::new(BlobRecorder::new(dir.clone()).unwrap())));
+        recorded.add(key, Arc::new(write_blob(&items, |_| true)), &visible_rect, 64);
+        recorded.update(
+            key,
+            Arc::new(write_blob(&items, |item| item.id == 2)),
+            &visible_rect,
+            &DirtyRect::Partial(items[2].bounds.cast_unit()),
+        );
+        recorded.prepare_resources(&NoResources, &params);
+        let results = recorded
+            .create_blob_rasterizer()
+            .rasterize(&params, false, &mut BlobTilePool::new());
+        assert_eq!(results.len(), 1);
+
+        let (mut replayed, replayed_backend) = handler_with_backend();
+        let replayed_results = replayed.replay_recording(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(replayed_results.len(), 1);
+        assert_eq!(replayed_results[0].0.key, key);
+        assert!(replayed_results[0].1.is_ok());
+        assert_eq!(
+            write_stored_blob(&replayed.blob_commands[&key].items),
+            write_stored_blob(&recorded.blob_commands[&key].items)
+        );
+        assert_eq!(
+            *replayed_backend.items.lock().unwrap(),
+            *recorded_backend.items.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn blobs_are_shared_between_keys_and_snapshots() {
+        let workers = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let mut handler = Moz2dBlobImageHandler::new(Arc::clone(&workers), workers);
+        let visible_rect = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(64, 64),
+        };
+        let items: Vec<_> = (0..3)
+            .map(|id| Item {
+                id,
+                bounds: DeviceIntRect {
+                    min: point2(id as i32 * 16, 0),
+                    max: point2(id as i32 * 16 + 16, 16),
+                },
+                font_count: 0,
+            })
+            .collect();
+        let first = BlobImageKey(ImageKey::new(IdNamespace(4), 1));
+        let second = BlobImageKey(ImageKey::new(IdNamespace(4), 2));
+        handler.add(first, Arc::new(write_blob(&items, |_| true)), &visible_rect, 64);
+        handler.add(second, Arc::new(write_blob(&items, |_| true)), &visible_rect, 64);
+        let shared = |a: &BlobCommand, b: &BlobCommand| {
+            a.items.len() == b.items.len()
+                && a.items.iter().zip(b.items.iter()).all(|(a, b)| Arc::ptr_eq(&a.payload, &b.payload))
+        };
+        assert!(shared(&handler.blob_commands[&first], &handler.blob_commands[&second]));
+
+        // Updating a blob doesn't change the rasterizers that were already created, and
+        // the other blobs are still shared with them.
+        let snapshot = Arc::clone(&handler.blob_commands);
+        let updated: Vec<_> = items[..2].to_vec();
+        handler.update(
+            first,
+            Arc::new(write_blob(&updated, |_| true)),
+            &visible_rect,
+            &DirtyRect::All,
+        );
+        assert!(!Arc::ptr_eq(&snapshot, &handler.blob_commands));
+        assert!(Arc::ptr_eq(&snapshot[&second], &handler.blob_commands[&second]));
+        assert_eq!(write_stored_blob(&snapshot[&first].items), write_blob(&items, |_| true));
+        assert_eq!(
+            write_stored_blob(&handler.blob_commands[&first].items),
+            write_blob(&updated, |_| true)
+        );
+
+        // The items that an update keeps are shared with the previous version.
+        handler.update(
+            second,
+            Arc::new(write_blob(&items, |item| item.id == 2)),
+            &visible_rect,
+            &DirtyRect::Partial(items[2].bounds.cast_unit()),
+        );
+        let (old, new) = (&snapshot[&second].items, &handler.blob_commands[&second].items);
+        assert!(Arc::ptr_eq(&old[0].payload, &new[0].payload));
+        assert!(Arc::ptr_eq(&old[1].payload, &new[1].payload));
+        assert_eq!(write_stored_blob(new), write_blob(&items, |_| true));
+    }
+
+    #[test]
+    fn changes_are_forgotten_once_rasterized() {
+        let workers = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let mut handler = Moz2dBlobImageHandler::new(Arc::clone(&workers), workers);
+        let visible_rect = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(64, 64),
+        };
+        let mut items: Vec<_> = (0..3)
+            .map(|id| Item {
+                id,
+                bounds: DeviceIntRect {
+                    min: point2(id as i32 * 16, 0),
+                    max: point2(id as i32 * 16 + 16, 16),
+                },
+                font_count: 0,
+            })
+            .collect();
+        let key = BlobImageKey(ImageKey::new(IdNamespace(4), 1));
+        handler.add(key, Arc::new(write_blob(&items, |_| true)), &visible_rect, 64);
+
+        // Replaces an item with another one with the same bounds.
+        let mut replace = |handler: &mut Moz2dBlobImageHandler, index: usize| {
+            items[index].id += 3;
+            let dirty_rect = items[index].bounds;
+            let blob = write_blob(&items, |item| item.bounds.intersects(&dirty_rect));
+            handler.update(
+                key,
+                Arc::new(blob),
+                &visible_rect,
+                &DirtyRect::Partial(dirty_rect.cast_unit()),
+            );
+            dirty_rect
+        };
+
+        let first = replace(&mut handler, 0);
+        let second = replace(&mut handler, 1);
+        assert_eq!(handler.blob_commands[&key].changed_rect, first.union(&second));
+
+        let rasterizer = handler.create_blob_rasterizer();
+        let third = replace(&mut handler, 2);
+        assert_eq!(handler.blob_commands[&key].changed_rect, third);
+        drop(rasterizer);
+    }
+
+    /// A registry that records the font instances, where native fonts only load if
+    /// `native_fonts_load` is set.
+    #[derive(Default)]
+    struct TestFonts {
+        native_fonts_load: bool,
+        instances: std::sync::Mutex<Vec<FontInstanceKey>>,
+    }
+
+    impl BlobFontRegistry for TestFonts {
+        fn has_font_data(&self, _key: FontKey) -> bool {
+            false
+        }
+        fn add_font_data(&self, _key: FontKey, _data: &Arc<Vec<u8>>, _index: u32) {}
+        fn add_native_font(&self, _key: FontKey, _handle: &NativeFontHandle) -> Result<(), BlobFontError> {
+            if self.native_fonts_load {
+                Ok(())
+            } else {
+                Err(BlobFontError::Unloadable)
+            }
+        }
+        fn delete_font_data(&self, _key: FontKey) {}
+        fn add_font_instance(
+            &self,
+            key: FontInstanceKey,
+            _font_key: FontKey,
+            _size: f32,
+            _options: Option<&FontInstanceOptions>,
+            _platform_options: Option<&FontInstancePlatformOptions>,
+            _variations: &[FontVariation],
+        ) {
+            self.instances.lock().unwrap().push(key);
+        }
+        fn delete_font_instance(&self, _key: FontInstanceKey) {}
+        fn clear_namespace(&self, _namespace: IdNamespace) {}
+    }
+
+    /// Resources where every font instance uses the same font, which is native and
+    /// missing from the system.
+    struct MissingNativeFont;
+
+    impl BlobImageResources for MissingNativeFont {
+        fn get_font_data(&self, key: FontKey) -> Option<FontTemplate> {
+            match key.1 {
+                1 => Some(FontTemplate::Native(missing_font())),
+                _ => None,
+            }
+        }
+
+        fn get_font_instance_data(&self, key: FontInstanceKey) -> Option<FontInstanceData> {
+            Some(FontInstanceData {
+                font_key: FontKey::new(IdNamespace(1), key.1 % 2),
+                size: 12.0,
+                options: None,
+                platform_options: None,
+                variations: Vec::new(),
+            })
+        }
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    fn missing_font() -> NativeFontHandle {
+        NativeFontHandle {
+            name: "Missing Font".to_string(),
+        }
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    fn missing_font() -> NativeFontHandle {
+        NativeFontHandle {
+            path: PathBuf::from("missing-font.ttf"),
+            index: 0,
+        }
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    fn fallback_font() -> NativeFontHandle {
+        NativeFontHandle {
+            name: "Fallback Font".to_string(),
+        }
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    fn fallback_font() -> NativeFontHandle {
+        NativeFontHandle {
+            path: PathBuf::from("fallback-font.ttf"),
+            index: 0,
+        }
+    }
+
+    #[test]
+    fn failed_fonts_are_reported_and_skipped() {
+        let workers = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let fonts = Arc::new(TestFonts::default());
+        let mut handler = Moz2dBlobImageHandler::with_backend(
+            Arc::clone(&workers),
+            workers,
+            Arc::new(Moz2dRenderBackend),
+            Arc::clone(&fonts) as Arc<dyn BlobFontRegistry>,
+        );
+        let visible_rect = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(64, 64),
+        };
+        // Each item uses an instance of font 0, which has no data, and an instance of
+        // font 1, which is native and missing.
+        let items: Vec<_> = (0..2)
+            .map(|id| Item {
+                id,
+                bounds: visible_rect,
+                font_count: 2,
+            })
+            .collect();
+        let key = BlobImageKey(ImageKey::new(IdNamespace(5), 1));
+        handler.add(key, Arc::new(write_blob(&items, |_| true)), &visible_rect, 64);
+        let params = [BlobImageParams {
+            request: BlobImageRequest {
+                key,
+                tile: point2(0, 0),
+            },
+            descriptor: BlobImageDescriptor {
+                rect: visible_rect.cast_unit(),
+                format: ImageFormat::BGRA8,
+            },
+            dirty_rect: DirtyRect::All,
+        }];
+        handler.prepare_resources(&MissingNativeFont, &params);
+
+        let mut failed_fonts = handler.failed_fonts().list();
+        failed_fonts.sort_by_key(|&(key, _)| key.1);
+        assert_eq!(
+            failed_fonts,
+            vec![
+                (FontKey::new(IdNamespace(1), 0), BlobFontError::MissingFontData),
+                (FontKey::new(IdNamespace(1), 1), BlobFontError::Unloadable),
+            ]
+        );
+        assert!(fonts.instances.lock().unwrap().is_empty());
+
+        handler.delete_font(FontKey::new(IdNamespace(1), 1));
+        assert_eq!(handler.failed_fonts().list().len(), 1);
+    }
+
+    #[test]
+    fn fonts_are_recorded_once_and_native_ones_fail_to_replay() {
+        let dir = std::env::temp_dir().join(format!("blob-font-recording-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let workers = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap());
+        let handler_with_fonts = || {
+            let fonts = Arc::new(TestFonts {
+                native_fonts_load: true,
+                ..TestFonts::default()
+            });
+            let handler = Moz2dBlobImageHandler::with_backend(
+                Arc::clone(&workers),
+                Arc::clone(&workers),
+                Arc::new(Moz2dRenderBackend),
+                Arc::clone(&fonts) as Arc<dyn BlobFontRegistry>,
+            );
+            (handler, fonts)
+        };
+        let visible_rect = DeviceIntRect {
+            min: point2(0, 0),
+            max: point2(64, 64),
+        };
+        // The odd font instances use font 1, which is native.
+        let items: Vec<_> = (0..2)
+            .map(|id| Item {
+                id,
+                bounds: visible_rect,
+                font_count: 2,
+            })
+            .collect();
+        let key = BlobImageKey(ImageKey::new(IdNamespace(5), 2));
+        let params = [BlobImageParams {
+            request: BlobImageRequest {
+                key,
+                tile: point2(0, 0),
+            },
+            descriptor: BlobImageDescriptor {
+                rect: visible_rect.cast_unit(),
+                format: ImageFormat::BGRA8,
+            },
+            dirty_rect: DirtyRect::All,
+        }];
+
+        let (mut recorded, recorded_fonts) = handler_with_fonts();
+        recorded.set_recorder(Some(Arc::new(BlobRecorder::new(dir.clone()).unwrap())));
+        recorded.add(key, Arc::new(write_blob(&items, |_| true)), &visible_rect, 64);
+        recorded.prepare_resources(&MissingNativeFont, &params);
+        recorded.prepare_resources(&MissingNativeFont, &params);
+        assert_eq!(recorded_fonts.instances.lock().unwrap().len(), 4);
+        let count_events = |kind: &str| {
+            fs::read_dir(&dir)
+                .unwrap()
+                .filter(|entry| {
+                    let name = entry.as_ref().unwrap().file_name();
+                    name.to_str().unwrap().ends_with(&format!("-{}.bin", kind))
+                })
+                .count()
+        };
+        assert_eq!(count_events("font-native"), 1);
+        assert_eq!(count_events("font-instance"), 2);
+
+        let (mut replayed, replayed_fonts) = handler_with_fonts();
+        replayed.replay_recording(&dir).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(
+            replayed.failed_fonts().list(),
+            vec![(FontKey::new(IdNamespace(1), 1), BlobFontError::MissingFontData)]
+        );
+        assert!(replayed_fonts.instances.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn missing_native_fonts_use_the_fallback() {
+        let key = FontKey::new(IdNamespace(1), 1);
+        // Only the fallback font loads.
+        let fallback = fallback_font();
+        let load = |handle: &NativeFontHandle| {
+            let name = format!("{:?}", handle);
+            if name == format!("{:?}", fallback) {
+                Some(name)
+            } else {
+                None
+            }
+        };
+
+        let registry = Moz2dFontRegistry::with_fallback_font(None);
+        assert_eq!(
+            registry.load_native_font(key, &missing_font(), &load),
+            Err(BlobFontError::Unloadable)
+        );
+        let registry = Moz2dFontRegistry::with_fallback_font(Some(fallback.clone()));
+        assert_eq!(
+            registry.load_native_font(key, &missing_font(), &load),
+            Ok(format!("{:?}", fallback))
+        );
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "windows", target_os = "android")))]
+    #[test]
+    fn fontconfig_provides_the_fallback_font() {
+        let font = platform_fallback_font().expect("fontconfig has no sans-serif font");
+        assert!(font.path.is_file());
+    }
+
+    #[test]
+    fn the_fallback_font_can_be_replaced() {
+        let font = missing_font();
+        set_blob_fallback_font(Some(font.clone()));
+        let registry = Moz2dFontRegistry::default();
+        set_blob_fallback_font(None);
+        assert_eq!(format!("{:?}", registry.fallback), format!("{:?}", Some(font)));
+    }
 }
@@ -3,27 +3,66 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::cell::RefCell;
-use std::ffi::OsString;
-use std::fs::{create_dir_all, read_dir, read_to_string, File};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, rename, File};
 use std::io::{Error, ErrorKind};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use memmap2::Mmap;
+#[cfg(feature = "gecko")]
 use nsstring::nsAString;
 use rayon::ThreadPool;
 use webrender::{ProgramBinary, ProgramCache, ProgramCacheObserver, ProgramSourceDigest};
 
 const MAX_LOAD_TIME_MS: u64 = 400;
 
-fn deserialize_program_binary(path: &Path) -> Result<Arc<ProgramBinary>, Error> {
-    let mut buf = vec![];
-    let mut file = File::open(path)?;
-    file.read_to_end(&mut buf)?;
+/// The default upper bound for the total size of the program binaries kept on disk.
+const DEFAULT_MAX_DISK_SIZE: u64 = 64 * 1024 * 1024;
 
+/// Why a program binary failed validation.
+///
+/// This is the payload of the `InvalidData` errors returned by
+/// `deserialize_program_binary_from_bytes`.
+#[derive(Clone, Copy, Debug)]
+enum InvalidProgramBinary {
+    Size,
+    Magic,
+    Hash,
+    Bincode,
+}
+
+impl fmt::Display for InvalidProgramBinary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            InvalidProgramBinary::Size => "File size is too small",
+            InvalidProgramBinary::Magic => "File data is invalid (magic+version)",
+            InvalidProgramBinary::Hash => "File data is invalid (hash)",
+            InvalidProgramBinary::Bincode => "Failed to deserialize ProgramBinary",
+        })
+    }
+}
+
+impl error::Error for InvalidProgramBinary {}
+
+impl From<InvalidProgramBinary> for Error {
+    fn from(reason: InvalidProgramBinary) -> Error {
+        Error::new(ErrorKind::InvalidData, reason)
+    }
+}
+
+fn deserialize_program_binary_from_bytes(buf: &[u8]) -> Result<Arc<ProgramBinary>, Error> {
     if buf.len() <= 8 + 4 {
-        return Err(Error::new(ErrorKind::InvalidData, "File size is too small"));
+        return Err(InvalidProgramBinary::Size.into());
     }
     let magic = &buf[0..4];
     let hash = &buf[4..8 + 4];
@@ -32,35 +71,28 @@ fn deserialize_program_binary(path: &Path) -> Result<Arc<ProgramBinary>, Error>
     // Check if magic + version are correct.
     let mv: u32 = bincode::deserialize(&magic).unwrap();
     if mv != MAGIC_AND_VERSION {
-        return Err(Error::new(
-            ErrorKind::InvalidData,
-            "File data is invalid (magic+version)",
-        ));
+        return Err(InvalidProgramBinary::Magic.into());
     }
 
     // Check if hash is correct
     let hash: u64 = bincode::deserialize(&hash).unwrap();
     let hash_data = fxhash::hash64(&data);
     if hash != hash_data {
-        return Err(Error::new(ErrorKind::InvalidData, "File data is invalid (hash)"));
+        return Err(InvalidProgramBinary::Hash.into());
     }
 
     // Deserialize ProgramBinary
     let binary = match bincode::deserialize(&data) {
         Ok(binary) => binary,
-        Err(_) => {
-            return Err(Error::new(
-                ErrorKind::InvalidData,
-                "Failed to deserialize ProgramBinary",
-            ))
-        },
+        Err(_) => return Err(InvalidProgramBinary::Bincode.into()),
     };
 
     Ok(Arc::new(binary))
 }
 
-#[cfg(target_os = "windows")]
-fn get_cache_path_from_prof_path(prof_path: &nsAString) -> Option<PathBuf> {
+/// Returns the root of the disk cache in the given profile directory, if any.
+#[cfg(all(feature = "gecko", target_os = "windows"))]
+pub fn get_cache_path_from_prof_path(prof_path: &nsAString) -> Option<PathBuf> {
     if prof_path.is_empty() {
         // Empty means that we do not use disk cache.
         return None;
@@ -75,8 +107,9 @@ fn get_cache_path_from_prof_path(prof_path: &nsAString) -> Option<PathBuf> {
     Some(cache_path)
 }
 
-#[cfg(not(target_os = "windows"))]
-fn get_cache_path_from_prof_path(prof_path: &nsAString) -> Option<PathBuf> {
+/// Returns the root of the disk cache in the given profile directory, if any.
+#[cfg(all(feature = "gecko", not(target_os = "windows")))]
+pub fn get_cache_path_from_prof_path(prof_path: &nsAString) -> Option<PathBuf> {
     if prof_path.is_empty() {
         // Empty means that we do not use disk cache.
         return None;
@@ -90,10 +123,516 @@ fn get_cache_path_from_prof_path(prof_path: &nsAString) -> Option<PathBuf> {
     Some(cache_path)
 }
 
+/// Identifies the device and driver that program binaries are compiled for.
+///
+/// Binaries are only valid for the driver that produced them, so the disk cache is
+/// partitioned by fingerprint to avoid loading binaries the driver will reject after
+/// a GPU switch or driver update.
+pub struct WrDeviceFingerprint {
+    pub gl_vendor: String,
+    pub gl_renderer: String,
+    pub gl_version: String,
+    pub is_software: bool,
+}
+
+impl WrDeviceFingerprint {
+    fn description(&self) -> String {
+        format!(
+            "{}{}{}{}{}{}{}",
+            self.gl_vendor,
+            WHITELIST_SEPARATOR,
+            self.gl_renderer,
+            WHITELIST_SEPARATOR,
+            self.gl_version,
+            WHITELIST_SEPARATOR,
+            if self.is_software { "software" } else { "native" },
+        )
+    }
+
+    /// The name of the cache subdirectory holding binaries for this fingerprint.
+    fn partition_name(&self) -> String {
+        format!("{:016x}", fxhash::hash64(self.description().as_bytes()))
+    }
+}
+
+/// Partitions that have not been used for this many launches are removed.
+const MAX_UNUSED_PARTITION_LAUNCHES: u64 = 8;
+
+/// Parses the partition index into the launch counter and the launch in which
+/// each partition was last used.
+fn parse_partition_index(contents: &str) -> (u64, HashMap<String, u64>) {
+    let mut lines = contents.split(PARTITION_INDEX_SEPARATOR);
+    let launch = lines.next().and_then(|line| line.trim().parse().ok()).unwrap_or(0);
+    let partitions = lines
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let partition = parts.next()?;
+            let last_launch = parts.next()?.parse().ok()?;
+            Some((partition.to_string(), last_launch))
+        })
+        .collect();
+    (launch, partitions)
+}
+
+/// Returns the directory holding the program binaries for `fingerprint`, recording
+/// that it is used in this launch.
+///
+/// Partitions that have not been used for `MAX_UNUSED_PARTITION_LAUNCHES` launches,
+/// and binaries left in `root` by versions of the cache that predate partitioning,
+/// are removed on a worker thread. Partitions missing from the index, for example
+/// because it was lost, are added back to it rather than removed.
+fn select_partition(root: &Path, fingerprint: &WrDeviceFingerprint, workers: &ThreadPool) -> PathBuf {
+    let index_path = root.join(PARTITION_INDEX_FILENAME);
+    let (launch, mut partitions) = read_to_string(&index_path)
+        .map(|contents| parse_partition_index(&contents))
+        .unwrap_or_default();
+    let launch = launch + 1;
+
+    for entry in read_dir(root).into_iter().flatten().filter_map(Result::ok) {
+        if let Ok(partition) = entry.file_name().into_string() {
+            if !partitions.contains_key(&partition) && entry.path().join(FINGERPRINT_FILENAME).is_file() {
+                partitions.insert(partition, launch);
+            }
+        }
+    }
+
+    let name = fingerprint.partition_name();
+    partitions.insert(name.clone(), launch);
+
+    let (keep, stale): (Vec<(String, u64)>, Vec<(String, u64)>) = partitions
+        .into_iter()
+        .partition(|(_, last_launch)| launch.saturating_sub(*last_launch) <= MAX_UNUSED_PARTITION_LAUNCHES);
+
+    let mut index = vec![launch.to_string()];
+    index.extend(
+        keep.iter()
+            .map(|(partition, last_launch)| format!("{} {}", partition, last_launch)),
+    );
+    if let Err(e) = write_file_atomically(&index_path, &[index.join(PARTITION_INDEX_SEPARATOR).as_bytes()]) {
+        error!("shader-cache: Failed to write partition index: {}", e);
+    }
+
+    let root = root.to_path_buf();
+    workers.spawn(move || {
+        for (partition, _) in stale {
+            info!("shader-cache: Removing unused partition {}", partition);
+            if let Err(e) = remove_dir_all::remove_dir_all(root.join(&partition)) {
+                error!("shader-cache: Failed to remove partition {}: {}", partition, e);
+            }
+        }
+
+        // Remove what versions of the cache that predate partitioning left in the root.
+        // Anything else is left alone, as it may belong to a partition.
+        for entry in read_dir(&root).into_iter().flatten().filter_map(Result::ok) {
+            let filename = entry.file_name();
+            let path = entry.path();
+            if filename == QUARANTINE_DIRNAME {
+                let _ = remove_dir_all::remove_dir_all(&path);
+            } else if path.is_file() && is_legacy_file(&filename) {
+                let _ = remove_file(&path);
+            }
+        }
+    });
+
+    let partition_path = root.join(&name);
+    if create_dir_all(&partition_path).is_ok() {
+        let _ = write_file_atomically(
+            &partition_path.join(FINGERPRINT_FILENAME),
+            &[fingerprint.description().as_bytes()],
+        );
+    }
+    partition_path
+}
+
+/// Used to give concurrent writes of the same file distinct temporary files.
+static NEXT_TEMP_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes `chunks` to the file at `path`, replacing it atomically.
+///
+/// The data is written to a temporary file in the same directory, which is synced
+/// to disk and then renamed over `path`. A crash mid-write therefore never leaves a
+/// truncated file behind, only a stale temporary file, which is cleaned up on the
+/// next startup.
+fn write_file_atomically(path: &Path, chunks: &[&[u8]]) -> Result<(), Error> {
+    let id = NEXT_TEMP_FILE_ID.fetch_add(1, Ordering::Relaxed);
+    let mut tmp_name = path.file_name().map(OsStr::to_os_string).unwrap_or_default();
+    tmp_name.push(format!(".{}.tmp", id));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = File::create(&tmp_path).and_then(|mut file| {
+        for chunk in chunks {
+            file.write_all(chunk)?;
+        }
+        file.sync_all()
+    });
+    if let Err(e) = result.and_then(|_| rename(&tmp_path, path)) {
+        let _ = remove_file(&tmp_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Milliseconds since the unix epoch, used to order cache entries by last use.
+fn system_time_to_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() * 1_000 + d.subsec_millis() as u64)
+}
+
+fn now_ms() -> u64 {
+    system_time_to_ms(SystemTime::now())
+}
+
+/// How recently and how often a program binary has been loaded, as persisted in the usage file.
+#[derive(Clone, Copy)]
+struct Usage {
+    last_used: u64,
+    use_count: u64,
+}
+
+/// The size and usage of a program binary stored on disk.
+struct DiskCacheEntry {
+    size: u64,
+    usage: Usage,
+}
+
+/// Book-keeping for the program binaries stored on disk.
+///
+/// This is shared between the render thread, which records when binaries are
+/// loaded, and the worker threads, which write new binaries and evict the least
+/// recently used ones whenever the cache grows beyond `max_size`.
+struct DiskCacheIndex {
+    entries: HashMap<String, DiskCacheEntry>,
+    total_size: u64,
+    max_size: u64,
+    /// Binaries listed in the startup whitelist. These are never evicted.
+    startup_shaders: HashSet<String>,
+}
+
+impl DiskCacheIndex {
+    fn new() -> Self {
+        DiskCacheIndex {
+            entries: HashMap::new(),
+            total_size: 0,
+            max_size: DEFAULT_MAX_DISK_SIZE,
+            startup_shaders: HashSet::new(),
+        }
+    }
+
+    fn insert(&mut self, filename: String, size: u64, usage: Usage) {
+        if let Some(old) = self.entries.insert(filename, DiskCacheEntry { size, usage }) {
+            self.total_size -= old.size;
+        }
+        self.total_size += size;
+    }
+
+    fn remove(&mut self, filename: &str) {
+        if let Some(old) = self.entries.remove(filename) {
+            self.total_size -= old.size;
+        }
+    }
+
+    fn touch(&mut self, filename: &str) {
+        if let Some(entry) = self.entries.get_mut(filename) {
+            entry.usage.last_used = now_ms();
+            entry.usage.use_count += 1;
+        }
+    }
+
+    fn use_count(&self, filename: &str) -> u64 {
+        self.entries.get(filename).map_or(0, |entry| entry.usage.use_count)
+    }
+
+    /// Removes the least recently used entries that are not in the startup whitelist
+    /// until the cache fits in its quota, and returns the names of the removed files.
+    fn evict(&mut self) -> Vec<String> {
+        if self.total_size <= self.max_size {
+            return Vec::new();
+        }
+
+        let mut candidates = self
+            .entries
+            .iter()
+            .filter(|(filename, _)| !self.startup_shaders.contains(*filename))
+            .map(|(filename, entry)| (entry.usage.last_used, filename.clone()))
+            .collect::<Vec<(u64, String)>>();
+        candidates.sort();
+
+        let mut evicted = Vec::new();
+        for (_, filename) in candidates {
+            if self.total_size <= self.max_size {
+                break;
+            }
+            self.remove(&filename);
+            evicted.push(filename);
+        }
+        evicted
+    }
+
+    /// Serializes the usage of every entry, in the format parsed by `parse_usage`.
+    fn serialize_usage(&self) -> String {
+        self.entries
+            .iter()
+            .map(|(filename, entry)| format!("{} {} {}", filename, entry.usage.last_used, entry.usage.use_count))
+            .collect::<Vec<String>>()
+            .join(WHITELIST_SEPARATOR)
+    }
+}
+
+/// Parses the contents of the usage file into a map from file name to usage.
+///
+/// Each line has the form `<file name> <last use time> <use count>`. The use count
+/// is missing from files written by older versions, in which case it defaults to 0.
+fn parse_usage(usage: &str) -> HashMap<String, Usage> {
+    usage
+        .split(WHITELIST_SEPARATOR)
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let filename = parts.next()?;
+            let last_used = parts.next()?.parse().ok()?;
+            let use_count = parts.next().and_then(|count| count.parse().ok()).unwrap_or(0);
+            Some((filename.to_string(), Usage { last_used, use_count }))
+        })
+        .collect()
+}
+
+/// Evicts entries from the cache until it fits in its quota, and writes the
+/// updated usage file. Must be called on a worker thread.
+fn enforce_disk_quota(cache_path: &Path, index: &Mutex<DiskCacheIndex>) {
+    let (evicted, usage) = {
+        let mut index = index.lock().unwrap();
+        let evicted = index.evict();
+        (evicted, index.serialize_usage())
+    };
+
+    for filename in &evicted {
+        info!("shader-cache: Evicting shader {}", filename);
+        match remove_file(cache_path.join(filename)) {
+            Ok(()) => {},
+            // Shaders stored in the archive are dropped the next time it is packed.
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => error!("shader-cache: Failed to evict shader {}: {}", filename, e),
+        }
+    }
+
+    if let Err(e) = write_file_atomically(&cache_path.join(USAGE_FILENAME), &[usage.as_bytes()]) {
+        error!("shader-cache: Failed to write usage file: {}", e);
+    }
+}
+
+/// An archive packing many serialized program binaries into a single file, so that
+/// startup loads only need to open (and map) one file.
+///
+/// Archive format:
+/// { ARCHIVE_MAGIC_AND_VERSION: u32, entry count: u32, table[..], entry data[..] }
+///
+/// Each table entry has the form:
+/// { digest length: u32, digest[..], offset: u64, length: u64, hash: u64 }
+///
+/// - offset and length locate the entry's data within the archive
+/// - hash is the fxhash of the entry's data
+///
+/// An entry's data uses the same format as an individual cache file, so it carries
+/// its own magic, version and hash and is validated by `deserialize_program_binary_from_bytes`.
+struct ShaderArchive {
+    data: Mmap,
+    entries: HashMap<String, ArchiveEntry>,
+}
+
+struct ArchiveEntry {
+    offset: usize,
+    len: usize,
+    hash: u64,
+}
+
+/// Reads a little-endian u32 from `buf` at `pos`, advancing `pos`.
+fn read_archive_u32(buf: &[u8], pos: &mut usize) -> Result<u32, Error> {
+    let bytes = buf
+        .get(*pos..*pos + 4)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Archive table is truncated"))?;
+    *pos += 4;
+    let mut value = [0; 4];
+    value.copy_from_slice(bytes);
+    Ok(u32::from_le_bytes(value))
+}
+
+/// Reads a little-endian u64 from `buf` at `pos`, advancing `pos`.
+fn read_archive_u64(buf: &[u8], pos: &mut usize) -> Result<u64, Error> {
+    let bytes = buf
+        .get(*pos..*pos + 8)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Archive table is truncated"))?;
+    *pos += 8;
+    let mut value = [0; 8];
+    value.copy_from_slice(bytes);
+    Ok(u64::from_le_bytes(value))
+}
+
+impl ShaderArchive {
+    fn open(path: &Path) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        // The archive is never modified while it is mapped, see `write_archive`, so
+        // the mapping stays valid for the lifetime of the cache.
+        let data = unsafe { Mmap::map(&file)? };
+
+        let mut pos = 0;
+        if read_archive_u32(&data, &mut pos)? != ARCHIVE_MAGIC_AND_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, "Archive is invalid (magic+version)"));
+        }
+
+        let count = read_archive_u32(&data, &mut pos)?;
+        let mut entries = HashMap::new();
+        for _ in 0..count {
+            let digest_len = read_archive_u32(&data, &mut pos)? as usize;
+            let digest = data
+                .get(pos..pos + digest_len)
+                .and_then(|digest| std::str::from_utf8(digest).ok())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Archive digest is invalid"))?
+                .to_string();
+            pos += digest_len;
+
+            let offset = read_archive_u64(&data, &mut pos)? as usize;
+            let len = read_archive_u64(&data, &mut pos)? as usize;
+            let hash = read_archive_u64(&data, &mut pos)?;
+            if offset.checked_add(len).map_or(true, |end| end > data.len()) {
+                return Err(Error::new(ErrorKind::InvalidData, "Archive entry is out of bounds"));
+            }
+            entries.insert(digest, ArchiveEntry { offset, len, hash });
+        }
+
+        Ok(ShaderArchive { data, entries })
+    }
+
+    fn contains(&self, digest: &str) -> bool {
+        self.entries.contains_key(digest)
+    }
+
+    /// Returns the data of the entry with the given digest, if it is present and
+    /// matches the hash recorded in the table.
+    fn get(&self, digest: &str) -> Option<&[u8]> {
+        let entry = self.entries.get(digest)?;
+        let data = &self.data[entry.offset..entry.offset + entry.len];
+        if fxhash::hash64(data) != entry.hash {
+            error!("shader-cache: Archive entry {} is invalid (hash)", digest);
+            return None;
+        }
+        Some(data)
+    }
+}
+
+/// Writes the given (digest, data) pairs to a new archive at `path`.
+///
+/// The file is replaced atomically rather than modified in place. Files that are
+/// mapped can't be replaced on Windows though, so the archive used by a running
+/// cache is written to `ARCHIVE_PENDING_FILENAME` instead, and only replaces
+/// `ARCHIVE_FILENAME` on the next startup, before it is mapped.
+fn write_archive(path: &Path, entries: &[(String, Vec<u8>)]) -> Result<(), Error> {
+    let table_size: usize = entries.iter().map(|(digest, _)| 4 + digest.len() + 8 * 3).sum();
+
+    let mut table = Vec::with_capacity(8 + table_size);
+    table.extend_from_slice(&ARCHIVE_MAGIC_AND_VERSION.to_le_bytes());
+    table.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+
+    let mut offset = 8 + table_size;
+    for (digest, data) in entries {
+        table.extend_from_slice(&(digest.len() as u32).to_le_bytes());
+        table.extend_from_slice(digest.as_bytes());
+        table.extend_from_slice(&(offset as u64).to_le_bytes());
+        table.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        table.extend_from_slice(&fxhash::hash64(data).to_le_bytes());
+        offset += data.len();
+    }
+
+    let mut chunks = vec![&table[..]];
+    chunks.extend(entries.iter().map(|(_, data)| &data[..]));
+    write_file_atomically(path, &chunks)
+}
+
+/// Returns the path of the most recently packed archive of a cache directory.
+fn latest_archive_path(cache_path: &Path) -> PathBuf {
+    let pending_path = cache_path.join(ARCHIVE_PENDING_FILENAME);
+    if pending_path.exists() {
+        pending_path
+    } else {
+        cache_path.join(ARCHIVE_FILENAME)
+    }
+}
+
+/// Makes the archive packed during the previous run, if any, the current one. Must be
+/// called before the archive is mapped.
+fn promote_pending_archive(cache_path: &Path) {
+    let pending_path = cache_path.join(ARCHIVE_PENDING_FILENAME);
+    match rename(&pending_path, cache_path.join(ARCHIVE_FILENAME)) {
+        Ok(()) => {},
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+        Err(e) => {
+            error!("shader-cache: Failed to replace archive: {}", e);
+            let _ = remove_file(&pending_path);
+        },
+    }
+}
+
+/// Packs every program binary known to the index, whether it is stored in the current
+/// archive or as an individual file, into a new archive that is used from the next
+/// startup. Must be called on a worker thread.
+///
+/// Individual files are left in place until the next startup, when they are removed if
+/// the archive loaded by the render thread contains them.
+fn pack_archive(cache_path: &Path, index: &Mutex<DiskCacheIndex>, stats: &DiskCacheStats) {
+    let start = Instant::now();
+
+    let archive = ShaderArchive::open(&latest_archive_path(cache_path)).ok();
+
+    let mut digests = index.lock().unwrap().entries.keys().cloned().collect::<Vec<String>>();
+    digests.sort();
+
+    let entries = digests
+        .into_iter()
+        .filter_map(|digest| {
+            let data = match archive.as_ref().and_then(|archive| archive.get(&digest)) {
+                Some(data) => data.to_vec(),
+                None => {
+                    let mut data = Vec::new();
+                    File::open(cache_path.join(&digest))
+                        .and_then(|mut file| file.read_to_end(&mut data))
+                        .ok()?;
+                    data
+                },
+            };
+            Some((digest, data))
+        })
+        .collect::<Vec<(String, Vec<u8>)>>();
+    // Unmap the previous pending archive, so that it can be replaced.
+    drop(archive);
+
+    match write_archive(&cache_path.join(ARCHIVE_PENDING_FILENAME), &entries) {
+        Ok(()) => {
+            let size = entries.iter().map(|(_, data)| data.len() as u64).sum();
+            stats.bytes_written.fetch_add(size, Ordering::Relaxed);
+            info!("Packed {} shaders in {:?}", entries.len(), start.elapsed());
+        },
+        Err(e) => error!("shader-cache: Failed to write archive: {}", e),
+    }
+}
+
+/// The number of program binaries stored on disk and their total size in bytes.
+#[repr(C)]
+pub struct WrProgramCacheDiskUsage {
+    pub entry_count: usize,
+    pub total_size: u64,
+}
+
 struct WrProgramBinaryDiskCache {
     cache_path: PathBuf,
     workers: Arc<ThreadPool>,
     cached_shader_filenames: Vec<OsString>,
+    index: Arc<Mutex<DiskCacheIndex>>,
+    /// Whether program binaries are packed into a single archive file.
+    use_archive: bool,
+    archive: Option<Arc<ShaderArchive>>,
+    /// The number of cache entries that failed validation since startup.
+    quarantined_count: usize,
+    stats: Arc<DiskCacheStats>,
+    /// How long loading the startup shaders may block the render thread.
+    max_load_time: Duration,
 }
 
 // Magic number + version. Increment the version when the binary format changes.
@@ -103,6 +642,154 @@ const MAGIC_AND_VERSION: u32 = MAGIC + VERSION;
 
 const WHITELIST_FILENAME: &str = "startup_shaders";
 const WHITELIST_SEPARATOR: &str = "\n";
+const USAGE_FILENAME: &str = "shader_usage";
+/// Lists the partitions of the cache and the launch in which they were last used.
+const PARTITION_INDEX_FILENAME: &str = "partitions";
+const PARTITION_INDEX_SEPARATOR: &str = "\n";
+/// Describes the device a partition's binaries were compiled for, for debugging.
+const FINGERPRINT_FILENAME: &str = "fingerprint";
+
+/// Program binaries that fail validation are moved into this subdirectory, so that
+/// they are not loaded again but remain available for inspection.
+const QUARANTINE_DIRNAME: &str = "quarantine";
+/// Past this many files in the quarantine directory, invalid files are deleted instead.
+const MAX_QUARANTINED_FILES: usize = 16;
+
+const ARCHIVE_FILENAME: &str = "shader_archive";
+/// The archive packed while the cache is running, which replaces `ARCHIVE_FILENAME`
+/// on the next startup.
+const ARCHIVE_PENDING_FILENAME: &str = "shader_archive.pending";
+// Increment the version when the archive table format changes.
+const ARCHIVE_MAGIC: u32 = 0xB154A4C0;
+const ARCHIVE_VERSION: u32 = 1;
+const ARCHIVE_MAGIC_AND_VERSION: u32 = ARCHIVE_MAGIC + ARCHIVE_VERSION;
+
+/// Where a program binary is stored on disk.
+#[derive(Clone, Copy, PartialEq)]
+enum BinarySource {
+    Archive,
+    File,
+}
+
+/// Reads and validates a program binary. This may be called on any thread.
+fn read_program_binary(
+    cache_path: &Path,
+    archive: Option<&Arc<ShaderArchive>>,
+    filename: &str,
+    source: BinarySource,
+    stats: &DiskCacheStats,
+) -> Result<Arc<ProgramBinary>, Error> {
+    match source {
+        BinarySource::File => {
+            let mut buf = vec![];
+            File::open(cache_path.join(filename))?.read_to_end(&mut buf)?;
+            stats.bytes_read.fetch_add(buf.len() as u64, Ordering::Relaxed);
+            deserialize_program_binary_from_bytes(&buf)
+        },
+        BinarySource::Archive => match archive.and_then(|archive| archive.get(filename)) {
+            Some(data) => {
+                stats.bytes_read.fetch_add(data.len() as u64, Ordering::Relaxed);
+                deserialize_program_binary_from_bytes(data)
+            },
+            None => Err(InvalidProgramBinary::Hash.into()),
+        },
+    }
+}
+
+/// Counters describing how effective the disk cache is, shared with the worker threads.
+#[derive(Default)]
+struct DiskCacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    invalid_size: AtomicU64,
+    invalid_magic: AtomicU64,
+    invalid_hash: AtomicU64,
+    invalid_bincode: AtomicU64,
+    startup_load_time_ms: AtomicU64,
+    startup_timed_out: AtomicBool,
+}
+
+impl DiskCacheStats {
+    fn record_failure(&self, err: &Error) {
+        let reason = err.get_ref().and_then(|e| e.downcast_ref::<InvalidProgramBinary>());
+        let counter = match reason {
+            Some(InvalidProgramBinary::Size) => &self.invalid_size,
+            Some(InvalidProgramBinary::Magic) => &self.invalid_magic,
+            Some(InvalidProgramBinary::Hash) => &self.invalid_hash,
+            Some(InvalidProgramBinary::Bincode) => &self.invalid_bincode,
+            None => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> WrProgramCacheStats {
+        WrProgramCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            invalid_size: self.invalid_size.load(Ordering::Relaxed),
+            invalid_magic: self.invalid_magic.load(Ordering::Relaxed),
+            invalid_hash: self.invalid_hash.load(Ordering::Relaxed),
+            invalid_bincode: self.invalid_bincode.load(Ordering::Relaxed),
+            startup_load_time_ms: self.startup_load_time_ms.load(Ordering::Relaxed),
+            startup_timed_out: self.startup_timed_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Statistics about the disk cache since startup.
+#[repr(C)]
+#[derive(Default)]
+pub struct WrProgramCacheStats {
+    /// Program binaries loaded from disk.
+    pub hits: u64,
+    /// Program binaries requested but missing from disk or failing validation.
+    pub misses: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Validation failures, by reason.
+    pub invalid_size: u64,
+    pub invalid_magic: u64,
+    pub invalid_hash: u64,
+    pub invalid_bincode: u64,
+    /// How long loading the startup shaders took.
+    pub startup_load_time_ms: u64,
+    /// Whether loading the startup shaders ran out of time.
+    pub startup_timed_out: bool,
+}
+
+/// Returns whether the given file in the cache directory holds a single program binary.
+fn is_program_binary_file(filename: &OsStr) -> bool {
+    // Program binaries are named after their hex digest, so anything with an
+    // extension is a temporary file.
+    !is_metadata_file(filename) && Path::new(filename).extension().is_none()
+}
+
+/// Returns whether the given file in the cache directory holds cache metadata.
+fn is_metadata_file(filename: &OsStr) -> bool {
+    [
+        WHITELIST_FILENAME,
+        USAGE_FILENAME,
+        ARCHIVE_FILENAME,
+        ARCHIVE_PENDING_FILENAME,
+        FINGERPRINT_FILENAME,
+        PARTITION_INDEX_FILENAME,
+    ]
+    .iter()
+    .any(|name| filename == *name)
+}
+
+/// Returns whether the given file in the cache root was written by a version of the
+/// cache that predates partitioning.
+fn is_legacy_file(filename: &OsStr) -> bool {
+    is_program_binary_file(filename)
+        || [WHITELIST_FILENAME, USAGE_FILENAME, ARCHIVE_FILENAME, ARCHIVE_PENDING_FILENAME]
+            .iter()
+            .any(|name| filename == *name)
+}
 
 /// Helper to convert a closure returning a `Result` to one that returns void.
 /// This allows the enclosed code to use the question-mark operator in a
@@ -114,11 +801,34 @@ fn result_to_void<F: FnOnce() -> Result<(), ()>>(f: F) {
 
 impl WrProgramBinaryDiskCache {
     #[allow(dead_code)]
-    fn new(cache_path: PathBuf, workers: &Arc<ThreadPool>) -> Self {
+    fn new(cache_path: PathBuf, workers: &Arc<ThreadPool>, use_archive: bool) -> Self {
         WrProgramBinaryDiskCache {
             cache_path,
             workers: Arc::clone(workers),
             cached_shader_filenames: Vec::new(),
+            index: Arc::new(Mutex::new(DiskCacheIndex::new())),
+            use_archive,
+            archive: None,
+            quarantined_count: 0,
+            stats: Arc::new(DiskCacheStats::default()),
+            max_load_time: Duration::from_millis(MAX_LOAD_TIME_MS),
+        }
+    }
+
+    /// Sets the maximum number of bytes the cached program binaries may use on disk.
+    fn set_max_disk_size(&mut self, max_size: u64) {
+        self.index.lock().unwrap().max_size = max_size;
+
+        let cache_path = self.cache_path.clone();
+        let index = Arc::clone(&self.index);
+        self.workers.spawn(move || enforce_disk_quota(&cache_path, &index));
+    }
+
+    fn disk_usage(&self) -> WrProgramCacheDiskUsage {
+        let index = self.index.lock().unwrap();
+        WrProgramCacheDiskUsage {
+            entry_count: index.entries.len(),
+            total_size: index.total_size,
         }
     }
 
@@ -129,7 +839,10 @@ impl WrProgramBinaryDiskCache {
         // Write the entries to disk on a worker thread.
         for entry in entries {
             let file_name = entry.source_digest().to_string();
+            let cache_path = self.cache_path.clone();
             let file_path = self.cache_path.join(&file_name);
+            let index = Arc::clone(&self.index);
+            let stats = Arc::clone(&self.stats);
 
             self.workers.spawn(move || {
                 result_to_void(move || {
@@ -141,28 +854,29 @@ impl WrProgramBinaryDiskCache {
                     let data: Vec<u8> =
                         bincode::serialize(&*entry).map_err(|e| error!("shader-cache: Failed to serialize: {}", e))?;
 
-                    let mut file =
-                        File::create(&file_path).map_err(|e| error!("shader-cache: Failed to create file: {}", e))?;
-
-                    // Write magic + version.
+                    // Magic + version.
                     let mv = MAGIC_AND_VERSION;
                     let mv = bincode::serialize(&mv).unwrap();
                     assert!(mv.len() == 4);
-                    file.write_all(&mv)
-                        .map_err(|e| error!("shader-cache: Failed to write magic + version: {}", e))?;
 
-                    // Write hash
+                    // Hash
                     let hash = fxhash::hash64(&data);
                     let hash = bincode::serialize(&hash).unwrap();
                     assert!(hash.len() == 8);
-                    file.write_all(&hash)
-                        .map_err(|e| error!("shader-cache: Failed to write hash: {}", e))?;
 
-                    // Write serialized data
-                    file.write_all(&data)
+                    write_file_atomically(&file_path, &[&mv, &hash, &data])
                         .map_err(|e| error!("shader-cache: Failed to write program binary: {}", e))?;
 
                     info!("Wrote shader {} in {:?}", file_name, start.elapsed());
+
+                    let size = (mv.len() + hash.len() + data.len()) as u64;
+                    stats.bytes_written.fetch_add(size, Ordering::Relaxed);
+                    let usage = Usage {
+                        last_used: now_ms(),
+                        use_count: 0,
+                    };
+                    index.lock().unwrap().insert(file_name, size, usage);
+                    enforce_disk_quota(&cache_path, &index);
                     Ok(())
                 })
             });
@@ -171,49 +885,140 @@ impl WrProgramBinaryDiskCache {
 
     /// Writes the whitelist containing the set of startup shaders to disk.
     fn set_startup_shaders(&mut self, entries: Vec<Arc<ProgramBinary>>) {
-        let whitelist = entries
+        let startup_shaders = entries
             .iter()
             .map(|e| e.source_digest().to_string())
-            .collect::<Vec<String>>()
-            .join(WHITELIST_SEPARATOR);
+            .collect::<Vec<String>>();
+        let whitelist = startup_shaders.join(WHITELIST_SEPARATOR);
+        self.index.lock().unwrap().startup_shaders = startup_shaders.into_iter().collect();
 
-        let mut whitelist_path = self.cache_path.clone();
-        whitelist_path.push(WHITELIST_FILENAME);
+        let cache_path = self.cache_path.clone();
+        let index = Arc::clone(&self.index);
+        let stats = Arc::clone(&self.stats);
+        let use_archive = self.use_archive;
         self.workers.spawn(move || {
             result_to_void(move || {
                 info!("Writing startup shader whitelist");
-                File::create(&whitelist_path)
-                    .and_then(|mut file| file.write_all(whitelist.as_bytes()))
+                write_file_atomically(&cache_path.join(WHITELIST_FILENAME), &[whitelist.as_bytes()])
                     .map_err(|e| error!("shader-cache: Failed to write startup whitelist: {}", e))?;
+
+                // Also persist the last use times recorded while loading the startup shaders.
+                enforce_disk_quota(&cache_path, &index);
+
+                // The startup shaders are now known, so this is a good time to pack the
+                // binaries saved so far into the archive.
+                if use_archive {
+                    pack_archive(&cache_path, &index, &stats);
+                }
                 Ok(())
             })
         });
     }
 
-    pub fn try_load_shader_from_disk(&mut self, filename: &str, program_cache: &Rc<ProgramCache>) {
-        if let Some(index) = self.cached_shader_filenames.iter().position(|e| e == filename) {
-            let mut path = self.cache_path.clone();
-            path.push(filename);
+    /// Moves an invalid program binary out of the way, so that it is not loaded again.
+    fn quarantine(&mut self, filename: &str) {
+        self.quarantined_count += 1;
+        self.index.lock().unwrap().remove(filename);
 
-            self.cached_shader_filenames.swap_remove(index);
+        let path = self.cache_path.join(filename);
+        let quarantine_path = self.cache_path.join(QUARANTINE_DIRNAME);
+        let quarantined_files = read_dir(&quarantine_path).map_or(0, |entries| entries.count());
+        let moved = quarantined_files < MAX_QUARANTINED_FILES
+            && create_dir_all(&quarantine_path).is_ok()
+            && rename(&path, quarantine_path.join(filename)).is_ok();
+        if !moved {
+            let _ = remove_file(&path);
+        }
+    }
 
-            info!("Loading shader: {}", filename);
+    /// Removes a program binary that the driver rejected, so that it is not loaded again.
+    fn remove_program_binary(&mut self, filename: &str) {
+        self.index.lock().unwrap().remove(filename);
+        self.cached_shader_filenames.retain(|e| e != filename);
 
-            match deserialize_program_binary(&path) {
-                Ok(program) => {
-                    program_cache.load_program_binary(program);
-                },
-                Err(err) => {
-                    error!("shader-cache: Failed to deserialize program binary: {}", err);
-                },
-            };
+        let path = self.cache_path.join(filename);
+        self.workers.spawn(move || match remove_file(&path) {
+            Ok(()) => {},
+            // Binaries stored in the archive are dropped the next time it is packed.
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => error!("shader-cache: Failed to remove rejected program binary: {}", e),
+        });
+    }
+
+    fn quarantined_count(&self) -> usize {
+        self.quarantined_count
+    }
+
+    fn stats(&self) -> WrProgramCacheStats {
+        self.stats.snapshot()
+    }
+
+    /// Returns where the program binary with the given name can be loaded from, if it
+    /// is on disk and has not been loaded yet.
+    fn find_program_binary(&self, filename: &str) -> Option<BinarySource> {
+        if self
+            .archive
+            .as_ref()
+            .map_or(false, |archive| archive.contains(filename))
+        {
+            Some(BinarySource::Archive)
+        } else if self.cached_shader_filenames.iter().any(|e| e == filename) {
+            Some(BinarySource::File)
+        } else {
+            None
+        }
+    }
+
+    /// Hands a program binary read from disk to the program cache, or takes care of
+    /// the invalid entry if it could not be read.
+    fn finish_load(
+        &mut self,
+        filename: &str,
+        source: BinarySource,
+        result: Result<Arc<ProgramBinary>, Error>,
+        program_cache: &Rc<ProgramCache>,
+    ) {
+        if source == BinarySource::File {
+            self.cached_shader_filenames.retain(|e| e != filename);
+        }
+
+        match result {
+            Ok(program) => {
+                self.stats.hits.fetch_add(1, Ordering::Relaxed);
+                self.index.lock().unwrap().touch(filename);
+                program_cache.load_program_binary(program);
+            },
+            Err(err) => {
+                error!("shader-cache: Failed to deserialize program binary: {}", err);
+                self.stats.misses.fetch_add(1, Ordering::Relaxed);
+                self.stats.record_failure(&err);
+                if err.kind() == ErrorKind::InvalidData {
+                    match source {
+                        BinarySource::File => self.quarantine(filename),
+                        BinarySource::Archive => {
+                            // Entries can't be moved out of the archive, but dropping them from
+                            // the index excludes them the next time it is packed.
+                            self.quarantined_count += 1;
+                            self.index.lock().unwrap().remove(filename);
+                        },
+                    }
+                }
+            },
+        };
+    }
+
+    pub fn try_load_shader_from_disk(&mut self, filename: &str, program_cache: &Rc<ProgramCache>) {
+        if let Some(source) = self.find_program_binary(filename) {
+            info!("Loading shader: {}", filename);
+            let result = read_program_binary(&self.cache_path, self.archive.as_ref(), filename, source, &self.stats);
+            self.finish_load(filename, source, result, program_cache);
         } else {
             info!("shader-cache: Program binary not found in disk cache");
+            self.stats.misses.fetch_add(1, Ordering::Relaxed);
         }
     }
 
     pub fn try_load_startup_shaders_from_disk(&mut self, program_cache: &Rc<ProgramCache>) {
-        use std::time::Instant;
         let start = Instant::now();
 
         // Load and parse the whitelist if it exists
@@ -222,6 +1027,7 @@ impl WrProgramBinaryDiskCache {
         let whitelist = match read_to_string(&whitelist_path) {
             Ok(whitelist) => whitelist
                 .split(WHITELIST_SEPARATOR)
+                .filter(|s| !s.is_empty())
                 .map(|s| s.to_string())
                 .collect::<Vec<String>>(),
             Err(err) => {
@@ -231,7 +1037,22 @@ impl WrProgramBinaryDiskCache {
         };
         info!("Loaded startup shader whitelist in {:?}", start.elapsed());
 
-        self.cached_shader_filenames = read_dir(&self.cache_path)
+        let usage = read_to_string(self.cache_path.join(USAGE_FILENAME))
+            .map(|usage| parse_usage(&usage))
+            .unwrap_or_default();
+
+        if self.use_archive {
+            promote_pending_archive(&self.cache_path);
+            self.archive = match ShaderArchive::open(&self.cache_path.join(ARCHIVE_FILENAME)) {
+                Ok(archive) => Some(Arc::new(archive)),
+                Err(err) => {
+                    info!("shader-cache: Could not open archive: {}", err);
+                    None
+                },
+            };
+        }
+
+        let (entries, stale_files): (Vec<_>, Vec<_>) = read_dir(&self.cache_path)
             .map_err(|err| {
                 error!(
                     "shader-cache: Error reading directory whilst loading startup shaders: {}",
@@ -241,27 +1062,148 @@ impl WrProgramBinaryDiskCache {
             .into_iter()
             .flatten()
             .filter_map(Result::ok)
-            .map(|entry| entry.file_name())
-            .filter(|filename| filename != WHITELIST_FILENAME)
-            .collect::<Vec<OsString>>();
+            .filter(|entry| entry.file_type().map_or(false, |t| t.is_file()))
+            .filter(|entry| !is_metadata_file(&entry.file_name()))
+            .partition(|entry| is_program_binary_file(&entry.file_name()));
+
+        // Remove temporary files left behind by writes that were interrupted by a crash.
+        if !stale_files.is_empty() {
+            let stale_files = stale_files.iter().map(|entry| entry.path()).collect::<Vec<PathBuf>>();
+            self.workers.spawn(move || {
+                for path in stale_files {
+                    let _ = remove_file(path);
+                }
+            });
+        }
 
-        // Load whitelisted program binaries if they exist
-        for entry in &whitelist {
-            self.try_load_shader_from_disk(&entry, program_cache);
+        {
+            let mut index = self.index.lock().unwrap();
+            index.startup_shaders = whitelist.iter().cloned().collect();
+            if let Some(ref archive) = self.archive {
+                let archive_last_used = self
+                    .cache_path
+                    .join(ARCHIVE_FILENAME)
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .map_or(0, system_time_to_ms);
+                for (digest, entry) in &archive.entries {
+                    let usage = usage.get(digest).cloned().unwrap_or(Usage {
+                        last_used: archive_last_used,
+                        use_count: 0,
+                    });
+                    index.insert(digest.clone(), entry.len as u64, usage);
+                }
+            }
+            for entry in &entries {
+                let filename = match entry.file_name().into_string() {
+                    Ok(filename) => filename,
+                    Err(_) => continue,
+                };
+                let metadata = match entry.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+                // Files written before the usage file existed fall back to their
+                // modification time.
+                let usage = usage.get(&filename).cloned().unwrap_or_else(|| Usage {
+                    last_used: metadata.modified().map_or(0, system_time_to_ms),
+                    use_count: 0,
+                });
+                index.insert(filename, metadata.len(), usage);
+            }
+        }
 
-            let elapsed = start.elapsed();
-            info!("Loaded shader in {:?}", elapsed);
-            let elapsed_ms = (elapsed.as_secs() * 1_000) + elapsed.subsec_millis() as u64;
+        self.cached_shader_filenames = entries.iter().map(|entry| entry.file_name()).collect();
 
-            if elapsed_ms > MAX_LOAD_TIME_MS {
-                // Loading the startup shaders is taking too long, so bail out now.
-                // Additionally clear the list of remaining shaders cached on disk,
-                // so that we do not attempt to load any on demand during rendering.
-                error!("shader-cache: Timed out before finishing loads");
-                self.cached_shader_filenames.clear();
-                break;
+        // Individual files that have been packed into the archive are no longer needed.
+        if let Some(ref archive) = self.archive {
+            let packed = self
+                .cached_shader_filenames
+                .iter()
+                .filter(|filename| filename.to_str().map_or(false, |digest| archive.contains(digest)))
+                .map(|filename| self.cache_path.join(filename))
+                .collect::<Vec<PathBuf>>();
+            self.cached_shader_filenames
+                .retain(|filename| !filename.to_str().map_or(false, |digest| archive.contains(digest)));
+            if !packed.is_empty() {
+                self.workers.spawn(move || {
+                    for path in packed {
+                        let _ = remove_file(path);
+                    }
+                });
             }
         }
+
+        // Load whitelisted program binaries if they exist, most frequently used first.
+        // Files are read and validated in parallel on the worker threads, and only
+        // handed to the program cache on this thread.
+        let mut missing = 0;
+        let mut pending = whitelist
+            .into_iter()
+            .filter_map(|filename| match self.find_program_binary(&filename) {
+                Some(source) => Some((filename, source)),
+                None => {
+                    info!("shader-cache: Startup shader {} not found in disk cache", filename);
+                    missing += 1;
+                    None
+                },
+            })
+            .collect::<Vec<(String, BinarySource)>>();
+        self.stats.misses.fetch_add(missing, Ordering::Relaxed);
+        {
+            let index = self.index.lock().unwrap();
+            pending.sort_by_key(|(filename, _)| Reverse(index.use_count(filename)));
+        }
+
+        let (sender, receiver) = channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        for (filename, source) in &pending {
+            let filename = filename.clone();
+            let source = *source;
+            let cache_path = self.cache_path.clone();
+            let archive = self.archive.clone();
+            let stats = Arc::clone(&self.stats);
+            let sender = sender.clone();
+            let cancelled = Arc::clone(&cancelled);
+            self.workers.spawn(move || {
+                if cancelled.load(Ordering::Relaxed) {
+                    return;
+                }
+                let result = read_program_binary(&cache_path, archive.as_ref(), &filename, source, &stats);
+                let _ = sender.send((filename, source, result));
+            });
+        }
+        drop(sender);
+
+        let deadline = start + self.max_load_time;
+        let mut loaded = 0;
+        while loaded < pending.len() {
+            let timeout = deadline.checked_duration_since(Instant::now()).unwrap_or_default();
+            match receiver.recv_timeout(timeout) {
+                Ok((filename, source, result)) => {
+                    self.finish_load(&filename, source, result, program_cache);
+                    loaded += 1;
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    // Loading the startup shaders is taking too long, so stop waiting now.
+                    // The remaining shaders stay available to be loaded on demand.
+                    error!("shader-cache: Timed out before finishing loads");
+                    cancelled.store(true, Ordering::Relaxed);
+                    self.stats.startup_timed_out.store(true, Ordering::Relaxed);
+                    break;
+                },
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        let elapsed = start.elapsed();
+        info!(
+            "Loaded {} of {} startup shaders in {:?}",
+            loaded,
+            pending.len(),
+            elapsed
+        );
+        let elapsed_ms = (elapsed.as_secs() * 1_000) + elapsed.subsec_millis() as u64;
+        self.stats.startup_load_time_ms.store(elapsed_ms, Ordering::Relaxed);
     }
 }
 
@@ -292,8 +1234,10 @@ impl ProgramCacheObserver for WrProgramCacheObserver {
             .try_load_shader_from_disk(&filename, program_cache);
     }
 
-    fn notify_program_binary_failed(&self, _program_binary: &Arc<ProgramBinary>) {
+    fn notify_program_binary_failed(&self, program_binary: &Arc<ProgramBinary>) {
         error!("shader-cache: Failed program_binary");
+        let filename = program_binary.source_digest().to_string();
+        self.disk_cache.borrow_mut().remove_program_binary(&filename);
     }
 }
 
@@ -303,13 +1247,23 @@ pub struct WrProgramCache {
 }
 
 impl WrProgramCache {
-    pub fn new(prof_path: &nsAString, workers: &Arc<ThreadPool>) -> Self {
-        let cache_path = get_cache_path_from_prof_path(prof_path);
-        let use_disk_cache = cache_path.as_ref().map_or(false, |p| create_dir_all(p).is_ok());
+    /// Creates a program cache that stores program binaries in the `cache_path`
+    /// directory, ie. `<profile>/shader-cache`, or only in memory if it is `None`.
+    pub fn new(
+        cache_path: Option<PathBuf>,
+        workers: &Arc<ThreadPool>,
+        use_archive: bool,
+        fingerprint: &WrDeviceFingerprint,
+    ) -> Self {
+        let cache_path = cache_path
+            .filter(|p| create_dir_all(p).is_ok())
+            .map(|p| select_partition(&p, fingerprint, workers));
+        let use_disk_cache = cache_path.as_ref().map_or(false, |p| p.is_dir());
         let (disk_cache, program_cache_observer) = if use_disk_cache {
             let cache = Rc::new(RefCell::new(WrProgramBinaryDiskCache::new(
                 cache_path.unwrap(),
                 workers,
+                use_archive,
             )));
             let obs = Box::new(WrProgramCacheObserver::new(Rc::clone(&cache))) as Box<dyn ProgramCacheObserver>;
             (Some(cache), Some(obs))
@@ -337,17 +1291,600 @@ impl WrProgramCache {
             error!("shader-cache: Shader disk cache is not supported");
         }
     }
+
+    /// Sets the quota of the on-disk cache. The least recently used program binaries
+    /// are evicted when the cache grows beyond it.
+    pub fn set_max_disk_size(&self, max_size: u64) {
+        if let Some(ref disk_cache) = self.disk_cache {
+            disk_cache.borrow_mut().set_max_disk_size(max_size);
+        }
+    }
+
+    /// Returns statistics about the disk cache since startup.
+    pub fn stats(&self) -> WrProgramCacheStats {
+        self.disk_cache
+            .as_ref()
+            .map_or_else(WrProgramCacheStats::default, |disk_cache| disk_cache.borrow().stats())
+    }
+
+    /// Returns the number of cache entries that failed validation and were quarantined.
+    pub fn quarantined_count(&self) -> usize {
+        self.disk_cache
+            .as_ref()
+            .map_or(0, |disk_cache| disk_cache.borrow().quarantined_count())
+    }
+
+    /// Returns the number of program binaries stored on disk and their total size.
+    pub fn disk_usage(&self) -> WrProgramCacheDiskUsage {
+        match self.disk_cache {
+            Some(ref disk_cache) => disk_cache.borrow().disk_usage(),
+            None => WrProgramCacheDiskUsage {
+                entry_count: 0,
+                total_size: 0,
+            },
+        }
+    }
 }
 
-pub fn remove_disk_cache(prof_path: &nsAString) -> Result<(), Error> {
+/// Removes the disk cache rooted at `cache_path`, see `WrProgramCache::new`.
+pub fn remove_disk_cache(cache_path: &Path) -> Result<(), Error> {
     use std::time::Instant;
 
-    if let Some(cache_path) = get_cache_path_from_prof_path(prof_path) {
-        if cache_path.exists() {
-            let start = Instant::now();
-            remove_dir_all::remove_dir_all(&cache_path)?;
-            info!("removed all disk cache shaders in {:?}", start.elapsed());
-        }
+    if cache_path.exists() {
+        let start = Instant::now();
+        remove_dir_all::remove_dir_all(cache_path)?;
+        info!("removed all disk cache shaders in {:?}", start.elapsed());
     }
     Ok(())
 }
+
+/// Whether a cached program binary passes validation.
+pub enum CacheEntryValidity {
+    Valid,
+    /// The reason validation failed (size, magic+version, hash or deserialization).
+    Invalid(String),
+}
+
+/// Describes a program binary found in a cache directory by `inspect_cache_dir`.
+pub struct CacheEntryInfo {
+    pub digest: String,
+    pub size: u64,
+    pub validity: CacheEntryValidity,
+    pub in_whitelist: bool,
+    /// Whether the entry is stored in the packed archive rather than its own file.
+    pub in_archive: bool,
+}
+
+impl CacheEntryInfo {
+    pub fn is_valid(&self) -> bool {
+        match self.validity {
+            CacheEntryValidity::Valid => true,
+            CacheEntryValidity::Invalid(_) => false,
+        }
+    }
+}
+
+fn validate_program_binary(data: &[u8]) -> CacheEntryValidity {
+    match deserialize_program_binary_from_bytes(data) {
+        Ok(_) => CacheEntryValidity::Valid,
+        Err(e) => CacheEntryValidity::Invalid(e.to_string()),
+    }
+}
+
+/// Reads the startup whitelist of a cache directory.
+fn read_whitelist(cache_path: &Path) -> HashSet<String> {
+    read_to_string(cache_path.join(WHITELIST_FILENAME))
+        .map(|whitelist| {
+            whitelist
+                .split(WHITELIST_SEPARATOR)
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reads every program binary in a cache directory, whether stored in its own file
+/// or in the packed archive, as (digest, data, in_archive) tuples.
+fn read_cache_dir(cache_path: &Path) -> Result<Vec<(String, Vec<u8>, bool)>, Error> {
+    let mut entries = Vec::new();
+
+    for entry in read_dir(cache_path)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() || !is_program_binary_file(&entry.file_name()) {
+            continue;
+        }
+        let digest = match entry.file_name().into_string() {
+            Ok(digest) => digest,
+            Err(_) => continue,
+        };
+        let mut data = Vec::new();
+        File::open(entry.path())?.read_to_end(&mut data)?;
+        entries.push((digest, data, false));
+    }
+
+    let archive_path = latest_archive_path(cache_path);
+    if archive_path.exists() {
+        let archive = ShaderArchive::open(&archive_path)?;
+        for (digest, entry) in &archive.entries {
+            if entries.iter().any(|(d, _, _)| d == digest) {
+                continue;
+            }
+            let data = archive.data[entry.offset..entry.offset + entry.len].to_vec();
+            entries.push((digest.clone(), data, true));
+        }
+    }
+
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(entries)
+}
+
+/// Lists and validates the program binaries in a cache directory, without loading
+/// them into a driver.
+///
+/// `cache_path` is a partition of the cache, ie. `<profile>/shader-cache/<fingerprint>`.
+pub fn inspect_cache_dir(cache_path: &Path) -> Result<Vec<CacheEntryInfo>, Error> {
+    let whitelist = read_whitelist(cache_path);
+    Ok(read_cache_dir(cache_path)?
+        .into_iter()
+        .map(|(digest, data, in_archive)| CacheEntryInfo {
+            in_whitelist: whitelist.contains(&digest),
+            size: data.len() as u64,
+            validity: validate_program_binary(&data),
+            digest,
+            in_archive,
+        })
+        .collect())
+}
+
+/// Removes the program binaries that fail validation from a cache directory, and
+/// returns how many were removed.
+///
+/// Invalid entries of the packed archive are dropped by rewriting it.
+pub fn prune_cache_dir(cache_path: &Path) -> Result<usize, Error> {
+    let mut pruned = 0;
+    let mut archive_entries = Vec::new();
+    let mut archive_pruned = false;
+
+    for (digest, data, in_archive) in read_cache_dir(cache_path)? {
+        let valid = match validate_program_binary(&data) {
+            CacheEntryValidity::Valid => true,
+            CacheEntryValidity::Invalid(_) => false,
+        };
+        if in_archive {
+            if valid {
+                archive_entries.push((digest, data));
+            } else {
+                archive_pruned = true;
+                pruned += 1;
+            }
+        } else if !valid {
+            remove_file(cache_path.join(&digest))?;
+            pruned += 1;
+        }
+    }
+
+    if archive_pruned {
+        write_archive(&latest_archive_path(cache_path), &archive_entries)?;
+    }
+    Ok(pruned)
+}
+
+/// Copies the valid program binaries and the startup whitelist of one cache directory
+/// into another, and returns how many binaries were copied.
+///
+/// Binaries are always written as individual files, so the result can be seeded into
+/// a cache regardless of whether it uses the packed archive. The whitelist of the
+/// destination is merged with the source's.
+pub fn copy_cache_dir(from: &Path, to: &Path) -> Result<usize, Error> {
+    create_dir_all(to)?;
+
+    let mut copied = 0;
+    for (digest, data, _) in read_cache_dir(from)? {
+        if let CacheEntryValidity::Valid = validate_program_binary(&data) {
+            write_file_atomically(&to.join(&digest), &[&data])?;
+            copied += 1;
+        }
+    }
+
+    let mut whitelist = read_whitelist(to).into_iter().collect::<Vec<String>>();
+    for digest in read_whitelist(from) {
+        if !whitelist.contains(&digest) {
+            whitelist.push(digest);
+        }
+    }
+    if !whitelist.is_empty() {
+        write_file_atomically(
+            &to.join(WHITELIST_FILENAME),
+            &[whitelist.join(WHITELIST_SEPARATOR).as_bytes()],
+        )?;
+    }
+
+    // Copy the device description along with the binaries, if there is one.
+    if let Ok(fingerprint) = read_to_string(from.join(FINGERPRINT_FILENAME)) {
+        write_file_atomically(&to.join(FINGERPRINT_FILENAME), &[fingerprint.as_bytes()])?;
+    }
+
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// A directory that is removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = env::temp_dir().join(format!("wr-program-cache-{}-{}", process::id(), name));
+            let _ = remove_dir_all::remove_dir_all(&path);
+            create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = remove_dir_all::remove_dir_all(&self.0);
+        }
+    }
+
+    fn usage(last_used: u64) -> Usage {
+        Usage {
+            last_used,
+            use_count: 0,
+        }
+    }
+
+    /// Returns the contents of a cache file holding a program binary with the given bytes.
+    fn program_binary_file(bytes: &[u8]) -> Vec<u8> {
+        // Serialized like a ProgramBinary: its bytes, format and source digest.
+        let data = bincode::serialize(&(bytes.to_vec(), 0u32, 0u64)).unwrap();
+        let mut file = bincode::serialize(&MAGIC_AND_VERSION).unwrap();
+        file.extend(bincode::serialize(&fxhash::hash64(&data)).unwrap());
+        file.extend(data);
+        file
+    }
+
+    /// Returns a pool whose tasks run one at a time, in the order they are spawned.
+    fn workers() -> Arc<ThreadPool> {
+        Arc::new(rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap())
+    }
+
+    /// Waits for the tasks spawned so far on a pool returned by `workers` to run.
+    fn flush(workers: &ThreadPool) {
+        let (sender, receiver) = channel();
+        workers.spawn(move || sender.send(()).unwrap());
+        receiver.recv().unwrap();
+    }
+
+    #[test]
+    fn disk_quota_evicts_least_recently_used_binaries() {
+        let dir = TempDir::new("quota");
+        let mut index = DiskCacheIndex::new();
+        for (filename, last_used) in &[("old", 1), ("startup", 2), ("recent", 3), ("newest", 4)] {
+            fs::write(dir.0.join(filename), [0; 10]).unwrap();
+            index.insert(filename.to_string(), 10, usage(*last_used));
+        }
+        index.max_size = 20;
+        index.startup_shaders.insert("startup".to_string());
+        let index = Mutex::new(index);
+
+        enforce_disk_quota(&dir.0, &index);
+
+        // The whitelisted binary is kept even though it is older than the others.
+        let index = index.into_inner().unwrap();
+        let mut kept = index.entries.keys().cloned().collect::<Vec<String>>();
+        kept.sort();
+        assert_eq!(kept, ["newest", "startup"]);
+        assert_eq!(index.total_size, 20);
+        assert!(!dir.0.join("old").exists());
+        assert!(!dir.0.join("recent").exists());
+        assert!(dir.0.join("startup").exists());
+
+        let usage = parse_usage(&read_to_string(dir.0.join(USAGE_FILENAME)).unwrap());
+        assert_eq!(usage.len(), 2);
+        assert_eq!(usage["newest"].last_used, 4);
+    }
+
+    #[test]
+    fn archives_reject_corrupt_entries() {
+        let dir = TempDir::new("archive");
+        let path = dir.0.join(ARCHIVE_FILENAME);
+        let (a, b) = (program_binary_file(&[1]), program_binary_file(&[2, 3]));
+        write_archive(&path, &[("a".to_string(), a.clone()), ("b".to_string(), b.clone())]).unwrap();
+
+        let archive = ShaderArchive::open(&path).unwrap();
+        assert_eq!(archive.get("a"), Some(&a[..]));
+        assert_eq!(archive.get("b"), Some(&b[..]));
+        assert!(!archive.contains("c"));
+        assert!(deserialize_program_binary_from_bytes(archive.get("a").unwrap()).is_ok());
+        drop(archive);
+
+        // The data of the last entry ends the file.
+        let mut data = fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        let archive = ShaderArchive::open(&path).unwrap();
+        assert_eq!(archive.get("a"), Some(&a[..]));
+        assert_eq!(archive.get("b"), None);
+        drop(archive);
+
+        // Tables that are truncated, or that point past the end of the file, are invalid.
+        fs::write(&path, &data[..10]).unwrap();
+        assert_eq!(ShaderArchive::open(&path).err().unwrap().kind(), ErrorKind::InvalidData);
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert_eq!(ShaderArchive::open(&path).err().unwrap().kind(), ErrorKind::InvalidData);
+        fs::write(&path, &[0; 8]).unwrap();
+        assert_eq!(ShaderArchive::open(&path).err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn packed_archives_replace_the_mapped_one_on_the_next_startup() {
+        let dir = TempDir::new("pack");
+        let archive_path = dir.0.join(ARCHIVE_FILENAME);
+        let a = program_binary_file(&[1]);
+        write_archive(&archive_path, &[("a".to_string(), a.clone())]).unwrap();
+        let mapped = ShaderArchive::open(&archive_path).unwrap();
+
+        let b = program_binary_file(&[2]);
+        fs::write(dir.0.join("b"), &b).unwrap();
+        let mut index = DiskCacheIndex::new();
+        index.insert("a".to_string(), a.len() as u64, usage(0));
+        index.insert("b".to_string(), b.len() as u64, usage(0));
+        let stats = DiskCacheStats::default();
+        pack_archive(&dir.0, &Mutex::new(index), &stats);
+        assert_eq!(stats.bytes_written.load(Ordering::Relaxed), (a.len() + b.len()) as u64);
+
+        // The mapped archive is left alone.
+        assert!(!mapped.contains("b"));
+        assert_eq!(mapped.get("a"), Some(&a[..]));
+        drop(mapped);
+
+        promote_pending_archive(&dir.0);
+        assert!(!dir.0.join(ARCHIVE_PENDING_FILENAME).exists());
+        let archive = ShaderArchive::open(&archive_path).unwrap();
+        assert_eq!(archive.get("a"), Some(&a[..]));
+        assert_eq!(archive.get("b"), Some(&b[..]));
+    }
+
+    #[test]
+    fn files_are_replaced_atomically() {
+        let dir = TempDir::new("atomic");
+        let path = dir.0.join("file");
+        write_file_atomically(&path, &[b"old"]).unwrap();
+        write_file_atomically(&path, &[b"ne", b"w"]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+
+        // No temporary file is left behind, even when the rename fails.
+        create_dir_all(dir.0.join("dir")).unwrap();
+        assert!(write_file_atomically(&dir.0.join("dir"), &[b"data"]).is_err());
+        let mut files = fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<OsString>>();
+        files.sort();
+        assert_eq!(files, ["dir", "file"]);
+    }
+
+    #[test]
+    fn invalid_binaries_are_quarantined() {
+        let dir = TempDir::new("quarantine");
+        let mut disk_cache = WrProgramBinaryDiskCache::new(dir.0.clone(), &workers(), false);
+        for i in 0..MAX_QUARANTINED_FILES + 1 {
+            let filename = format!("{:016x}", i);
+            fs::write(dir.0.join(&filename), b"invalid").unwrap();
+            disk_cache.index.lock().unwrap().insert(filename.clone(), 7, usage(0));
+            disk_cache.quarantine(&filename);
+            assert!(!dir.0.join(&filename).exists());
+        }
+
+        // Once the quarantine is full, invalid binaries are removed instead.
+        let quarantine_path = dir.0.join(QUARANTINE_DIRNAME);
+        assert_eq!(
+            fs::read(quarantine_path.join(format!("{:016x}", 0))).unwrap(),
+            b"invalid"
+        );
+        assert_eq!(fs::read_dir(&quarantine_path).unwrap().count(), MAX_QUARANTINED_FILES);
+        assert_eq!(disk_cache.quarantined_count(), MAX_QUARANTINED_FILES + 1);
+        assert!(disk_cache.index.lock().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn stale_temporary_files_are_removed_at_startup() {
+        let dir = TempDir::new("stale");
+        fs::write(dir.0.join("0123456789abcdef"), program_binary_file(&[1])).unwrap();
+        fs::write(dir.0.join("0123456789abcdef.3.tmp"), b"partial").unwrap();
+        fs::write(dir.0.join(WHITELIST_FILENAME.to_string() + ".0.tmp"), b"partial").unwrap();
+
+        let workers = workers();
+        let mut disk_cache = WrProgramBinaryDiskCache::new(dir.0.clone(), &workers, false);
+        disk_cache.try_load_startup_shaders_from_disk(&ProgramCache::new(None));
+        flush(&workers);
+
+        let files = fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<OsString>>();
+        assert_eq!(files, ["0123456789abcdef"]);
+        assert_eq!(disk_cache.disk_usage().entry_count, 1);
+    }
+
+    #[test]
+    fn unused_partitions_are_pruned() {
+        let dir = TempDir::new("partitions");
+        let fingerprint = |gl_renderer: &str| WrDeviceFingerprint {
+            gl_vendor: "vendor".to_string(),
+            gl_renderer: gl_renderer.to_string(),
+            gl_version: "1.0".to_string(),
+            is_software: false,
+        };
+        let (current, other) = (fingerprint("current"), fingerprint("other"));
+        let workers = workers();
+
+        let other_path = select_partition(&dir.0, &other, &workers);
+        flush(&workers);
+        assert_eq!(other_path, dir.0.join(other.partition_name()));
+        assert_eq!(
+            read_to_string(other_path.join(FINGERPRINT_FILENAME)).unwrap(),
+            other.description()
+        );
+        // Binaries written before the cache was partitioned.
+        fs::write(dir.0.join("0123456789abcdef"), b"binary").unwrap();
+
+        for launch in 2..=MAX_UNUSED_PARTITION_LAUNCHES + 2 {
+            let current_path = select_partition(&dir.0, &current, &workers);
+            flush(&workers);
+            assert_ne!(current_path, other_path);
+            assert!(current_path.join(FINGERPRINT_FILENAME).exists());
+            assert!(!dir.0.join("0123456789abcdef").exists());
+
+            let (last_launch, partitions) =
+                parse_partition_index(&read_to_string(dir.0.join(PARTITION_INDEX_FILENAME)).unwrap());
+            assert_eq!(last_launch, launch);
+            assert_eq!(partitions[&current.partition_name()], launch);
+            // The other partition is removed once it has been unused for too many launches.
+            let kept = launch - 1 <= MAX_UNUSED_PARTITION_LAUNCHES;
+            assert_eq!(partitions.contains_key(&other.partition_name()), kept);
+            assert_eq!(other_path.exists(), kept);
+        }
+    }
+
+    #[test]
+    fn partitions_missing_from_the_index_are_kept() {
+        let dir = TempDir::new("lost-index");
+        let fingerprint = |gl_renderer: &str| WrDeviceFingerprint {
+            gl_vendor: "vendor".to_string(),
+            gl_renderer: gl_renderer.to_string(),
+            gl_version: "1.0".to_string(),
+            is_software: false,
+        };
+        let (current, other) = (fingerprint("current"), fingerprint("other"));
+        let workers = workers();
+
+        let other_path = select_partition(&dir.0, &other, &workers);
+        flush(&workers);
+        fs::write(other_path.join("0123456789abcdef"), b"binary").unwrap();
+        fs::write(dir.0.join(PARTITION_INDEX_FILENAME), b"corrupt").unwrap();
+        create_dir_all(dir.0.join("unknown")).unwrap();
+
+        select_partition(&dir.0, &current, &workers);
+        flush(&workers);
+        assert!(other_path.join("0123456789abcdef").exists());
+        assert!(dir.0.join("unknown").exists());
+        let (_, partitions) = parse_partition_index(&read_to_string(dir.0.join(PARTITION_INDEX_FILENAME)).unwrap());
+        assert!(partitions.contains_key(&other.partition_name()));
+        assert!(partitions.contains_key(&current.partition_name()));
+    }
+
+    #[test]
+    fn cache_dirs_are_inspected_pruned_and_copied() {
+        let dir = TempDir::new("tool");
+        let cache_path = dir.0.join("cache");
+        create_dir_all(&cache_path).unwrap();
+        fs::write(cache_path.join("a"), program_binary_file(&[1])).unwrap();
+        fs::write(cache_path.join("b"), b"invalid").unwrap();
+        let mut invalid = program_binary_file(&[4]);
+        *invalid.last_mut().unwrap() ^= 0xff;
+        write_archive(
+            &cache_path.join(ARCHIVE_FILENAME),
+            &[("c".to_string(), program_binary_file(&[3])), ("d".to_string(), invalid)],
+        )
+        .unwrap();
+        fs::write(cache_path.join(WHITELIST_FILENAME), "a\nc").unwrap();
+        fs::write(cache_path.join(FINGERPRINT_FILENAME), "device").unwrap();
+        fs::write(cache_path.join(PARTITION_INDEX_FILENAME), "1").unwrap();
+
+        let entries = inspect_cache_dir(&cache_path).unwrap();
+        let summary = entries
+            .iter()
+            .map(|e| (e.digest.as_str(), e.is_valid(), e.in_whitelist, e.in_archive))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("a", true, true, false),
+                ("b", false, false, false),
+                ("c", true, true, true),
+                ("d", false, false, true),
+            ]
+        );
+
+        let copy_path = dir.0.join("copy");
+        assert_eq!(copy_cache_dir(&cache_path, &copy_path).unwrap(), 2);
+        assert_eq!(fs::read(copy_path.join("c")).unwrap(), program_binary_file(&[3]));
+        assert_eq!(read_whitelist(&copy_path), read_whitelist(&cache_path));
+        assert_eq!(read_to_string(copy_path.join(FINGERPRINT_FILENAME)).unwrap(), "device");
+
+        assert_eq!(prune_cache_dir(&cache_path).unwrap(), 2);
+        assert!(!cache_path.join("b").exists());
+        let digests = inspect_cache_dir(&cache_path)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.digest)
+            .collect::<Vec<String>>();
+        assert_eq!(digests, ["a", "c"]);
+        assert_eq!(prune_cache_dir(&cache_path).unwrap(), 0);
+    }
+
+    #[test]
+    fn startup_shaders_that_miss_the_deadline_load_on_demand() {
+        let dir = TempDir::new("deadline");
+        fs::write(dir.0.join("a"), program_binary_file(&[1])).unwrap();
+        fs::write(dir.0.join(WHITELIST_FILENAME), "a").unwrap();
+
+        // Keep the worker busy until the deadline has passed.
+        let workers = workers();
+        let (sender, receiver) = channel::<()>();
+        workers.spawn(move || {
+            let _ = receiver.recv();
+        });
+
+        let program_cache = ProgramCache::new(None);
+        let mut disk_cache = WrProgramBinaryDiskCache::new(dir.0.clone(), &workers, false);
+        disk_cache.max_load_time = Duration::from_millis(10);
+        disk_cache.try_load_startup_shaders_from_disk(&program_cache);
+        let stats = disk_cache.stats();
+        assert!(stats.startup_timed_out);
+        assert!(stats.startup_load_time_ms >= 10);
+        assert_eq!(stats.hits, 0);
+
+        drop(sender);
+        flush(&workers);
+        // The cancelled load didn't read the binary.
+        assert_eq!(disk_cache.stats().bytes_read, 0);
+        disk_cache.try_load_shader_from_disk("a", &program_cache);
+        assert_eq!(disk_cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn stats_count_hits_misses_and_failures() {
+        let dir = TempDir::new("stats");
+        let valid = program_binary_file(&[1]);
+        fs::write(dir.0.join("a"), &valid).unwrap();
+        fs::write(dir.0.join("b"), b"invalid").unwrap();
+        // "c" is whitelisted but missing.
+        fs::write(dir.0.join(WHITELIST_FILENAME), "a\nb\nc\n").unwrap();
+
+        let program_cache = ProgramCache::new(None);
+        let mut disk_cache = WrProgramBinaryDiskCache::new(dir.0.clone(), &workers(), false);
+        disk_cache.try_load_startup_shaders_from_disk(&program_cache);
+        let stats = disk_cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.invalid_size, 1);
+        assert_eq!(stats.invalid_hash + stats.invalid_magic + stats.invalid_bincode, 0);
+        assert_eq!(stats.bytes_read, valid.len() as u64 + 7);
+        assert!(!stats.startup_timed_out);
+        assert_eq!(disk_cache.quarantined_count(), 1);
+
+        // Binaries are only loaded once.
+        disk_cache.try_load_shader_from_disk("a", &program_cache);
+        assert_eq!(disk_cache.stats().hits, 1);
+        assert_eq!(disk_cache.stats().misses, 3);
+    }
+}
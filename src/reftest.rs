@@ -0,0 +1,314 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Comparison of rendered frames with reference images, for reftest-style tests.
+//!
+//! A test renders a display list with a `HeadlessWindow`, whose frames are read back
+//! with `api::read_back` like `wr_renderer_readback` does, or reads back the frame of
+//! another window itself, and checks it against a reference PNG with `check_frame`.
+//! Small differences, such as those caused by antialiasing, can be allowed with a
+//! `Fuzz`. When a frame doesn't match, an image of the differences can be written next
+//! to it.
+//!
+//! PNGs store colors with straight alpha, while frames are premultiplied, so they are
+//! converted when reading and writing PNGs.
+
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use api::{self, HeadlessWindow, RgbaFrame};
+use image_encoding::{encode_png, premultiply, unpremultiply};
+use webrender::api::units::DeviceIntSize;
+
+/// How much a frame may differ from its reference.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Fuzz {
+    /// The maximum difference of any channel of any pixel.
+    pub max_difference: u8,
+    /// The maximum number of pixels that differ.
+    pub max_pixels: usize,
+}
+
+impl Fuzz {
+    /// Allows no difference at all.
+    pub const EXACT: Fuzz = Fuzz {
+        max_difference: 0,
+        max_pixels: 0,
+    };
+}
+
+/// How a frame differs from its reference.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comparison {
+    /// The largest difference of any channel of any pixel.
+    pub max_difference: u8,
+    /// The number of pixels that differ.
+    pub num_differences: usize,
+    /// An image of the differences: pixels that differ are red, with an intensity
+    /// depending on how much they differ, and the others are a faded grayscale
+    /// version of the reference.
+    pub diff: RgbaFrame,
+}
+
+impl Comparison {
+    /// Returns whether the differences are within the given fuzz.
+    pub fn is_within(&self, fuzz: Fuzz) -> bool {
+        self.max_difference <= fuzz.max_difference && self.num_differences <= fuzz.max_pixels
+    }
+}
+
+/// The ways in which a reftest can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The frame couldn't be rendered.
+    Render(api::Error),
+    /// A PNG couldn't be read or written.
+    Io(io::Error),
+    /// The reference isn't a valid PNG.
+    Decode(png::DecodingError),
+    /// A PNG couldn't be encoded.
+    Encode(png::EncodingError),
+    /// The frame and its reference have different sizes.
+    SizeMismatch {
+        /// The size of the frame.
+        actual: DeviceIntSize,
+        /// The size of the reference.
+        expected: DeviceIntSize,
+    },
+    /// The frame differs from its reference by more than the fuzz.
+    Mismatch(Comparison),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Render(ref err) => write!(f, "{}", err),
+            Error::Io(ref err) => write!(f, "failed to access a PNG: {}", err),
+            Error::Decode(ref err) => write!(f, "failed to decode a PNG: {}", err),
+            Error::Encode(ref err) => write!(f, "failed to encode a PNG: {}", err),
+            Error::SizeMismatch { actual, expected } => write!(
+                f,
+                "the frame is {}x{} but its reference is {}x{}",
+                actual.width, actual.height, expected.width, expected.height
+            ),
+            Error::Mismatch(ref comparison) => write!(
+                f,
+                "{} pixels differ from the reference, by up to {}",
+                comparison.num_differences, comparison.max_difference
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<png::DecodingError> for Error {
+    fn from(err: png::DecodingError) -> Self {
+        Error::Decode(err)
+    }
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(err: png::EncodingError) -> Self {
+        Error::Encode(err)
+    }
+}
+
+/// Reads a PNG as a frame.
+pub fn read_png(path: &Path) -> Result<RgbaFrame, Error> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    // Palettes, low bit depths and transparency chunks are expanded to 8-bit channels.
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    buffer.truncate(info.buffer_size());
+
+    let mut pixels = match info.color_type {
+        png::ColorType::Rgba => buffer,
+        png::ColorType::Rgb => buffer.chunks(3).flat_map(|p| vec![p[0], p[1], p[2], 255]).collect(),
+        png::ColorType::GrayscaleAlpha => buffer.chunks(2).flat_map(|p| vec![p[0], p[0], p[0], p[1]]).collect(),
+        png::ColorType::Grayscale => buffer.iter().flat_map(|&p| vec![p, p, p, 255]).collect(),
+        // Expanded into RGB.
+        png::ColorType::Indexed => unreachable!(),
+    };
+    premultiply(&mut pixels);
+    Ok(RgbaFrame {
+        size: DeviceIntSize::new(info.width as i32, info.height as i32),
+        pixels,
+    })
+}
+
+/// Writes a frame as a PNG.
+pub fn write_png(frame: &RgbaFrame, path: &Path) -> Result<(), Error> {
+    let mut pixels = frame.pixels.clone();
+    unpremultiply(&mut pixels);
+    fs::write(path, encode_png(frame.size, &pixels)?)?;
+    Ok(())
+}
+
+/// Compares a frame with its reference.
+pub fn compare(actual: &RgbaFrame, expected: &RgbaFrame) -> Result<Comparison, Error> {
+    if actual.size != expected.size {
+        return Err(Error::SizeMismatch {
+            actual: actual.size,
+            expected: expected.size,
+        });
+    }
+
+    let mut max_difference = 0;
+    let mut num_differences = 0;
+    let mut diff = Vec::with_capacity(expected.pixels.len());
+    for (actual, expected) in actual.pixels.chunks(4).zip(expected.pixels.chunks(4)) {
+        let difference = actual
+            .iter()
+            .zip(expected)
+            .map(|(a, e)| (*a as i32 - *e as i32).abs() as u8)
+            .max()
+            .unwrap_or(0);
+        if difference > 0 {
+            max_difference = max_difference.max(difference);
+            num_differences += 1;
+            // Even the smallest differences should stand out.
+            diff.extend_from_slice(&[128 + difference / 2, 0, 0, 255]);
+        } else {
+            let luma = (expected[0] as u32 * 3 + expected[1] as u32 * 6 + expected[2] as u32) / 10;
+            let faded = (luma / 4) as u8;
+            diff.extend_from_slice(&[faded, faded, faded, 255]);
+        }
+    }
+
+    Ok(Comparison {
+        max_difference,
+        num_differences,
+        diff: RgbaFrame {
+            size: expected.size,
+            pixels: diff,
+        },
+    })
+}
+
+/// Checks a frame against a reference PNG.
+///
+/// If the frame differs by more than `fuzz`, the image of the differences is written
+/// to `diff_path`, if any, and `Error::Mismatch` is returned.
+pub fn check_frame(
+    frame: &RgbaFrame,
+    reference: &Path,
+    fuzz: Fuzz,
+    diff_path: Option<&Path>,
+) -> Result<Comparison, Error> {
+    let comparison = compare(frame, &read_png(reference)?)?;
+    if comparison.is_within(fuzz) {
+        return Ok(comparison);
+    }
+    if let Some(diff_path) = diff_path {
+        write_png(&comparison.diff, diff_path)?;
+    }
+    Err(Error::Mismatch(comparison))
+}
+
+/// Renders the frame requested by the last transaction sent to a window, and checks
+/// it against a reference PNG like `check_frame`.
+pub fn reftest(
+    window: &mut HeadlessWindow,
+    reference: &Path,
+    fuzz: Fuzz,
+    diff_path: Option<&Path>,
+) -> Result<Comparison, Error> {
+    let frame = window.render().map_err(Error::Render)?;
+    check_frame(&frame, reference, fuzz, diff_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::{make_transaction, DisplayListBuilder};
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+    use webrender::api::units::{LayoutRect, LayoutSize};
+    use webrender::api::{ColorF, Epoch, PipelineId, RenderReasons};
+
+    fn frame(pixels: &[[u8; 4]]) -> RgbaFrame {
+        RgbaFrame {
+            size: DeviceIntSize::new(pixels.len() as i32, 1),
+            pixels: pixels.iter().flatten().cloned().collect(),
+        }
+    }
+
+    #[test]
+    fn fuzz_bounds_differences() {
+        let expected = frame(&[[255, 0, 0, 255], [0, 0, 0, 0], [64, 64, 64, 128]]);
+        let actual = frame(&[[255, 0, 0, 255], [2, 0, 0, 2], [64, 63, 64, 128]]);
+
+        let comparison = compare(&actual, &expected).unwrap();
+        assert_eq!(comparison.max_difference, 2);
+        assert_eq!(comparison.num_differences, 2);
+        assert_eq!(comparison.diff.pixels[3], 255);
+        assert_eq!(comparison.diff.pixels[4..8], [129, 0, 0, 255]);
+        assert!(!comparison.is_within(Fuzz::EXACT));
+        assert!(!comparison.is_within(Fuzz {
+            max_difference: 2,
+            max_pixels: 1,
+        }));
+        assert!(comparison.is_within(Fuzz {
+            max_difference: 2,
+            max_pixels: 2,
+        }));
+
+        match compare(&frame(&[[0; 4]]), &expected) {
+            Err(Error::SizeMismatch { .. }) => {},
+            other => panic!("unexpected comparison {:?}", other),
+        }
+    }
+
+    #[test]
+    fn pngs_round_trip_premultiplied_frames() {
+        let dir = env::temp_dir().join(format!("wr-reftest-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("frame.png");
+
+        let expected = frame(&[[255, 0, 0, 255], [0, 0, 0, 0], [64, 32, 0, 128]]);
+        write_png(&expected, &path).unwrap();
+        assert_eq!(read_png(&path).unwrap(), expected);
+        assert!(check_frame(&expected, &path, Fuzz::EXACT, None).is_ok());
+
+        let diff_path = dir.join("diff.png");
+        let actual = frame(&[[255, 0, 0, 255], [0, 0, 0, 0], [64, 32, 1, 128]]);
+        match check_frame(&actual, &path, Fuzz::EXACT, Some(&diff_path)) {
+            Err(Error::Mismatch(comparison)) => assert_eq!(read_png(&diff_path).unwrap(), comparison.diff),
+            other => panic!("unexpected check {:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn headless_windows_match_their_reference() {
+        let mut window = api::headless_window(DeviceIntSize::new(16, 8));
+
+        let pipeline_id = PipelineId(0, 1);
+        let mut builder = DisplayListBuilder::new(pipeline_id);
+        let rect = LayoutRect::from_size(LayoutSize::new(8.0, 4.0));
+        builder.push_rect(rect, rect, ColorF::new(1.0, 0.0, 0.0, 1.0));
+        let mut txn = make_transaction(false);
+        txn.set_root_pipeline(pipeline_id);
+        txn.set_display_list(Epoch(0), builder.build());
+        txn.generate_frame(0, RenderReasons::TESTING);
+        window.document().send_transaction(txn);
+
+        let reference = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/reftests/red-rect.png");
+        let comparison = reftest(&mut window, &reference, Fuzz::EXACT, None).unwrap();
+        assert_eq!(comparison.num_differences, 0);
+    }
+}
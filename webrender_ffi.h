@@ -24,6 +24,7 @@ bool is_glcontext_angle(void* glcontext_ptr);
 bool gfx_use_wrench();
 const char* gfx_wr_resource_path_override();
 bool gfx_wr_use_optimized_shaders();
+const char* gfx_wr_blob_recording_dir();
 void gfx_critical_note(const char* msg);
 void gfx_critical_error(const char* msg);
 void gecko_printf_stderr_output(const char* msg);